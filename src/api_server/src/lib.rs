@@ -371,7 +371,7 @@ mod tests {
     use vmm::rpc_interface::VmmActionError;
     use vmm::seccomp_filters::{get_filters, SeccompConfig};
     use vmm::vmm_config::instance_info::InstanceInfo;
-    use vmm::vmm_config::snapshot::CreateSnapshotParams;
+    use vmm::vmm_config::snapshot::{CreateSnapshotParams, MemFileFormat, SnapshotDestinationType};
 
     #[test]
     fn test_error_messages() {
@@ -439,7 +439,10 @@ mod tests {
                 snapshot_type: SnapshotType::Diff,
                 snapshot_path: PathBuf::new(),
                 mem_file_path: PathBuf::new(),
+                destination_type: SnapshotDestinationType::File,
+                mem_file_format: MemFileFormat::Raw,
                 version: None,
+                integrity_key: None,
             })),
             start_time_us,
         );
@@ -453,7 +456,10 @@ mod tests {
                 snapshot_type: SnapshotType::Diff,
                 snapshot_path: PathBuf::new(),
                 mem_file_path: PathBuf::new(),
+                destination_type: SnapshotDestinationType::File,
+                mem_file_format: MemFileFormat::Raw,
                 version: None,
+                integrity_key: None,
             })),
             start_time_us,
         );
@@ -8,6 +8,7 @@ use super::VmmData;
 use crate::request::actions::parse_put_actions;
 use crate::request::balloon::{parse_get_balloon, parse_patch_balloon, parse_put_balloon};
 use crate::request::boot_source::parse_put_boot_source;
+use crate::request::dirty_pages::{parse_get_dirty_pages, parse_put_dirty_pages};
 use crate::request::drive::{parse_patch_drive, parse_put_drive};
 use crate::request::instance_info::parse_get_instance_info;
 use crate::request::logger::parse_put_logger;
@@ -15,10 +16,13 @@ use crate::request::machine_configuration::{
     parse_get_machine_config, parse_patch_machine_config, parse_put_machine_config,
 };
 use crate::request::metrics::parse_put_metrics;
+use crate::request::migration::parse_put_migration;
 use crate::request::mmds::{parse_get_mmds, parse_patch_mmds, parse_put_mmds};
 use crate::request::net::{parse_patch_net, parse_put_net};
+use crate::request::shared_memory::parse_put_shared_memory;
 use crate::request::snapshot::parse_patch_vm_state;
 use crate::request::snapshot::parse_put_snapshot;
+use crate::request::vcpu_hotplug::parse_put_vcpus;
 use crate::request::vsock::parse_put_vsock;
 use crate::ApiServer;
 use micro_http::{Body, Method, Request, Response, StatusCode, Version};
@@ -59,6 +63,7 @@ impl ParsedRequest {
         match (request.method(), path, request.body.as_ref()) {
             (Method::Get, "", None) => parse_get_instance_info(),
             (Method::Get, "balloon", None) => parse_get_balloon(path_tokens.get(1)),
+            (Method::Get, "dirty-pages", None) => parse_get_dirty_pages(),
             (Method::Get, "vm", None) if path_tokens.get(1) == Some(&"config") => {
                 Ok(ParsedRequest::new_sync(VmmAction::GetFullVmConfig))
             }
@@ -68,16 +73,20 @@ impl ParsedRequest {
             (Method::Put, "actions", Some(body)) => parse_put_actions(body),
             (Method::Put, "balloon", Some(body)) => parse_put_balloon(body),
             (Method::Put, "boot-source", Some(body)) => parse_put_boot_source(body),
+            (Method::Put, "dirty-pages", Some(body)) => parse_put_dirty_pages(body),
             (Method::Put, "drives", Some(body)) => parse_put_drive(body, path_tokens.get(1)),
             (Method::Put, "logger", Some(body)) => parse_put_logger(body),
             (Method::Put, "machine-config", Some(body)) => parse_put_machine_config(body),
             (Method::Put, "metrics", Some(body)) => parse_put_metrics(body),
+            (Method::Put, "migration", Some(body)) => parse_put_migration(body, path_tokens.get(1)),
             (Method::Put, "mmds", Some(body)) => parse_put_mmds(body, path_tokens.get(1)),
             (Method::Put, "network-interfaces", Some(body)) => {
                 parse_put_net(body, path_tokens.get(1))
             }
+            (Method::Put, "shared-memory", Some(body)) => parse_put_shared_memory(body),
             (Method::Put, "shutdown-internal", None) => Ok(ParsedRequest::ShutdownInternal),
             (Method::Put, "snapshot", Some(body)) => parse_put_snapshot(body, path_tokens.get(1)),
+            (Method::Put, "vcpus", Some(body)) => parse_put_vcpus(body),
             (Method::Put, "vsock", Some(body)) => parse_put_vsock(body),
             (Method::Put, _, None) => method_to_error(Method::Put),
             (Method::Patch, "balloon", Some(body)) => parse_patch_balloon(body, path_tokens.get(1)),
@@ -121,6 +130,7 @@ impl ParsedRequest {
                     Self::success_response_with_data(balloon_config)
                 }
                 VmmData::BalloonStats(stats) => Self::success_response_with_data(stats),
+                VmmData::DirtyPageStats(stats) => Self::success_response_with_data(stats),
                 VmmData::InstanceInformation(info) => Self::success_response_with_data(info),
                 VmmData::FullVmConfig(config) => Self::success_response_with_data(config),
             },
@@ -162,6 +172,10 @@ fn log_received_api_request(api_description: String) {
 fn describe(method: Method, path: &str, body: Option<&Body>) -> String {
     match (path, body) {
         ("/mmds", Some(_)) | (_, None) => format!("{:?} request on {:?}", method, path),
+        // The integrity key of the snapshot files is a secret, which must not be logged.
+        (_, Some(value)) if path.starts_with("/snapshot/") && has_integrity_key(value) => {
+            format!("{:?} request on {:?}", method, path)
+        }
         (_, Some(value)) => format!(
             "{:?} request on {:?} with body {:?}",
             method,
@@ -173,6 +187,12 @@ fn describe(method: Method, path: &str, body: Option<&Body>) -> String {
     }
 }
 
+fn has_integrity_key(body: &Body) -> bool {
+    body.body
+        .windows(b"integrity_key".len())
+        .any(|window| window == b"integrity_key")
+}
+
 /// Generates a `GenericError` for each request method.
 pub(crate) fn method_to_error(method: Method) -> Result<ParsedRequest, Error> {
     match method {
@@ -271,6 +291,7 @@ pub(crate) mod tests {
     use vmm::resources::VmmConfig;
     use vmm::rpc_interface::VmmActionError;
     use vmm::vmm_config::balloon::{BalloonDeviceConfig, BalloonStats};
+    use vmm::vmm_config::dirty_pages::DirtyPageStats;
     use vmm::vmm_config::instance_info::InstanceInfo;
     use vmm::vmm_config::machine_config::VmConfig;
 
@@ -490,6 +511,14 @@ pub(crate) mod tests {
             describe(Method::Put, "path", Some(&Body::new("body"))),
             "Put request on \"path\" with body \"body\""
         );
+        assert_eq!(
+            describe(
+                Method::Put,
+                "/snapshot/load",
+                Some(&Body::new("{\"integrity_key\": \"00ff\"}"))
+            ),
+            "Put request on \"/snapshot/load\""
+        );
     }
 
     #[test]
@@ -504,6 +533,9 @@ pub(crate) mod tests {
                 VmmData::BalloonStats(stats) => {
                     http_response(&serde_json::to_string(stats).unwrap(), 200)
                 }
+                VmmData::DirtyPageStats(stats) => {
+                    http_response(&serde_json::to_string(stats).unwrap(), 200)
+                }
                 VmmData::Empty => http_response("", 204),
                 VmmData::FullVmConfig(cfg) => {
                     http_response(&serde_json::to_string(cfg).unwrap(), 200)
@@ -526,6 +558,7 @@ pub(crate) mod tests {
             swap_out: Some(1),
             ..Default::default()
         }));
+        verify_ok_response_with(VmmData::DirtyPageStats(DirtyPageStats::default()));
         verify_ok_response_with(VmmData::Empty);
         verify_ok_response_with(VmmData::FullVmConfig(VmmConfig::default()));
         verify_ok_response_with(VmmData::MachineConfiguration(VmConfig::default()));
@@ -578,6 +611,18 @@ pub(crate) mod tests {
         assert!(ParsedRequest::try_from_request(&req).is_ok());
     }
 
+    #[test]
+    fn test_try_from_get_dirty_pages() {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let mut connection = HttpConnection::new(receiver);
+        sender
+            .write_all(http_request("GET", "/dirty-pages", None).as_bytes())
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert!(ParsedRequest::try_from_request(&req).is_ok());
+    }
+
     #[test]
     fn test_try_from_get_machine_config() {
         let (mut sender, receiver) = UnixStream::pair().unwrap();
@@ -650,6 +695,51 @@ pub(crate) mod tests {
         assert!(ParsedRequest::try_from_request(&req).is_ok());
     }
 
+    #[test]
+    fn test_try_from_put_dirty_pages() {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let mut connection = HttpConnection::new(receiver);
+        let body = "{ \
+            \"reset\": true \
+            }";
+        sender
+            .write_all(http_request("PUT", "/dirty-pages", Some(&body)).as_bytes())
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert!(ParsedRequest::try_from_request(&req).is_ok());
+    }
+
+    #[test]
+    fn test_try_from_put_shared_memory() {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let mut connection = HttpConnection::new(receiver);
+        let body = "{ \
+            \"socket_path\": \"string\" \
+            }";
+        sender
+            .write_all(http_request("PUT", "/shared-memory", Some(&body)).as_bytes())
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert!(ParsedRequest::try_from_request(&req).is_ok());
+    }
+
+    #[test]
+    fn test_try_from_put_vcpus() {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let mut connection = HttpConnection::new(receiver);
+        let body = "{ \
+            \"vcpu_count\": 2 \
+            }";
+        sender
+            .write_all(http_request("PUT", "/vcpus", Some(&body)).as_bytes())
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert!(ParsedRequest::try_from_request(&req).is_ok());
+    }
+
     #[test]
     fn test_try_from_put_drives() {
         let (mut sender, receiver) = UnixStream::pair().unwrap();
@@ -760,6 +850,7 @@ pub(crate) mod tests {
             \"guest_mac\": \"12:34:56:78:9a:BC\", \
             \"host_dev_name\": \"string\", \
             \"allow_mmds_requests\": true, \
+            \"num_queue_pairs\": 2, \
             \"rx_rate_limiter\": { \
                 \"bandwidth\": { \
                     \"size\": 0, \
@@ -0,0 +1,59 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use super::super::VmmAction;
+use crate::parsed_request::{Error, ParsedRequest};
+use crate::request::Body;
+use vmm::vmm_config::dirty_pages::DirtyPageStatsParams;
+
+pub(crate) fn parse_get_dirty_pages() -> Result<ParsedRequest, Error> {
+    // Getting the statistics keeps the dirty page log.
+    Ok(ParsedRequest::new_sync(VmmAction::GetDirtyPageStats(
+        DirtyPageStatsParams { reset: false },
+    )))
+}
+
+pub(crate) fn parse_put_dirty_pages(body: &Body) -> Result<ParsedRequest, Error> {
+    Ok(ParsedRequest::new_sync(VmmAction::GetDirtyPageStats(
+        serde_json::from_slice::<DirtyPageStatsParams>(body.raw()).map_err(Error::SerdeJson)?,
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsed_request::tests::vmm_action_from_request;
+
+    #[test]
+    fn test_parse_get_dirty_pages_request() {
+        match vmm_action_from_request(parse_get_dirty_pages().unwrap()) {
+            VmmAction::GetDirtyPageStats(params) => assert!(!params.reset),
+            _ => panic!("Test failed."),
+        }
+    }
+
+    #[test]
+    fn test_parse_put_dirty_pages_request() {
+        let body = r#"{
+                "reset": true
+              }"#;
+        match vmm_action_from_request(parse_put_dirty_pages(&Body::new(body)).unwrap()) {
+            VmmAction::GetDirtyPageStats(params) => assert!(params.reset),
+            _ => panic!("Test failed."),
+        }
+
+        match vmm_action_from_request(parse_put_dirty_pages(&Body::new("{}")).unwrap()) {
+            VmmAction::GetDirtyPageStats(params) => assert!(!params.reset),
+            _ => panic!("Test failed."),
+        }
+
+        let body = r#"{
+                "reset": "true"
+              }"#;
+        assert!(parse_put_dirty_pages(&Body::new(body)).is_err());
+        let body = r#"{
+                "invalid_field": true
+              }"#;
+        assert!(parse_put_dirty_pages(&Body::new(body)).is_err());
+    }
+}
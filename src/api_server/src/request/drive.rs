@@ -65,15 +65,18 @@ pub(crate) fn parse_patch_drive(
 
     // Validate request - we need to have at least one parameter set:
     // - path_on_host
+    // - overlay_path_on_host
     // - rate_limiter
     if block_device_update_cfg.path_on_host.is_none()
+        && block_device_update_cfg.overlay_path_on_host.is_none()
         && block_device_update_cfg.rate_limiter.is_none()
     {
         METRICS.patch_api_requests.drive_fails.inc();
         return Err(Error::Generic(
             StatusCode::BadRequest,
             String::from(
-                "Please specify at least one property to patch: path_on_host, rate_limiter.",
+                "Please specify at least one property to patch: path_on_host, \
+                 overlay_path_on_host, rate_limiter.",
             ),
         ));
     }
@@ -181,6 +184,20 @@ mod tests {
         // Validate that updating just the ratelimiter works.
         assert!(parse_patch_drive(&Body::new(body), Some(&"foo")).is_ok());
 
+        let body = r#"{
+            "drive_id": "foo",
+            "overlay_path_on_host": "/overlay"
+        }"#;
+        // Validate that updating just the overlay path works.
+        #[allow(clippy::match_wild_err_arm)]
+        match vmm_action_from_request(parse_patch_drive(&Body::new(body), Some(&"foo")).unwrap()) {
+            VmmAction::UpdateBlockDevice(cfg) => {
+                assert_eq!(cfg.path_on_host, None);
+                assert_eq!(cfg.overlay_path_on_host.unwrap(), "/overlay".to_string());
+            }
+            _ => panic!("Test failed: Invalid parameters"),
+        };
+
         let body = r#"{
             "drive_id": "foo",
             "path_on_host": "/there",
@@ -46,8 +46,10 @@ pub(crate) fn parse_patch_machine_config(body: &Body) -> Result<ParsedRequest, E
     check_unsupported_fields(&vm_config)?;
 
     if vm_config.vcpu_count.is_none()
+        && vm_config.max_vcpu_count.is_none()
         && vm_config.mem_size_mib.is_none()
         && vm_config.cpu_template.is_none()
+        && vm_config.custom_cpu_template_path.is_none()
         && vm_config.ht_enabled.is_none()
     {
         return method_to_error(Method::Patch);
@@ -60,7 +62,7 @@ pub(crate) fn parse_patch_machine_config(body: &Body) -> Result<ParsedRequest, E
 fn check_unsupported_fields(_vm_config: &VmConfig) -> Result<(), Error> {
     #[cfg(target_arch = "aarch64")]
     {
-        if _vm_config.cpu_template.is_some() {
+        if _vm_config.cpu_template.is_some() || _vm_config.custom_cpu_template_path.is_some() {
             // cpu_template is not supported on aarch64
             return Err(Error::Generic(
                 StatusCode::BadRequest,
@@ -75,6 +77,7 @@ fn check_unsupported_fields(_vm_config: &VmConfig) -> Result<(), Error> {
 mod tests {
     use super::*;
     use crate::parsed_request::tests::vmm_action_from_request;
+    use vmm::vmm_config::machine_config::HugePageConfig;
 
     #[test]
     fn test_parse_get_machine_config_request() {
@@ -116,10 +119,14 @@ mod tests {
               }"#;
         let expected_config = VmConfig {
             vcpu_count: Some(8),
+            max_vcpu_count: None,
             mem_size_mib: Some(1024),
             ht_enabled: Some(true),
             cpu_template: None,
+            custom_cpu_template_path: None,
             track_dirty_pages: true,
+            huge_pages: HugePageConfig::None,
+            shared_memory: false,
         };
 
         match vmm_action_from_request(parse_put_machine_config(&Body::new(body)).unwrap()) {
@@ -127,7 +134,39 @@ mod tests {
             _ => panic!("Test failed."),
         }
 
-        // 4. Test that applying a CPU template is successful on x86_64 while on aarch64, it is not.
+        let body = r#"{
+                "vcpu_count": 8,
+                "max_vcpu_count": 16,
+                "mem_size_mib": 1024,
+                "ht_enabled": false
+              }"#;
+        match vmm_action_from_request(parse_put_machine_config(&Body::new(body)).unwrap()) {
+            VmmAction::SetVmConfiguration(config) => assert_eq!(config.max_vcpu_count, Some(16)),
+            _ => panic!("Test failed."),
+        }
+
+        // 4. Test that the guest memory can be backed by huge pages.
+        let body = r#"{
+                "vcpu_count": 8,
+                "mem_size_mib": 1024,
+                "ht_enabled": true,
+                "huge_pages": "2M"
+              }"#;
+        match vmm_action_from_request(parse_put_machine_config(&Body::new(body)).unwrap()) {
+            VmmAction::SetVmConfiguration(config) => {
+                assert_eq!(config.huge_pages, HugePageConfig::Hugetlbfs2M)
+            }
+            _ => panic!("Test failed."),
+        }
+        let body = r#"{
+                "vcpu_count": 8,
+                "mem_size_mib": 1024,
+                "ht_enabled": true,
+                "huge_pages": "1G"
+              }"#;
+        assert!(parse_put_machine_config(&Body::new(body)).is_err());
+
+        // 5. Test that applying a CPU template is successful on x86_64 while on aarch64, it is not.
         let body = r#"{
                 "vcpu_count": 8,
                 "mem_size_mib": 1024,
@@ -141,10 +180,14 @@ mod tests {
             use vmm::vmm_config::machine_config::CpuFeaturesTemplate;
             let expected_config = VmConfig {
                 vcpu_count: Some(8),
+                max_vcpu_count: None,
                 mem_size_mib: Some(1024),
                 ht_enabled: Some(true),
                 cpu_template: Some(CpuFeaturesTemplate::T2),
+                custom_cpu_template_path: None,
                 track_dirty_pages: true,
+                huge_pages: HugePageConfig::None,
+                shared_memory: false,
             };
 
             match vmm_action_from_request(parse_put_machine_config(&Body::new(body)).unwrap()) {
@@ -157,6 +200,25 @@ mod tests {
         {
             assert!(parse_put_machine_config(&Body::new(body)).is_err());
         }
+
+        // 6. Test that a custom CPU template is only accepted on x86_64.
+        let body = r#"{
+                "vcpu_count": 8,
+                "mem_size_mib": 1024,
+                "ht_enabled": true,
+                "custom_cpu_template_path": "/tmp/template.json"
+              }"#;
+
+        #[cfg(target_arch = "x86_64")]
+        match vmm_action_from_request(parse_put_machine_config(&Body::new(body)).unwrap()) {
+            VmmAction::SetVmConfiguration(config) => assert_eq!(
+                config.custom_cpu_template_path,
+                Some(std::path::PathBuf::from("/tmp/template.json"))
+            ),
+            _ => panic!("Test failed."),
+        }
+        #[cfg(target_arch = "aarch64")]
+        assert!(parse_put_machine_config(&Body::new(body)).is_err());
     }
 
     #[test]
@@ -180,6 +242,14 @@ mod tests {
         #[cfg(target_arch = "x86_64")]
         assert!(parse_patch_machine_config(&Body::new(body)).is_ok());
 
+        let body = r#"{
+                "custom_cpu_template_path": "/tmp/template.json"
+              }"#;
+        #[cfg(target_arch = "aarch64")]
+        assert!(parse_patch_machine_config(&Body::new(body)).is_err());
+        #[cfg(target_arch = "x86_64")]
+        assert!(parse_patch_machine_config(&Body::new(body)).is_ok());
+
         let body = r#"{
                 "vcpu_count": 8,
                 "mem_size_mib": 1024
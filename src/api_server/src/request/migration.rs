@@ -0,0 +1,119 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use super::super::VmmAction;
+use crate::parsed_request::{Error, ParsedRequest};
+use crate::request::Body;
+use crate::request::{Method, StatusCode};
+use vmm::vmm_config::migration::{ReceiveMigrationParams, SendMigrationParams};
+
+pub(crate) fn parse_put_migration(
+    body: &Body,
+    request_type_from_path: Option<&&str>,
+) -> Result<ParsedRequest, Error> {
+    match request_type_from_path {
+        Some(&request_type) => match request_type {
+            "send" => Ok(ParsedRequest::new_sync(VmmAction::SendMigration(
+                serde_json::from_slice::<SendMigrationParams>(body.raw())
+                    .map_err(Error::SerdeJson)?,
+            ))),
+            "receive" => Ok(ParsedRequest::new_sync(VmmAction::ReceiveMigration(
+                serde_json::from_slice::<ReceiveMigrationParams>(body.raw())
+                    .map_err(Error::SerdeJson)?,
+            ))),
+            _ => Err(Error::InvalidPathMethod(
+                format!("/migration/{}", request_type),
+                Method::Put,
+            )),
+        },
+        None => Err(Error::Generic(
+            StatusCode::BadRequest,
+            "Missing migration operation type.".to_string(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsed_request::tests::vmm_action_from_request;
+
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_parse_put_migration() {
+        let mut body = r#"{
+                "socket_path": "foo"
+              }"#;
+
+        let expected_send = SendMigrationParams {
+            socket_path: PathBuf::from("foo"),
+            max_iterations: None,
+            version: None,
+        };
+        match vmm_action_from_request(parse_put_migration(&Body::new(body), Some(&"send")).unwrap())
+        {
+            VmmAction::SendMigration(cfg) => assert_eq!(cfg, expected_send),
+            _ => panic!("Test failed."),
+        }
+
+        let expected_receive = ReceiveMigrationParams {
+            socket_path: PathBuf::from("foo"),
+            enable_diff_snapshots: false,
+            network_overrides: vec![],
+            drive_path_overrides: HashMap::new(),
+        };
+        match vmm_action_from_request(
+            parse_put_migration(&Body::new(body), Some(&"receive")).unwrap(),
+        ) {
+            VmmAction::ReceiveMigration(cfg) => assert_eq!(cfg, expected_receive),
+            _ => panic!("Test failed."),
+        }
+
+        body = r#"{
+                "socket_path": "foo",
+                "max_iterations": 3,
+                "version": "0.25.0"
+              }"#;
+
+        let expected_send = SendMigrationParams {
+            socket_path: PathBuf::from("foo"),
+            max_iterations: Some(3),
+            version: Some(String::from("0.25.0")),
+        };
+        match vmm_action_from_request(parse_put_migration(&Body::new(body), Some(&"send")).unwrap())
+        {
+            VmmAction::SendMigration(cfg) => assert_eq!(cfg, expected_send),
+            _ => panic!("Test failed."),
+        }
+        // The receive parameters don't have these fields.
+        assert!(parse_put_migration(&Body::new(body), Some(&"receive")).is_err());
+
+        body = r#"{
+                "socket_path": "foo",
+                "enable_diff_snapshots": true,
+                "drive_path_overrides": {
+                    "rootfs": "/srv/rootfs.ext4"
+                }
+              }"#;
+
+        let expected_receive = ReceiveMigrationParams {
+            socket_path: PathBuf::from("foo"),
+            enable_diff_snapshots: true,
+            network_overrides: vec![],
+            drive_path_overrides: vec![(String::from("rootfs"), String::from("/srv/rootfs.ext4"))]
+                .into_iter()
+                .collect(),
+        };
+        match vmm_action_from_request(
+            parse_put_migration(&Body::new(body), Some(&"receive")).unwrap(),
+        ) {
+            VmmAction::ReceiveMigration(cfg) => assert_eq!(cfg, expected_receive),
+            _ => panic!("Test failed."),
+        }
+
+        assert!(parse_put_migration(&Body::new(body), Some(&"invalid")).is_err());
+        assert!(parse_put_migration(&Body::new(body), None).is_err());
+    }
+}
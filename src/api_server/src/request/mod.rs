@@ -4,14 +4,18 @@
 pub mod actions;
 pub mod balloon;
 pub mod boot_source;
+pub mod dirty_pages;
 pub mod drive;
 pub mod instance_info;
 pub mod logger;
 pub mod machine_configuration;
 pub mod metrics;
+pub mod migration;
 pub mod mmds;
 pub mod net;
+pub mod shared_memory;
 pub mod snapshot;
+pub mod vcpu_hotplug;
 pub mod vsock;
 pub use micro_http::{
     Body, HttpServer, Method, Request, RequestError, Response, StatusCode, Version,
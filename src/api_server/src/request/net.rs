@@ -117,7 +117,9 @@ mod tests {
                 "rx_rate_limiter": {
                 },
                 "tx_rate_limiter": {
-                }
+                },
+                "guest_mac": "12:34:56:78:9A:BC",
+                "link_up": false
         }"#;
         // 1. Exercise infamous "The id from the path does not match id from the body!".
         assert!(parse_patch_net(&Body::new(body), Some(&"bar")).is_err());
@@ -0,0 +1,41 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use super::super::VmmAction;
+use crate::parsed_request::{Error, ParsedRequest};
+use crate::request::Body;
+use vmm::vmm_config::shared_memory::SharedMemoryParams;
+
+pub(crate) fn parse_put_shared_memory(body: &Body) -> Result<ParsedRequest, Error> {
+    Ok(ParsedRequest::new_sync(VmmAction::ShareGuestMemory(
+        serde_json::from_slice::<SharedMemoryParams>(body.raw()).map_err(Error::SerdeJson)?,
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsed_request::tests::vmm_action_from_request;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_parse_put_shared_memory_request() {
+        let body = r#"{
+                "socket_path": "foo"
+              }"#;
+        let expected_params = SharedMemoryParams {
+            socket_path: PathBuf::from("foo"),
+        };
+        match vmm_action_from_request(parse_put_shared_memory(&Body::new(body)).unwrap()) {
+            VmmAction::ShareGuestMemory(params) => assert_eq!(params, expected_params),
+            _ => panic!("Test failed."),
+        }
+
+        assert!(parse_put_shared_memory(&Body::new("{}")).is_err());
+        let body = r#"{
+                "socket_path": "foo",
+                "invalid_field": true
+              }"#;
+        assert!(parse_put_shared_memory(&Body::new(body)).is_err());
+    }
+}
@@ -5,7 +5,7 @@ use super::super::VmmAction;
 use crate::parsed_request::{Error, ParsedRequest};
 use crate::request::Body;
 use crate::request::{Method, StatusCode};
-use vmm::vmm_config::snapshot::{CreateSnapshotParams, LoadSnapshotParams};
+use vmm::vmm_config::snapshot::{CheckSnapshotParams, CreateSnapshotParams, LoadSnapshotParams};
 use vmm::vmm_config::snapshot::{Vm, VmState};
 
 pub(crate) fn parse_put_snapshot(
@@ -14,6 +14,10 @@ pub(crate) fn parse_put_snapshot(
 ) -> Result<ParsedRequest, Error> {
     match request_type_from_path {
         Some(&request_type) => match request_type {
+            "check" => Ok(ParsedRequest::new_sync(VmmAction::CheckSnapshot(
+                serde_json::from_slice::<CheckSnapshotParams>(body.raw())
+                    .map_err(Error::SerdeJson)?,
+            ))),
             "create" => Ok(ParsedRequest::new_sync(VmmAction::CreateSnapshot(
                 serde_json::from_slice::<CreateSnapshotParams>(body.raw())
                     .map_err(Error::SerdeJson)?,
@@ -50,8 +54,14 @@ mod tests {
 
     #[test]
     fn test_parse_put_snapshot() {
+        use std::collections::HashMap;
         use std::path::PathBuf;
+        use utils::net::mac::MacAddr;
         use vmm::vmm_config::snapshot::SnapshotType;
+        use vmm::vmm_config::snapshot::{
+            MemBackendConfig, MemBackendType, MemFileFormat, NetworkOverride,
+            SnapshotDestinationType,
+        };
 
         let mut body = r#"{
                 "snapshot_type": "Diff",
@@ -64,7 +74,10 @@ mod tests {
             snapshot_type: SnapshotType::Diff,
             snapshot_path: PathBuf::from("foo"),
             mem_file_path: PathBuf::from("bar"),
+            destination_type: SnapshotDestinationType::File,
+            mem_file_format: MemFileFormat::Raw,
             version: Some(String::from("0.23.0")),
+            integrity_key: None,
         };
 
         match vmm_action_from_request(
@@ -83,7 +96,57 @@ mod tests {
             snapshot_type: SnapshotType::Full,
             snapshot_path: PathBuf::from("foo"),
             mem_file_path: PathBuf::from("bar"),
+            destination_type: SnapshotDestinationType::File,
+            mem_file_format: MemFileFormat::Raw,
+            version: None,
+            integrity_key: None,
+        };
+
+        match vmm_action_from_request(
+            parse_put_snapshot(&Body::new(body), Some(&"create")).unwrap(),
+        ) {
+            VmmAction::CreateSnapshot(cfg) => assert_eq!(cfg, expected_cfg),
+            _ => panic!("Test failed."),
+        }
+
+        body = r#"{
+                "snapshot_path": "foo",
+                "mem_file_path": "bar",
+                "mem_file_format": "Compressed",
+                "integrity_key": "00ff"
+              }"#;
+
+        expected_cfg = CreateSnapshotParams {
+            snapshot_type: SnapshotType::Full,
+            snapshot_path: PathBuf::from("foo"),
+            mem_file_path: PathBuf::from("bar"),
+            destination_type: SnapshotDestinationType::File,
+            mem_file_format: MemFileFormat::Compressed,
             version: None,
+            integrity_key: Some(String::from("00ff")),
+        };
+
+        match vmm_action_from_request(
+            parse_put_snapshot(&Body::new(body), Some(&"create")).unwrap(),
+        ) {
+            VmmAction::CreateSnapshot(cfg) => assert_eq!(cfg, expected_cfg),
+            _ => panic!("Test failed."),
+        }
+
+        body = r#"{
+                "snapshot_path": "foo.sock",
+                "mem_file_path": "bar.sock",
+                "destination_type": "UnixSocket"
+              }"#;
+
+        expected_cfg = CreateSnapshotParams {
+            snapshot_type: SnapshotType::Full,
+            snapshot_path: PathBuf::from("foo.sock"),
+            mem_file_path: PathBuf::from("bar.sock"),
+            destination_type: SnapshotDestinationType::UnixSocket,
+            mem_file_format: MemFileFormat::Raw,
+            version: None,
+            integrity_key: None,
         };
 
         match vmm_action_from_request(
@@ -107,9 +170,13 @@ mod tests {
 
         let mut expected_cfg = LoadSnapshotParams {
             snapshot_path: PathBuf::from("foo"),
-            mem_file_path: PathBuf::from("bar"),
+            mem_file_path: Some(PathBuf::from("bar")),
+            mem_backend: None,
             enable_diff_snapshots: false,
             resume_vm: false,
+            network_overrides: vec![],
+            drive_path_overrides: HashMap::new(),
+            integrity_key: None,
         };
         match vmm_action_from_request(parse_put_snapshot(&Body::new(body), Some(&"load")).unwrap())
         {
@@ -125,9 +192,13 @@ mod tests {
 
         expected_cfg = LoadSnapshotParams {
             snapshot_path: PathBuf::from("foo"),
-            mem_file_path: PathBuf::from("bar"),
+            mem_file_path: Some(PathBuf::from("bar")),
+            mem_backend: None,
             enable_diff_snapshots: true,
             resume_vm: false,
+            network_overrides: vec![],
+            drive_path_overrides: HashMap::new(),
+            integrity_key: None,
         };
 
         match vmm_action_from_request(parse_put_snapshot(&Body::new(body), Some(&"load")).unwrap())
@@ -144,9 +215,82 @@ mod tests {
 
         expected_cfg = LoadSnapshotParams {
             snapshot_path: PathBuf::from("foo"),
-            mem_file_path: PathBuf::from("bar"),
+            mem_file_path: Some(PathBuf::from("bar")),
+            mem_backend: None,
             enable_diff_snapshots: false,
             resume_vm: true,
+            network_overrides: vec![],
+            drive_path_overrides: HashMap::new(),
+            integrity_key: None,
+        };
+
+        match vmm_action_from_request(parse_put_snapshot(&Body::new(body), Some(&"load")).unwrap())
+        {
+            VmmAction::LoadSnapshot(cfg) => assert_eq!(cfg, expected_cfg),
+            _ => panic!("Test failed."),
+        }
+
+        body = r#"{
+                "snapshot_path": "foo",
+                "mem_file_path": "bar",
+                "network_overrides": [
+                    {
+                        "iface_id": "eth0",
+                        "host_dev_name": "vmtap1",
+                        "guest_mac": "12:34:56:78:9a:bc"
+                    }
+                ],
+                "drive_path_overrides": {
+                    "rootfs": "/srv/rootfs.ext4"
+                }
+              }"#;
+
+        expected_cfg = LoadSnapshotParams {
+            snapshot_path: PathBuf::from("foo"),
+            mem_file_path: Some(PathBuf::from("bar")),
+            mem_backend: None,
+            enable_diff_snapshots: false,
+            resume_vm: false,
+            network_overrides: vec![NetworkOverride {
+                iface_id: String::from("eth0"),
+                host_dev_name: Some(String::from("vmtap1")),
+                guest_mac: Some(MacAddr::parse_str("12:34:56:78:9a:bc").unwrap()),
+                rx_rate_limiter: None,
+                tx_rate_limiter: None,
+            }],
+            drive_path_overrides: vec![(String::from("rootfs"), String::from("/srv/rootfs.ext4"))]
+                .into_iter()
+                .collect(),
+            integrity_key: None,
+        };
+
+        match vmm_action_from_request(parse_put_snapshot(&Body::new(body), Some(&"load")).unwrap())
+        {
+            VmmAction::LoadSnapshot(cfg) => assert_eq!(cfg, expected_cfg),
+            _ => panic!("Test failed."),
+        }
+
+        body = r#"{
+                "snapshot_path": "foo",
+                "mem_backend": {
+                    "backend_path": "bar",
+                    "backend_type": "Uffd"
+                },
+                "integrity_key": "00ff"
+              }"#;
+
+        expected_cfg = LoadSnapshotParams {
+            snapshot_path: PathBuf::from("foo"),
+            mem_file_path: None,
+            mem_backend: Some(MemBackendConfig {
+                backend_path: PathBuf::from("bar"),
+                backend_type: MemBackendType::Uffd,
+            }),
+            enable_diff_snapshots: false,
+            resume_vm: false,
+            network_overrides: vec![],
+            drive_path_overrides: HashMap::new(),
+            integrity_key: Some(String::from("00ff")),
         };
 
         match vmm_action_from_request(parse_put_snapshot(&Body::new(body), Some(&"load")).unwrap())
@@ -157,6 +301,25 @@ mod tests {
 
         assert!(parse_put_snapshot(&Body::new(body), Some(&"invalid")).is_err());
         assert!(parse_put_snapshot(&Body::new(body), None).is_err());
+
+        body = r#"{
+                "snapshot_path": "foo"
+              }"#;
+        let expected_cfg = CheckSnapshotParams {
+            snapshot_path: PathBuf::from("foo"),
+        };
+
+        match vmm_action_from_request(parse_put_snapshot(&Body::new(body), Some(&"check")).unwrap())
+        {
+            VmmAction::CheckSnapshot(cfg) => assert_eq!(cfg, expected_cfg),
+            _ => panic!("Test failed."),
+        }
+
+        body = r#"{
+                "snapshot_path": "foo",
+                "mem_file_path": "bar"
+              }"#;
+        assert!(parse_put_snapshot(&Body::new(body), Some(&"check")).is_err());
     }
 
     #[test]
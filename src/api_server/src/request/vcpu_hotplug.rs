@@ -0,0 +1,41 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use super::super::VmmAction;
+use crate::parsed_request::{Error, ParsedRequest};
+use crate::request::Body;
+use vmm::vmm_config::vcpu_hotplug::VcpuHotplugConfig;
+
+pub(crate) fn parse_put_vcpus(body: &Body) -> Result<ParsedRequest, Error> {
+    Ok(ParsedRequest::new_sync(VmmAction::HotplugVcpus(
+        serde_json::from_slice::<VcpuHotplugConfig>(body.raw()).map_err(Error::SerdeJson)?,
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsed_request::tests::vmm_action_from_request;
+
+    #[test]
+    fn test_parse_put_vcpus_request() {
+        let body = r#"{
+                "vcpu_count": 4
+              }"#;
+        match vmm_action_from_request(parse_put_vcpus(&Body::new(body)).unwrap()) {
+            VmmAction::HotplugVcpus(cfg) => assert_eq!(cfg, VcpuHotplugConfig { vcpu_count: 4 }),
+            _ => panic!("Test failed."),
+        }
+
+        assert!(parse_put_vcpus(&Body::new("{}")).is_err());
+        let body = r#"{
+                "vcpu_count": 256
+              }"#;
+        assert!(parse_put_vcpus(&Body::new(body)).is_err());
+        let body = r#"{
+                "vcpu_count": 4,
+                "invalid_field": true
+              }"#;
+        assert!(parse_put_vcpus(&Body::new(body)).is_err());
+    }
+}
@@ -7,25 +7,26 @@ use std::ops::Range;
 
 use kvm_bindings::kvm_device_attr;
 use kvm_ioctls::DeviceFd;
+use serde::Serialize;
 use versionize::{VersionMap, Versionize, VersionizeResult};
 use versionize_derive::Versionize;
 
 use crate::aarch64::gic::{Error, Result};
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct GicRegState<T: Versionize> {
     pub(crate) chunks: Vec<T>,
 }
 
 /// Structure for serializing the state of the Vgic ICC regs
-#[derive(Debug, Default, Versionize)]
+#[derive(Debug, Default, Serialize, Versionize)]
 pub struct VgicSysRegsState {
     pub main_icc_regs: Vec<GicRegState<u64>>,
     pub ap_icc_regs: Vec<Option<GicRegState<u64>>>,
 }
 
 /// Structure used for serializing the state of the GIC registers.
-#[derive(Debug, Default, Versionize)]
+#[derive(Debug, Default, Serialize, Versionize)]
 pub struct GicState {
     /// The state of the distributor registers.
     pub dist: Vec<GicRegState<u32>>,
@@ -34,7 +35,7 @@ pub struct GicState {
 }
 
 /// Structure used for serializing the state of the GIC registers for a specific vCPU.
-#[derive(Debug, Default, Versionize)]
+#[derive(Debug, Default, Serialize, Versionize)]
 pub struct GicVcpuState {
     pub rdist: Vec<GicRegState<u32>>,
     pub icc: VgicSysRegsState,
@@ -0,0 +1,165 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! cpuid-dump prints the CPUID table and the MSRs a guest would see when booted with the given
+//! vCPU count and CPU templates on this host, as JSON, or the values that differ between two
+//! such dumps.
+
+#[cfg(target_arch = "x86_64")]
+use std::fs;
+#[cfg(target_arch = "x86_64")]
+use std::path::Path;
+use std::process;
+
+#[cfg(target_arch = "x86_64")]
+use cpu_tools::dump::{diff, CpuDump};
+use utils::arg_parser::{ArgParser, Argument, Arguments};
+#[cfg(target_arch = "x86_64")]
+use vmm::builder::guest_cpu_config;
+#[cfg(target_arch = "x86_64")]
+use vmm::resources::VmResources;
+#[cfg(target_arch = "x86_64")]
+use vmm::vmm_config::machine_config::{CpuFeaturesTemplate, VmConfig};
+
+const CPUID_DUMP_VERSION: &str = env!("CARGO_PKG_VERSION");
+const EXIT_CODE_ERROR: i32 = 1;
+
+fn build_arg_parser() -> ArgParser<'static> {
+    ArgParser::new()
+        .arg(
+            Argument::new("vcpu-count")
+                .required(false)
+                .takes_value(true)
+                .default_value("1")
+                .help("Number of vCPUs of the guest."),
+        )
+        .arg(
+            Argument::new("ht-enabled")
+                .required(false)
+                .takes_value(false)
+                .help("Enables hyperthreading in the guest."),
+        )
+        .arg(
+            Argument::new("cpu-template")
+                .required(false)
+                .takes_value(true)
+                .help("Static CPU template applied to the guest: C3, T2 or Zen1."),
+        )
+        .arg(
+            Argument::new("custom-cpu-template")
+                .required(false)
+                .takes_value(true)
+                .help("Path of a JSON file holding a custom CPU template applied to the guest."),
+        )
+        .arg(
+            Argument::new("dump-path")
+                .required(false)
+                .takes_value(true)
+                .forbids(vec!["ht-enabled", "cpu-template", "custom-cpu-template"])
+                .help("Path of a dump to load instead of computing the CPU configuration of a guest."),
+        )
+        .arg(
+            Argument::new("diff-with")
+                .required(false)
+                .takes_value(true)
+                .help("Path of a dump to compare with, value by value, instead of printing the CPU configuration."),
+        )
+}
+
+fn exit_with_error(err: impl std::fmt::Display) -> ! {
+    eprintln!("{}", err);
+    process::exit(EXIT_CODE_ERROR);
+}
+
+#[cfg(target_arch = "x86_64")]
+fn load_dump(path: &Path) -> CpuDump {
+    let content = fs::read_to_string(path).unwrap_or_else(|err| {
+        exit_with_error(format!("Cannot read the dump file {:?}: {}", path, err))
+    });
+    serde_json::from_str(&content).unwrap_or_else(|err| {
+        exit_with_error(format!("Cannot parse the dump file {:?}: {}", path, err))
+    })
+}
+
+#[cfg(target_arch = "x86_64")]
+fn build_dump(arguments: &Arguments) -> CpuDump {
+    // It's safe to unwrap because the argument has a default value.
+    let vcpu_count = arguments
+        .single_value("vcpu-count")
+        .unwrap()
+        .parse::<u8>()
+        .unwrap_or_else(|err| exit_with_error(format!("Invalid vCPU count: {}", err)));
+    let cpu_template = arguments.single_value("cpu-template").map(|template| {
+        serde_json::from_value::<CpuFeaturesTemplate>(serde_json::Value::String(template.clone()))
+            .unwrap_or_else(|_| exit_with_error(format!("Invalid CPU template: {}", template)))
+    });
+
+    let mut vm_resources = VmResources::default();
+    vm_resources
+        .set_vm_config(&VmConfig {
+            vcpu_count: Some(vcpu_count),
+            ht_enabled: Some(arguments.flag_present("ht-enabled")),
+            cpu_template,
+            custom_cpu_template_path: arguments
+                .single_value("custom-cpu-template")
+                .map(Into::into),
+            ..Default::default()
+        })
+        .unwrap_or_else(|err| exit_with_error(err));
+
+    let cpu_config = guest_cpu_config(&vm_resources).unwrap_or_else(|err| exit_with_error(err));
+    CpuDump::from(&cpu_config)
+}
+
+#[cfg(target_arch = "x86_64")]
+fn run(arguments: &Arguments) {
+    let dump = match arguments.single_value("dump-path") {
+        Some(path) => load_dump(Path::new(path)),
+        None => build_dump(arguments),
+    };
+
+    match arguments.single_value("diff-with") {
+        Some(other_path) => {
+            let differences = diff(&dump, &load_dump(Path::new(other_path)));
+            if differences.is_empty() {
+                println!("The CPU configurations are identical.");
+            }
+            for difference in differences {
+                println!("{}", difference);
+            }
+        }
+        // It's safe to unwrap because the dump structures can always be serialized.
+        None => println!("{}", serde_json::to_string_pretty(&dump).unwrap()),
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn run(_arguments: &Arguments) {
+    exit_with_error("cpuid-dump is only supported on x86_64 hosts.");
+}
+
+fn main() {
+    let mut arg_parser = build_arg_parser();
+
+    if let Err(err) = arg_parser.parse_from_cmdline() {
+        eprintln!(
+            "Arguments parsing error: {} \n\n\
+             For more information try --help.",
+            err
+        );
+        process::exit(EXIT_CODE_ERROR);
+    }
+
+    let arguments = arg_parser.arguments();
+    if arguments.flag_present("help") {
+        println!("cpuid-dump v{}\n", CPUID_DUMP_VERSION);
+        println!("{}", arg_parser.formatted_help());
+        return;
+    }
+    if arguments.flag_present("version") {
+        println!("cpuid-dump v{}\n", CPUID_DUMP_VERSION);
+        return;
+    }
+
+    run(arguments);
+}
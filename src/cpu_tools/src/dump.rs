@@ -0,0 +1,363 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! The CPUID table and MSR list a guest sees, in a JSON friendly form, and the comparison of
+//! two such configurations.
+//!
+//! All the numbers are serialized as hexadecimal strings, e.g. `"0x00000001"`, which is how
+//! they're written in the vendor manuals and in the custom CPU templates.
+
+use std::fmt::{Display, Formatter};
+
+use kvm_bindings::{kvm_cpuid_entry2, kvm_msr_entry};
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use vmm::builder::GuestCpuConfig;
+
+/// The CPU configuration of all the vCPUs of a guest.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct CpuDump {
+    /// The CPUID of each vCPU, in vCPU index order.
+    pub vcpus: Vec<VcpuDump>,
+    /// The MSRs set on the vCPUs when they're configured.
+    pub msrs: Vec<MsrDump>,
+}
+
+/// The CPU configuration of a single vCPU.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct VcpuDump {
+    /// The CPUID entries, as set through `KVM_SET_CPUID2`.
+    pub cpuid: Vec<CpuidEntryDump>,
+}
+
+/// A CPUID entry.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct CpuidEntryDump {
+    /// The CPUID function.
+    #[serde(serialize_with = "serialize_u32", deserialize_with = "deserialize_u32")]
+    pub leaf: u32,
+    /// The CPUID index.
+    #[serde(serialize_with = "serialize_u32", deserialize_with = "deserialize_u32")]
+    pub subleaf: u32,
+    /// The KVM flags of the entry.
+    #[serde(serialize_with = "serialize_u32", deserialize_with = "deserialize_u32")]
+    pub flags: u32,
+    /// The value of the EAX register.
+    #[serde(serialize_with = "serialize_u32", deserialize_with = "deserialize_u32")]
+    pub eax: u32,
+    /// The value of the EBX register.
+    #[serde(serialize_with = "serialize_u32", deserialize_with = "deserialize_u32")]
+    pub ebx: u32,
+    /// The value of the ECX register.
+    #[serde(serialize_with = "serialize_u32", deserialize_with = "deserialize_u32")]
+    pub ecx: u32,
+    /// The value of the EDX register.
+    #[serde(serialize_with = "serialize_u32", deserialize_with = "deserialize_u32")]
+    pub edx: u32,
+}
+
+/// An MSR along with its value.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct MsrDump {
+    /// The MSR index.
+    #[serde(serialize_with = "serialize_u32", deserialize_with = "deserialize_u32")]
+    pub index: u32,
+    /// The value of the MSR.
+    #[serde(serialize_with = "serialize_u64", deserialize_with = "deserialize_u64")]
+    pub value: u64,
+}
+
+fn serialize_u32<S: Serializer>(value: &u32, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&format!("{:#010x}", value))
+}
+
+fn serialize_u64<S: Serializer>(value: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&format!("{:#018x}", value))
+}
+
+fn parse_hex<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    let digits = value.strip_prefix("0x").ok_or_else(|| {
+        D::Error::custom(format!("Expected a hexadecimal number, found {:?}", value))
+    })?;
+    u64::from_str_radix(digits, 16).map_err(D::Error::custom)
+}
+
+fn deserialize_u32<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+    let value = parse_hex(deserializer)?;
+    if value > u64::from(u32::MAX) {
+        return Err(D::Error::custom(format!(
+            "The value {:#x} doesn't fit in 32 bits",
+            value
+        )));
+    }
+    Ok(value as u32)
+}
+
+fn deserialize_u64<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    parse_hex(deserializer)
+}
+
+impl From<&kvm_cpuid_entry2> for CpuidEntryDump {
+    fn from(entry: &kvm_cpuid_entry2) -> Self {
+        CpuidEntryDump {
+            leaf: entry.function,
+            subleaf: entry.index,
+            flags: entry.flags,
+            eax: entry.eax,
+            ebx: entry.ebx,
+            ecx: entry.ecx,
+            edx: entry.edx,
+        }
+    }
+}
+
+impl From<&kvm_msr_entry> for MsrDump {
+    fn from(entry: &kvm_msr_entry) -> Self {
+        MsrDump {
+            index: entry.index,
+            value: entry.data,
+        }
+    }
+}
+
+impl From<&GuestCpuConfig> for CpuDump {
+    fn from(cpu_config: &GuestCpuConfig) -> Self {
+        CpuDump {
+            vcpus: cpu_config
+                .cpuids
+                .iter()
+                .map(|cpuid| VcpuDump {
+                    cpuid: cpuid.as_slice().iter().map(CpuidEntryDump::from).collect(),
+                })
+                .collect(),
+            msrs: cpu_config.msrs.iter().map(MsrDump::from).collect(),
+        }
+    }
+}
+
+/// A value that differs between two CPU configurations.
+#[derive(Debug, PartialEq)]
+pub struct Difference {
+    /// Path of the value, e.g. `vcpus[0].cpuid[0x7/0x0].ebx` or `msrs[0x1a0]`.
+    pub path: String,
+    /// The value in the first configuration, if present.
+    pub left: Option<String>,
+    /// The value in the second configuration, if present.
+    pub right: Option<String>,
+}
+
+impl Display for Difference {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        let describe = |value: &Option<String>| match value {
+            Some(value) => value.clone(),
+            None => String::from("<missing>"),
+        };
+        write!(
+            f,
+            "{}: {} -> {}",
+            self.path,
+            describe(&self.left),
+            describe(&self.right)
+        )
+    }
+}
+
+// Walks the keys of `left` and then the keys only present in `right`, calling `compare`
+// with the entries found under each key.
+fn diff_keyed<'a, T, K: PartialEq>(
+    left: &'a [T],
+    right: &'a [T],
+    key: impl Fn(&T) -> K,
+    mut compare: impl FnMut(&K, Option<&'a T>, Option<&'a T>),
+) {
+    let find = |entries: &'a [T], k: &K| entries.iter().find(|entry| key(entry) == *k);
+    for entry in left {
+        let k = key(entry);
+        compare(&k, Some(entry), find(right, &k));
+    }
+    for entry in right {
+        let k = key(entry);
+        if find(left, &k).is_none() {
+            compare(&k, None, Some(entry));
+        }
+    }
+}
+
+fn diff_cpuid(
+    vcpu_path: &str,
+    left: &[CpuidEntryDump],
+    right: &[CpuidEntryDump],
+    differences: &mut Vec<Difference>,
+) {
+    diff_keyed(
+        left,
+        right,
+        |entry| (entry.leaf, entry.subleaf),
+        |(leaf, subleaf), left, right| {
+            let path = format!("{}.cpuid[{:#x}/{:#x}]", vcpu_path, leaf, subleaf);
+            match (left, right) {
+                (Some(left), Some(right)) => {
+                    let registers = [
+                        ("flags", left.flags, right.flags),
+                        ("eax", left.eax, right.eax),
+                        ("ebx", left.ebx, right.ebx),
+                        ("ecx", left.ecx, right.ecx),
+                        ("edx", left.edx, right.edx),
+                    ];
+                    for (name, left, right) in registers.iter() {
+                        if left != right {
+                            differences.push(Difference {
+                                path: format!("{}.{}", path, name),
+                                left: Some(format!("{:#010x}", left)),
+                                right: Some(format!("{:#010x}", right)),
+                            });
+                        }
+                    }
+                }
+                // A missing entry is reported whole. It's safe to unwrap because the entries
+                // can always be serialized.
+                (left, right) => differences.push(Difference {
+                    path,
+                    left: left.map(|entry| serde_json::to_string(entry).unwrap()),
+                    right: right.map(|entry| serde_json::to_string(entry).unwrap()),
+                }),
+            }
+        },
+    );
+}
+
+/// Returns the values that differ between `left` and `right`.
+///
+/// The CPUID entries are matched by leaf and subleaf, and the MSRs by index, so that an entry
+/// missing from one configuration is reported once rather than shifting all those after it.
+pub fn diff(left: &CpuDump, right: &CpuDump) -> Vec<Difference> {
+    let mut differences = Vec::new();
+
+    // A vCPU missing from one configuration is compared as a vCPU without CPUID entries.
+    let no_entries = Vec::new();
+    for index in 0..std::cmp::max(left.vcpus.len(), right.vcpus.len()) {
+        diff_cpuid(
+            &format!("vcpus[{}]", index),
+            left.vcpus
+                .get(index)
+                .map_or(&no_entries, |vcpu| &vcpu.cpuid),
+            right
+                .vcpus
+                .get(index)
+                .map_or(&no_entries, |vcpu| &vcpu.cpuid),
+            &mut differences,
+        );
+    }
+
+    diff_keyed(
+        &left.msrs,
+        &right.msrs,
+        |msr| msr.index,
+        |index, left, right| {
+            let describe = |msr: Option<&MsrDump>| msr.map(|msr| format!("{:#018x}", msr.value));
+            if left != right {
+                differences.push(Difference {
+                    path: format!("msrs[{:#x}]", index),
+                    left: describe(left),
+                    right: describe(right),
+                });
+            }
+        },
+    );
+
+    differences
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cpuid_entry(leaf: u32, subleaf: u32, ebx: u32) -> CpuidEntryDump {
+        CpuidEntryDump {
+            leaf,
+            subleaf,
+            ebx,
+            ..Default::default()
+        }
+    }
+
+    fn build_dump() -> CpuDump {
+        CpuDump {
+            vcpus: vec![VcpuDump {
+                cpuid: vec![
+                    cpuid_entry(0x1, 0x0, 0x0000_0800),
+                    cpuid_entry(0x7, 0x0, 0xff),
+                ],
+            }],
+            msrs: vec![MsrDump {
+                index: 0x174,
+                value: 0x10,
+            }],
+        }
+    }
+
+    #[test]
+    fn test_serialization() {
+        let dump = build_dump();
+        let json = serde_json::to_string(&dump.msrs).unwrap();
+        assert_eq!(
+            json,
+            r#"[{"index":"0x00000174","value":"0x0000000000000010"}]"#
+        );
+
+        let json = serde_json::to_string(&dump).unwrap();
+        assert_eq!(serde_json::from_str::<CpuDump>(&json).unwrap(), dump);
+
+        // The numbers must be hexadecimal strings that fit in their field.
+        for json in [
+            r#"{"index":"174","value":"0x10"}"#,
+            r#"{"index":"0x100000000","value":"0x10"}"#,
+            r#"{"index":"0x174","value":16}"#,
+        ]
+        .iter()
+        {
+            assert!(serde_json::from_str::<MsrDump>(json).is_err());
+        }
+    }
+
+    #[test]
+    fn test_diff() {
+        let left = build_dump();
+        assert!(diff(&left, &build_dump()).is_empty());
+
+        let mut right = build_dump();
+        right.vcpus[0].cpuid.remove(0);
+        right.vcpus[0].cpuid[0].ebx = 0x0f;
+        right.vcpus[0].cpuid.push(cpuid_entry(0xd, 0x1, 0x0));
+        right.vcpus.push(VcpuDump {
+            cpuid: vec![cpuid_entry(0x1, 0x0, 0x0100_0800)],
+        });
+        right.msrs[0].value = 0x20;
+
+        let differences: Vec<String> = diff(&left, &right)
+            .iter()
+            .map(Difference::to_string)
+            .collect();
+        assert_eq!(
+            differences,
+            vec![
+                "vcpus[0].cpuid[0x1/0x0]: {\"leaf\":\"0x00000001\",\"subleaf\":\"0x00000000\",\
+                 \"flags\":\"0x00000000\",\"eax\":\"0x00000000\",\"ebx\":\"0x00000800\",\
+                 \"ecx\":\"0x00000000\",\"edx\":\"0x00000000\"} -> <missing>",
+                "vcpus[0].cpuid[0x7/0x0].ebx: 0x000000ff -> 0x0000000f",
+                "vcpus[0].cpuid[0xd/0x1]: <missing> -> {\"leaf\":\"0x0000000d\",\
+                 \"subleaf\":\"0x00000001\",\"flags\":\"0x00000000\",\"eax\":\"0x00000000\",\
+                 \"ebx\":\"0x00000000\",\"ecx\":\"0x00000000\",\"edx\":\"0x00000000\"}",
+                "vcpus[1].cpuid[0x1/0x0]: <missing> -> {\"leaf\":\"0x00000001\",\
+                 \"subleaf\":\"0x00000000\",\"flags\":\"0x00000000\",\"eax\":\"0x00000000\",\
+                 \"ebx\":\"0x01000800\",\"ecx\":\"0x00000000\",\"edx\":\"0x00000000\"}",
+                "msrs[0x174]: 0x0000000000000010 -> 0x0000000000000020",
+            ]
+        );
+    }
+}
@@ -0,0 +1,9 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Offline tools operating on the CPU configuration exposed to Firecracker guests.
+//!
+//! Only x86_64 hosts are supported, as the CPU templates are specific to them.
+
+#[cfg(target_arch = "x86_64")]
+pub mod dump;
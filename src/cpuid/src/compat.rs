@@ -0,0 +1,165 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fmt;
+
+use crate::cpu_leaf::*;
+use crate::custom::CpuidRegister;
+use kvm_bindings::{kvm_cpuid_entry2, CpuId};
+
+// The CPUID registers holding feature flags, along with the bits of each register which
+// are set by Firecracker or updated by KVM at runtime, and are therefore not expected to
+// be reported by the host.
+const FEATURE_REGISTERS: [(u32, u32, CpuidRegister, u32); 11] = [
+    (
+        leaf_0x1::LEAF_NUM,
+        0,
+        CpuidRegister::Ecx,
+        (1 << leaf_0x1::ecx::TSC_DEADLINE_TIMER_BITINDEX)
+            | (1 << leaf_0x1::ecx::OSXSAVE_BITINDEX)
+            | (1 << leaf_0x1::ecx::HYPERVISOR_BITINDEX),
+    ),
+    (
+        leaf_0x1::LEAF_NUM,
+        0,
+        CpuidRegister::Edx,
+        1 << leaf_0x1::edx::HTT_BITINDEX,
+    ),
+    (leaf_0x7::LEAF_NUM, 0, CpuidRegister::Ebx, 0),
+    (
+        leaf_0x7::LEAF_NUM,
+        0,
+        CpuidRegister::Ecx,
+        1 << leaf_0x7::index0::ecx::OSPKE_BITINDEX,
+    ),
+    (leaf_0x7::LEAF_NUM, 0, CpuidRegister::Edx, 0),
+    (leaf_0x7::LEAF_NUM, 1, CpuidRegister::Eax, 0),
+    (leaf_0xd::LEAF_NUM, 0, CpuidRegister::Eax, 0),
+    (leaf_0xd::LEAF_NUM, 1, CpuidRegister::Eax, 0),
+    (
+        leaf_0x80000001::LEAF_NUM,
+        0,
+        CpuidRegister::Ecx,
+        1 << leaf_0x80000001::ecx::TOPOEXT_INDEX,
+    ),
+    (leaf_0x80000001::LEAF_NUM, 0, CpuidRegister::Edx, 0),
+    (leaf_0x80000008::LEAF_NUM, 0, CpuidRegister::Ebx, 0),
+];
+
+/// Bits of a CPUID register exposed to a guest that the host doesn't support.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MissingCpuidBits {
+    /// The CPUID function.
+    pub leaf: u32,
+    /// The CPUID index.
+    pub subleaf: u32,
+    /// The register holding the feature flags.
+    pub register: CpuidRegister,
+    /// The feature flags that are set for the guest but not supported by the host.
+    pub bits: u32,
+}
+
+impl fmt::Display for MissingCpuidBits {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "CPUID leaf {:#x} subleaf {:#x} register {:?}: bits {:#010x}",
+            self.leaf, self.subleaf, self.register, self.bits
+        )
+    }
+}
+
+fn find_entry(cpuid: &CpuId, leaf: u32, subleaf: u32) -> Option<&kvm_cpuid_entry2> {
+    cpuid
+        .as_slice()
+        .iter()
+        .find(|entry| entry.function == leaf && entry.index == subleaf)
+}
+
+fn read_register(entry: &kvm_cpuid_entry2, register: CpuidRegister) -> u32 {
+    match register {
+        CpuidRegister::Eax => entry.eax,
+        CpuidRegister::Ebx => entry.ebx,
+        CpuidRegister::Ecx => entry.ecx,
+        CpuidRegister::Edx => entry.edx,
+    }
+}
+
+/// Returns the feature flags of `guest_cpuid` which are not set in `host_cpuid`.
+///
+/// # Arguments
+///
+/// * `guest_cpuid` - The CPUID exposed to a guest, e.g. the one saved in a snapshot.
+/// * `host_cpuid` - The CPUID supported by KVM on the host.
+pub fn missing_cpuid_bits(guest_cpuid: &CpuId, host_cpuid: &CpuId) -> Vec<MissingCpuidBits> {
+    let mut missing = Vec::new();
+    for (leaf, subleaf, register, ignored_bits) in FEATURE_REGISTERS.iter() {
+        let guest_bits = match find_entry(guest_cpuid, *leaf, *subleaf) {
+            Some(entry) => read_register(entry, *register) & !ignored_bits,
+            None => continue,
+        };
+        let host_bits = find_entry(host_cpuid, *leaf, *subleaf)
+            .map_or(0, |entry| read_register(entry, *register));
+
+        let bits = guest_bits & !host_bits;
+        if bits != 0 {
+            missing.push(MissingCpuidBits {
+                leaf: *leaf,
+                subleaf: *subleaf,
+                register: *register,
+                bits,
+            });
+        }
+    }
+    missing
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build_cpuid(leaf_0x7_ebx: u32) -> CpuId {
+        let mut cpuid = CpuId::new(2).unwrap();
+        let entries = cpuid.as_mut_slice();
+        entries[0].function = leaf_0x1::LEAF_NUM;
+        entries[0].ecx = 0x0000_00ff;
+        entries[1].function = leaf_0x7::LEAF_NUM;
+        entries[1].ebx = leaf_0x7_ebx;
+        cpuid
+    }
+
+    #[test]
+    fn test_missing_cpuid_bits() {
+        let host_cpuid = build_cpuid(0x0000_ffff);
+
+        let guest_cpuid = build_cpuid(0x0000_00ff);
+        assert!(missing_cpuid_bits(&guest_cpuid, &host_cpuid).is_empty());
+
+        let mut guest_cpuid = build_cpuid(0x0003_00ff);
+        // The bits set by Firecracker are ignored.
+        guest_cpuid.as_mut_slice()[0].ecx |= 1 << leaf_0x1::ecx::HYPERVISOR_BITINDEX;
+        // So are the leaves that hold no feature flags.
+        guest_cpuid.as_mut_slice()[0].eax = 0xffff_ffff;
+        assert_eq!(
+            missing_cpuid_bits(&guest_cpuid, &host_cpuid),
+            vec![MissingCpuidBits {
+                leaf: leaf_0x7::LEAF_NUM,
+                subleaf: 0,
+                register: CpuidRegister::Ebx,
+                bits: 0x0003_0000,
+            }]
+        );
+
+        // All the feature flags of a leaf the host doesn't report are missing.
+        let mut guest_cpuid = CpuId::new(1).unwrap();
+        guest_cpuid.as_mut_slice()[0].function = leaf_0x80000008::LEAF_NUM;
+        guest_cpuid.as_mut_slice()[0].ebx = 0x0000_0201;
+        let missing = missing_cpuid_bits(&guest_cpuid, &host_cpuid);
+        assert_eq!(missing.len(), 1);
+        assert_eq!(missing[0].bits, 0x0000_0201);
+        assert_eq!(
+            missing[0].to_string(),
+            "CPUID leaf 0x80000008 subleaf 0x0 register Ebx: bits 0x00000201"
+        );
+    }
+}
@@ -119,7 +119,8 @@ pub mod leaf_0x7 {
             pub const FPDP_BITINDEX: u32 = 6;
             // 7 = SMEP (Supervisor-Mode Execution Prevention if 1)
             pub const BMI2_BITINDEX: u32 = 8;
-            // 9 = Enhanced REP MOVSB/STOSB if 1
+            // ERMS = Enhanced REP MOVSB/STOSB if 1
+            pub const ERMS_BITINDEX: u32 = 9;
             // 10 = INVPCID
             pub const INVPCID_BITINDEX: u32 = 10;
             pub const RTM_BITINDEX: u32 = 11;
@@ -165,17 +166,33 @@ pub mod leaf_0x7 {
             // 0 = PREFETCHWT1 (move data closer to the processor in anticipation of future use)
             // AVX512_VBMI = AVX-512 Vector Byte Manipulation Instructions
             pub const AVX512_VBMI_BITINDEX: u32 = 1;
-            // 2 = UMIP (User Mode Instruction Prevention)
+            // UMIP = User Mode Instruction Prevention
+            pub const UMIP_BITINDEX: u32 = 2;
             // PKU = Protection Keys for user-mode pages
             pub const PKU_BITINDEX: u32 = 3;
             // OSPKE = If 1, OS has set CR4.PKE to enable protection keys
             pub const OSPKE_BITINDEX: u32 = 4;
             // 5 = WAITPKG
-            // 7-6 reserved
-            // 8 = GFNI
-            // 13-09 reserved
+            // AVX512_VBMI2 = AVX-512 Vector Byte Manipulation Instructions 2
+            pub const AVX512_VBMI2_BITINDEX: u32 = 6;
+            // CET_SS = Control-flow Enforcement Technology Shadow Stack
+            pub const CET_SS_BITINDEX: u32 = 7;
+            // GFNI = Galois Field New Instructions
+            pub const GFNI_BITINDEX: u32 = 8;
+            // VAES = Vector AES instructions
+            pub const VAES_BITINDEX: u32 = 9;
+            // VPCLMULQDQ = Carry-Less Multiplication of vectors
+            pub const VPCLMULQDQ_BITINDEX: u32 = 10;
+            // AVX512_VNNI = AVX-512 Vector Neural Network Instructions
+            pub const AVX512_VNNI_BITINDEX: u32 = 11;
+            // AVX512_BITALG = AVX-512 Bit Algorithms
+            pub const AVX512_BITALG_BITINDEX: u32 = 12;
+            // 13 reserved
             // AVX512_VPOPCNTDQ = Vector population count instruction (Intel® Xeon Phi™ only.)
             pub const AVX512_VPOPCNTDQ_BITINDEX: u32 = 14;
+            // 15 reserved
+            // LA57 = 5-level paging
+            pub const LA57_BITINDEX: u32 = 16;
             // 21 - 17 = The value of MAWAU used by the BNDLDX and BNDSTX instructions in 64-bit mode.
             // Read Processor ID
             pub const RDPID_BITINDEX: u32 = 22;
@@ -190,6 +207,8 @@ pub mod leaf_0x7 {
             pub const AVX512_4VNNIW_BITINDEX: u32 = 2;
             // AVX-512 4-register Multiply Accumulation Single precision
             pub const AVX512_4FMAPS_BITINDEX: u32 = 3;
+            // FSRM = Fast Short REP MOV
+            pub const FSRM_BITINDEX: u32 = 4;
             pub const ARCH_CAPABILITIES_BITINDEX: u32 = 29;
         }
     }
@@ -280,6 +299,15 @@ pub mod leaf_0x80000001 {
 pub mod leaf_0x80000008 {
     pub const LEAF_NUM: u32 = 0x8000_0008;
 
+    pub mod ebx {
+        // RDPRU = Read Processor Register at User level
+        pub const RDPRU_BITINDEX: u32 = 4;
+        // MCOMMIT = Memory Commit instruction
+        pub const MCOMMIT_BITINDEX: u32 = 8;
+        // WBNOINVD = Write Back and Do Not Invalidate Cache
+        pub const WBNOINVD_BITINDEX: u32 = 9;
+    }
+
     pub mod ecx {
         use crate::bit_helper::BitRange;
 
@@ -18,7 +18,12 @@ use crate::common::*;
 /// Contains helper methods for bit operations.
 pub mod bit_helper;
 
+/// Checks the CPUID exposed to a guest against the features supported by a host.
+pub mod compat;
+
 mod template;
+pub use crate::template::amd::zen1;
+pub use crate::template::custom;
 pub use crate::template::intel::c3;
 pub use crate::template::intel::t2;
 
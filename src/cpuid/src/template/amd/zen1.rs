@@ -0,0 +1,230 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::bit_helper::BitHelper;
+use crate::cpu_leaf::*;
+use crate::template::amd::validate_vendor_id;
+use crate::transformer::*;
+use kvm_bindings::{kvm_cpuid_entry2, CpuId};
+
+// The features masked below were introduced by the Zen 2 and later generations, so the guest
+// sees the same features on all the Zen hosts. The speculation control features are left
+// as reported by KVM, since hiding them would leave the guest unprotected.
+
+// Largest extended function of Zen 1. The later leaves, e.g. 0x80000021 which reports Zen 3
+// and Zen 4 features, are cleared.
+const LARGEST_EXTENDED_FN: u32 = 0x8000_001f;
+
+fn update_structured_extended_entry(
+    entry: &mut kvm_cpuid_entry2,
+    _vm_spec: &VmSpec,
+) -> Result<(), Error> {
+    use crate::cpu_leaf::leaf_0x7::index0::*;
+
+    if entry.index == 0 {
+        entry
+            .ebx
+            .write_bit(ebx::ERMS_BITINDEX, false)
+            .write_bit(ebx::INVPCID_BITINDEX, false)
+            .write_bit(ebx::AVX512F_BITINDEX, false)
+            .write_bit(ebx::AVX512DQ_BITINDEX, false)
+            .write_bit(ebx::AVX512IFMA_BITINDEX, false)
+            .write_bit(ebx::CLWB_BITINDEX, false)
+            .write_bit(ebx::AVX512PF_BITINDEX, false)
+            .write_bit(ebx::AVX512ER_BITINDEX, false)
+            .write_bit(ebx::AVX512CD_BITINDEX, false)
+            .write_bit(ebx::AVX512BW_BITINDEX, false)
+            .write_bit(ebx::AVX512VL_BITINDEX, false);
+
+        entry
+            .ecx
+            .write_bit(ecx::AVX512_VBMI_BITINDEX, false)
+            .write_bit(ecx::UMIP_BITINDEX, false)
+            .write_bit(ecx::PKU_BITINDEX, false)
+            .write_bit(ecx::OSPKE_BITINDEX, false)
+            .write_bit(ecx::AVX512_VBMI2_BITINDEX, false)
+            .write_bit(ecx::CET_SS_BITINDEX, false)
+            .write_bit(ecx::GFNI_BITINDEX, false)
+            .write_bit(ecx::VAES_BITINDEX, false)
+            .write_bit(ecx::VPCLMULQDQ_BITINDEX, false)
+            .write_bit(ecx::AVX512_VNNI_BITINDEX, false)
+            .write_bit(ecx::AVX512_BITALG_BITINDEX, false)
+            .write_bit(ecx::AVX512_VPOPCNTDQ_BITINDEX, false)
+            .write_bit(ecx::LA57_BITINDEX, false)
+            .write_bit(ecx::RDPID_BITINDEX, false);
+
+        entry
+            .edx
+            .write_bit(edx::AVX512_4VNNIW_BITINDEX, false)
+            .write_bit(edx::AVX512_4FMAPS_BITINDEX, false)
+            .write_bit(edx::FSRM_BITINDEX, false);
+    }
+
+    // None of the features of the subleaf 1 are available on Zen 1.
+    if entry.index == 1 {
+        entry.eax = 0;
+    }
+
+    Ok(())
+}
+
+fn update_xsave_features_entry(
+    entry: &mut kvm_cpuid_entry2,
+    _vm_spec: &VmSpec,
+) -> Result<(), Error> {
+    use crate::cpu_leaf::leaf_0xd::*;
+
+    if entry.index == 0 {
+        // AVX-512 instructions are masked out with the current template so the size in bytes
+        // of the save area should be 0 (or invalid).
+        entry
+            .eax
+            .write_bits_in_range(&index0::eax::AVX512_STATE_BITRANGE, 0);
+
+        // OSPKE is masked in leaf_0x7 index 0 - RDPKRU/WRPKRU not exposed.
+        // Here we mask the XSAVE PKRU capabilities.
+        entry.eax.write_bit(index0::eax::PKRU_BITINDEX, false);
+    }
+
+    Ok(())
+}
+
+fn update_largest_extended_fn_entry(
+    entry: &mut kvm_cpuid_entry2,
+    _vm_spec: &VmSpec,
+) -> Result<(), Error> {
+    use crate::cpu_leaf::leaf_0x80000000::*;
+
+    let largest_extended_fn = entry
+        .eax
+        .read_bits_in_range(&eax::LARGEST_EXTENDED_FN_BITRANGE);
+    entry.eax.write_bits_in_range(
+        &eax::LARGEST_EXTENDED_FN_BITRANGE,
+        std::cmp::min(largest_extended_fn, LARGEST_EXTENDED_FN),
+    );
+
+    Ok(())
+}
+
+fn update_amd_features_entry(entry: &mut kvm_cpuid_entry2, _vm_spec: &VmSpec) -> Result<(), Error> {
+    use crate::cpu_leaf::leaf_0x80000008::*;
+
+    entry
+        .ebx
+        .write_bit(ebx::RDPRU_BITINDEX, false)
+        .write_bit(ebx::MCOMMIT_BITINDEX, false)
+        .write_bit(ebx::WBNOINVD_BITINDEX, false);
+
+    Ok(())
+}
+
+fn clear_entry(entry: &mut kvm_cpuid_entry2, _vm_spec: &VmSpec) -> Result<(), Error> {
+    entry.eax = 0;
+    entry.ebx = 0;
+    entry.ecx = 0;
+    entry.edx = 0;
+
+    Ok(())
+}
+
+/// Sets up the cpuid entries for a given VCPU following a Zen 1 template.
+struct Zen1CpuidTransformer {}
+
+impl CpuidTransformer for Zen1CpuidTransformer {
+    fn entry_transformer_fn(&self, entry: &mut kvm_cpuid_entry2) -> Option<EntryTransformerFn> {
+        match entry.function {
+            leaf_0x7::LEAF_NUM => Some(update_structured_extended_entry),
+            leaf_0xd::LEAF_NUM => Some(update_xsave_features_entry),
+            leaf_0x80000000::LEAF_NUM => Some(update_largest_extended_fn_entry),
+            leaf_0x80000008::LEAF_NUM => Some(update_amd_features_entry),
+            function if function > LARGEST_EXTENDED_FN => Some(clear_entry),
+            _ => None,
+        }
+    }
+}
+
+/// Sets up the cpuid entries for a given VCPU following a Zen 1 template.
+pub fn set_cpuid_entries(kvm_cpuid: &mut CpuId, vm_spec: &VmSpec) -> Result<(), Error> {
+    validate_vendor_id()?;
+    Zen1CpuidTransformer {}.process_cpuid(kvm_cpuid, vm_spec)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::{get_vendor_id_from_host, VENDOR_ID_AMD};
+
+    #[test]
+    fn test_zen1_cpuid_transformer() {
+        let vm_spec = VmSpec::new(0, 1, false).unwrap();
+        let mut cpuid = CpuId::new(6).unwrap();
+        {
+            let entries = cpuid.as_mut_slice();
+            entries[0].function = leaf_0x7::LEAF_NUM;
+            entries[0].ebx = u32::MAX;
+            entries[0].ecx = u32::MAX;
+            entries[0].edx = u32::MAX;
+            entries[1].function = leaf_0x7::LEAF_NUM;
+            entries[1].index = 1;
+            entries[1].eax = u32::MAX;
+            entries[2].function = leaf_0xd::LEAF_NUM;
+            entries[2].eax = u32::MAX;
+            entries[3].function = leaf_0x80000008::LEAF_NUM;
+            entries[3].ebx = u32::MAX;
+            entries[4].function = leaf_0x80000000::LEAF_NUM;
+            entries[4].eax = 0x8000_0021;
+            entries[5].function = 0x8000_0021;
+            entries[5].eax = u32::MAX;
+            entries[5].ecx = u32::MAX;
+        }
+
+        Zen1CpuidTransformer {}
+            .process_cpuid(&mut cpuid, &vm_spec)
+            .unwrap();
+
+        let entries = cpuid.as_slice();
+        // SHA, CLFLUSHOPT and the other Zen 1 features are kept.
+        assert!(entries[0].ebx.read_bit(leaf_0x7::index0::ebx::SHA_BITINDEX));
+        assert!(entries[0]
+            .ebx
+            .read_bit(leaf_0x7::index0::ebx::CLFLUSHOPT_BITINDEX));
+        assert!(!entries[0]
+            .ebx
+            .read_bit(leaf_0x7::index0::ebx::CLWB_BITINDEX));
+        assert!(!entries[0]
+            .ecx
+            .read_bit(leaf_0x7::index0::ecx::VAES_BITINDEX));
+        assert!(!entries[0]
+            .ecx
+            .read_bit(leaf_0x7::index0::ecx::UMIP_BITINDEX));
+        assert!(!entries[0]
+            .ecx
+            .read_bit(leaf_0x7::index0::ecx::CET_SS_BITINDEX));
+        assert!(!entries[0]
+            .ecx
+            .read_bit(leaf_0x7::index0::ecx::LA57_BITINDEX));
+        assert!(!entries[0]
+            .edx
+            .read_bit(leaf_0x7::index0::edx::FSRM_BITINDEX));
+        assert_eq!(entries[1].eax, 0);
+        assert!(!entries[2]
+            .eax
+            .read_bit(leaf_0xd::index0::eax::PKRU_BITINDEX));
+        assert!(!entries[3]
+            .ebx
+            .read_bit(leaf_0x80000008::ebx::WBNOINVD_BITINDEX));
+        assert_eq!(entries[4].eax, LARGEST_EXTENDED_FN);
+        assert_eq!((entries[5].eax, entries[5].ecx), (0, 0));
+
+        // The template is only available on AMD hosts.
+        let res = set_cpuid_entries(&mut cpuid, &vm_spec);
+        if &get_vendor_id_from_host().unwrap() == VENDOR_ID_AMD {
+            assert!(res.is_ok());
+        } else {
+            match res {
+                Err(Error::InvalidVendor) => (),
+                _ => panic!("Test failed."),
+            }
+        }
+    }
+}
@@ -0,0 +1,378 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fmt;
+
+use crate::compat::missing_cpuid_bits;
+use crate::transformer::*;
+use kvm_bindings::{kvm_cpuid_entry2, CpuId};
+use serde::de::{self, Deserializer, Visitor};
+use serde::{Deserialize, Serialize};
+
+/// A user-defined CPU template, made of CPUID and MSR modifiers.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct CustomCpuTemplate {
+    /// Modifiers of the CPUID leaves exposed to the guest.
+    #[serde(default)]
+    pub cpuid_modifiers: Vec<CpuidLeafModifier>,
+    /// Values of the MSRs set when the vCPUs are configured.
+    #[serde(default)]
+    pub msr_modifiers: Vec<MsrModifier>,
+}
+
+/// Modifies the registers of a CPUID leaf.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct CpuidLeafModifier {
+    /// The CPUID function.
+    #[serde(deserialize_with = "deserialize_u32")]
+    pub leaf: u32,
+    /// The CPUID index. It is 0 for leaves that don't have subleaves.
+    #[serde(default, deserialize_with = "deserialize_u32")]
+    pub subleaf: u32,
+    /// The modifiers of the leaf registers.
+    pub modifiers: Vec<CpuidRegisterModifier>,
+}
+
+/// CPUID registers.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CpuidRegister {
+    /// EAX register.
+    Eax,
+    /// EBX register.
+    Ebx,
+    /// ECX register.
+    Ecx,
+    /// EDX register.
+    Edx,
+}
+
+/// Overwrites the bits of a CPUID register selected by `bitmask` with the ones in `value`.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct CpuidRegisterModifier {
+    /// The modified register.
+    pub register: CpuidRegister,
+    /// The bits of the register that are modified.
+    #[serde(deserialize_with = "deserialize_u32")]
+    pub bitmask: u32,
+    /// The new value of the modified bits.
+    #[serde(deserialize_with = "deserialize_u32")]
+    pub value: u32,
+}
+
+/// Overrides the value of an MSR.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct MsrModifier {
+    /// The MSR index.
+    #[serde(deserialize_with = "deserialize_u32")]
+    pub index: u32,
+    /// The value of the MSR.
+    #[serde(deserialize_with = "deserialize_u64")]
+    pub value: u64,
+}
+
+fn register_mut(entry: &mut kvm_cpuid_entry2, register: CpuidRegister) -> &mut u32 {
+    match register {
+        CpuidRegister::Eax => &mut entry.eax,
+        CpuidRegister::Ebx => &mut entry.ebx,
+        CpuidRegister::Ecx => &mut entry.ecx,
+        CpuidRegister::Edx => &mut entry.edx,
+    }
+}
+
+impl CpuidLeafModifier {
+    fn apply(&self, entry: &mut kvm_cpuid_entry2) {
+        for modifier in self.modifiers.iter() {
+            let register = register_mut(entry, modifier.register);
+            *register = (*register & !modifier.bitmask) | (modifier.value & modifier.bitmask);
+        }
+    }
+}
+
+/// Applies the CPUID modifiers of a custom template.
+struct CustomCpuidTransformer<'a> {
+    modifiers: &'a [CpuidLeafModifier],
+    supported_cpuid: &'a CpuId,
+}
+
+impl CustomCpuidTransformer<'_> {
+    // Checks that the modifiers only set the feature flags that the host supports.
+    fn check_features(&self) -> Result<(), Error> {
+        let mut set_bits: Vec<kvm_cpuid_entry2> = Vec::new();
+        for modifier in self.modifiers.iter() {
+            let position = match set_bits.iter().position(|entry| {
+                entry.function == modifier.leaf && entry.index == modifier.subleaf
+            }) {
+                Some(position) => position,
+                None => {
+                    set_bits.push(kvm_cpuid_entry2 {
+                        function: modifier.leaf,
+                        index: modifier.subleaf,
+                        ..Default::default()
+                    });
+                    set_bits.len() - 1
+                }
+            };
+            for register_modifier in modifier.modifiers.iter() {
+                *register_mut(&mut set_bits[position], register_modifier.register) |=
+                    register_modifier.value & register_modifier.bitmask;
+            }
+        }
+
+        let set_bits = CpuId::from_entries(&set_bits).map_err(Error::FamError)?;
+        let unsupported_bits = missing_cpuid_bits(&set_bits, self.supported_cpuid);
+        if !unsupported_bits.is_empty() {
+            return Err(Error::UnsupportedFeatures(unsupported_bits));
+        }
+        Ok(())
+    }
+}
+
+impl CpuidTransformer for CustomCpuidTransformer<'_> {
+    fn process_cpuid(&self, cpuid: &mut CpuId, _vm_spec: &VmSpec) -> Result<(), Error> {
+        // A template can only modify the leaves the host supports, so every leaf is
+        // looked up before any entry gets modified.
+        let mut positions = Vec::with_capacity(self.modifiers.len());
+        for modifier in self.modifiers.iter() {
+            let position = cpuid
+                .as_slice()
+                .iter()
+                .position(|entry| {
+                    entry.function == modifier.leaf && entry.index == modifier.subleaf
+                })
+                .ok_or(Error::UnsupportedLeaf(modifier.leaf, modifier.subleaf))?;
+            positions.push(position);
+        }
+        self.check_features()?;
+
+        let entries = cpuid.as_mut_slice();
+        for (modifier, position) in self.modifiers.iter().zip(positions) {
+            modifier.apply(&mut entries[position]);
+        }
+
+        Ok(())
+    }
+}
+
+/// Sets up the cpuid entries for a given VCPU following a custom template. The modifiers
+/// can't set the feature flags missing from `supported_cpuid`, the CPUID supported by KVM.
+pub fn set_cpuid_entries(
+    kvm_cpuid: &mut CpuId,
+    vm_spec: &VmSpec,
+    modifiers: &[CpuidLeafModifier],
+    supported_cpuid: &CpuId,
+) -> Result<(), Error> {
+    CustomCpuidTransformer {
+        modifiers,
+        supported_cpuid,
+    }
+    .process_cpuid(kvm_cpuid, vm_spec)
+}
+
+// Numbers in templates are either JSON integers or hex strings, as CPUID leaves and
+// MSR indices are usually written in hex.
+struct HexOrIntVisitor;
+
+impl<'de> Visitor<'de> for HexOrIntVisitor {
+    type Value = u64;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("an unsigned integer or a hex string prefixed with 0x")
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<u64, E> {
+        Ok(value)
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<u64, E> {
+        value
+            .strip_prefix("0x")
+            .and_then(|hex| u64::from_str_radix(hex, 16).ok())
+            .ok_or_else(|| E::invalid_value(de::Unexpected::Str(value), &self))
+    }
+}
+
+fn deserialize_u64<'de, D: Deserializer<'de>>(d: D) -> Result<u64, D::Error> {
+    d.deserialize_any(HexOrIntVisitor)
+}
+
+fn deserialize_u32<'de, D: Deserializer<'de>>(d: D) -> Result<u32, D::Error> {
+    let value = deserialize_u64(d)?;
+    if value > u64::from(u32::MAX) {
+        return Err(de::Error::invalid_value(
+            de::Unexpected::Unsigned(value),
+            &"a 32-bit unsigned integer",
+        ));
+    }
+    Ok(value as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compat::MissingCpuidBits;
+
+    fn build_cpuid() -> CpuId {
+        let mut cpuid = CpuId::new(2).unwrap();
+        let entries = cpuid.as_mut_slice();
+        entries[0].function = 0x1;
+        entries[0].eax = 0x0000_ffff;
+        entries[0].ecx = 0xffff_ffff;
+        entries[1].function = 0x7;
+        entries[1].index = 0x1;
+        entries[1].ebx = 0xffff_0000;
+        cpuid
+    }
+
+    #[test]
+    fn test_deserialize_template() {
+        let template = r#"{
+            "cpuid_modifiers": [
+                {
+                    "leaf": "0x80000001",
+                    "modifiers": [
+                        { "register": "ecx", "bitmask": "0x100", "value": 0 }
+                    ]
+                }
+            ],
+            "msr_modifiers": [
+                { "index": 266, "value": "0xffffffffffffffff" }
+            ]
+        }"#;
+        let template = serde_json::from_str::<CustomCpuTemplate>(template).unwrap();
+        assert_eq!(
+            template,
+            CustomCpuTemplate {
+                cpuid_modifiers: vec![CpuidLeafModifier {
+                    leaf: 0x8000_0001,
+                    subleaf: 0,
+                    modifiers: vec![CpuidRegisterModifier {
+                        register: CpuidRegister::Ecx,
+                        bitmask: 0x100,
+                        value: 0,
+                    }],
+                }],
+                msr_modifiers: vec![MsrModifier {
+                    index: 0x10a,
+                    value: u64::MAX,
+                }],
+            }
+        );
+
+        assert_eq!(
+            serde_json::from_str::<CustomCpuTemplate>("{}").unwrap(),
+            CustomCpuTemplate::default()
+        );
+        // The leaf doesn't fit a u32.
+        assert!(serde_json::from_str::<CpuidLeafModifier>(
+            r#"{ "leaf": "0x100000000", "modifiers": [] }"#
+        )
+        .is_err());
+        // The hex string is invalid.
+        assert!(
+            serde_json::from_str::<CpuidLeafModifier>(r#"{ "leaf": "1", "modifiers": [] }"#)
+                .is_err()
+        );
+        // The register doesn't exist.
+        assert!(serde_json::from_str::<CpuidRegisterModifier>(
+            r#"{ "register": "rax", "bitmask": 1, "value": 1 }"#
+        )
+        .is_err());
+        assert!(serde_json::from_str::<CustomCpuTemplate>(r#"{ "invalid_field": [] }"#).is_err());
+    }
+
+    #[test]
+    fn test_set_cpuid_entries() {
+        let vm_spec = VmSpec::new(0, 1, false).unwrap();
+        let modifiers = vec![
+            CpuidLeafModifier {
+                leaf: 0x1,
+                subleaf: 0,
+                modifiers: vec![
+                    CpuidRegisterModifier {
+                        register: CpuidRegister::Eax,
+                        bitmask: 0x0000_00ff,
+                        value: 0x1234_5612,
+                    },
+                    CpuidRegisterModifier {
+                        register: CpuidRegister::Ecx,
+                        bitmask: 0x8000_0001,
+                        value: 0,
+                    },
+                ],
+            },
+            CpuidLeafModifier {
+                leaf: 0x7,
+                subleaf: 0x1,
+                modifiers: vec![CpuidRegisterModifier {
+                    register: CpuidRegister::Ebx,
+                    bitmask: 0xffff_ffff,
+                    value: 0x0000_abcd,
+                }],
+            },
+        ];
+
+        let mut cpuid = build_cpuid();
+        set_cpuid_entries(&mut cpuid, &vm_spec, &modifiers, &build_cpuid()).unwrap();
+        let entries = cpuid.as_slice();
+        assert_eq!(entries[0].eax, 0x0000_ff12);
+        assert_eq!(entries[0].ecx, 0x7fff_fffe);
+        assert_eq!(entries[0].edx, 0);
+        assert_eq!(entries[1].ebx, 0x0000_abcd);
+
+        // The subleaf 0x7/0x0 isn't reported, so nothing is modified.
+        let modifiers = vec![
+            modifiers[0].clone(),
+            CpuidLeafModifier {
+                leaf: 0x7,
+                subleaf: 0,
+                modifiers: vec![],
+            },
+        ];
+        let mut cpuid = build_cpuid();
+        match set_cpuid_entries(&mut cpuid, &vm_spec, &modifiers, &build_cpuid()) {
+            Err(Error::UnsupportedLeaf(0x7, 0)) => (),
+            _ => panic!("Test failed."),
+        }
+        assert_eq!(cpuid.as_slice(), build_cpuid().as_slice());
+
+        // The host doesn't support FPU, so it can't be enabled, while the hypervisor flag,
+        // which is set by Firecracker, can.
+        let modifiers = vec![CpuidLeafModifier {
+            leaf: 0x1,
+            subleaf: 0,
+            modifiers: vec![
+                CpuidRegisterModifier {
+                    register: CpuidRegister::Edx,
+                    bitmask: 0x0000_0003,
+                    value: 0x0000_0001,
+                },
+                CpuidRegisterModifier {
+                    register: CpuidRegister::Ecx,
+                    bitmask: 0x8000_0000,
+                    value: 0x8000_0000,
+                },
+            ],
+        }];
+        let mut supported_cpuid = build_cpuid();
+        supported_cpuid.as_mut_slice()[0].ecx = 0;
+        let mut cpuid = build_cpuid();
+        match set_cpuid_entries(&mut cpuid, &vm_spec, &modifiers, &supported_cpuid) {
+            Err(Error::UnsupportedFeatures(bits)) => assert_eq!(
+                bits,
+                vec![MissingCpuidBits {
+                    leaf: 0x1,
+                    subleaf: 0,
+                    register: CpuidRegister::Edx,
+                    bits: 0x0000_0001,
+                }]
+            ),
+            _ => panic!("Test failed."),
+        }
+        assert_eq!(cpuid.as_slice(), build_cpuid().as_slice());
+    }
+}
@@ -1,5 +1,9 @@
 // Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
 
+/// Follows a user-defined template in setting up the CPUID.
+pub mod custom;
+// Contains AMD specific templates.
+pub mod amd;
 // Contains Intel specific templates.
 pub mod intel;
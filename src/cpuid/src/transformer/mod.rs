@@ -10,6 +10,7 @@ pub use kvm_bindings::{kvm_cpuid_entry2, CpuId};
 use crate::brand_string::BrandString;
 use crate::brand_string::Reg as BsReg;
 use crate::common::get_vendor_id_from_host;
+use crate::compat::MissingCpuidBits;
 
 /// Structure containing the specifications of the VM
 pub struct VmSpec {
@@ -62,6 +63,10 @@ pub enum Error {
     InternalError(super::common::Error),
     /// The operation is not permitted for the current vendor
     InvalidVendor,
+    /// The CPUID leaf and subleaf are not supported by the host.
+    UnsupportedLeaf(u32, u32),
+    /// The feature flags are set, but not supported by the host.
+    UnsupportedFeatures(Vec<MissingCpuidBits>),
     /// The maximum number of addressable logical CPUs cannot be stored in an `u8`.
     VcpuCountOverflow,
 }
@@ -41,6 +41,9 @@ pub enum Error {
     MalformedPayload,
     /// Device received malformed descriptor.
     MalformedDescriptor,
+    /// The net driver can't be asked to pick up a new MAC address, since it didn't
+    /// negotiate the announcement of MAC address changes.
+    MacUpdateNotAnnounced,
     /// Error during queue processing.
     QueueError(QueueError),
     /// Vsock device error.
@@ -8,6 +8,7 @@ use std::sync::Arc;
 use std::time::Duration;
 use timerfd::{SetTimeFlags, TimerState};
 
+use serde::Serialize;
 use snapshot::Persist;
 use versionize::{VersionMap, Versionize, VersionizeResult};
 use versionize_derive::Versionize;
@@ -20,14 +21,14 @@ use crate::virtio::balloon::device::{BalloonStats, ConfigSpace};
 use crate::virtio::persist::VirtioDeviceState;
 use crate::virtio::{DeviceState, TYPE_BALLOON};
 
-#[derive(Clone, Versionize)]
+#[derive(Clone, Debug, Serialize, Versionize)]
 // NOTICE: Any changes to this structure require a snapshot version bump.
 pub struct BalloonConfigSpaceState {
     num_pages: u32,
     actual_pages: u32,
 }
 
-#[derive(Clone, Versionize)]
+#[derive(Clone, Debug, Serialize, Versionize)]
 // NOTICE: Any changes to this structure require a snapshot version bump.
 pub struct BalloonStatsState {
     swap_in: Option<u64>,
@@ -78,7 +79,7 @@ impl BalloonStatsState {
     }
 }
 
-#[derive(Clone, Versionize)]
+#[derive(Clone, Debug, Serialize, Versionize)]
 // NOTICE: Any changes to this structure require a snapshot version bump.
 pub struct BalloonState {
     stats_polling_interval_s: u16,
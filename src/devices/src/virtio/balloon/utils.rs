@@ -98,14 +98,16 @@ pub(crate) fn remove_range(
             }
         };
 
-        // Madvise the region in order to mark it as not used.
-        let ret = unsafe {
-            libc::madvise(
-                phys_address as *mut _,
-                range_len as usize,
-                libc::MADV_DONTNEED,
-            )
+        // Pages of a shared mapping are still referenced by the backing file after
+        // `MADV_DONTNEED`, so they need to be punched out of it instead.
+        let advice = if region.flags() & libc::MAP_SHARED != 0 {
+            libc::MADV_REMOVE
+        } else {
+            libc::MADV_DONTNEED
         };
+
+        // Madvise the region in order to mark it as not used.
+        let ret = unsafe { libc::madvise(phys_address as *mut _, range_len as usize, advice) };
         if ret < 0 {
             return Err(RemoveRegionError::MadviseFail(io::Error::last_os_error()));
         }
@@ -264,6 +266,44 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_remove_range_on_shared() {
+        use std::os::unix::fs::FileExt;
+        use utils::tempfile::TempFile;
+        use vm_memory::FileOffset;
+
+        let page_size: usize = 0x1000;
+        let file = TempFile::new().unwrap();
+        file.as_file().set_len(2 * page_size as u64).unwrap();
+        let file_offset = FileOffset::new(file.as_file().try_clone().unwrap(), 0);
+        let mem = GuestMemoryMmap::from_ranges_with_files_guarded(
+            &[(GuestAddress(0), 2 * page_size, Some(file_offset))],
+            false,
+        )
+        .unwrap();
+
+        // Fill the memory with ones.
+        let ones = vec![1u8; 2 * page_size];
+        mem.write(&ones[..], GuestAddress(0)).unwrap();
+
+        // Remove the first page.
+        assert!(remove_range(&mem, (GuestAddress(0), page_size as u64), false).is_ok());
+
+        // Check that the first page is zeroed both in memory and in the backing file.
+        let mut actual_page = vec![0u8; page_size];
+        mem.read(&mut actual_page.as_mut_slice(), GuestAddress(0))
+            .unwrap();
+        assert_eq!(vec![0u8; page_size], actual_page);
+        file.as_file().read_exact_at(&mut actual_page, 0).unwrap();
+        assert_eq!(vec![0u8; page_size], actual_page);
+
+        // Check that the second page still contains ones.
+        file.as_file()
+            .read_exact_at(&mut actual_page, page_size as u64)
+            .unwrap();
+        assert_eq!(vec![1u8; page_size], actual_page);
+    }
+
     /// -------------------------------------
     /// BEGIN PROPERTY BASED TESTING
     use proptest::prelude::*;
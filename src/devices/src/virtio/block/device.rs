@@ -8,7 +8,7 @@
 use std::cmp;
 use std::convert::From;
 use std::fs::{File, OpenOptions};
-use std::io::{self, Seek, SeekFrom, Write};
+use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::os::linux::fs::MetadataExt;
 use std::path::PathBuf;
 use std::result;
@@ -17,16 +17,32 @@ use std::sync::Arc;
 
 use logger::{error, warn, IncMetric, METRICS};
 use rate_limiter::{BucketUpdate, RateLimiter, TokenType};
+use utils::byte_order;
 use utils::eventfd::EventFd;
 use virtio_gen::virtio_blk::*;
-use vm_memory::{Bytes, GuestMemoryMmap};
+use vm_memory::GuestMemoryMmap;
 
 use super::{
     super::{ActivateResult, DeviceState, Queue, VirtioDevice, TYPE_BLOCK},
+    io_engine::{AsyncFileEngine, FileEngineType},
+    overlay::OverlayImage,
     request::*,
-    Error, CONFIG_SPACE_SIZE, QUEUE_SIZES, SECTOR_SHIFT, SECTOR_SIZE,
+    Error, CONFIG_SPACE_SIZE, MAX_DISCARD_SEG, MAX_NUM_QUEUES, MAX_WRITE_ZEROES_SEG, QUEUE_SIZE,
+    SECTOR_SHIFT, SECTOR_SIZE,
 };
 
+// Offset of the number of queues in the config space.
+const NUM_QUEUES_OFFSET: usize = 34;
+// Size of the config space up to, and including, the discard and write zeroes fields.
+const DISCARD_WRITE_ZEROES_CONFIG_SPACE_SIZE: usize = 60;
+// Offsets of the discard and write zeroes fields in the config space.
+const MAX_DISCARD_SECTORS_OFFSET: usize = 36;
+const MAX_DISCARD_SEG_OFFSET: usize = 40;
+const DISCARD_SECTOR_ALIGNMENT_OFFSET: usize = 44;
+const MAX_WRITE_ZEROES_SECTORS_OFFSET: usize = 48;
+const MAX_WRITE_ZEROES_SEG_OFFSET: usize = 52;
+const WRITE_ZEROES_MAY_UNMAP_OFFSET: usize = 56;
+
 use crate::virtio::{IrqTrigger, IrqType};
 
 use serde::{Deserialize, Serialize};
@@ -49,26 +65,90 @@ impl Default for CacheType {
     }
 }
 
+/// Provides access to the contents of the disk, either directly from the backing file or
+/// through its copy-on-write overlay.
+pub(crate) enum DiskImage<'a> {
+    Raw(&'a mut File),
+    Overlay(&'a mut OverlayImage, &'a mut File),
+}
+
+impl Read for DiskImage<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            DiskImage::Raw(file) => file.read(buf),
+            DiskImage::Overlay(overlay, base) => overlay.read(base, buf),
+        }
+    }
+}
+
+impl Write for DiskImage<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            DiskImage::Raw(file) => file.write(buf),
+            DiskImage::Overlay(overlay, base) => overlay.write(base, buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            DiskImage::Raw(file) => file.flush(),
+            DiskImage::Overlay(overlay, _) => overlay.file().flush(),
+        }
+    }
+}
+
+impl Seek for DiskImage<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            DiskImage::Raw(file) => file.seek(pos),
+            DiskImage::Overlay(overlay, _) => overlay.seek(pos),
+        }
+    }
+}
+
 /// Helper object for setting up all `Block` fields derived from its backing file.
 pub(crate) struct DiskProperties {
     cache_type: CacheType,
     file_path: String,
+    // The backing file, which is opened read-only when the disk has an overlay.
     file: File,
+    overlay: Option<OverlayImage>,
     nsectors: u64,
     image_id: Vec<u8>,
+    async_engine: Option<AsyncFileEngine>,
+    is_discard_enabled: bool,
+    is_write_zeroes_enabled: bool,
 }
 
 impl DiskProperties {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         disk_image_path: String,
         is_disk_read_only: bool,
         cache_type: CacheType,
+        file_engine_type: FileEngineType,
+        is_discard_enabled: bool,
+        is_write_zeroes_enabled: bool,
+        num_queues: u16,
+        overlay_path: Option<String>,
     ) -> io::Result<Self> {
+        if overlay_path.is_some() && file_engine_type == FileEngineType::Async {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Overlay images are not supported by the async I/O engine.",
+            ));
+        }
+
+        // Writes only ever reach the overlay, so the base image is never opened for writing.
         let mut disk_image = OpenOptions::new()
             .read(true)
-            .write(!is_disk_read_only)
+            .write(!is_disk_read_only && overlay_path.is_none())
             .open(PathBuf::from(&disk_image_path))?;
         let disk_size = disk_image.seek(SeekFrom::End(0))? as u64;
+        let overlay = match overlay_path {
+            Some(path) => Some(OverlayImage::open(path, is_disk_read_only, disk_size)?),
+            None => None,
+        };
 
         // We only support disk size, which uses the first two words of the configuration space.
         // If the image is not a multiple of the sector size, the tail bits are not exposed.
@@ -80,12 +160,31 @@ impl DiskProperties {
             );
         }
 
+        let async_engine = match file_engine_type {
+            FileEngineType::Async => Some(AsyncFileEngine::new(num_queues).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("Cannot create the async I/O engine: {:?}", e),
+                )
+            })?),
+            FileEngineType::Sync => None,
+        };
+
         Ok(Self {
             cache_type,
             nsectors: disk_size >> SECTOR_SHIFT,
-            image_id: Self::build_disk_image_id(&disk_image),
+            // Disks sharing the same base image are told apart by their overlays.
+            image_id: Self::build_disk_image_id(
+                overlay
+                    .as_ref()
+                    .map_or(&disk_image, |overlay| overlay.file()),
+            ),
             file_path: disk_image_path,
             file: disk_image,
+            overlay,
+            async_engine,
+            is_discard_enabled,
+            is_write_zeroes_enabled,
         })
     }
 
@@ -97,6 +196,25 @@ impl DiskProperties {
         &mut self.file
     }
 
+    /// Provides the file holding the data written to the disk: the overlay, if any, or the
+    /// backing file.
+    pub fn data_file(&self) -> &File {
+        self.overlay
+            .as_ref()
+            .map_or(&self.file, |overlay| overlay.file())
+    }
+
+    pub fn image_mut(&mut self) -> DiskImage {
+        match self.overlay.as_mut() {
+            Some(overlay) => DiskImage::Overlay(overlay, &mut self.file),
+            None => DiskImage::Raw(&mut self.file),
+        }
+    }
+
+    pub fn overlay(&self) -> Option<&OverlayImage> {
+        self.overlay.as_ref()
+    }
+
     pub fn nsectors(&self) -> u64 {
         self.nsectors
     }
@@ -105,6 +223,29 @@ impl DiskProperties {
         &self.image_id
     }
 
+    pub fn async_engine(&self) -> Option<&AsyncFileEngine> {
+        self.async_engine.as_ref()
+    }
+
+    pub fn async_engine_mut(&mut self) -> Option<&mut AsyncFileEngine> {
+        self.async_engine.as_mut()
+    }
+
+    pub fn file_engine_type(&self) -> FileEngineType {
+        match self.async_engine {
+            Some(_) => FileEngineType::Async,
+            None => FileEngineType::Sync,
+        }
+    }
+
+    pub fn is_discard_enabled(&self) -> bool {
+        self.is_discard_enabled
+    }
+
+    pub fn is_write_zeroes_enabled(&self) -> bool {
+        self.is_write_zeroes_enabled
+    }
+
     fn build_device_id(disk_file: &File) -> result::Result<String, Error> {
         let blk_metadata = disk_file.metadata().map_err(Error::GetFileMetadata)?;
         // This is how kvmtool does it.
@@ -141,13 +282,33 @@ impl DiskProperties {
 
     /// Provides vec containing the virtio block configuration space
     /// buffer. The config space is populated with the disk size based
-    /// on the backing file size.
+    /// on the backing file size. When discard or write zeroes requests
+    /// are enabled, the config space also describes their limits.
     pub fn virtio_block_config_space(&self) -> Vec<u8> {
         // The config space is little endian.
         let mut config = Vec::with_capacity(CONFIG_SPACE_SIZE);
         for i in 0..CONFIG_SPACE_SIZE {
             config.push((self.nsectors >> (8 * i)) as u8);
         }
+
+        if self.is_discard_enabled || self.is_write_zeroes_enabled {
+            // The fields in between are only valid for features we don't advertise.
+            config.resize(DISCARD_WRITE_ZEROES_CONFIG_SPACE_SIZE, 0);
+        }
+        if self.is_discard_enabled {
+            byte_order::write_le_u32(&mut config[MAX_DISCARD_SECTORS_OFFSET..], u32::MAX);
+            byte_order::write_le_u32(&mut config[MAX_DISCARD_SEG_OFFSET..], MAX_DISCARD_SEG);
+            byte_order::write_le_u32(&mut config[DISCARD_SECTOR_ALIGNMENT_OFFSET..], 1);
+        }
+        if self.is_write_zeroes_enabled {
+            byte_order::write_le_u32(&mut config[MAX_WRITE_ZEROES_SECTORS_OFFSET..], u32::MAX);
+            byte_order::write_le_u32(
+                &mut config[MAX_WRITE_ZEROES_SEG_OFFSET..],
+                MAX_WRITE_ZEROES_SEG,
+            );
+            // Zeroed ranges may be deallocated, by punching holes in the backing file.
+            config[WRITE_ZEROES_MAY_UNMAP_OFFSET] = 1;
+        }
         config
     }
 
@@ -161,11 +322,11 @@ impl Drop for DiskProperties {
         match self.cache_type {
             CacheType::Writeback => {
                 // flush() first to force any cached data out.
-                if self.file.flush().is_err() {
+                if self.data_file().flush().is_err() {
                     error!("Failed to flush block data on drop.");
                 }
                 // Sync data out to physical media on host.
-                if self.file.sync_all().is_err() {
+                if self.data_file().sync_all().is_err() {
                     error!("Failed to sync block data on drop.")
                 }
                 METRICS.block.flush_count.inc();
@@ -190,7 +351,7 @@ pub struct Block {
 
     // Transport related fields.
     pub(crate) queues: Vec<Queue>,
-    pub(crate) queue_evts: [EventFd; 1],
+    pub(crate) queue_evts: Vec<EventFd>,
     pub(crate) device_state: DeviceState,
     pub(crate) irq_trigger: IrqTrigger,
 
@@ -205,6 +366,7 @@ impl Block {
     /// Create a new virtio block device that operates on the given file.
     ///
     /// The given file must be seekable and sizable.
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         id: String,
         partuuid: Option<String>,
@@ -213,8 +375,32 @@ impl Block {
         is_disk_read_only: bool,
         is_disk_root: bool,
         rate_limiter: RateLimiter,
+        file_engine_type: FileEngineType,
+        is_discard_enabled: bool,
+        is_write_zeroes_enabled: bool,
+        num_queues: u16,
+        overlay_path: Option<String>,
     ) -> io::Result<Block> {
-        let disk_properties = DiskProperties::new(disk_image_path, is_disk_read_only, cache_type)?;
+        if num_queues == 0 || num_queues > MAX_NUM_QUEUES {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "The number of queues must be between 1 and {}",
+                    MAX_NUM_QUEUES
+                ),
+            ));
+        }
+
+        let disk_properties = DiskProperties::new(
+            disk_image_path,
+            is_disk_read_only,
+            cache_type,
+            file_engine_type,
+            is_discard_enabled,
+            is_write_zeroes_enabled,
+            num_queues,
+            overlay_path,
+        )?;
 
         let mut avail_features = (1u64 << VIRTIO_F_VERSION_1) | (1u64 << VIRTIO_BLK_F_FLUSH);
 
@@ -222,16 +408,31 @@ impl Block {
             avail_features |= 1u64 << VIRTIO_BLK_F_RO;
         };
 
-        let queue_evts = [EventFd::new(libc::EFD_NONBLOCK)?];
+        if is_discard_enabled {
+            avail_features |= 1u64 << VIRTIO_BLK_F_DISCARD;
+        }
+
+        if is_write_zeroes_enabled {
+            avail_features |= 1u64 << VIRTIO_BLK_F_WRITE_ZEROES;
+        }
 
-        let queues = QUEUE_SIZES.iter().map(|&s| Queue::new(s)).collect();
+        if num_queues > 1 {
+            avail_features |= 1u64 << VIRTIO_BLK_F_MQ;
+        }
+
+        let mut queue_evts = Vec::with_capacity(num_queues as usize);
+        for _ in 0..num_queues {
+            queue_evts.push(EventFd::new(libc::EFD_NONBLOCK)?);
+        }
+
+        let queues = (0..num_queues).map(|_| Queue::new(QUEUE_SIZE)).collect();
 
         Ok(Block {
             id,
             root_device: is_disk_root,
             partuuid,
             rate_limiter,
-            config_space: disk_properties.virtio_block_config_space(),
+            config_space: Self::build_config_space(&disk_properties, num_queues),
             disk: disk_properties,
             avail_features,
             acked_features: 0u64,
@@ -243,21 +444,55 @@ impl Block {
         })
     }
 
-    pub(crate) fn process_queue_event(&mut self) {
+    // Builds the config space, which holds the number of queues after the fields
+    // describing the disk, when there is more than one.
+    fn build_config_space(disk: &DiskProperties, num_queues: u16) -> Vec<u8> {
+        let mut config = disk.virtio_block_config_space();
+        if num_queues > 1 {
+            if config.len() < NUM_QUEUES_OFFSET + 2 {
+                // The fields in between are only valid for features we don't advertise.
+                config.resize(NUM_QUEUES_OFFSET + 2, 0);
+            }
+            byte_order::write_le_u16(&mut config[NUM_QUEUES_OFFSET..], num_queues);
+        }
+        config
+    }
+
+    pub(crate) fn process_queue_event(&mut self, queue_index: usize) {
         METRICS.block.queue_event_count.inc();
-        if let Err(e) = self.queue_evts[0].read() {
+        if let Err(e) = self.queue_evts[queue_index].read() {
             error!("Failed to get queue event: {:?}", e);
             METRICS.block.event_fails.inc();
         } else if self.rate_limiter.is_blocked() {
             METRICS.block.rate_limiter_throttled_events.inc();
         } else {
-            self.process_virtio_queues();
+            self.process_queue(queue_index);
         }
     }
 
     /// Process device virtio queue(s).
     pub fn process_virtio_queues(&mut self) {
-        self.process_queue(0);
+        for queue_index in 0..self.queues.len() {
+            self.process_queue(queue_index);
+        }
+    }
+
+    pub(crate) fn process_async_completion_event(&mut self) {
+        let engine = match self.disk.async_engine_mut() {
+            Some(engine) => engine,
+            None => {
+                error!("Block: Unexpected async completion event for a sync I/O engine");
+                METRICS.block.event_fails.inc();
+                return;
+            }
+        };
+
+        if let Err(e) = engine.completion_evt().read() {
+            error!("Failed to get async completion event: {:?}", e);
+            METRICS.block.event_fails.inc();
+        } else {
+            self.process_async_completion_queue();
+        }
     }
 
     pub(crate) fn process_rate_limiter_event(&mut self) {
@@ -265,7 +500,7 @@ impl Block {
         // Upon rate limiter event, call the rate limiter handler
         // and restart processing the queue.
         if self.rate_limiter.event_handler().is_ok() {
-            self.process_queue(0);
+            self.process_virtio_queues();
         }
     }
 
@@ -275,6 +510,7 @@ impl Block {
 
         let queue = &mut self.queues[queue_index];
         let mut used_any = false;
+        let mut submitted_any = false;
         while let Some(head) = queue.pop(mem) {
             let len = match Request::parse(&head, mem) {
                 Ok(request) => {
@@ -307,22 +543,14 @@ impl Block {
                         }
                     }
 
-                    let status = Status::from_result(request.execute(&mut self.disk, mem));
-                    let virtio_blk_status = status.virtio_blk_status();
-                    let num_used_bytes = status.num_used_bytes();
-                    if let Status::Err(err_status) = status {
-                        METRICS.block.invalid_reqs_count.inc();
-                        error!(
-                            "Failed to execute {:?} virtio block request: {:?}",
-                            request.request_type, err_status
-                        );
-                    }
-
-                    if let Err(e) = mem.write_obj(virtio_blk_status, request.status_addr) {
-                        error!("Failed to write virtio block status: {:?}", e)
+                    match request.process(&mut self.disk, queue_index, head.index, mem) {
+                        // The descriptor chain is returned to the guest once the request completes.
+                        ProcessingResult::Submitted => {
+                            submitted_any = true;
+                            continue;
+                        }
+                        ProcessingResult::Executed(num_used_bytes) => num_used_bytes,
                     }
-
-                    num_used_bytes
                 }
                 Err(e) => {
                     error!("Failed to parse available descriptor chain: {:?}", e);
@@ -340,23 +568,111 @@ impl Block {
             used_any = true;
         }
 
+        if submitted_any {
+            // Safe to unwrap since requests are only submitted to an asynchronous engine.
+            if let Err(e) = self
+                .disk
+                .async_engine_mut()
+                .unwrap()
+                .kick_submission_queue()
+            {
+                error!("Failed to submit block async I/O requests: {:?}", e);
+                METRICS.block.event_fails.inc();
+            }
+        }
+
         if used_any {
             self.irq_trigger
                 .trigger_irq(IrqType::Vring)
                 .unwrap_or_else(|_| {
                     METRICS.block.event_fails.inc();
                 });
-        } else {
+        } else if !submitted_any {
             METRICS.block.no_avail_buffer.inc();
         }
     }
 
+    fn process_async_completion_queue(&mut self) {
+        // This is safe since async requests are only submitted by an activated device.
+        let mem = self.device_state.mem().unwrap();
+        // Safe to unwrap since callers checked that the disk has an asynchronous engine.
+        let engine = self.disk.async_engine_mut().unwrap();
+
+        let mut used_any = false;
+        while let Some((pending, result)) = engine.pop() {
+            let num_used_bytes = pending.finish(mem, result);
+            self.queues[pending.queue_index]
+                .add_used(mem, pending.desc_idx, num_used_bytes)
+                .unwrap_or_else(|e| {
+                    error!(
+                        "Failed to add available descriptor head {}: {}",
+                        pending.desc_idx, e
+                    )
+                });
+            used_any = true;
+        }
+
+        if used_any {
+            self.irq_trigger
+                .trigger_irq(IrqType::Vring)
+                .unwrap_or_else(|_| {
+                    METRICS.block.event_fails.inc();
+                });
+        }
+    }
+
+    /// Waits for all the requests handed to the asynchronous engine to complete and
+    /// returns their descriptor chains to the guest.
+    fn drain_async_requests(&mut self) {
+        if !self.is_activated() {
+            return;
+        }
+        if let Some(engine) = self.disk.async_engine_mut() {
+            if let Err(e) = engine.drain() {
+                error!("Failed to drain block async I/O requests: {:?}", e);
+                METRICS.block.event_fails.inc();
+            }
+            self.process_async_completion_queue();
+        }
+    }
+
+    /// Prepares the device for saving its state, by completing all in-flight requests.
+    pub fn prepare_save(&mut self) {
+        self.drain_async_requests();
+    }
+
     /// Update the backing file and the config space of the block device.
-    pub fn update_disk_image(&mut self, disk_image_path: String) -> io::Result<()> {
-        let disk_properties =
-            DiskProperties::new(disk_image_path, self.is_read_only(), self.cache_type())?;
+    ///
+    /// If no `overlay_path` is provided, the current overlay, if any, is kept on top of the
+    /// new backing file.
+    pub fn update_disk_image(
+        &mut self,
+        disk_image_path: String,
+        overlay_path: Option<String>,
+    ) -> io::Result<()> {
+        // Requests in flight on the old backing file must complete before it is replaced.
+        self.drain_async_requests();
+        let overlay_path = overlay_path.or_else(|| self.overlay_path().cloned());
+        if overlay_path.is_some() && self.file_engine_type() == FileEngineType::Async {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Overlay images are not supported by the async I/O engine.",
+            ));
+        }
+        let mut disk_properties = DiskProperties::new(
+            disk_image_path,
+            self.is_read_only(),
+            self.cache_type(),
+            FileEngineType::Sync,
+            self.disk.is_discard_enabled(),
+            self.disk.is_write_zeroes_enabled(),
+            self.queues.len() as u16,
+            overlay_path,
+        )?;
+        // Keep using the existing (now idle) asynchronous engine, if any.
+        disk_properties.async_engine = self.disk.async_engine.take();
         self.disk = disk_properties;
-        self.config_space = self.disk.virtio_block_config_space();
+        self.config_space = Self::build_config_space(&self.disk, self.queues.len() as u16);
 
         // Kick the driver to pick up the changes.
         self.irq_trigger.trigger_irq(IrqType::Config).unwrap();
@@ -380,6 +696,11 @@ impl Block {
         self.disk.file_path()
     }
 
+    /// Provides the overlay file path of this block device, if any.
+    pub fn overlay_path(&self) -> Option<&String> {
+        self.disk.overlay().map(OverlayImage::path)
+    }
+
     /// Provides the PARTUUID of this block device.
     pub fn partuuid(&self) -> Option<&String> {
         self.partuuid.as_ref()
@@ -400,6 +721,27 @@ impl Block {
         self.disk.cache_type()
     }
 
+    /// Specifies the engine executing the requests on the backing file.
+    pub fn file_engine_type(&self) -> FileEngineType {
+        self.disk.file_engine_type()
+    }
+
+    /// Specifies if the device accepts discard requests.
+    pub fn is_discard_enabled(&self) -> bool {
+        self.disk.is_discard_enabled()
+    }
+
+    /// Specifies if the device accepts write zeroes requests.
+    pub fn is_write_zeroes_enabled(&self) -> bool {
+        self.disk.is_write_zeroes_enabled()
+    }
+
+    /// Provides the number of queues of this block device.
+    pub fn num_queues(&self) -> u16 {
+        // Safe to cast since the number of queues is checked at creation time.
+        self.queues.len() as u16
+    }
+
     /// Provides non-mutable reference to this device's rate limiter.
     pub fn rate_limiter(&self) -> &RateLimiter {
         &self.rate_limiter
@@ -496,11 +838,12 @@ pub(crate) mod tests {
     use super::*;
     use crate::virtio::queue::tests::*;
     use utils::tempfile::TempFile;
-    use vm_memory::GuestAddress;
+    use vm_memory::{Bytes, GuestAddress};
 
     use crate::check_metric_after_block;
     use crate::virtio::block::test_utils::{
-        default_block, invoke_handler_for_queue_event, set_queue, set_rate_limiter,
+        default_block, default_block_with_engine, invoke_handler_for_queue_event, set_queue,
+        set_rate_limiter,
     };
     use crate::virtio::test_utils::{default_mem, initialize_virtqueue, VirtQueue};
 
@@ -515,6 +858,11 @@ pub(crate) mod tests {
             String::from(f.as_path().to_str().unwrap()),
             true,
             CacheType::Unsafe,
+            FileEngineType::Sync,
+            false,
+            false,
+            1,
+            None,
         )
         .unwrap();
 
@@ -528,9 +876,17 @@ pub(crate) mod tests {
         // Testing `backing_file.virtio_block_disk_image_id()` implies
         // duplicating that logic in tests, so skipping it.
 
-        assert!(
-            DiskProperties::new("invalid-disk-path".to_string(), true, CacheType::Unsafe).is_err()
-        );
+        assert!(DiskProperties::new(
+            "invalid-disk-path".to_string(),
+            true,
+            CacheType::Unsafe,
+            FileEngineType::Sync,
+            false,
+            false,
+            1,
+            None,
+        )
+        .is_err());
     }
 
     #[test]
@@ -1064,6 +1420,101 @@ pub(crate) mod tests {
         }
     }
 
+    #[test]
+    fn test_async_read_write() {
+        let mut block = default_block_with_engine(FileEngineType::Async);
+        let mem = default_mem();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        set_queue(&mut block, 0, vq.create_queue());
+        block.activate(mem.clone()).unwrap();
+        initialize_virtqueue(&vq);
+
+        let request_type_addr = GuestAddress(vq.dtable[0].addr.get());
+        let data_addr = GuestAddress(vq.dtable[1].addr.get());
+        let status_addr = GuestAddress(vq.dtable[2].addr.get());
+        let rand_data = utils::rand::rand_alphanumerics(512).as_bytes().to_vec();
+
+        // Write.
+        {
+            mem.write_obj::<u32>(VIRTIO_BLK_T_OUT, request_type_addr)
+                .unwrap();
+            // Make data read only, 512 bytes in len, and set the actual value to be written.
+            vq.dtable[1].flags.set(VIRTQ_DESC_F_NEXT);
+            vq.dtable[1].len.set(512);
+            mem.write_slice(&rand_data, data_addr).unwrap();
+
+            block.queue_evts[0].write(1).unwrap();
+            block.process_queue_event(0);
+            // The descriptor chain is only returned to the guest after the write completes.
+            assert_eq!(vq.used.idx.get(), 0);
+            assert!(!block.irq_trigger.has_pending_irq(IrqType::Vring));
+
+            // Saving the device state completes all in-flight requests.
+            check_metric_after_block!(&METRICS.block.write_count, 1, block.prepare_save());
+            assert!(block.irq_trigger.has_pending_irq(IrqType::Vring));
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(vq.used.ring[0].get().id, 0);
+            assert_eq!(vq.used.ring[0].get().len, 1);
+            assert_eq!(mem.read_obj::<u32>(status_addr).unwrap(), VIRTIO_BLK_S_OK);
+
+            let mut buf = [0u8; 512];
+            block.disk.file.seek(SeekFrom::Start(0)).unwrap();
+            block.disk.file.read_exact(&mut buf).unwrap();
+            assert_eq!(buf, rand_data.as_slice());
+        }
+
+        // Read.
+        {
+            vq.used.idx.set(0);
+            set_queue(&mut block, 0, vq.create_queue());
+
+            mem.write_obj::<u32>(VIRTIO_BLK_T_IN, request_type_addr)
+                .unwrap();
+            vq.dtable[1]
+                .flags
+                .set(VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE);
+            mem.write_slice(&[0u8; 512], data_addr).unwrap();
+
+            block.queue_evts[0].write(1).unwrap();
+            block.process_queue_event(0);
+            block.disk.async_engine_mut().unwrap().drain().unwrap();
+            check_metric_after_block!(
+                &METRICS.block.read_count,
+                1,
+                block.process_async_completion_event()
+            );
+
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(vq.used.ring[0].get().id, 0);
+            // Added status byte length.
+            assert_eq!(vq.used.ring[0].get().len, 513);
+            assert_eq!(mem.read_obj::<u32>(status_addr).unwrap(), VIRTIO_BLK_S_OK);
+
+            let mut buf = [0u8; 512];
+            mem.read_slice(&mut buf, data_addr).unwrap();
+            assert_eq!(buf, rand_data.as_slice());
+        }
+
+        // Out of bounds requests fail without being submitted.
+        {
+            vq.used.idx.set(0);
+            set_queue(&mut block, 0, vq.create_queue());
+
+            let request_header = RequestHeader::new(VIRTIO_BLK_T_IN, 0x1000);
+            mem.write_obj::<RequestHeader>(request_header, request_type_addr)
+                .unwrap();
+
+            invoke_handler_for_queue_event(&mut block);
+            assert_eq!(block.disk.async_engine_mut().unwrap().num_pending(), 0);
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(vq.used.ring[0].get().len, 1);
+            assert_eq!(
+                mem.read_obj::<u32>(status_addr).unwrap(),
+                VIRTIO_BLK_S_IOERR
+            );
+        }
+    }
+
     #[test]
     fn test_get_device_id() {
         let mut block = default_block();
@@ -1130,6 +1581,251 @@ pub(crate) mod tests {
         }
     }
 
+    #[test]
+    fn test_discard_write_zeroes() {
+        let f = TempFile::new().unwrap();
+        f.as_file().write_all(&[0xff; 0x1000]).unwrap();
+        let mut block = Block::new(
+            "test".to_string(),
+            None,
+            CacheType::Unsafe,
+            f.as_path().to_str().unwrap().to_string(),
+            false,
+            false,
+            RateLimiter::default(),
+            FileEngineType::Sync,
+            true,
+            true,
+            1,
+            None,
+        )
+        .unwrap();
+        assert!(block.is_discard_enabled());
+        assert!(block.is_write_zeroes_enabled());
+        assert_ne!(block.avail_features & (1u64 << VIRTIO_BLK_F_DISCARD), 0);
+        assert_ne!(
+            block.avail_features & (1u64 << VIRTIO_BLK_F_WRITE_ZEROES),
+            0
+        );
+
+        // The config space describes the discard and write zeroes limits.
+        let mut config_space = [0u8; DISCARD_WRITE_ZEROES_CONFIG_SPACE_SIZE];
+        block.read_config(0, &mut config_space);
+        assert_eq!(byte_order::read_le_u64(&config_space[..]), 8);
+        assert_eq!(
+            byte_order::read_le_u32(&config_space[MAX_DISCARD_SECTORS_OFFSET..]),
+            u32::MAX
+        );
+        assert_eq!(
+            byte_order::read_le_u32(&config_space[MAX_DISCARD_SEG_OFFSET..]),
+            MAX_DISCARD_SEG
+        );
+        assert_eq!(
+            byte_order::read_le_u32(&config_space[MAX_WRITE_ZEROES_SEG_OFFSET..]),
+            MAX_WRITE_ZEROES_SEG
+        );
+        assert_eq!(config_space[WRITE_ZEROES_MAY_UNMAP_OFFSET], 1);
+
+        let mem = default_mem();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        set_queue(&mut block, 0, vq.create_queue());
+        block.activate(mem.clone()).unwrap();
+        initialize_virtqueue(&vq);
+        // The segment is read by the device.
+        vq.dtable[1].flags.set(VIRTQ_DESC_F_NEXT);
+        vq.dtable[1]
+            .len
+            .set(std::mem::size_of::<DiscardWriteZeroesSegment>() as u32);
+
+        let request_type_addr = GuestAddress(vq.dtable[0].addr.get());
+        let data_addr = GuestAddress(vq.dtable[1].addr.get());
+        let status_addr = GuestAddress(vq.dtable[2].addr.get());
+
+        // Zero the second and third sectors.
+        {
+            mem.write_obj::<u32>(VIRTIO_BLK_T_WRITE_ZEROES, request_type_addr)
+                .unwrap();
+            mem.write_obj(DiscardWriteZeroesSegment::new(1, 2, 0), data_addr)
+                .unwrap();
+
+            check_metric_after_block!(
+                &METRICS.block.write_zeroes_count,
+                1,
+                invoke_handler_for_queue_event(&mut block)
+            );
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(vq.used.ring[0].get().len, 1);
+            assert_eq!(mem.read_obj::<u32>(status_addr).unwrap(), VIRTIO_BLK_S_OK);
+
+            let mut buf = [0u8; 0x1000];
+            block.disk.file.seek(SeekFrom::Start(0)).unwrap();
+            block.disk.file.read_exact(&mut buf).unwrap();
+            assert!(buf[..512].iter().all(|&b| b == 0xff));
+            assert!(buf[512..1536].iter().all(|&b| b == 0));
+            assert!(buf[1536..].iter().all(|&b| b == 0xff));
+        }
+
+        // Discard the first sector.
+        {
+            vq.used.idx.set(0);
+            set_queue(&mut block, 0, vq.create_queue());
+            mem.write_obj::<u32>(VIRTIO_BLK_T_DISCARD, request_type_addr)
+                .unwrap();
+            mem.write_obj(DiscardWriteZeroesSegment::new(0, 1, 0), data_addr)
+                .unwrap();
+
+            check_metric_after_block!(
+                &METRICS.block.discard_count,
+                1,
+                invoke_handler_for_queue_event(&mut block)
+            );
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(mem.read_obj::<u32>(status_addr).unwrap(), VIRTIO_BLK_S_OK);
+        }
+
+        // Discard requests must not set the unmap flag.
+        {
+            vq.used.idx.set(0);
+            set_queue(&mut block, 0, vq.create_queue());
+            mem.write_obj(
+                DiscardWriteZeroesSegment::new(0, 1, VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP),
+                data_addr,
+            )
+            .unwrap();
+
+            invoke_handler_for_queue_event(&mut block);
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(
+                mem.read_obj::<u32>(status_addr).unwrap(),
+                VIRTIO_BLK_S_UNSUPP
+            );
+        }
+
+        // The segment must be within the disk bounds.
+        {
+            vq.used.idx.set(0);
+            set_queue(&mut block, 0, vq.create_queue());
+            mem.write_obj::<u32>(VIRTIO_BLK_T_WRITE_ZEROES, request_type_addr)
+                .unwrap();
+            mem.write_obj(DiscardWriteZeroesSegment::new(7, 2, 0), data_addr)
+                .unwrap();
+
+            invoke_handler_for_queue_event(&mut block);
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(
+                mem.read_obj::<u32>(status_addr).unwrap(),
+                VIRTIO_BLK_S_IOERR
+            );
+        }
+
+        // Only a single segment is accepted.
+        {
+            vq.used.idx.set(0);
+            set_queue(&mut block, 0, vq.create_queue());
+            vq.dtable[1]
+                .len
+                .set(2 * std::mem::size_of::<DiscardWriteZeroesSegment>() as u32);
+
+            invoke_handler_for_queue_event(&mut block);
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(
+                mem.read_obj::<u32>(status_addr).unwrap(),
+                VIRTIO_BLK_S_IOERR
+            );
+        }
+
+        // The requests are not supported unless enabled.
+        {
+            let mut block = default_block();
+            assert!(!block.is_discard_enabled());
+            assert_eq!(block.config_space.len(), CONFIG_SPACE_SIZE);
+
+            set_queue(&mut block, 0, vq.create_queue());
+            block.activate(mem.clone()).unwrap();
+            vq.used.idx.set(0);
+            vq.dtable[1]
+                .len
+                .set(std::mem::size_of::<DiscardWriteZeroesSegment>() as u32);
+            mem.write_obj::<u32>(VIRTIO_BLK_T_DISCARD, request_type_addr)
+                .unwrap();
+            mem.write_obj(DiscardWriteZeroesSegment::new(0, 1, 0), data_addr)
+                .unwrap();
+
+            invoke_handler_for_queue_event(&mut block);
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(
+                mem.read_obj::<u32>(status_addr).unwrap(),
+                VIRTIO_BLK_S_UNSUPP
+            );
+        }
+    }
+
+    #[test]
+    fn test_multi_queue() {
+        let f = TempFile::new().unwrap();
+        f.as_file().set_len(0x1000).unwrap();
+        let new_block = |num_queues| {
+            Block::new(
+                "test".to_string(),
+                None,
+                CacheType::Unsafe,
+                f.as_path().to_str().unwrap().to_string(),
+                false,
+                false,
+                RateLimiter::default(),
+                FileEngineType::Sync,
+                false,
+                false,
+                num_queues,
+                None,
+            )
+        };
+
+        assert!(new_block(0).is_err());
+        assert!(new_block(MAX_NUM_QUEUES + 1).is_err());
+
+        let mut block = new_block(2).unwrap();
+        assert_eq!(block.num_queues(), 2);
+        assert_eq!(block.queues().len(), 2);
+        assert_eq!(block.queue_events().len(), 2);
+        assert_ne!(block.avail_features & (1u64 << VIRTIO_BLK_F_MQ), 0);
+
+        // The number of queues follows the disk size in the config space.
+        let mut config_space = [0u8; NUM_QUEUES_OFFSET + 2];
+        block.read_config(0, &mut config_space);
+        assert_eq!(byte_order::read_le_u64(&config_space[..]), 8);
+        assert_eq!(
+            byte_order::read_le_u16(&config_space[NUM_QUEUES_OFFSET..]),
+            2
+        );
+
+        // Requests are completed on the queue they were placed on.
+        let mem = default_mem();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        set_queue(&mut block, 1, vq.create_queue());
+        block.activate(mem.clone()).unwrap();
+        initialize_virtqueue(&vq);
+
+        let request_type_addr = GuestAddress(vq.dtable[0].addr.get());
+        let status_addr = GuestAddress(vq.dtable[2].addr.get());
+        mem.write_obj::<u32>(VIRTIO_BLK_T_IN, request_type_addr)
+            .unwrap();
+        vq.dtable[1].len.set(512);
+
+        block.queue_evts[1].write(1).unwrap();
+        block.process_queue_event(1);
+        assert!(block.irq_trigger.has_pending_irq(IrqType::Vring));
+        assert_eq!(vq.used.idx.get(), 1);
+        assert_eq!(vq.used.ring[0].get().id, 0);
+        assert_eq!(vq.used.ring[0].get().len, 513);
+        assert_eq!(mem.read_obj::<u32>(status_addr).unwrap(), VIRTIO_BLK_S_OK);
+
+        // A single queue device doesn't advertise multi-queue support.
+        let block = new_block(1).unwrap();
+        assert_eq!(block.avail_features & (1u64 << VIRTIO_BLK_F_MQ), 0);
+        assert_eq!(block.config_space.len(), CONFIG_SPACE_SIZE);
+    }
+
     #[test]
     fn test_bandwidth_rate_limiter() {
         let mut block = default_block();
@@ -1164,7 +1860,7 @@ pub(crate) mod tests {
             check_metric_after_block!(
                 &METRICS.block.rate_limiter_throttled_events,
                 1,
-                block.process_queue_event()
+                block.process_queue_event(0)
             );
 
             // Assert that limiter is blocked.
@@ -1234,7 +1930,7 @@ pub(crate) mod tests {
             check_metric_after_block!(
                 &METRICS.block.rate_limiter_throttled_events,
                 1,
-                block.process_queue_event()
+                block.process_queue_event(0)
             );
 
             // Assert that limiter is blocked.
@@ -1252,7 +1948,7 @@ pub(crate) mod tests {
             check_metric_after_block!(
                 &METRICS.block.rate_limiter_throttled_events,
                 1,
-                block.process_queue_event()
+                block.process_queue_event(0)
             );
 
             // Assert that limiter is blocked.
@@ -1300,10 +1996,88 @@ pub(crate) mod tests {
             .clone_from_slice(&part_id[..cmp::min(part_id.len(), VIRTIO_BLK_ID_BYTES as usize)]);
 
         block
-            .update_disk_image(String::from(path.to_str().unwrap()))
+            .update_disk_image(String::from(path.to_str().unwrap()), None)
             .unwrap();
 
         assert_eq!(block.disk.file.metadata().unwrap().st_ino(), mdata.st_ino());
         assert_eq!(block.disk.image_id, id);
     }
+
+    #[test]
+    fn test_overlay() {
+        let base = TempFile::new().unwrap();
+        base.as_file().write_all(&[0xaa; 0x2000]).unwrap();
+        let base_path = base.as_path().to_str().unwrap().to_string();
+        let overlay = TempFile::new().unwrap();
+        let overlay_path = overlay.as_path().to_str().unwrap().to_string();
+        let new_block = |file_engine_type| {
+            Block::new(
+                "test".to_string(),
+                None,
+                CacheType::Writeback,
+                base_path.clone(),
+                false,
+                false,
+                RateLimiter::default(),
+                file_engine_type,
+                false,
+                false,
+                1,
+                Some(overlay_path.clone()),
+            )
+        };
+
+        // The async engine can't access overlay images.
+        assert!(new_block(FileEngineType::Async).is_err());
+
+        let mut block = new_block(FileEngineType::Sync).unwrap();
+        assert_eq!(block.overlay_path(), Some(&overlay_path));
+        assert_eq!(block.file_path(), &base_path);
+        assert!(!block.is_read_only());
+        assert_eq!(block.disk.nsectors(), 0x2000 / SECTOR_SIZE);
+        // The disk is identified by its overlay.
+        assert_eq!(
+            block.disk.image_id,
+            DiskProperties::build_disk_image_id(overlay.as_file())
+        );
+
+        let mut image = block.disk.image_mut();
+        image.seek(SeekFrom::Start(0x1000)).unwrap();
+        image.write_all(&[0xbb; 0x200]).unwrap();
+        let mut data = vec![0u8; 0x2000];
+        image.seek(SeekFrom::Start(0)).unwrap();
+        image.read_exact(&mut data).unwrap();
+        assert!(data[..0x1000].iter().all(|&b| b == 0xaa));
+        assert!(data[0x1000..0x1200].iter().all(|&b| b == 0xbb));
+        assert!(data[0x1200..].iter().all(|&b| b == 0xaa));
+
+        // The base image is left untouched.
+        let mut base_data = Vec::new();
+        File::open(base.as_path())
+            .unwrap()
+            .read_to_end(&mut base_data)
+            .unwrap();
+        assert!(base_data.iter().all(|&b| b == 0xaa));
+
+        // The overlay is kept when only the backing file is updated.
+        let new_base = TempFile::new().unwrap();
+        new_base.as_file().write_all(&[0xaa; 0x2000]).unwrap();
+        let new_base_path = new_base.as_path().to_str().unwrap().to_string();
+        block
+            .update_disk_image(new_base_path.clone(), None)
+            .unwrap();
+        assert_eq!(block.file_path(), &new_base_path);
+        assert_eq!(block.overlay_path(), Some(&overlay_path));
+        let mut image = block.disk.image_mut();
+        image.seek(SeekFrom::Start(0x1000)).unwrap();
+        image.read_exact(&mut data[..0x200]).unwrap();
+        assert!(data[..0x200].iter().all(|&b| b == 0xbb));
+
+        // The overlay must match the size of the backing file.
+        let small_base = TempFile::new().unwrap();
+        small_base.as_file().set_len(0x1000).unwrap();
+        assert!(block
+            .update_disk_image(small_base.as_path().to_str().unwrap().to_string(), None)
+            .is_err());
+    }
 }
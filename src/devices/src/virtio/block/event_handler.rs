@@ -11,12 +11,19 @@ use crate::virtio::VirtioDevice;
 
 impl Block {
     fn register_runtime_events(&self, ops: &mut EventOps) {
-        if let Err(e) = ops.add(Events::new(&self.queue_evts[0], EventSet::IN)) {
-            error!("Failed to register queue event: {}", e);
+        for queue_evt in &self.queue_evts {
+            if let Err(e) = ops.add(Events::new(queue_evt, EventSet::IN)) {
+                error!("Failed to register queue event: {}", e);
+            }
         }
         if let Err(e) = ops.add(Events::new(&self.rate_limiter, EventSet::IN)) {
             error!("Failed to register ratelimiter event: {}", e);
         }
+        if let Some(engine) = self.disk.async_engine() {
+            if let Err(e) = ops.add(Events::new(engine.completion_evt(), EventSet::IN)) {
+                error!("Failed to register async completion event: {}", e);
+            }
+        }
     }
 
     fn register_activate_event(&self, ops: &mut EventOps) {
@@ -55,14 +62,26 @@ impl MutEventSubscriber for Block {
         }
 
         if self.is_activated() {
-            let queue_evt = self.queue_evts[0].as_raw_fd();
+            let queue_index = self
+                .queue_evts
+                .iter()
+                .position(|queue_evt| queue_evt.as_raw_fd() == source);
             let rate_limiter_evt = self.rate_limiter.as_raw_fd();
             let activate_fd = self.activate_evt.as_raw_fd();
+            let completion_evt = self
+                .disk
+                .async_engine()
+                .map(|engine| engine.completion_evt().as_raw_fd());
+
+            if let Some(queue_index) = queue_index {
+                self.process_queue_event(queue_index);
+                return;
+            }
 
             // Looks better than C style if/else if/else.
             match source {
-                _ if queue_evt == source => self.process_queue_event(),
                 _ if rate_limiter_evt == source => self.process_rate_limiter_event(),
+                _ if completion_evt == Some(source) => self.process_async_completion_event(),
                 _ if activate_fd == source => self.process_activate_event(ops),
                 _ => warn!("Block: Spurious event received: {:?}", source),
             }
@@ -0,0 +1,189 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Defines the engines used for executing block requests on the backing file.
+
+use std::collections::HashMap;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use logger::error;
+use serde::{Deserialize, Serialize};
+use utils::eventfd::EventFd;
+use utils::io_uring::{self, IoUring, Operation};
+use vm_memory::{
+    Address, GuestAddress, GuestMemory, GuestMemoryError, GuestMemoryMmap, GuestMemoryRegion,
+};
+
+use super::request::PendingRequest;
+use super::QUEUE_SIZE;
+
+/// Type of engine used for executing the requests on the backing file.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum FileEngineType {
+    /// Read, write and flush requests are submitted to an io_uring instance and
+    /// completed asynchronously, without blocking the VMM thread.
+    Async,
+    /// All requests are executed synchronously on the VMM thread.
+    Sync,
+}
+
+impl Default for FileEngineType {
+    fn default() -> FileEngineType {
+        FileEngineType::Sync
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// Creating the completion eventfd failed.
+    EventFd(io::Error),
+    /// The request points to an invalid guest memory range.
+    GuestMemory(GuestMemoryError),
+    /// Operating the io_uring instance failed.
+    IoUring(io_uring::Error),
+}
+
+/// Engine handing the block requests to an io_uring instance.
+///
+/// Completions are signaled through `completion_evt`, after which they can be
+/// retrieved with `pop`.
+pub struct AsyncFileEngine {
+    ring: IoUring,
+    completion_evt: EventFd,
+    pending: HashMap<u64, PendingRequest>,
+    next_user_data: u64,
+}
+
+impl AsyncFileEngine {
+    /// Creates an engine serving the requests of `num_queues` queues.
+    pub fn new(num_queues: u16) -> Result<Self, Error> {
+        // There can't be more requests in flight than descriptor chains in all the queues.
+        let ring =
+            IoUring::new(u32::from(num_queues) * u32::from(QUEUE_SIZE)).map_err(Error::IoUring)?;
+        let completion_evt = EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd)?;
+        ring.register_eventfd(completion_evt.as_raw_fd())
+            .map_err(Error::IoUring)?;
+
+        Ok(AsyncFileEngine {
+            ring,
+            completion_evt,
+            pending: HashMap::new(),
+            next_user_data: 0,
+        })
+    }
+
+    /// Provides the eventfd signaled when requests complete.
+    pub fn completion_evt(&self) -> &EventFd {
+        &self.completion_evt
+    }
+
+    /// Submits a read of `count` bytes at `offset` in `fd` to the guest memory at `addr`.
+    pub fn push_read(
+        &mut self,
+        fd: RawFd,
+        offset: u64,
+        mem: &GuestMemoryMmap,
+        addr: GuestAddress,
+        count: u32,
+        req: PendingRequest,
+    ) -> Result<(), Error> {
+        let buf = Self::host_address(mem, addr, count, true)?;
+        let user_data = self.next_user_data;
+        self.push(Operation::read(fd, buf, count, offset, user_data), req)
+    }
+
+    /// Submits a write of `count` bytes from the guest memory at `addr` at `offset` in `fd`.
+    pub fn push_write(
+        &mut self,
+        fd: RawFd,
+        offset: u64,
+        mem: &GuestMemoryMmap,
+        addr: GuestAddress,
+        count: u32,
+        req: PendingRequest,
+    ) -> Result<(), Error> {
+        let buf = Self::host_address(mem, addr, count, false)?;
+        let user_data = self.next_user_data;
+        self.push(Operation::write(fd, buf, count, offset, user_data), req)
+    }
+
+    /// Submits a sync of `fd` to the physical media.
+    pub fn push_flush(&mut self, fd: RawFd, req: PendingRequest) -> Result<(), Error> {
+        let user_data = self.next_user_data;
+        self.push(Operation::fsync(fd, user_data), req)
+    }
+
+    fn push(&mut self, op: Operation, req: PendingRequest) -> Result<(), Error> {
+        // Safe because the guest memory buffers outlive the in-flight operations: the
+        // engine is drained before the device state is saved or the backing file changes.
+        unsafe { self.ring.push(op) }.map_err(Error::IoUring)?;
+        self.pending.insert(self.next_user_data, req);
+        self.next_user_data = self.next_user_data.wrapping_add(1);
+        Ok(())
+    }
+
+    /// Hands the pushed requests to the kernel.
+    pub fn kick_submission_queue(&mut self) -> Result<(), Error> {
+        self.ring.submit().map(|_| ()).map_err(Error::IoUring)
+    }
+
+    /// Hands the pushed requests to the kernel and waits for all in-flight requests to complete.
+    pub fn drain(&mut self) -> Result<(), Error> {
+        self.ring
+            .submit_and_wait_all()
+            .map(|_| ())
+            .map_err(Error::IoUring)
+    }
+
+    /// Pops the next completed request together with its outcome.
+    pub fn pop(&mut self) -> Option<(PendingRequest, io::Result<u32>)> {
+        while let Some(completion) = self.ring.pop() {
+            match self.pending.remove(&completion.user_data()) {
+                Some(req) => return Some((req, completion.result())),
+                None => error!(
+                    "Unknown block async I/O completion: {}",
+                    completion.user_data()
+                ),
+            }
+        }
+        None
+    }
+
+    /// Returns the number of requests that were submitted and not yet popped.
+    pub fn num_pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    // Translates the guest memory buffer to a host address. The buffer must be contained in
+    // a single memory region, since the regions are not contiguous in the host address space.
+    fn host_address(
+        mem: &GuestMemoryMmap,
+        addr: GuestAddress,
+        count: u32,
+        mark_dirty: bool,
+    ) -> Result<*mut u8, Error> {
+        let region = mem.find_region(addr).ok_or(Error::GuestMemory(
+            GuestMemoryError::InvalidGuestAddress(addr),
+        ))?;
+        // Safe to unwrap because `find_region` checked that `addr` is part of the region.
+        let region_addr = region.to_region_addr(addr).unwrap();
+        if count > 0
+            && region
+                .checked_offset(region_addr, count as usize - 1)
+                .is_none()
+        {
+            return Err(Error::GuestMemory(GuestMemoryError::InvalidBackendAddress));
+        }
+
+        let host_addr = region
+            .get_host_address(region_addr)
+            .map_err(Error::GuestMemory)?;
+        // The kernel writes the guest memory directly, so dirty pages have to be
+        // tracked here.
+        if mark_dirty {
+            region.mark_dirty_pages(region_addr.raw_value() as usize, count as usize);
+        }
+        Ok(host_addr)
+    }
+}
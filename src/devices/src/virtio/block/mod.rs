@@ -3,6 +3,8 @@
 
 pub mod device;
 pub mod event_handler;
+pub mod io_engine;
+pub mod overlay;
 pub mod persist;
 pub mod request;
 #[cfg(test)]
@@ -10,6 +12,7 @@ pub mod test_utils;
 
 pub use self::device::{Block, CacheType};
 pub use self::event_handler::*;
+pub use self::io_engine::FileEngineType;
 pub use self::request::*;
 
 use vm_memory::GuestMemoryError;
@@ -18,8 +21,12 @@ pub const CONFIG_SPACE_SIZE: usize = 8;
 pub const SECTOR_SHIFT: u8 = 9;
 pub const SECTOR_SIZE: u64 = (0x01_u64) << SECTOR_SHIFT;
 pub const QUEUE_SIZE: u16 = 256;
-pub const NUM_QUEUES: usize = 1;
-pub const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE];
+/// Maximum number of queues of a block device.
+pub const MAX_NUM_QUEUES: u16 = 32;
+/// Maximum number of segments in a discard request.
+pub const MAX_DISCARD_SEG: u32 = 1;
+/// Maximum number of segments in a write zeroes request.
+pub const MAX_WRITE_ZEROES_SEG: u32 = 1;
 
 #[derive(Debug)]
 pub enum Error {
@@ -0,0 +1,453 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Defines the copy-on-write overlay images used on top of read-only base images.
+//!
+//! An overlay image stores the clusters written by the guest, while the clusters that were
+//! never written are read from the base image. This way, multiple block devices can share
+//! the same base image, each one with its own, sparse, overlay.
+//!
+//! The overlay file is laid out as follows:
+//!   * the header, at offset 0;
+//!   * the cluster map, right after the header, holding one u64 entry for each cluster of
+//!     the disk. An entry holds the offset of the cluster in the overlay file, or 0 if the
+//!     cluster is not allocated in the overlay;
+//!   * the allocated clusters, aligned to the cluster size.
+//!
+//! An empty overlay file is initialized when opened.
+
+use std::cmp;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::mem::size_of;
+
+use utils::byte_order;
+use vm_memory::ByteValued;
+
+/// Identifies the overlay files; the ASCII string "FCOVRLAY".
+pub const OVERLAY_MAGIC: u64 = 0x5941_4c52_564f_4346;
+/// Version of the overlay file format.
+pub const OVERLAY_VERSION: u32 = 1;
+/// Size of the clusters of the overlay images created by Firecracker.
+pub const DEFAULT_CLUSTER_SIZE: u32 = 64 * 1024;
+
+// Size of an entry of the cluster map.
+const CLUSTER_MAP_ENTRY_SIZE: u64 = size_of::<u64>() as u64;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[repr(C)]
+struct OverlayHeader {
+    magic: u64,
+    version: u32,
+    cluster_size: u32,
+    disk_size: u64,
+    num_clusters: u64,
+}
+
+// Safe because OverlayHeader only contains plain data.
+unsafe impl ByteValued for OverlayHeader {}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn align_up(value: u64, alignment: u64) -> u64 {
+    (value + alignment - 1) / alignment * alignment
+}
+
+/// Copy-on-write overlay on top of a base image.
+///
+/// The base image itself is not owned by the overlay, and has to be provided to the
+/// operations accessing the disk contents.
+pub struct OverlayImage {
+    file: File,
+    path: String,
+    cluster_size: u64,
+    disk_size: u64,
+    cluster_map: Vec<u64>,
+    // Offset in the overlay file where the next cluster is allocated.
+    next_cluster_offset: u64,
+    // Offset in the disk of the next read or write.
+    position: u64,
+}
+
+impl OverlayImage {
+    /// Opens the overlay at `path` for a base image of `disk_size` bytes, initializing it
+    /// if the overlay file is empty.
+    pub fn open(path: String, is_read_only: bool, disk_size: u64) -> io::Result<Self> {
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(!is_read_only)
+            .open(&path)?;
+        let file_size = file.seek(SeekFrom::End(0))?;
+
+        let header = if file_size == 0 {
+            Self::initialize(&mut file, disk_size)?
+        } else {
+            Self::read_header(&mut file, disk_size)?
+        };
+
+        let cluster_size = u64::from(header.cluster_size);
+        let mut map_bytes = vec![0u8; (header.num_clusters * CLUSTER_MAP_ENTRY_SIZE) as usize];
+        file.seek(SeekFrom::Start(size_of::<OverlayHeader>() as u64))?;
+        file.read_exact(&mut map_bytes)?;
+        let cluster_map: Vec<u64> = map_bytes
+            .chunks_exact(CLUSTER_MAP_ENTRY_SIZE as usize)
+            .map(byte_order::read_le_u64)
+            .collect();
+
+        let data_offset = Self::data_offset(cluster_size, header.num_clusters);
+        for &offset in cluster_map.iter().filter(|&&offset| offset != 0) {
+            if offset < data_offset || offset % cluster_size != 0 || offset >= file_size {
+                return Err(invalid_data(format!(
+                    "Invalid cluster offset in the overlay image: {}",
+                    offset
+                )));
+            }
+        }
+
+        Ok(OverlayImage {
+            file,
+            path,
+            cluster_size,
+            disk_size,
+            cluster_map,
+            next_cluster_offset: cmp::max(data_offset, align_up(file_size, cluster_size)),
+            position: 0,
+        })
+    }
+
+    // Writes the header and an empty cluster map to a new overlay file.
+    fn initialize(file: &mut File, disk_size: u64) -> io::Result<OverlayHeader> {
+        let cluster_size = u64::from(DEFAULT_CLUSTER_SIZE);
+        let header = OverlayHeader {
+            magic: OVERLAY_MAGIC,
+            version: OVERLAY_VERSION,
+            cluster_size: DEFAULT_CLUSTER_SIZE,
+            disk_size,
+            num_clusters: align_up(disk_size, cluster_size) / cluster_size,
+        };
+
+        // The cluster map is zeroed by extending the file, which keeps it sparse.
+        file.set_len(Self::data_offset(cluster_size, header.num_clusters))?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(header.as_slice())?;
+        file.sync_all()?;
+        Ok(header)
+    }
+
+    fn read_header(file: &mut File, disk_size: u64) -> io::Result<OverlayHeader> {
+        let mut header = OverlayHeader::default();
+        file.seek(SeekFrom::Start(0))?;
+        file.read_exact(header.as_mut_slice())?;
+
+        if header.magic != OVERLAY_MAGIC {
+            return Err(invalid_data("Invalid overlay image magic.".to_string()));
+        }
+        if header.version != OVERLAY_VERSION {
+            return Err(invalid_data(format!(
+                "Unsupported overlay image version: {}",
+                header.version
+            )));
+        }
+        let cluster_size = u64::from(header.cluster_size);
+        if !header.cluster_size.is_power_of_two() || cluster_size < super::SECTOR_SIZE {
+            return Err(invalid_data(format!(
+                "Invalid overlay image cluster size: {}",
+                header.cluster_size
+            )));
+        }
+        if header.disk_size != disk_size {
+            return Err(invalid_data(format!(
+                "The overlay image was created for a base image of {} bytes, not {} bytes.",
+                header.disk_size, disk_size
+            )));
+        }
+        if header.num_clusters != align_up(disk_size, cluster_size) / cluster_size {
+            return Err(invalid_data(format!(
+                "Invalid number of clusters in the overlay image: {}",
+                header.num_clusters
+            )));
+        }
+        Ok(header)
+    }
+
+    // Offset of the first cluster in the overlay file.
+    fn data_offset(cluster_size: u64, num_clusters: u64) -> u64 {
+        align_up(
+            size_of::<OverlayHeader>() as u64 + num_clusters * CLUSTER_MAP_ENTRY_SIZE,
+            cluster_size,
+        )
+    }
+
+    /// Provides the overlay file.
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    /// Provides the path of the overlay file.
+    pub fn path(&self) -> &String {
+        &self.path
+    }
+
+    /// Returns the number of clusters allocated in the overlay.
+    pub fn num_allocated_clusters(&self) -> usize {
+        self.cluster_map
+            .iter()
+            .filter(|&&offset| offset != 0)
+            .count()
+    }
+
+    // Returns the number of bytes that can be accessed at the current position without
+    // crossing a cluster boundary or the end of the disk.
+    fn chunk_len(&self, len: usize) -> usize {
+        let cluster_remaining = self.cluster_size - self.position % self.cluster_size;
+        let disk_remaining = self.disk_size - self.position;
+        cmp::min(len as u64, cmp::min(cluster_remaining, disk_remaining)) as usize
+    }
+
+    /// Reads from the current position, either from the overlay or from `base`.
+    pub fn read(&mut self, base: &mut File, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.disk_size {
+            return Ok(0);
+        }
+        let count = self.chunk_len(buf.len());
+        let buf = &mut buf[..count];
+
+        let cluster = (self.position / self.cluster_size) as usize;
+        match self.cluster_map[cluster] {
+            0 => {
+                base.seek(SeekFrom::Start(self.position))?;
+                base.read_exact(buf)?;
+            }
+            cluster_offset => {
+                self.file.seek(SeekFrom::Start(
+                    cluster_offset + self.position % self.cluster_size,
+                ))?;
+                self.file.read_exact(buf)?;
+            }
+        }
+
+        self.position += count as u64;
+        Ok(count)
+    }
+
+    /// Writes to the overlay at the current position. The first write to a cluster copies
+    /// it from `base` to the overlay.
+    pub fn write(&mut self, base: &mut File, buf: &[u8]) -> io::Result<usize> {
+        if self.position >= self.disk_size {
+            return Ok(0);
+        }
+        let count = self.chunk_len(buf.len());
+        let buf = &buf[..count];
+
+        let cluster = (self.position / self.cluster_size) as usize;
+        let offset_in_cluster = self.position % self.cluster_size;
+        match self.cluster_map[cluster] {
+            0 => self.allocate_cluster(base, cluster, offset_in_cluster, buf)?,
+            cluster_offset => {
+                self.file
+                    .seek(SeekFrom::Start(cluster_offset + offset_in_cluster))?;
+                self.file.write_all(buf)?;
+            }
+        }
+
+        self.position += count as u64;
+        Ok(count)
+    }
+
+    // Copies the cluster from the base image to the end of the overlay, together with the
+    // data being written, and records it in the cluster map.
+    fn allocate_cluster(
+        &mut self,
+        base: &mut File,
+        cluster: usize,
+        offset_in_cluster: u64,
+        buf: &[u8],
+    ) -> io::Result<()> {
+        let cluster_start = cluster as u64 * self.cluster_size;
+        let cluster_len = cmp::min(self.cluster_size, self.disk_size - cluster_start);
+        let mut data = vec![0u8; self.cluster_size as usize];
+        if (buf.len() as u64) < cluster_len {
+            base.seek(SeekFrom::Start(cluster_start))?;
+            base.read_exact(&mut data[..cluster_len as usize])?;
+        }
+        data[offset_in_cluster as usize..offset_in_cluster as usize + buf.len()]
+            .copy_from_slice(buf);
+
+        // The cluster data is written before the map entry, so that the entry never points
+        // to a cluster that was not written.
+        let cluster_offset = self.next_cluster_offset;
+        self.file.seek(SeekFrom::Start(cluster_offset))?;
+        self.file.write_all(&data)?;
+
+        let mut entry = [0u8; CLUSTER_MAP_ENTRY_SIZE as usize];
+        byte_order::write_le_u64(&mut entry, cluster_offset);
+        self.file.seek(SeekFrom::Start(
+            size_of::<OverlayHeader>() as u64 + cluster as u64 * CLUSTER_MAP_ENTRY_SIZE,
+        ))?;
+        self.file.write_all(&entry)?;
+
+        self.cluster_map[cluster] = cluster_offset;
+        self.next_cluster_offset += self.cluster_size;
+        Ok(())
+    }
+
+    /// Moves the position of the next read or write.
+    pub fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => (self.disk_size as i64)
+                .checked_add(offset)
+                .map(|p| p as u64),
+            SeekFrom::Current(offset) => {
+                (self.position as i64).checked_add(offset).map(|p| p as u64)
+            }
+        };
+        match position {
+            Some(position) if (position as i64) >= 0 => {
+                self.position = position;
+                Ok(position)
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Invalid seek to a negative or overflowing position.",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use utils::tempfile::TempFile;
+
+    const DISK_SIZE: u64 = 3 * DEFAULT_CLUSTER_SIZE as u64 - 512;
+
+    fn base_image() -> TempFile {
+        let base = TempFile::new().unwrap();
+        base.as_file().write_all(&base_image_data()).unwrap();
+        base
+    }
+
+    fn read_disk(overlay: &mut OverlayImage, base: &mut File, offset: u64, len: usize) -> Vec<u8> {
+        let mut buf = vec![0u8; len];
+        overlay.seek(SeekFrom::Start(offset)).unwrap();
+        let mut read = 0;
+        while read < len {
+            let count = overlay.read(base, &mut buf[read..]).unwrap();
+            assert_ne!(count, 0);
+            read += count;
+        }
+        buf
+    }
+
+    fn write_disk(overlay: &mut OverlayImage, base: &mut File, offset: u64, buf: &[u8]) {
+        overlay.seek(SeekFrom::Start(offset)).unwrap();
+        let mut written = 0;
+        while written < buf.len() {
+            written += overlay.write(base, &buf[written..]).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_overlay_read_write() {
+        let base_file = base_image();
+        let mut base = File::open(base_file.as_path()).unwrap();
+        let overlay_file = TempFile::new().unwrap();
+        let overlay_path = overlay_file.as_path().to_str().unwrap().to_string();
+
+        let mut overlay = OverlayImage::open(overlay_path.clone(), false, DISK_SIZE).unwrap();
+        assert_eq!(overlay.path(), &overlay_path);
+        assert_eq!(overlay.num_allocated_clusters(), 0);
+        let original = read_disk(&mut overlay, &mut base, 0, DISK_SIZE as usize);
+        assert_eq!(original, base_image_data());
+
+        // Write across the boundary of the first two clusters.
+        let offset = u64::from(DEFAULT_CLUSTER_SIZE) - 512;
+        write_disk(&mut overlay, &mut base, offset, &[0xaa; 1024]);
+        assert_eq!(overlay.num_allocated_clusters(), 2);
+
+        let mut expected = original.clone();
+        expected[offset as usize..offset as usize + 1024].copy_from_slice(&[0xaa; 1024]);
+        assert_eq!(
+            read_disk(&mut overlay, &mut base, 0, DISK_SIZE as usize),
+            expected
+        );
+
+        // Overwrite part of an allocated cluster and write the last, partial, cluster.
+        write_disk(&mut overlay, &mut base, offset + 512, &[0xbb; 512]);
+        write_disk(&mut overlay, &mut base, DISK_SIZE - 512, &[0xcc; 512]);
+        assert_eq!(overlay.num_allocated_clusters(), 3);
+        expected[offset as usize + 512..offset as usize + 1024].copy_from_slice(&[0xbb; 512]);
+        expected[DISK_SIZE as usize - 512..].copy_from_slice(&[0xcc; 512]);
+        assert_eq!(
+            read_disk(&mut overlay, &mut base, 0, DISK_SIZE as usize),
+            expected
+        );
+
+        // Nothing can be accessed past the end of the disk.
+        overlay.seek(SeekFrom::End(0)).unwrap();
+        assert_eq!(overlay.read(&mut base, &mut [0u8; 512]).unwrap(), 0);
+        assert_eq!(overlay.write(&mut base, &[0u8; 512]).unwrap(), 0);
+        assert!(overlay
+            .seek(SeekFrom::Current(-(DISK_SIZE as i64) - 1))
+            .is_err());
+
+        // The base image is left untouched.
+        let mut base_data = Vec::new();
+        base.seek(SeekFrom::Start(0)).unwrap();
+        base.read_to_end(&mut base_data).unwrap();
+        assert_eq!(base_data, original);
+
+        // The allocated clusters persist after reopening the overlay.
+        drop(overlay);
+        let mut overlay = OverlayImage::open(overlay_path, false, DISK_SIZE).unwrap();
+        assert_eq!(overlay.num_allocated_clusters(), 3);
+        assert_eq!(
+            read_disk(&mut overlay, &mut base, 0, DISK_SIZE as usize),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_overlay_open_errors() {
+        let overlay_file = TempFile::new().unwrap();
+        let overlay_path = overlay_file.as_path().to_str().unwrap().to_string();
+        assert!(OverlayImage::open(overlay_path.clone(), false, DISK_SIZE).is_ok());
+
+        // The overlay was created for a base image of a different size.
+        assert_eq!(
+            OverlayImage::open(overlay_path.clone(), false, DISK_SIZE + 512)
+                .err()
+                .unwrap()
+                .kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        // An empty read-only overlay can't be initialized.
+        let read_only_overlay = TempFile::new().unwrap();
+        assert!(OverlayImage::open(
+            read_only_overlay.as_path().to_str().unwrap().to_string(),
+            true,
+            DISK_SIZE
+        )
+        .is_err());
+
+        // The file is not an overlay image.
+        let mut file = overlay_file.as_file();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.write_all(&[0xff; 8]).unwrap();
+        assert_eq!(
+            OverlayImage::open(overlay_path, false, DISK_SIZE)
+                .err()
+                .unwrap()
+                .kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    fn base_image_data() -> Vec<u8> {
+        (0..DISK_SIZE).map(|i| (i / 512) as u8).collect()
+    }
+}
@@ -9,18 +9,19 @@ use std::sync::Arc;
 
 use logger::{error, warn};
 use rate_limiter::{persist::RateLimiterState, RateLimiter};
+use serde::Serialize;
 use snapshot::Persist;
 use versionize::{VersionMap, Versionize, VersionizeError, VersionizeResult};
 use versionize_derive::Versionize;
-use virtio_gen::virtio_blk::VIRTIO_BLK_F_RO;
+use virtio_gen::virtio_blk::{VIRTIO_BLK_F_DISCARD, VIRTIO_BLK_F_RO, VIRTIO_BLK_F_WRITE_ZEROES};
 use vm_memory::GuestMemoryMmap;
 
 use super::*;
 
-use crate::virtio::persist::VirtioDeviceState;
+use crate::virtio::persist::{Error as VirtioStateError, VirtioDeviceState};
 use crate::virtio::{DeviceState, TYPE_BLOCK};
 
-#[derive(Clone, Copy, Debug, Versionize, PartialEq)]
+#[derive(Clone, Copy, Debug, Serialize, Versionize, PartialEq)]
 // NOTICE: Any changes to this structure require a snapshot version bump.
 pub enum CacheTypeState {
     Unsafe,
@@ -45,7 +46,32 @@ impl From<CacheTypeState> for CacheType {
     }
 }
 
-#[derive(Clone, Versionize)]
+#[derive(Clone, Copy, Debug, Serialize, Versionize, PartialEq)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub enum FileEngineTypeState {
+    Async,
+    Sync,
+}
+
+impl From<FileEngineType> for FileEngineTypeState {
+    fn from(file_engine_type: FileEngineType) -> Self {
+        match file_engine_type {
+            FileEngineType::Async => FileEngineTypeState::Async,
+            FileEngineType::Sync => FileEngineTypeState::Sync,
+        }
+    }
+}
+
+impl From<FileEngineTypeState> for FileEngineType {
+    fn from(file_engine_type_state: FileEngineTypeState) -> Self {
+        match file_engine_type_state {
+            FileEngineTypeState::Async => FileEngineType::Async,
+            FileEngineTypeState::Sync => FileEngineType::Sync,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Versionize)]
 // NOTICE: Any changes to this structure require a snapshot version bump.
 pub struct BlockState {
     id: String,
@@ -60,6 +86,28 @@ pub struct BlockState {
     disk_path: String,
     virtio_state: VirtioDeviceState,
     rate_limiter_state: RateLimiterState,
+    #[version(
+        start = 2,
+        ser_fn = "block_file_engine_type_ser",
+        default_fn = "default_file_engine_type"
+    )]
+    file_engine_type: FileEngineTypeState,
+    #[version(
+        start = 2,
+        ser_fn = "block_num_queues_ser",
+        default_fn = "default_num_queues"
+    )]
+    num_queues: u16,
+    #[version(
+        start = 2,
+        ser_fn = "block_overlay_path_ser",
+        default_fn = "default_overlay_path"
+    )]
+    overlay_path: Option<String>,
+    // Zero when restoring a state that predates this field, in which case the size of the
+    // disk image is not checked.
+    #[version(start = 2, default_fn = "default_nsectors")]
+    nsectors: u64,
 }
 
 impl BlockState {
@@ -77,23 +125,83 @@ impl BlockState {
     fn default_cache_type_flush(_source_version: u16) -> CacheTypeState {
         CacheTypeState::Unsafe
     }
+
+    fn block_file_engine_type_ser(&mut self, target_version: u16) -> VersionizeResult<()> {
+        if target_version < 3 && self.file_engine_type != FileEngineTypeState::Sync {
+            warn!(
+                "Target version does not implement the current file engine type. \
+                Defaulting to \"Sync\" engine."
+            );
+        }
+
+        Ok(())
+    }
+
+    fn default_file_engine_type(_source_version: u16) -> FileEngineTypeState {
+        FileEngineTypeState::Sync
+    }
+
+    fn block_num_queues_ser(&mut self, target_version: u16) -> VersionizeResult<()> {
+        if target_version < 3 && self.num_queues != 1 {
+            return Err(VersionizeError::Semantic(
+                "Target version does not support multi-queue block devices.".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn default_num_queues(_source_version: u16) -> u16 {
+        1
+    }
+
+    fn block_overlay_path_ser(&mut self, target_version: u16) -> VersionizeResult<()> {
+        if target_version < 3 && self.overlay_path.is_some() {
+            return Err(VersionizeError::Semantic(
+                "Target version does not support block devices with overlay images.".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn default_overlay_path(_source_version: u16) -> Option<String> {
+        None
+    }
+
+    fn default_nsectors(_source_version: u16) -> u64 {
+        0
+    }
 }
 
 pub struct BlockConstructorArgs {
     pub mem: GuestMemoryMmap,
+    /// Disk image to back the restored device with, instead of the one saved in the state.
+    pub disk_path: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    CreateBlock(io::Error),
+    CreateRateLimiter(io::Error),
+    /// The disk image has a different number of sectors than the one saved in the state.
+    DiskSizeMismatch(u64, u64),
+    /// The disk image of a read-write device can't be opened for writing.
+    ReadOnlyDisk(String),
+    VirtioState(VirtioStateError),
 }
 
 impl Persist<'_> for Block {
     type State = BlockState;
     type ConstructorArgs = BlockConstructorArgs;
-    type Error = io::Error;
+    type Error = Error;
 
     fn save(&self) -> Self::State {
-        if let Err(e) = self.disk.file().flush() {
+        if let Err(e) = self.disk.data_file().flush() {
             error!("Failed to flush block data on serialization. Error: {}", e);
         }
         // Sync data out to backing file on host.
-        if let Err(e) = self.disk.file().sync_all() {
+        if let Err(e) = self.disk.data_file().sync_all() {
             error!("Failed to sync block data on serialization. Error: {}", e);
         }
         // Save device state.
@@ -105,6 +213,10 @@ impl Persist<'_> for Block {
             disk_path: self.disk.file_path().clone(),
             virtio_state: VirtioDeviceState::from_device(self),
             rate_limiter_state: self.rate_limiter.save(),
+            file_engine_type: FileEngineTypeState::from(self.file_engine_type()),
+            num_queues: self.num_queues(),
+            overlay_path: self.overlay_path().cloned(),
+            nsectors: self.disk.nsectors(),
         }
     }
 
@@ -113,22 +225,54 @@ impl Persist<'_> for Block {
         state: &Self::State,
     ) -> Result<Self, Self::Error> {
         let is_disk_read_only = state.virtio_state.avail_features & (1u64 << VIRTIO_BLK_F_RO) != 0;
-        let rate_limiter = RateLimiter::restore((), &state.rate_limiter_state)?;
+        let is_discard_enabled =
+            state.virtio_state.avail_features & (1u64 << VIRTIO_BLK_F_DISCARD) != 0;
+        let is_write_zeroes_enabled =
+            state.virtio_state.avail_features & (1u64 << VIRTIO_BLK_F_WRITE_ZEROES) != 0;
+        let rate_limiter = RateLimiter::restore((), &state.rate_limiter_state)
+            .map_err(Error::CreateRateLimiter)?;
+        let disk_path = constructor_args
+            .disk_path
+            .unwrap_or_else(|| state.disk_path.clone());
 
         let mut block = Block::new(
             state.id.clone(),
             state.partuuid.clone(),
             state.cache_type.into(),
-            state.disk_path.clone(),
+            disk_path.clone(),
             is_disk_read_only,
             state.root_device,
             rate_limiter,
-        )?;
+            state.file_engine_type.into(),
+            is_discard_enabled,
+            is_write_zeroes_enabled,
+            state.num_queues,
+            state.overlay_path.clone(),
+        )
+        .map_err(|err| match err.raw_os_error() {
+            Some(libc::EACCES) | Some(libc::EPERM) | Some(libc::EROFS) if !is_disk_read_only => {
+                Error::ReadOnlyDisk(disk_path)
+            }
+            _ => Error::CreateBlock(err),
+        })?;
+
+        // The guest would otherwise see the disk changing size underneath it.
+        if state.nsectors != 0 && state.nsectors != block.disk.nsectors() {
+            return Err(Error::DiskSizeMismatch(
+                state.nsectors,
+                block.disk.nsectors(),
+            ));
+        }
 
         block.queues = state
             .virtio_state
-            .build_queues_checked(&constructor_args.mem, TYPE_BLOCK, NUM_QUEUES, QUEUE_SIZE)
-            .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+            .build_queues_checked(
+                &constructor_args.mem,
+                TYPE_BLOCK,
+                state.num_queues as usize,
+                QUEUE_SIZE,
+            )
+            .map_err(Error::VirtioState)?;
         block.irq_trigger.irq_status =
             Arc::new(AtomicUsize::new(state.virtio_state.interrupt_status));
         block.avail_features = state.virtio_state.avail_features;
@@ -169,6 +313,32 @@ mod tests {
         assert_eq!(CacheType::Writeback, CacheTypeState::Writeback.into());
     }
 
+    #[test]
+    fn test_file_engine_type_state_from() {
+        assert_eq!(
+            FileEngineTypeState::Async,
+            FileEngineTypeState::from(FileEngineType::Async)
+        );
+        assert_eq!(
+            FileEngineTypeState::Sync,
+            FileEngineTypeState::from(FileEngineType::Sync)
+        );
+    }
+
+    #[test]
+    fn test_file_engine_type_state_into() {
+        assert_eq!(FileEngineType::Async, FileEngineTypeState::Async.into());
+        assert_eq!(FileEngineType::Sync, FileEngineTypeState::Sync.into());
+    }
+
+    #[test]
+    fn test_default_file_engine_type() {
+        assert_eq!(
+            BlockState::default_file_engine_type(2),
+            FileEngineTypeState::Sync
+        );
+    }
+
     #[test]
     fn test_default_cache_type_flush() {
         assert_eq!(
@@ -196,6 +366,11 @@ mod tests {
             false,
             false,
             RateLimiter::default(),
+            FileEngineType::Sync,
+            false,
+            false,
+            1,
+            None,
         )
         .unwrap();
 
@@ -212,6 +387,190 @@ mod tests {
             .is_ok());
     }
 
+    #[test]
+    fn test_default_num_queues() {
+        assert_eq!(BlockState::default_num_queues(1), 1);
+    }
+
+    #[test]
+    fn test_multi_queue_persistence() {
+        // We create the backing file here so that it exists for the whole lifetime of the test.
+        let f = TempFile::new().unwrap();
+        f.as_file().set_len(0x1000).unwrap();
+
+        let block = Block::new(
+            "test".to_string(),
+            None,
+            CacheType::Unsafe,
+            f.as_path().to_str().unwrap().to_string(),
+            false,
+            false,
+            RateLimiter::default(),
+            FileEngineType::Sync,
+            false,
+            false,
+            4,
+            None,
+        )
+        .unwrap();
+
+        let mut mem = vec![0; 4096];
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .set_type_version(BlockState::type_id(), 2);
+
+        // The first version of the block state doesn't describe multiple queues.
+        assert!(<Block as Persist>::save(&block)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 1)
+            .is_err());
+
+        <Block as Persist>::save(&block)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 2)
+            .unwrap();
+        let restored_block = Block::restore(
+            BlockConstructorArgs {
+                mem: default_mem(),
+                disk_path: None,
+            },
+            &BlockState::deserialize(&mut mem.as_slice(), &version_map, 2).unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(restored_block.num_queues(), 4);
+        assert_eq!(restored_block.queues(), block.queues());
+        assert_eq!(restored_block.avail_features(), block.avail_features());
+    }
+
+    #[test]
+    fn test_default_overlay_path() {
+        assert_eq!(BlockState::default_overlay_path(1), None);
+    }
+
+    #[test]
+    fn test_overlay_persistence() {
+        // We create the backing files here so that they exist for the whole lifetime of the test.
+        let base = TempFile::new().unwrap();
+        base.as_file().set_len(0x1000).unwrap();
+        let overlay = TempFile::new().unwrap();
+        let overlay_path = overlay.as_path().to_str().unwrap().to_string();
+
+        let block = Block::new(
+            "test".to_string(),
+            None,
+            CacheType::Unsafe,
+            base.as_path().to_str().unwrap().to_string(),
+            false,
+            false,
+            RateLimiter::default(),
+            FileEngineType::Sync,
+            false,
+            false,
+            1,
+            Some(overlay_path.clone()),
+        )
+        .unwrap();
+
+        let mut mem = vec![0; 4096];
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .set_type_version(BlockState::type_id(), 2);
+
+        // The first version of the block state doesn't describe overlay images.
+        assert!(<Block as Persist>::save(&block)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 1)
+            .is_err());
+
+        <Block as Persist>::save(&block)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 2)
+            .unwrap();
+        let restored_block = Block::restore(
+            BlockConstructorArgs {
+                mem: default_mem(),
+                disk_path: None,
+            },
+            &BlockState::deserialize(&mut mem.as_slice(), &version_map, 2).unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(restored_block.overlay_path(), Some(&overlay_path));
+        assert_eq!(restored_block.file_path(), block.file_path());
+        assert_eq!(restored_block.avail_features(), block.avail_features());
+    }
+
+    #[test]
+    fn test_default_nsectors() {
+        assert_eq!(BlockState::default_nsectors(1), 0);
+    }
+
+    #[test]
+    fn test_disk_path_override() {
+        // We create the backing files here so that they exist for the whole lifetime of the test.
+        let f = TempFile::new().unwrap();
+        f.as_file().set_len(0x1000).unwrap();
+        let moved = TempFile::new().unwrap();
+        moved.as_file().set_len(0x1000).unwrap();
+        let moved_path = moved.as_path().to_str().unwrap().to_string();
+        let resized = TempFile::new().unwrap();
+        resized.as_file().set_len(0x2000).unwrap();
+
+        let block = Block::new(
+            "test".to_string(),
+            None,
+            CacheType::Unsafe,
+            f.as_path().to_str().unwrap().to_string(),
+            false,
+            false,
+            RateLimiter::default(),
+            FileEngineType::Sync,
+            false,
+            false,
+            1,
+            None,
+        )
+        .unwrap();
+        let state = <Block as Persist>::save(&block);
+
+        let restored_block = Block::restore(
+            BlockConstructorArgs {
+                mem: default_mem(),
+                disk_path: Some(moved_path.clone()),
+            },
+            &state,
+        )
+        .unwrap();
+        assert_eq!(restored_block.file_path(), &moved_path);
+        assert_eq!(restored_block.disk.nsectors(), block.disk.nsectors());
+
+        // The disk image must keep the size the guest knows about.
+        match Block::restore(
+            BlockConstructorArgs {
+                mem: default_mem(),
+                disk_path: Some(resized.as_path().to_str().unwrap().to_string()),
+            },
+            &state,
+        ) {
+            Err(Error::DiskSizeMismatch(expected, actual)) => {
+                assert_eq!(expected, 0x1000 / SECTOR_SIZE);
+                assert_eq!(actual, 0x2000 / SECTOR_SIZE);
+            }
+            _ => panic!("Unexpected result."),
+        }
+
+        // Missing disk images are reported as such.
+        match Block::restore(
+            BlockConstructorArgs {
+                mem: default_mem(),
+                disk_path: Some("/invalid/path".to_string()),
+            },
+            &state,
+        ) {
+            Err(Error::CreateBlock(err)) => assert_eq!(err.kind(), io::ErrorKind::NotFound),
+            _ => panic!("Unexpected result."),
+        }
+    }
+
     #[test]
     fn test_persistence() {
         // We create the backing file here so that it exists for the whole lifetime of the test.
@@ -227,6 +586,11 @@ mod tests {
             false,
             false,
             RateLimiter::default(),
+            FileEngineType::Sync,
+            false,
+            false,
+            1,
+            None,
         )
         .unwrap();
         let guest_mem = default_mem();
@@ -241,7 +605,10 @@ mod tests {
 
         // Restore the block device.
         let restored_block = Block::restore(
-            BlockConstructorArgs { mem: guest_mem },
+            BlockConstructorArgs {
+                mem: guest_mem,
+                disk_path: None,
+            },
             &BlockState::deserialize(&mut mem.as_slice(), &version_map, 1).unwrap(),
         )
         .unwrap();
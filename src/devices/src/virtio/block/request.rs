@@ -5,27 +5,34 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the THIRD-PARTY file.
 
+use std::cmp;
 use std::convert::From;
+use std::fs::File;
 use std::io::{self, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
 use std::result;
 
-use logger::{IncMetric, METRICS};
+use logger::{error, IncMetric, METRICS};
 use virtio_gen::virtio_blk::*;
 use vm_memory::{ByteValued, Bytes, GuestAddress, GuestMemoryError, GuestMemoryMmap};
 
 use super::super::DescriptorChain;
-use super::device::{CacheType, DiskProperties};
+use super::device::{CacheType, DiskImage, DiskProperties};
+use super::io_engine::{Error as IoEngineError, FileEngineType};
 use super::{Error, SECTOR_SHIFT, SECTOR_SIZE};
 
 #[derive(Debug)]
 pub enum IoErrStatus {
+    Async(IoEngineError),
     BadRequest(Error),
+    Discard(io::Error),
     Flush(io::Error),
     // Read(num_used_bytes, GuestMemoryError)
     Read(u32, GuestMemoryError),
     Seek(io::Error),
     SyncAll(io::Error),
     Write(GuestMemoryError),
+    WriteZeroes(io::Error),
 }
 
 #[derive(Debug)]
@@ -83,6 +90,8 @@ pub enum RequestType {
     Out,
     Flush,
     GetDeviceID,
+    Discard,
+    WriteZeroes,
     Unsupported(u32),
 }
 
@@ -93,11 +102,106 @@ impl From<u32> for RequestType {
             VIRTIO_BLK_T_OUT => RequestType::Out,
             VIRTIO_BLK_T_FLUSH => RequestType::Flush,
             VIRTIO_BLK_T_GET_ID => RequestType::GetDeviceID,
+            VIRTIO_BLK_T_DISCARD => RequestType::Discard,
+            VIRTIO_BLK_T_WRITE_ZEROES => RequestType::WriteZeroes,
             t => RequestType::Unsupported(t),
         }
     }
 }
 
+/// The outcome of processing a request.
+pub enum ProcessingResult {
+    /// The request was handed to the asynchronous engine and will complete later.
+    Submitted,
+    /// The request was completed; holds the number of bytes written to the descriptor chain.
+    Executed(u32),
+}
+
+/// A request handed to the asynchronous engine, waiting for completion.
+#[derive(Debug)]
+pub struct PendingRequest {
+    pub(crate) queue_index: usize,
+    pub(crate) desc_idx: u16,
+    request_type: RequestType,
+    data_len: u32,
+    status_addr: GuestAddress,
+}
+
+impl PendingRequest {
+    /// Completes the request using the outcome reported by the asynchronous engine.
+    /// Returns the number of bytes written to the descriptor chain.
+    pub(crate) fn finish(&self, mem: &GuestMemoryMmap, result: io::Result<u32>) -> u32 {
+        let partial_buffer = |completed: u32| GuestMemoryError::PartialBuffer {
+            expected: self.data_len as usize,
+            completed: completed as usize,
+        };
+
+        let status = match (self.request_type, result) {
+            (RequestType::In, Ok(count)) => {
+                METRICS.block.read_bytes.add(count as usize);
+                if count == self.data_len {
+                    METRICS.block.read_count.inc();
+                    Status::Ok(count)
+                } else {
+                    Status::Err(ErrStatus::IoErr(IoErrStatus::Read(
+                        count,
+                        partial_buffer(count),
+                    )))
+                }
+            }
+            (RequestType::In, Err(e)) => Status::Err(ErrStatus::IoErr(IoErrStatus::Read(
+                0,
+                GuestMemoryError::IOError(e),
+            ))),
+            (RequestType::Out, Ok(count)) => {
+                METRICS.block.write_bytes.add(count as usize);
+                if count == self.data_len {
+                    METRICS.block.write_count.inc();
+                    Status::Ok(0)
+                } else {
+                    Status::Err(ErrStatus::IoErr(IoErrStatus::Write(partial_buffer(count))))
+                }
+            }
+            (RequestType::Out, Err(e)) => Status::Err(ErrStatus::IoErr(IoErrStatus::Write(
+                GuestMemoryError::IOError(e),
+            ))),
+            // Only flush requests are submitted besides reads and writes.
+            (_, Ok(_)) => {
+                METRICS.block.flush_count.inc();
+                Status::Ok(0)
+            }
+            (_, Err(e)) => Status::Err(ErrStatus::IoErr(IoErrStatus::SyncAll(e))),
+        };
+
+        finish_request(status, self.request_type, self.status_addr, mem)
+    }
+}
+
+/// Logs the outcome of a request and writes its status to the guest memory.
+/// Returns the number of bytes written to the descriptor chain.
+fn finish_request(
+    status: Status,
+    request_type: RequestType,
+    status_addr: GuestAddress,
+    mem: &GuestMemoryMmap,
+) -> u32 {
+    let virtio_blk_status = status.virtio_blk_status();
+    let num_used_bytes = status.num_used_bytes();
+    if let Status::Err(err_status) = status {
+        METRICS.block.invalid_reqs_count.inc();
+        error!(
+            "Failed to execute {:?} virtio block request: {:?}",
+            request_type, err_status
+        );
+    }
+
+    if let Err(e) = mem.write_obj(virtio_blk_status, status_addr) {
+        error!("Failed to write virtio block status: {:?}", e)
+    }
+
+    num_used_bytes
+}
+
 #[cfg_attr(test, derive(Debug, PartialEq))]
 pub struct Request {
     pub request_type: RequestType,
@@ -127,6 +231,35 @@ pub struct RequestHeader {
 // Safe because RequestHeader only contains plain data.
 unsafe impl ByteValued for RequestHeader {}
 
+/// The data of discard and write zeroes requests consists of segments describing the
+/// affected sector ranges.
+///
+/// A segment contains the following fields:
+///   * sector: an u64 value representing the first sector of the range.
+///   * num_sectors: an u32 value representing the number of sectors in the range.
+///   * flags: an u32 value; only `VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP` is defined, for
+///     write zeroes requests.
+#[derive(Copy, Clone, Default)]
+#[repr(C)]
+pub struct DiscardWriteZeroesSegment {
+    sector: u64,
+    num_sectors: u32,
+    flags: u32,
+}
+
+// Safe because DiscardWriteZeroesSegment only contains plain data.
+unsafe impl ByteValued for DiscardWriteZeroesSegment {}
+
+impl DiscardWriteZeroesSegment {
+    pub fn new(sector: u64, num_sectors: u32, flags: u32) -> DiscardWriteZeroesSegment {
+        DiscardWriteZeroesSegment {
+            sector,
+            num_sectors,
+            flags,
+        }
+    }
+}
+
 impl RequestHeader {
     pub fn new(request_type: u32, sector: u64) -> RequestHeader {
         RequestHeader {
@@ -186,7 +319,11 @@ impl Request {
                 .next_descriptor()
                 .ok_or(Error::DescriptorChainTooShort)?;
 
-            if data_desc.is_write_only() && req.request_type == RequestType::Out {
+            if data_desc.is_write_only()
+                && (req.request_type == RequestType::Out
+                    || req.request_type == RequestType::Discard
+                    || req.request_type == RequestType::WriteZeroes)
+            {
                 return Err(Error::UnexpectedWriteOnlyDescriptor);
             }
             if !data_desc.is_write_only() && req.request_type == RequestType::In {
@@ -214,7 +351,79 @@ impl Request {
         Ok(req)
     }
 
-    fn execute_seek(&self, disk: &mut DiskProperties) -> result::Result<(), ErrStatus> {
+    /// Processes the request, either by executing it or by handing it to the asynchronous
+    /// engine of the disk.
+    pub(crate) fn process(
+        &self,
+        disk: &mut DiskProperties,
+        queue_index: usize,
+        desc_idx: u16,
+        mem: &GuestMemoryMmap,
+    ) -> ProcessingResult {
+        let status = match self.submit_async(disk, queue_index, desc_idx, mem) {
+            Some(Ok(())) => return ProcessingResult::Submitted,
+            Some(Err(err_status)) => Status::Err(err_status),
+            None => Status::from_result(self.execute(disk, mem)),
+        };
+
+        ProcessingResult::Executed(finish_request(
+            status,
+            self.request_type,
+            self.status_addr,
+            mem,
+        ))
+    }
+
+    // Hands the request to the asynchronous engine of the disk, if it has one and the request
+    // accesses the backing file. Returns `None` if the request has to be executed synchronously.
+    fn submit_async(
+        &self,
+        disk: &mut DiskProperties,
+        queue_index: usize,
+        desc_idx: u16,
+        mem: &GuestMemoryMmap,
+    ) -> Option<result::Result<(), ErrStatus>> {
+        match self.request_type {
+            RequestType::In | RequestType::Out => {}
+            RequestType::Flush if disk.cache_type() == CacheType::Writeback => {}
+            _ => return None,
+        }
+        if disk.file_engine_type() != FileEngineType::Async {
+            return None;
+        }
+
+        let offset = match self.request_type {
+            RequestType::Flush => 0,
+            _ => match self.offset(disk) {
+                Ok(offset) => offset,
+                Err(err_status) => return Some(Err(err_status)),
+            },
+        };
+        let fd = disk.file().as_raw_fd();
+        let pending = PendingRequest {
+            queue_index,
+            desc_idx,
+            request_type: self.request_type,
+            data_len: self.data_len,
+            status_addr: self.status_addr,
+        };
+
+        // Safe to unwrap since we checked above that the disk has an asynchronous engine.
+        let engine = disk.async_engine_mut().unwrap();
+        let result = match self.request_type {
+            RequestType::In => {
+                engine.push_read(fd, offset, mem, self.data_addr, self.data_len, pending)
+            }
+            RequestType::Out => {
+                engine.push_write(fd, offset, mem, self.data_addr, self.data_len, pending)
+            }
+            _ => engine.push_flush(fd, pending),
+        };
+        Some(result.map_err(|e| ErrStatus::IoErr(IoErrStatus::Async(e))))
+    }
+
+    // Checks that the request is within the disk bounds and returns its offset in bytes.
+    fn offset(&self, disk: &DiskProperties) -> result::Result<u64, ErrStatus> {
         // TODO: perform this logic at request parsing level in the future.
         // Check that the data length is a multiple of 512 as specified in the virtio standard.
         if u64::from(self.data_len) % SECTOR_SIZE != 0 {
@@ -234,11 +443,131 @@ impl Request {
             )));
         }
 
-        disk.file_mut()
-            .seek(SeekFrom::Start(self.sector << SECTOR_SHIFT))
+        Ok(self.sector << SECTOR_SHIFT)
+    }
+
+    // Reads the single segment of a discard or write zeroes request from the guest memory,
+    // checks that it's within the disk bounds and returns its offset and length in bytes.
+    fn segment_range(
+        &self,
+        disk: &DiskProperties,
+        mem: &GuestMemoryMmap,
+    ) -> result::Result<(u64, u64, u32), ErrStatus> {
+        let bad_request = |e| ErrStatus::IoErr(IoErrStatus::BadRequest(e));
+
+        // We advertise a maximum of one segment per request.
+        if self.data_len as usize != std::mem::size_of::<DiscardWriteZeroesSegment>() {
+            return Err(bad_request(Error::InvalidDataLength));
+        }
+        let segment: DiscardWriteZeroesSegment = mem
+            .read_obj(self.data_addr)
+            .map_err(|e| bad_request(Error::GuestMemory(e)))?;
+
+        let top_sector = segment
+            .sector
+            .checked_add(u64::from(segment.num_sectors))
+            .ok_or_else(|| bad_request(Error::InvalidOffset))?;
+        if top_sector > disk.nsectors() {
+            return Err(bad_request(Error::InvalidOffset));
+        }
+
+        Ok((
+            segment.sector << SECTOR_SHIFT,
+            u64::from(segment.num_sectors) << SECTOR_SHIFT,
+            segment.flags,
+        ))
+    }
+
+    fn execute_discard(
+        &self,
+        disk: &DiskProperties,
+        mem: &GuestMemoryMmap,
+    ) -> result::Result<u32, ErrStatus> {
+        if !disk.is_discard_enabled() {
+            return Err(ErrStatus::Unsupported(VIRTIO_BLK_T_DISCARD));
+        }
+        let (offset, len, flags) = self.segment_range(disk, mem)?;
+        // No flags are defined for discard requests.
+        if flags != 0 {
+            return Err(ErrStatus::Unsupported(VIRTIO_BLK_T_DISCARD));
+        }
+
+        // Discarding is only a hint, so the clusters of an overlay image are kept allocated,
+        // while punching holes in its base image would corrupt the disks sharing it.
+        if disk.overlay().is_none() {
+            match fallocate(
+                disk.file(),
+                libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                offset,
+                len,
+            ) {
+                // It's also fine if the backing file does not support it.
+                Err(ref e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => {}
+                Err(e) => return Err(ErrStatus::IoErr(IoErrStatus::Discard(e))),
+                Ok(()) => {}
+            }
+        }
+        METRICS.block.discard_count.inc();
+        Ok(0)
+    }
+
+    fn execute_write_zeroes(
+        &self,
+        disk: &mut DiskProperties,
+        mem: &GuestMemoryMmap,
+    ) -> result::Result<u32, ErrStatus> {
+        if !disk.is_write_zeroes_enabled() {
+            return Err(ErrStatus::Unsupported(VIRTIO_BLK_T_WRITE_ZEROES));
+        }
+        let (offset, len, flags) = self.segment_range(disk, mem)?;
+        if flags & !VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP != 0 {
+            return Err(ErrStatus::Unsupported(VIRTIO_BLK_T_WRITE_ZEROES));
+        }
+
+        let mut result = Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP));
+        if disk.overlay().is_some() {
+            // The zeroes have to be written to the overlay, on top of the base image.
+            result = write_zeroes(&mut disk.image_mut(), offset, len);
+        } else if flags & VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP != 0 {
+            // Deallocated ranges read back as zeroes, so the range is unmapped if allowed.
+            result = fallocate(
+                disk.file(),
+                libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                offset,
+                len,
+            );
+        }
+        if is_unsupported(&result) {
+            result = fallocate(
+                disk.file(),
+                libc::FALLOC_FL_ZERO_RANGE | libc::FALLOC_FL_KEEP_SIZE,
+                offset,
+                len,
+            );
+        }
+        if is_unsupported(&result) {
+            result = write_zeroes(disk.file_mut(), offset, len);
+        }
+
+        result
+            .map(|_| {
+                METRICS.block.write_zeroes_count.inc();
+                0
+            })
+            .map_err(|e| ErrStatus::IoErr(IoErrStatus::WriteZeroes(e)))
+    }
+
+    fn execute_seek<'a>(
+        &self,
+        disk: &'a mut DiskProperties,
+    ) -> result::Result<DiskImage<'a>, ErrStatus> {
+        let offset = self.offset(disk)?;
+        let mut image = disk.image_mut();
+        image
+            .seek(SeekFrom::Start(offset))
             .map_err(|e| ErrStatus::IoErr(IoErrStatus::Seek(e)))?;
 
-        Ok(())
+        Ok(image)
     }
 
     pub(crate) fn execute(
@@ -250,8 +579,8 @@ impl Request {
 
         match self.request_type {
             RequestType::In => {
-                self.execute_seek(disk)?;
-                mem.read_exact_from(self.data_addr, disk.file_mut(), self.data_len as usize)
+                let mut image = self.execute_seek(disk)?;
+                mem.read_exact_from(self.data_addr, &mut image, self.data_len as usize)
                     .map(|_| {
                         METRICS.block.read_bytes.add(self.data_len as usize);
                         METRICS.block.read_count.inc();
@@ -268,8 +597,8 @@ impl Request {
                     })
             }
             RequestType::Out => {
-                self.execute_seek(disk)?;
-                mem.write_all_to(self.data_addr, disk.file_mut(), self.data_len as usize)
+                let mut image = self.execute_seek(disk)?;
+                mem.write_all_to(self.data_addr, &mut image, self.data_len as usize)
                     .map(|_| {
                         METRICS.block.write_bytes.add(self.data_len as usize);
                         METRICS.block.write_count.inc();
@@ -286,11 +615,11 @@ impl Request {
                 match cache_type {
                     CacheType::Writeback => {
                         // flush() first to force any cached data out.
-                        disk.file_mut()
+                        disk.data_file()
                             .flush()
                             .map_err(|e| ErrStatus::IoErr(IoErrStatus::Flush(e)))?;
                         // Sync data out to physical media on host.
-                        disk.file_mut()
+                        disk.data_file()
                             .sync_all()
                             .map_err(|e| ErrStatus::IoErr(IoErrStatus::SyncAll(e)))?;
                         METRICS.block.flush_count.inc();
@@ -312,11 +641,55 @@ impl Request {
                     .map(|_| VIRTIO_BLK_ID_BYTES)
                     .map_err(|e| ErrStatus::IoErr(IoErrStatus::Write(e)))
             }
+            RequestType::Discard => self.execute_discard(disk, mem),
+            RequestType::WriteZeroes => self.execute_write_zeroes(disk, mem),
             RequestType::Unsupported(op) => Err(ErrStatus::Unsupported(op)),
         }
     }
 }
 
+fn fallocate(file: &File, mode: libc::c_int, offset: u64, len: u64) -> io::Result<()> {
+    // `fallocate` rejects empty ranges, which are valid in requests.
+    if len == 0 {
+        return Ok(());
+    }
+    // Safe because the file descriptor is valid and the return value is checked.
+    let ret = unsafe {
+        libc::fallocate(
+            file.as_raw_fd(),
+            mode,
+            offset as libc::off_t,
+            len as libc::off_t,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn is_unsupported(result: &io::Result<()>) -> bool {
+    match result {
+        Err(e) => e.raw_os_error() == Some(libc::EOPNOTSUPP),
+        Ok(()) => false,
+    }
+}
+
+// Fallback for disk images that can't zero ranges through `fallocate`.
+fn write_zeroes<W: Write + Seek>(image: &mut W, offset: u64, len: u64) -> io::Result<()> {
+    const CHUNK_SIZE: u64 = 64 * 1024;
+    let zeroes = vec![0u8; cmp::min(len, CHUNK_SIZE) as usize];
+
+    image.seek(SeekFrom::Start(offset))?;
+    let mut remaining = len;
+    while remaining > 0 {
+        let count = cmp::min(remaining, CHUNK_SIZE);
+        image.write_all(&zeroes[..count as usize])?;
+        remaining -= count;
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -337,6 +710,8 @@ mod tests {
             VIRTIO_BLK_T_OUT,
             VIRTIO_BLK_T_FLUSH,
             VIRTIO_BLK_T_GET_ID,
+            VIRTIO_BLK_T_DISCARD,
+            VIRTIO_BLK_T_WRITE_ZEROES,
         ];
 
         for request_type in supported_request_types {
@@ -363,6 +738,14 @@ mod tests {
             RequestType::from(VIRTIO_BLK_T_GET_ID),
             RequestType::GetDeviceID
         );
+        assert_eq!(
+            RequestType::from(VIRTIO_BLK_T_DISCARD),
+            RequestType::Discard
+        );
+        assert_eq!(
+            RequestType::from(VIRTIO_BLK_T_WRITE_ZEROES),
+            RequestType::WriteZeroes
+        );
         assert_eq!(RequestType::from(42), RequestType::Unsupported(42));
     }
 
@@ -427,6 +810,14 @@ mod tests {
             assert_eq!(status.num_used_bytes(), 1);
         }
 
+        {
+            let status = Status::from_result(Err(ErrStatus::IoErr(IoErrStatus::WriteZeroes(
+                io::Error::from_raw_os_error(42),
+            ))));
+            assert_eq!(status.virtio_blk_status(), VIRTIO_BLK_S_IOERR as u8);
+            assert_eq!(status.num_used_bytes(), 1);
+        }
+
         {
             let status = Status::from_result(Err(ErrStatus::Unsupported(0)));
             assert_eq!(status.virtio_blk_status(), VIRTIO_BLK_S_UNSUPP as u8);
@@ -494,6 +885,17 @@ mod tests {
             ));
         }
 
+        // Write only data for DISCARD and WRITE_ZEROES.
+        for request_type in &[VIRTIO_BLK_T_DISCARD, VIRTIO_BLK_T_WRITE_ZEROES] {
+            let mut q = vq.create_queue();
+            m.write_obj::<u32>(*request_type, GuestAddress(0x1000))
+                .unwrap();
+            assert!(matches!(
+                Request::parse(&q.pop(m).unwrap(), m),
+                Err(Error::UnexpectedWriteOnlyDescriptor)
+            ));
+        }
+
         {
             let mut q = vq.create_queue();
             // Read only data for GetDeviceID.
@@ -1,21 +1,27 @@
 // Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
 
+use crate::virtio::block::io_engine::FileEngineType;
 use crate::virtio::{Block, CacheType, IrqType, Queue};
 use rate_limiter::RateLimiter;
 use utils::tempfile::TempFile;
 
 /// Create a default Block instance to be used in tests.
 pub fn default_block() -> Block {
+    default_block_with_engine(FileEngineType::Sync)
+}
+
+/// Create a default Block instance using the specified I/O engine to be used in tests.
+pub fn default_block_with_engine(file_engine_type: FileEngineType) -> Block {
     // Create backing file.
     let f = TempFile::new().unwrap();
     f.as_file().set_len(0x1000).unwrap();
 
-    default_block_with_path(f.as_path().to_str().unwrap().to_string())
+    default_block_with_path(f.as_path().to_str().unwrap().to_string(), file_engine_type)
 }
 
 /// Create a default Block instance using file at the specified path to be used in tests.
-pub fn default_block_with_path(path: String) -> Block {
+pub fn default_block_with_path(path: String, file_engine_type: FileEngineType) -> Block {
     // Rate limiting is enabled but with a high operation rate (10 million ops/s).
     let rate_limiter = RateLimiter::new(0, 0, 0, 100_000, 0, 10).unwrap();
 
@@ -29,6 +35,11 @@ pub fn default_block_with_path(path: String) -> Block {
         false,
         false,
         rate_limiter,
+        file_engine_type,
+        false,
+        false,
+        1,
+        None,
     )
     .unwrap()
 }
@@ -37,7 +48,7 @@ pub fn invoke_handler_for_queue_event(b: &mut Block) {
     // Trigger the queue event.
     b.queue_evts[0].write(1).unwrap();
     // Handle event.
-    b.process_queue_event();
+    b.process_queue_event(0);
     // Validate the queue operation finished successfully.
     assert!(b.irq_trigger.has_pending_irq(IrqType::Vring));
 }
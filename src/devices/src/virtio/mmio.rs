@@ -8,7 +8,7 @@
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex, MutexGuard};
 
-use logger::warn;
+use logger::{error, warn};
 use utils::byte_order;
 use vm_memory::{GuestAddress, GuestMemoryMmap};
 
@@ -179,9 +179,18 @@ impl MmioTransport {
                 self.device_status = status;
                 let device_activated = self.locked_device().is_activated();
                 if !device_activated && self.are_queues_valid() {
-                    self.locked_device()
-                        .activate(self.mem.clone())
-                        .expect("Failed to activate device");
+                    let activate_result = self.locked_device().activate(self.mem.clone());
+                    if let Err(e) = activate_result {
+                        error!("Failed to activate virtio device: {:?}", e);
+                        // The driver is notified through a configuration change interrupt,
+                        // as per section 2.1.2 of the virtio 1.0 specification.
+                        self.device_status |= DEVICE_NEEDS_RESET;
+                        self.interrupt_status
+                            .fetch_or(VIRTIO_MMIO_INT_CONFIG as usize, Ordering::SeqCst);
+                        if let Err(e) = self.locked_device().interrupt_evt().write(1) {
+                            error!("Failed to send irq to the guest: {:?}", e);
+                        }
+                    }
                 }
             }
             _ if (status & FAILED) != 0 => {
@@ -342,6 +351,7 @@ pub(crate) mod tests {
         queue_evts: Vec<EventFd>,
         queues: Vec<Queue>,
         device_activated: bool,
+        activate_fails: bool,
         config_bytes: [u8; 0xeff],
     }
 
@@ -358,6 +368,7 @@ pub(crate) mod tests {
                 ],
                 queues: vec![Queue::new(16), Queue::new(32)],
                 device_activated: false,
+                activate_fails: false,
                 config_bytes: [0; 0xeff],
             }
         }
@@ -395,6 +406,9 @@ pub(crate) mod tests {
         }
 
         fn activate(&mut self, _: GuestMemoryMmap) -> ActivateResult {
+            if self.activate_fails {
+                return Err(ActivateError::BadActivate);
+            }
             self.device_activated = true;
             Ok(())
         }
@@ -816,6 +830,56 @@ pub(crate) mod tests {
         assert!(d.locked_device().is_activated());
     }
 
+    #[test]
+    fn test_bus_device_activate_failure() {
+        let m = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let mut dummy = DummyDevice::new();
+        dummy.activate_fails = true;
+        let mut d = MmioTransport::new(m, Arc::new(Mutex::new(dummy)));
+
+        set_device_status(&mut d, device_status::ACKNOWLEDGE);
+        set_device_status(&mut d, device_status::ACKNOWLEDGE | device_status::DRIVER);
+        set_device_status(
+            &mut d,
+            device_status::ACKNOWLEDGE | device_status::DRIVER | device_status::FEATURES_OK,
+        );
+        let mut buf = vec![0; 4];
+        let queues_count = d.locked_device().queues().len();
+        for q in 0..queues_count {
+            d.queue_select = q as u32;
+            write_le_u32(&mut buf[..], 16);
+            d.write(0x38, &buf[..]);
+            write_le_u32(&mut buf[..], 1);
+            d.write(0x44, &buf[..]);
+        }
+        assert!(d.are_queues_valid());
+
+        // The driver is told that the device needs a reset, through a config interrupt.
+        set_device_status(
+            &mut d,
+            device_status::ACKNOWLEDGE
+                | device_status::DRIVER
+                | device_status::FEATURES_OK
+                | device_status::DRIVER_OK,
+        );
+        assert!(!d.locked_device().is_activated());
+        assert_eq!(
+            d.device_status,
+            device_status::ACKNOWLEDGE
+                | device_status::DRIVER
+                | device_status::FEATURES_OK
+                | device_status::DRIVER_OK
+                | device_status::DEVICE_NEEDS_RESET
+        );
+        d.read(0x60, &mut buf[..]);
+        assert_eq!(read_le_u32(&buf[..]), VIRTIO_MMIO_INT_CONFIG);
+        assert_eq!(d.locked_device().interrupt_evt().read().unwrap(), 1);
+
+        // The driver can reset the device afterwards.
+        set_device_status(&mut d, device_status::INIT);
+        assert_eq!(d.device_status, device_status::INIT);
+    }
+
     #[test]
     fn test_bus_device_reset() {
         let m = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x1000)]).unwrap();
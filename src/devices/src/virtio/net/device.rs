@@ -8,37 +8,49 @@
 use crate::virtio::net::tap::Tap;
 #[cfg(test)]
 use crate::virtio::net::test_utils::Mocks;
+use crate::virtio::net::vhost::VhostNet;
 use crate::virtio::net::Error;
 use crate::virtio::net::Result;
-use crate::virtio::net::{MAX_BUFFER_SIZE, QUEUE_SIZE, QUEUE_SIZES, RX_INDEX, TX_INDEX};
+use crate::virtio::net::{MAX_BUFFER_SIZE, MAX_QUEUE_PAIRS, QUEUE_SIZE, RX_INDEX, TX_INDEX};
 use crate::virtio::{
-    ActivateResult, DeviceState, IrqTrigger, IrqType, Queue, VirtioDevice, TYPE_NET,
+    ActivateResult, DescriptorChain, DeviceState, IrqTrigger, IrqType, Queue, VirtioDevice,
+    TYPE_NET,
 };
 use crate::{report_net_event_fail, Error as DeviceError};
 
 use dumbo::pdu::ethernet::EthernetFrame;
 use libc::EAGAIN;
-use logger::{error, warn, IncMetric, METRICS};
+use logger::{error, warn, IncMetric, NetQueuePairMetrics, METRICS};
 use mmds::ns::MmdsNetworkStack;
 use rate_limiter::{BucketUpdate, RateLimiter, TokenType};
+use snapshot::Persist;
 #[cfg(not(test))]
 use std::io;
 use std::io::{Read, Write};
 use std::net::Ipv4Addr;
+use std::os::unix::io::AsRawFd;
 use std::sync::atomic::AtomicUsize;
 use std::sync::Arc;
 use std::{cmp, mem, result};
+use utils::byte_order;
 use utils::eventfd::EventFd;
 use utils::net::mac::{MacAddr, MAC_ADDR_LEN};
 use virtio_gen::virtio_net::{
-    virtio_net_hdr_v1, VIRTIO_F_VERSION_1, VIRTIO_NET_F_CSUM, VIRTIO_NET_F_GUEST_CSUM,
-    VIRTIO_NET_F_GUEST_TSO4, VIRTIO_NET_F_GUEST_UFO, VIRTIO_NET_F_HOST_TSO4, VIRTIO_NET_F_HOST_UFO,
-    VIRTIO_NET_F_MAC,
+    virtio_net_ctrl_hdr, virtio_net_hdr_v1, VIRTIO_F_VERSION_1, VIRTIO_NET_CTRL_ANNOUNCE,
+    VIRTIO_NET_CTRL_ANNOUNCE_ACK, VIRTIO_NET_CTRL_MQ, VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET,
+    VIRTIO_NET_ERR, VIRTIO_NET_F_CSUM, VIRTIO_NET_F_CTRL_VQ, VIRTIO_NET_F_GUEST_ANNOUNCE,
+    VIRTIO_NET_F_GUEST_CSUM, VIRTIO_NET_F_GUEST_TSO4, VIRTIO_NET_F_GUEST_UFO,
+    VIRTIO_NET_F_HOST_TSO4, VIRTIO_NET_F_HOST_UFO, VIRTIO_NET_F_MAC, VIRTIO_NET_F_MQ,
+    VIRTIO_NET_F_STATUS, VIRTIO_NET_OK, VIRTIO_NET_S_ANNOUNCE, VIRTIO_NET_S_LINK_UP,
 };
 use vm_memory::{ByteValued, Bytes, GuestAddress, GuestMemoryError, GuestMemoryMmap};
 
+// Maximum length of a command received on the control queue.
+const MAX_CTRL_COMMAND_LEN: usize = 4096;
+
 enum FrontendError {
     AddUsed,
+    DescriptorChainTooLarge,
     DescriptorChainTooSmall,
     EmptyQueue,
     GuestMemory(GuestMemoryError),
@@ -49,6 +61,16 @@ pub(crate) fn vnet_hdr_len() -> usize {
     mem::size_of::<virtio_net_hdr_v1>()
 }
 
+// Index of the RX queue of a queue pair, in the device queues/queue_evts vectors.
+pub(crate) fn rx_queue_index(pair: usize) -> usize {
+    2 * pair + RX_INDEX
+}
+
+// Index of the TX queue of a queue pair, in the device queues/queue_evts vectors.
+pub(crate) fn tx_queue_index(pair: usize) -> usize {
+    2 * pair + TX_INDEX
+}
+
 // Frames being sent/received through the network device model have a VNET header. This
 // function returns a slice which holds the L2 frame bytes without this header.
 fn frame_bytes_from_buf(buf: &[u8]) -> Result<&[u8]> {
@@ -77,24 +99,64 @@ fn init_vnet_hdr(buf: &mut [u8]) {
 }
 
 #[derive(Clone, Copy)]
+#[repr(C)]
 pub struct ConfigSpace {
     pub guest_mac: [u8; MAC_ADDR_LEN],
+    pub status: u16,
+    pub max_virtqueue_pairs: u16,
 }
 
 impl Default for ConfigSpace {
     fn default() -> ConfigSpace {
         ConfigSpace {
             guest_mac: [0; MAC_ADDR_LEN],
+            status: 0,
+            max_virtqueue_pairs: 0,
         }
     }
 }
 
 unsafe impl ByteValued for ConfigSpace {}
 
+/// A RX/TX queue pair, along with the host TAP queue backing it.
+pub(crate) struct QueuePair {
+    pub(crate) tap: Tap,
+
+    pub(crate) rx_rate_limiter: RateLimiter,
+    pub(crate) tx_rate_limiter: RateLimiter,
+
+    pub(crate) rx_deferred_frame: bool,
+
+    rx_bytes_read: usize,
+    rx_frame_buf: [u8; MAX_BUFFER_SIZE],
+
+    metrics: &'static NetQueuePairMetrics,
+}
+
+impl QueuePair {
+    fn new(
+        tap: Tap,
+        rx_rate_limiter: RateLimiter,
+        tx_rate_limiter: RateLimiter,
+        metrics: &'static NetQueuePairMetrics,
+    ) -> Self {
+        QueuePair {
+            tap,
+            rx_rate_limiter,
+            tx_rate_limiter,
+            rx_deferred_frame: false,
+            rx_bytes_read: 0,
+            rx_frame_buf: [0u8; MAX_BUFFER_SIZE],
+            metrics,
+        }
+    }
+}
+
 pub struct Net {
     pub(crate) id: String,
 
-    pub tap: Tap,
+    pub(crate) queue_pairs: Vec<QueuePair>,
+    pub(crate) active_queue_pairs: u16,
 
     pub(crate) avail_features: u64,
     pub(crate) acked_features: u64,
@@ -102,15 +164,8 @@ pub struct Net {
     pub(crate) queues: Vec<Queue>,
     pub(crate) queue_evts: Vec<EventFd>,
 
-    pub(crate) rx_rate_limiter: RateLimiter,
-    pub(crate) tx_rate_limiter: RateLimiter,
-
-    pub(crate) rx_deferred_frame: bool,
     rx_deferred_irqs: bool,
 
-    rx_bytes_read: usize,
-    rx_frame_buf: [u8; MAX_BUFFER_SIZE],
-
     tx_iovec: Vec<(GuestAddress, usize)>,
     tx_frame_buf: [u8; MAX_BUFFER_SIZE],
 
@@ -124,12 +179,18 @@ pub struct Net {
 
     pub(crate) mmds_ns: Option<MmdsNetworkStack>,
 
+    pub(crate) vhost_net: Option<VhostNet>,
+
     #[cfg(test)]
     pub(crate) mocks: Mocks,
 }
 
 impl Net {
     /// Create a new virtio network device with the given TAP interface.
+    ///
+    /// The device has `num_queue_pairs` RX/TX queue pairs, each of them backed by a queue of
+    /// the TAP interface and rate limited independently, using the configuration of
+    /// `rx_rate_limiter` and `tx_rate_limiter`.
     pub fn new_with_tap(
         id: String,
         tap_if_name: String,
@@ -137,18 +198,46 @@ impl Net {
         rx_rate_limiter: RateLimiter,
         tx_rate_limiter: RateLimiter,
         allow_mmds_requests: bool,
+        num_queue_pairs: u16,
     ) -> Result<Self> {
-        let tap = Tap::open_named(&tap_if_name).map_err(Error::TapOpen)?;
+        if num_queue_pairs == 0 || num_queue_pairs > MAX_QUEUE_PAIRS {
+            return Err(Error::InvalidNumQueuePairs(num_queue_pairs));
+        }
 
-        // Set offload flags to match the virtio features below.
-        tap.set_offload(
-            net_gen::TUN_F_CSUM | net_gen::TUN_F_UFO | net_gen::TUN_F_TSO4 | net_gen::TUN_F_TSO6,
-        )
-        .map_err(Error::TapSetOffload)?;
+        let taps = if num_queue_pairs > 1 {
+            Tap::open_named_multi_queue(&tap_if_name, num_queue_pairs as usize)
+        } else {
+            Tap::open_named(&tap_if_name).map(|tap| vec![tap])
+        }
+        .map_err(Error::TapOpen)?;
+
+        let mut queue_pairs = Vec::with_capacity(taps.len());
+        for (pair, tap) in taps.into_iter().enumerate() {
+            // Set offload flags to match the virtio features below.
+            tap.set_offload(
+                net_gen::TUN_F_CSUM
+                    | net_gen::TUN_F_UFO
+                    | net_gen::TUN_F_TSO4
+                    | net_gen::TUN_F_TSO6,
+            )
+            .map_err(Error::TapSetOffload)?;
+
+            let vnet_hdr_size = vnet_hdr_len() as i32;
+            tap.set_vnet_hdr_size(vnet_hdr_size)
+                .map_err(Error::TapSetVnetHdrSize)?;
+
+            // Only the first queue pair is active until the driver asks for more.
+            if pair > 0 {
+                tap.set_queue_enabled(false).map_err(Error::TapSetQueue)?;
+            }
 
-        let vnet_hdr_size = vnet_hdr_len() as i32;
-        tap.set_vnet_hdr_size(vnet_hdr_size)
-            .map_err(Error::TapSetVnetHdrSize)?;
+            queue_pairs.push(QueuePair::new(
+                tap,
+                RateLimiter::restore((), &rx_rate_limiter.save()).map_err(Error::IO)?,
+                RateLimiter::restore((), &tx_rate_limiter.save()).map_err(Error::IO)?,
+                &METRICS.net.queue_pairs[pair],
+            ));
+        }
 
         let mut avail_features = 1 << VIRTIO_NET_F_GUEST_CSUM
             | 1 << VIRTIO_NET_F_CSUM
@@ -156,9 +245,13 @@ impl Net {
             | 1 << VIRTIO_NET_F_GUEST_UFO
             | 1 << VIRTIO_NET_F_HOST_TSO4
             | 1 << VIRTIO_NET_F_HOST_UFO
+            | 1 << VIRTIO_NET_F_STATUS
             | 1 << VIRTIO_F_VERSION_1;
 
-        let mut config_space = ConfigSpace::default();
+        let mut config_space = ConfigSpace {
+            status: VIRTIO_NET_S_LINK_UP as u16,
+            ..Default::default()
+        };
         if let Some(mac) = guest_mac {
             config_space.guest_mac.copy_from_slice(mac.get_bytes());
             // When this feature isn't available, the driver generates a random MAC address.
@@ -166,12 +259,22 @@ impl Net {
             avail_features |= 1 << VIRTIO_NET_F_MAC;
         }
 
-        let mut queue_evts = Vec::new();
-        for _ in QUEUE_SIZES.iter() {
+        // The number of active queue pairs is negotiated through the control queue,
+        // which follows the RX/TX queues.
+        let mut num_queues = 2 * num_queue_pairs as usize;
+        if num_queue_pairs > 1 {
+            avail_features |=
+                1 << VIRTIO_NET_F_MQ | 1 << VIRTIO_NET_F_CTRL_VQ | 1 << VIRTIO_NET_F_GUEST_ANNOUNCE;
+            config_space.max_virtqueue_pairs = num_queue_pairs;
+            num_queues += 1;
+        }
+
+        let mut queue_evts = Vec::with_capacity(num_queues);
+        for _ in 0..num_queues {
             queue_evts.push(EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd)?);
         }
 
-        let queues = QUEUE_SIZES.iter().map(|&s| Queue::new(s)).collect();
+        let queues = (0..num_queues).map(|_| Queue::new(QUEUE_SIZE)).collect();
 
         let mmds_ns = if allow_mmds_requests {
             Some(MmdsNetworkStack::new_with_defaults(None))
@@ -180,17 +283,13 @@ impl Net {
         };
         Ok(Net {
             id,
-            tap,
+            queue_pairs,
+            active_queue_pairs: 1,
             avail_features,
             acked_features: 0u64,
             queues,
             queue_evts,
-            rx_rate_limiter,
-            tx_rate_limiter,
-            rx_deferred_frame: false,
             rx_deferred_irqs: false,
-            rx_bytes_read: 0,
-            rx_frame_buf: [0u8; MAX_BUFFER_SIZE],
             tx_frame_buf: [0u8; MAX_BUFFER_SIZE],
             tx_iovec: Vec::with_capacity(QUEUE_SIZE as usize),
             irq_trigger: IrqTrigger::new().map_err(Error::EventFd)?,
@@ -198,6 +297,7 @@ impl Net {
             activate_evt: EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd)?,
             config_space,
             mmds_ns,
+            vhost_net: None,
             guest_mac: guest_mac.copied(),
 
             #[cfg(test)]
@@ -215,9 +315,50 @@ impl Net {
         self.guest_mac.as_ref()
     }
 
+    /// Changes the MAC of this net device, and asks the driver to announce it to the
+    /// network. Once the device is activated, this requires a driver supporting
+    /// announcements, since the driver only reads the MAC when it is probed otherwise.
+    pub fn set_guest_mac(&mut self, guest_mac: MacAddr) -> result::Result<(), DeviceError> {
+        let announce = self.acked_features & (1 << VIRTIO_NET_F_GUEST_ANNOUNCE) != 0;
+        if self.is_activated() && !announce {
+            return Err(DeviceError::MacUpdateNotAnnounced);
+        }
+
+        self.config_space
+            .guest_mac
+            .copy_from_slice(guest_mac.get_bytes());
+        self.guest_mac = Some(guest_mac);
+        METRICS.net.mac_address_updates.inc();
+
+        if announce {
+            self.config_space.status |= VIRTIO_NET_S_ANNOUNCE as u16;
+        }
+        self.signal_config_change()
+    }
+
+    /// Says if the link of this net device is reported as up to the driver.
+    pub fn link_up(&self) -> bool {
+        self.config_space.status & VIRTIO_NET_S_LINK_UP as u16 != 0
+    }
+
+    /// Sets the link state of this net device reported to the driver.
+    pub fn set_link_up(&mut self, link_up: bool) -> result::Result<(), DeviceError> {
+        if link_up {
+            self.config_space.status |= VIRTIO_NET_S_LINK_UP as u16;
+        } else {
+            self.config_space.status &= !(VIRTIO_NET_S_LINK_UP as u16);
+        }
+        self.signal_config_change()
+    }
+
     /// Provides the host IFACE name of this net device.
     pub fn iface_name(&self) -> String {
-        self.tap.if_name_as_str().to_string()
+        self.queue_pairs[0].tap.if_name_as_str().to_string()
+    }
+
+    /// Provides the number of RX/TX queue pairs of this net device.
+    pub fn num_queue_pairs(&self) -> u16 {
+        self.queue_pairs.len() as u16
     }
 
     /// Says if this device supports MMDS.
@@ -232,14 +373,56 @@ impl Net {
         }
     }
 
+    /// Hands the data path of this net device to the in-kernel vhost-net backend.
+    ///
+    /// The frames are then moved by the host kernel, without going through the device
+    /// model, so neither MMDS nor rate limiting are applied to them. Only single queue
+    /// pair devices can be backed by vhost-net.
+    pub fn enable_vhost_net(&mut self) -> Result<()> {
+        if self.num_queue_pairs() > 1 {
+            return Err(Error::InvalidNumQueuePairs(self.num_queue_pairs()));
+        }
+        self.vhost_net = Some(VhostNet::new(self.queues.len()).map_err(Error::VhostNet)?);
+        Ok(())
+    }
+
+    /// Says if the data path of this device is handled by vhost-net.
+    pub fn vhost_net_enabled(&self) -> bool {
+        self.vhost_net.is_some()
+    }
+
     /// Provides a reference to the configured RX rate limiter.
+    ///
+    /// All the queue pairs share the same rate limiter configuration.
     pub fn rx_rate_limiter(&self) -> &RateLimiter {
-        &self.rx_rate_limiter
+        &self.queue_pairs[0].rx_rate_limiter
     }
 
     /// Provides a reference to the configured TX rate limiter.
+    ///
+    /// All the queue pairs share the same rate limiter configuration.
     pub fn tx_rate_limiter(&self) -> &RateLimiter {
-        &self.tx_rate_limiter
+        &self.queue_pairs[0].tx_rate_limiter
+    }
+
+    // Attaches or detaches the TAP queues so that only the first `num_pairs` queue pairs
+    // exchange traffic with the host.
+    pub(crate) fn set_active_queue_pairs(&mut self, num_pairs: u16) -> Result<()> {
+        if num_pairs == 0 || num_pairs > self.num_queue_pairs() {
+            return Err(Error::InvalidNumQueuePairs(num_pairs));
+        }
+
+        let first = cmp::min(self.active_queue_pairs, num_pairs) as usize;
+        let last = cmp::max(self.active_queue_pairs, num_pairs) as usize;
+        for (pair, queue_pair) in self.queue_pairs[first..last].iter().enumerate() {
+            queue_pair
+                .tap
+                .set_queue_enabled(first + pair < num_pairs as usize)
+                .map_err(Error::TapSetQueue)?;
+        }
+        self.active_queue_pairs = num_pairs;
+
+        Ok(())
     }
 
     fn signal_used_queue(&mut self) -> result::Result<(), DeviceError> {
@@ -252,6 +435,19 @@ impl Net {
         Ok(())
     }
 
+    // Notifies the driver that the config space changed. The driver is only notified once
+    // the device is activated, since it reads the config space during initialization.
+    fn signal_config_change(&mut self) -> result::Result<(), DeviceError> {
+        if self.is_activated() {
+            self.irq_trigger.trigger_irq(IrqType::Config).map_err(|e| {
+                METRICS.net.event_fails.inc();
+                DeviceError::FailedSignalingIrq(e)
+            })?;
+        }
+
+        Ok(())
+    }
+
     fn signal_rx_used_queue(&mut self) -> result::Result<(), DeviceError> {
         if self.rx_deferred_irqs {
             return self.signal_used_queue();
@@ -263,53 +459,60 @@ impl Net {
     // Attempts to copy a single frame into the guest if there is enough
     // rate limiting budget.
     // Returns true on successful frame delivery.
-    fn rate_limited_rx_single_frame(&mut self) -> bool {
+    fn rate_limited_rx_single_frame(&mut self, pair: usize) -> bool {
+        let queue_pair = &mut self.queue_pairs[pair];
+        let rx_bytes_read = queue_pair.rx_bytes_read as u64;
         // If limiter.consume() fails it means there is no more TokenType::Ops
         // budget and rate limiting is in effect.
-        if !self.rx_rate_limiter.consume(1, TokenType::Ops) {
+        if !queue_pair.rx_rate_limiter.consume(1, TokenType::Ops) {
             METRICS.net.rx_rate_limiter_throttled.inc();
+            queue_pair.metrics.rx_rate_limiter_throttled.inc();
             return false;
         }
         // If limiter.consume() fails it means there is no more TokenType::Bytes
         // budget and rate limiting is in effect.
-        if !self
+        if !queue_pair
             .rx_rate_limiter
-            .consume(self.rx_bytes_read as u64, TokenType::Bytes)
+            .consume(rx_bytes_read, TokenType::Bytes)
         {
             // revert the OPS consume()
-            self.rx_rate_limiter.manual_replenish(1, TokenType::Ops);
+            queue_pair
+                .rx_rate_limiter
+                .manual_replenish(1, TokenType::Ops);
             METRICS.net.rx_rate_limiter_throttled.inc();
+            queue_pair.metrics.rx_rate_limiter_throttled.inc();
             return false;
         }
 
         // Attempt frame delivery.
-        let success = self.write_frame_to_guest();
+        let success = self.write_frame_to_guest(pair);
 
         // Undo the tokens consumption if guest delivery failed.
         if !success {
+            let rx_rate_limiter = &mut self.queue_pairs[pair].rx_rate_limiter;
             // revert the OPS consume()
-            self.rx_rate_limiter.manual_replenish(1, TokenType::Ops);
+            rx_rate_limiter.manual_replenish(1, TokenType::Ops);
             // revert the BYTES consume()
-            self.rx_rate_limiter
-                .manual_replenish(self.rx_bytes_read as u64, TokenType::Bytes);
+            rx_rate_limiter.manual_replenish(rx_bytes_read, TokenType::Bytes);
         }
         success
     }
 
-    // Copies a single frame from `self.rx_frame_buf` into the guest.
-    fn do_write_frame_to_guest(&mut self) -> std::result::Result<(), FrontendError> {
+    // Copies a single frame from the `rx_frame_buf` of the queue pair into the guest.
+    fn do_write_frame_to_guest(&mut self, pair: usize) -> std::result::Result<(), FrontendError> {
         let mut result: std::result::Result<(), FrontendError> = Ok(());
         // This is safe since we checked in the event handler that the device is activated.
         let mem = self.device_state.mem().unwrap();
 
-        let queue = &mut self.queues[RX_INDEX];
+        let queue = &mut self.queues[rx_queue_index(pair)];
         let head_descriptor = queue.pop(mem).ok_or_else(|| {
             METRICS.net.no_rx_avail_buffer.inc();
             FrontendError::EmptyQueue
         })?;
         let head_index = head_descriptor.index;
 
-        let mut frame_slice = &self.rx_frame_buf[..self.rx_bytes_read];
+        let queue_pair = &self.queue_pairs[pair];
+        let mut frame_slice = &queue_pair.rx_frame_buf[..queue_pair.rx_bytes_read];
         let frame_len = frame_slice.len();
         let mut maybe_next_descriptor = Some(head_descriptor);
         while let Some(descriptor) = &maybe_next_descriptor {
@@ -359,16 +562,19 @@ impl Net {
         if result.is_ok() {
             METRICS.net.rx_bytes_count.add(frame_len);
             METRICS.net.rx_packets_count.inc();
+            queue_pair.metrics.rx_bytes_count.add(frame_len);
+            queue_pair.metrics.rx_packets_count.inc();
         }
         result
     }
 
-    // Copies a single frame from `self.rx_frame_buf` into the guest. In case of an error retries
-    // the operation if possible. Returns true if the operation was successfull.
-    fn write_frame_to_guest(&mut self) -> bool {
-        let max_iterations = self.queues[RX_INDEX].actual_size();
+    // Copies a single frame from the `rx_frame_buf` of the queue pair into the guest. In case
+    // of an error retries the operation if possible. Returns true if the operation was
+    // successfull.
+    fn write_frame_to_guest(&mut self, pair: usize) -> bool {
+        let max_iterations = self.queues[rx_queue_index(pair)].actual_size();
         for _ in 0..max_iterations {
-            match self.do_write_frame_to_guest() {
+            match self.do_write_frame_to_guest(pair) {
                 Ok(()) => return true,
                 Err(FrontendError::EmptyQueue) | Err(FrontendError::AddUsed) => {
                     return false;
@@ -383,15 +589,15 @@ impl Net {
         false
     }
 
-    // Tries to detour the frame to MMDS and if MMDS doesn't accept it, sends it on the host TAP.
+    // Tries to detour the frame to MMDS and if MMDS doesn't accept it, sends it on the host TAP
+    // queue of the queue pair.
     //
     // `frame_buf` should contain the frame bytes in a slice of exact length.
     // Returns whether MMDS consumed the frame.
     fn write_to_mmds_or_tap(
         mmds_ns: Option<&mut MmdsNetworkStack>,
-        rate_limiter: &mut RateLimiter,
+        queue_pair: &mut QueuePair,
         frame_buf: &[u8],
-        tap: &mut Tap,
         guest_mac: Option<MacAddr>,
     ) -> Result<bool> {
         let checked_frame = |frame_buf| {
@@ -406,6 +612,7 @@ impl Net {
                 METRICS.mmds.rx_accepted.inc();
 
                 // MMDS frames are not accounted by the rate limiter.
+                let rate_limiter = &mut queue_pair.tx_rate_limiter;
                 rate_limiter.manual_replenish(frame_buf.len() as u64, TokenType::Bytes);
                 rate_limiter.manual_replenish(1, TokenType::Ops);
 
@@ -425,11 +632,13 @@ impl Net {
             });
         }
 
-        match tap.write(frame_buf) {
+        match queue_pair.tap.write(frame_buf) {
             Ok(_) => {
                 METRICS.net.tx_bytes_count.add(frame_buf.len());
                 METRICS.net.tx_packets_count.inc();
                 METRICS.net.tx_count.inc();
+                queue_pair.metrics.tx_bytes_count.add(frame_buf.len());
+                queue_pair.metrics.tx_packets_count.inc();
             }
             Err(e) => {
                 error!("Failed to write to tap: {:?}", e);
@@ -440,31 +649,38 @@ impl Net {
     }
 
     // We currently prioritize packets from the MMDS over regular network packets.
-    fn read_from_mmds_or_tap(&mut self) -> Result<usize> {
-        if let Some(ns) = self.mmds_ns.as_mut() {
+    // MMDS frames are only delivered on the first queue pair.
+    fn read_from_mmds_or_tap(&mut self, pair: usize) -> Result<usize> {
+        let queue_pair = &mut self.queue_pairs[pair];
+        let mmds_ns = if pair == 0 {
+            self.mmds_ns.as_mut()
+        } else {
+            None
+        };
+        if let Some(ns) = mmds_ns {
             if let Some(len) =
-                ns.write_next_frame(frame_bytes_from_buf_mut(&mut self.rx_frame_buf)?)
+                ns.write_next_frame(frame_bytes_from_buf_mut(&mut queue_pair.rx_frame_buf)?)
             {
                 let len = len.get();
                 METRICS.mmds.tx_frames.inc();
                 METRICS.mmds.tx_bytes.add(len);
-                init_vnet_hdr(&mut self.rx_frame_buf);
+                init_vnet_hdr(&mut queue_pair.rx_frame_buf);
                 return Ok(vnet_hdr_len() + len);
             }
         }
 
-        self.read_tap().map_err(Error::IO)
+        self.read_tap(pair).map_err(Error::IO)
     }
 
-    fn process_rx(&mut self) -> result::Result<(), DeviceError> {
+    fn process_rx(&mut self, pair: usize) -> result::Result<(), DeviceError> {
         // Read as many frames as possible.
         loop {
-            match self.read_from_mmds_or_tap() {
+            match self.read_from_mmds_or_tap(pair) {
                 Ok(count) => {
-                    self.rx_bytes_read = count;
+                    self.queue_pairs[pair].rx_bytes_read = count;
                     METRICS.net.rx_count.inc();
-                    if !self.rate_limited_rx_single_frame() {
-                        self.rx_deferred_frame = true;
+                    if !self.rate_limited_rx_single_frame(pair) {
+                        self.queue_pairs[pair].rx_deferred_frame = true;
                         break;
                     }
                 }
@@ -493,26 +709,26 @@ impl Net {
     }
 
     // Process the deferred frame first, then continue reading from tap.
-    fn handle_deferred_frame(&mut self) -> result::Result<(), DeviceError> {
-        if self.rate_limited_rx_single_frame() {
-            self.rx_deferred_frame = false;
+    fn handle_deferred_frame(&mut self, pair: usize) -> result::Result<(), DeviceError> {
+        if self.rate_limited_rx_single_frame(pair) {
+            self.queue_pairs[pair].rx_deferred_frame = false;
             // process_rx() was interrupted possibly before consuming all
             // packets in the tap; try continuing now.
-            return self.process_rx();
+            return self.process_rx(pair);
         }
 
         self.signal_rx_used_queue()
     }
 
-    fn resume_rx(&mut self) -> result::Result<(), DeviceError> {
-        if self.rx_deferred_frame {
-            self.handle_deferred_frame()
+    fn resume_rx(&mut self, pair: usize) -> result::Result<(), DeviceError> {
+        if self.queue_pairs[pair].rx_deferred_frame {
+            self.handle_deferred_frame(pair)
         } else {
             Ok(())
         }
     }
 
-    fn process_tx(&mut self) -> result::Result<(), DeviceError> {
+    fn process_tx(&mut self, pair: usize) -> result::Result<(), DeviceError> {
         // This is safe since we checked in the event handler that the device is activated.
         let mem = self.device_state.mem().unwrap();
 
@@ -522,16 +738,18 @@ impl Net {
         // with the MMDS network stack.
         let mut process_rx_for_mmds = false;
         let mut raise_irq = false;
-        let tx_queue = &mut self.queues[TX_INDEX];
+        let tx_queue = &mut self.queues[tx_queue_index(pair)];
+        let queue_pair = &mut self.queue_pairs[pair];
 
         while let Some(head) = tx_queue.pop(mem) {
             // If limiter.consume() fails it means there is no more TokenType::Ops
             // budget and rate limiting is in effect.
-            if !self.tx_rate_limiter.consume(1, TokenType::Ops) {
+            if !queue_pair.tx_rate_limiter.consume(1, TokenType::Ops) {
                 // Stop processing the queue and return this descriptor chain to the
                 // avail ring, for later processing.
                 tx_queue.undo_pop();
                 METRICS.net.tx_rate_limiter_throttled.inc();
+                queue_pair.metrics.tx_rate_limiter_throttled.inc();
                 break;
             }
 
@@ -552,16 +770,19 @@ impl Net {
 
             // If limiter.consume() fails it means there is no more TokenType::Bytes
             // budget and rate limiting is in effect.
-            if !self
+            if !queue_pair
                 .tx_rate_limiter
                 .consume(read_count as u64, TokenType::Bytes)
             {
                 // revert the OPS consume()
-                self.tx_rate_limiter.manual_replenish(1, TokenType::Ops);
+                queue_pair
+                    .tx_rate_limiter
+                    .manual_replenish(1, TokenType::Ops);
                 // Stop processing the queue and return this descriptor chain to the
                 // avail ring, for later processing.
                 tx_queue.undo_pop();
                 METRICS.net.tx_rate_limiter_throttled.inc();
+                queue_pair.metrics.tx_rate_limiter_throttled.inc();
                 break;
             }
 
@@ -596,13 +817,12 @@ impl Net {
 
             let frame_consumed_by_mmds = Self::write_to_mmds_or_tap(
                 self.mmds_ns.as_mut(),
-                &mut self.tx_rate_limiter,
+                queue_pair,
                 &self.tx_frame_buf[..read_count],
-                &mut self.tap,
                 self.guest_mac,
             )
             .unwrap_or(false);
-            if frame_consumed_by_mmds && !self.rx_deferred_frame {
+            if frame_consumed_by_mmds {
                 // MMDS consumed this frame/request, let's also try to process the response.
                 process_rx_for_mmds = true;
             }
@@ -619,15 +839,124 @@ impl Net {
             METRICS.net.no_tx_avail_buffer.inc();
         }
 
-        // An incoming frame for the MMDS may trigger the transmission of a new message.
-        if process_rx_for_mmds {
-            self.process_rx()
+        // An incoming frame for the MMDS may trigger the transmission of a new message,
+        // which is delivered on the first queue pair.
+        if process_rx_for_mmds && !self.queue_pairs[0].rx_deferred_frame {
+            self.process_rx(0)
         } else {
             Ok(())
         }
     }
 
-    /// Updates the parameters for the rate limiters
+    // Reads a command from a control queue descriptor chain. Returns the command header,
+    // its payload and the address where the acknowledgement has to be written.
+    fn read_ctrl_command(
+        mem: &GuestMemoryMmap,
+        head: DescriptorChain,
+    ) -> result::Result<(virtio_net_ctrl_hdr, Vec<u8>, GuestAddress), FrontendError> {
+        let mut command = Vec::new();
+        let mut maybe_next_descriptor = Some(head);
+        while let Some(descriptor) = maybe_next_descriptor {
+            if descriptor.is_write_only() {
+                // The acknowledgement goes in the first device writable descriptor.
+                let hdr_len = mem::size_of::<virtio_net_ctrl_hdr>();
+                if command.len() < hdr_len || descriptor.len == 0 {
+                    return Err(FrontendError::DescriptorChainTooSmall);
+                }
+                let hdr = virtio_net_ctrl_hdr {
+                    class: command[0],
+                    cmd: command[1],
+                };
+                return Ok((hdr, command.split_off(hdr_len), descriptor.addr));
+            }
+
+            let len = command.len();
+            if len + descriptor.len as usize > MAX_CTRL_COMMAND_LEN {
+                return Err(FrontendError::DescriptorChainTooLarge);
+            }
+            command.resize(len + descriptor.len as usize, 0);
+            mem.read_slice(&mut command[len..], descriptor.addr)
+                .map_err(FrontendError::GuestMemory)?;
+
+            maybe_next_descriptor = descriptor.next_descriptor();
+        }
+
+        Err(FrontendError::DescriptorChainTooSmall)
+    }
+
+    // Executes a command received on the control queue and returns its acknowledgement.
+    fn execute_ctrl_command(&mut self, hdr: virtio_net_ctrl_hdr, data: &[u8]) -> u8 {
+        match (u32::from(hdr.class), u32::from(hdr.cmd)) {
+            (VIRTIO_NET_CTRL_MQ, VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET) if data.len() == 2 => {
+                match self.set_active_queue_pairs(byte_order::read_le_u16(data)) {
+                    Ok(()) => VIRTIO_NET_OK as u8,
+                    Err(e) => {
+                        error!("Failed to set the number of active queue pairs: {:?}", e);
+                        VIRTIO_NET_ERR as u8
+                    }
+                }
+            }
+            (VIRTIO_NET_CTRL_ANNOUNCE, VIRTIO_NET_CTRL_ANNOUNCE_ACK) => {
+                self.config_space.status &= !(VIRTIO_NET_S_ANNOUNCE as u16);
+                VIRTIO_NET_OK as u8
+            }
+            (class, cmd) => {
+                warn!("Unsupported control command: class {}, cmd {}", class, cmd);
+                VIRTIO_NET_ERR as u8
+            }
+        }
+    }
+
+    fn process_ctrl_queue(&mut self) -> result::Result<(), DeviceError> {
+        let ctrl_index = self.queues.len() - 1;
+        let mut raise_irq = false;
+
+        loop {
+            // This is safe since we checked in the event handler that the device is activated.
+            let mem = self.device_state.mem().unwrap();
+            let head = match self.queues[ctrl_index].pop(mem) {
+                Some(head) => head,
+                None => break,
+            };
+            let head_index = head.index;
+
+            let used_len = match Self::read_ctrl_command(mem, head) {
+                Ok((hdr, data, ack_addr)) => {
+                    let ack = self.execute_ctrl_command(hdr, &data);
+                    // The device state is borrowed again since executing the command
+                    // requires mutable access to the device.
+                    let mem = self.device_state.mem().unwrap();
+                    match mem.write_obj(ack, ack_addr) {
+                        Ok(()) => mem::size_of::<u8>() as u32,
+                        Err(e) => {
+                            error!("Failed to write control command ack: {:?}", e);
+                            METRICS.net.event_fails.inc();
+                            0
+                        }
+                    }
+                }
+                Err(_) => {
+                    error!("Malformed control command");
+                    METRICS.net.event_fails.inc();
+                    0
+                }
+            };
+
+            let mem = self.device_state.mem().unwrap();
+            self.queues[ctrl_index]
+                .add_used(mem, head_index, used_len)
+                .map_err(DeviceError::QueueError)?;
+            raise_irq = true;
+        }
+
+        if raise_irq {
+            self.signal_used_queue()?;
+        }
+
+        Ok(())
+    }
+
+    /// Updates the parameters for the rate limiters of all the queue pairs.
     pub fn patch_rate_limiters(
         &mut self,
         rx_bytes: BucketUpdate,
@@ -635,86 +964,103 @@ impl Net {
         tx_bytes: BucketUpdate,
         tx_ops: BucketUpdate,
     ) {
-        self.rx_rate_limiter.update_buckets(rx_bytes, rx_ops);
-        self.tx_rate_limiter.update_buckets(tx_bytes, tx_ops);
+        for queue_pair in self.queue_pairs.iter_mut() {
+            queue_pair
+                .rx_rate_limiter
+                .update_buckets(rx_bytes.clone(), rx_ops.clone());
+            queue_pair
+                .tx_rate_limiter
+                .update_buckets(tx_bytes.clone(), tx_ops.clone());
+        }
     }
 
     #[cfg(not(test))]
-    fn read_tap(&mut self) -> io::Result<usize> {
-        self.tap.read(&mut self.rx_frame_buf)
+    fn read_tap(&mut self, pair: usize) -> io::Result<usize> {
+        let queue_pair = &mut self.queue_pairs[pair];
+        queue_pair.tap.read(&mut queue_pair.rx_frame_buf)
     }
 
-    pub fn process_rx_queue_event(&mut self) {
+    pub fn process_rx_queue_event(&mut self, pair: usize) {
         METRICS.net.rx_queue_event_count.inc();
 
-        if let Err(e) = self.queue_evts[RX_INDEX].read() {
+        if let Err(e) = self.queue_evts[rx_queue_index(pair)].read() {
             // rate limiters present but with _very high_ allowed rate
             error!("Failed to get rx queue event: {:?}", e);
             METRICS.net.event_fails.inc();
         } else {
             // If the limiter is not blocked, resume the receiving of bytes.
-            if !self.rx_rate_limiter.is_blocked() {
-                self.resume_rx().unwrap_or_else(report_net_event_fail);
+            if !self.queue_pairs[pair].rx_rate_limiter.is_blocked() {
+                self.resume_rx(pair).unwrap_or_else(report_net_event_fail);
             } else {
                 METRICS.net.rx_rate_limiter_throttled.inc();
+                self.queue_pairs[pair]
+                    .metrics
+                    .rx_rate_limiter_throttled
+                    .inc();
             }
         }
     }
 
-    pub fn process_tap_rx_event(&mut self) {
+    pub fn process_tap_rx_event(&mut self, pair: usize) {
         // This is safe since we checked in the event handler that the device is activated.
         let mem = self.device_state.mem().unwrap();
         METRICS.net.rx_tap_event_count.inc();
 
+        let queue_pair = &self.queue_pairs[pair];
         // While there are no available RX queue buffers and there's a deferred_frame
         // don't process any more incoming. Otherwise start processing a frame. In the
         // process the deferred_frame flag will be set in order to avoid freezing the
         // RX queue.
-        if self.queues[RX_INDEX].is_empty(mem) && self.rx_deferred_frame {
+        if self.queues[rx_queue_index(pair)].is_empty(mem) && queue_pair.rx_deferred_frame {
             METRICS.net.no_rx_avail_buffer.inc();
             return;
         }
 
         // While limiter is blocked, don't process any more incoming.
-        if self.rx_rate_limiter.is_blocked() {
+        if queue_pair.rx_rate_limiter.is_blocked() {
             METRICS.net.rx_rate_limiter_throttled.inc();
+            queue_pair.metrics.rx_rate_limiter_throttled.inc();
             return;
         }
 
-        if self.rx_deferred_frame
+        if queue_pair.rx_deferred_frame
         // Process a deferred frame first if available. Don't read from tap again
         // until we manage to receive this deferred frame.
         {
-            self.handle_deferred_frame()
+            self.handle_deferred_frame(pair)
                 .unwrap_or_else(report_net_event_fail);
         } else {
-            self.process_rx().unwrap_or_else(report_net_event_fail);
+            self.process_rx(pair).unwrap_or_else(report_net_event_fail);
         }
     }
 
-    pub fn process_tx_queue_event(&mut self) {
+    pub fn process_tx_queue_event(&mut self, pair: usize) {
         METRICS.net.tx_queue_event_count.inc();
-        if let Err(e) = self.queue_evts[TX_INDEX].read() {
+        if let Err(e) = self.queue_evts[tx_queue_index(pair)].read() {
             error!("Failed to get tx queue event: {:?}", e);
             METRICS.net.event_fails.inc();
-        } else if !self.tx_rate_limiter.is_blocked()
+        } else if !self.queue_pairs[pair].tx_rate_limiter.is_blocked()
         // If the limiter is not blocked, continue transmitting bytes.
         {
-            self.process_tx().unwrap_or_else(report_net_event_fail);
+            self.process_tx(pair).unwrap_or_else(report_net_event_fail);
         } else {
             METRICS.net.tx_rate_limiter_throttled.inc();
+            self.queue_pairs[pair]
+                .metrics
+                .tx_rate_limiter_throttled
+                .inc();
         }
     }
 
-    pub fn process_rx_rate_limiter_event(&mut self) {
+    pub fn process_rx_rate_limiter_event(&mut self, pair: usize) {
         METRICS.net.rx_event_rate_limiter_count.inc();
         // Upon rate limiter event, call the rate limiter handler
         // and restart processing the queue.
 
-        match self.rx_rate_limiter.event_handler() {
+        match self.queue_pairs[pair].rx_rate_limiter.event_handler() {
             Ok(_) => {
                 // There might be enough budget now to receive the frame.
-                self.resume_rx().unwrap_or_else(report_net_event_fail);
+                self.resume_rx(pair).unwrap_or_else(report_net_event_fail);
             }
             Err(e) => {
                 error!("Failed to get rx rate-limiter event: {:?}", e);
@@ -723,14 +1069,14 @@ impl Net {
         }
     }
 
-    pub fn process_tx_rate_limiter_event(&mut self) {
+    pub fn process_tx_rate_limiter_event(&mut self, pair: usize) {
         METRICS.net.tx_rate_limiter_event_count.inc();
         // Upon rate limiter event, call the rate limiter handler
         // and restart processing the queue.
-        match self.tx_rate_limiter.event_handler() {
+        match self.queue_pairs[pair].tx_rate_limiter.event_handler() {
             Ok(_) => {
                 // There might be enough budget now to send the frame.
-                self.process_tx().unwrap_or_else(report_net_event_fail);
+                self.process_tx(pair).unwrap_or_else(report_net_event_fail);
             }
             Err(e) => {
                 error!("Failed to get tx rate-limiter event: {:?}", e);
@@ -739,10 +1085,66 @@ impl Net {
         }
     }
 
+    pub fn process_ctrl_queue_event(&mut self) {
+        if let Err(e) = self.queue_evts[self.queues.len() - 1].read() {
+            error!("Failed to get ctrl queue event: {:?}", e);
+            METRICS.net.event_fails.inc();
+        } else {
+            self.process_ctrl_queue()
+                .unwrap_or_else(report_net_event_fail);
+        }
+    }
+
+    // Configures the vhost-net backend with the queues set up by the driver.
+    fn activate_vhost_net(&self, mem: &GuestMemoryMmap) -> Result<()> {
+        if let Some(vhost_net) = self.vhost_net.as_ref() {
+            vhost_net
+                .activate(
+                    mem,
+                    self.acked_features,
+                    &self.queues,
+                    &self.queue_evts,
+                    self.queue_pairs[0].tap.as_raw_fd(),
+                )
+                .map_err(Error::VhostNet)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn process_vhost_call_event(&mut self, queue_index: usize) {
+        if let Some(vhost_net) = self.vhost_net.as_ref() {
+            if let Err(e) = vhost_net.call_evts[queue_index].read() {
+                error!("Failed to get vhost-net call event: {:?}", e);
+                METRICS.net.event_fails.inc();
+                return;
+            }
+        }
+        // The backend added used buffers to the queue, on behalf of the device.
+        self.signal_used_queue()
+            .unwrap_or_else(report_net_event_fail);
+    }
+
     /// Process device virtio queue(s).
     pub fn process_virtio_queues(&mut self) {
-        let _ = self.resume_rx();
-        let _ = self.process_tx();
+        for pair in 0..self.queue_pairs.len() {
+            let _ = self.resume_rx(pair);
+            let _ = self.process_tx(pair);
+        }
+        if self.num_queue_pairs() > 1 {
+            let _ = self.process_ctrl_queue();
+        }
+    }
+
+    // Only the config space fields backed by the offered features are exposed.
+    fn config_space_len(&self) -> usize {
+        if self.avail_features & (1 << VIRTIO_NET_F_MQ) != 0 {
+            mem::size_of::<ConfigSpace>()
+        } else if self.avail_features & (1 << VIRTIO_NET_F_STATUS) != 0 {
+            MAC_ADDR_LEN + mem::size_of::<u16>()
+        } else {
+            MAC_ADDR_LEN
+        }
     }
 }
 
@@ -784,7 +1186,7 @@ impl VirtioDevice for Net {
     }
 
     fn read_config(&self, offset: u64, mut data: &mut [u8]) {
-        let config_space_bytes = self.config_space.as_slice();
+        let config_space_bytes = &self.config_space.as_slice()[..self.config_space_len()];
         let config_len = config_space_bytes.len() as u64;
         if offset >= config_len {
             error!("Failed to read config space");
@@ -802,7 +1204,8 @@ impl VirtioDevice for Net {
 
     fn write_config(&mut self, offset: u64, data: &[u8]) {
         let data_len = data.len() as u64;
-        let config_space_bytes = self.config_space.as_mut_slice();
+        // Only the MAC address is writable by the driver.
+        let config_space_bytes = &mut self.config_space.guest_mac;
         let config_len = config_space_bytes.len() as u64;
         if offset + data_len > config_len {
             error!("Failed to write config space");
@@ -822,6 +1225,13 @@ impl VirtioDevice for Net {
     }
 
     fn activate(&mut self, mem: GuestMemoryMmap) -> ActivateResult {
+        // The backend is configured before the device is activated, so that the driver
+        // finds out when there is no data path.
+        if let Err(e) = self.activate_vhost_net(&mem) {
+            error!("Failed to activate the vhost-net backend: {:?}", e);
+            METRICS.net.activate_fails.inc();
+            return Err(super::super::ActivateError::BadActivate);
+        }
         if self.activate_evt.write(1).is_err() {
             error!("Net: Cannot write to activate_evt");
             return Err(super::super::ActivateError::BadActivate);
@@ -839,18 +1249,20 @@ pub mod tests {
         frame_bytes_from_buf, frame_bytes_from_buf_mut, init_vnet_hdr, vnet_hdr_len,
     };
     use std::net::Ipv4Addr;
+    use std::sync::atomic::Ordering;
     use std::time::Duration;
     use std::{io, mem, thread};
 
     use crate::check_metric_after_block;
     use crate::virtio::net::test_utils::test::TestHelper;
     use crate::virtio::net::test_utils::{
-        default_net, if_index, inject_tap_tx_frame, set_mac, NetEvent, NetQueue, ReadTapMock,
-        TapTrafficSimulator,
+        default_guest_memory, default_net, default_net_with_queue_pairs, if_index,
+        inject_tap_tx_frame, set_mac, NetEvent, NetQueue, ReadTapMock, TapTrafficSimulator,
     };
-    use crate::virtio::net::QUEUE_SIZES;
+    use crate::virtio::test_utils::{VirtQueue, VirtqDesc};
     use crate::virtio::{
-        Net, VirtioDevice, MAX_BUFFER_SIZE, RX_INDEX, TX_INDEX, TYPE_NET, VIRTQ_DESC_F_WRITE,
+        Net, VirtioDevice, MAX_BUFFER_SIZE, RX_INDEX, TX_INDEX, TYPE_NET, VIRTQ_DESC_F_NEXT,
+        VIRTQ_DESC_F_WRITE,
     };
     use dumbo::pdu::arp::{EthIPv4ArpFrame, ETH_IPV4_FRAME_LEN};
     use dumbo::pdu::ethernet::ETHERTYPE_ARP;
@@ -864,17 +1276,18 @@ pub mod tests {
     use vm_memory::{Address, GuestMemory};
 
     impl Net {
-        pub fn read_tap(&mut self) -> io::Result<usize> {
+        pub fn read_tap(&mut self, pair: usize) -> io::Result<usize> {
+            let queue_pair = &mut self.queue_pairs[pair];
             match &self.mocks.read_tap {
                 ReadTapMock::MockFrame(frame) => {
-                    self.rx_frame_buf[..frame.len()].copy_from_slice(&frame);
+                    queue_pair.rx_frame_buf[..frame.len()].copy_from_slice(&frame);
                     Ok(frame.len())
                 }
                 ReadTapMock::Failure => Err(io::Error::new(
                     io::ErrorKind::Other,
                     "Read tap synthetically failed.",
                 )),
-                ReadTapMock::TapFrame => self.tap.read(&mut self.rx_frame_buf),
+                ReadTapMock::TapFrame => queue_pair.tap.read(&mut queue_pair.rx_frame_buf),
             }
         }
     }
@@ -930,6 +1343,7 @@ pub mod tests {
             | 1 << VIRTIO_NET_F_GUEST_UFO
             | 1 << VIRTIO_NET_F_HOST_TSO4
             | 1 << VIRTIO_NET_F_HOST_UFO
+            | 1 << VIRTIO_NET_F_STATUS
             | 1 << VIRTIO_F_VERSION_1;
 
         assert_eq!(net.avail_features_by_page(0), features as u32);
@@ -956,9 +1370,14 @@ pub mod tests {
         net.read_config(0, &mut config_mac);
         assert_eq!(config_mac, mac.get_bytes());
 
+        // The link status follows the MAC.
+        let mut status = [0u8; 2];
+        net.read_config(MAC_ADDR_LEN as u64, &mut status);
+        assert_eq!(u16::from_le_bytes(status), VIRTIO_NET_S_LINK_UP as u16);
+
         // Invalid read.
         config_mac = [0u8; MAC_ADDR_LEN];
-        net.read_config(MAC_ADDR_LEN as u64 + 1, &mut config_mac);
+        net.read_config(MAC_ADDR_LEN as u64 + 2, &mut config_mac);
         assert_eq!(config_mac, [0u8, 0u8, 0u8, 0u8, 0u8, 0u8]);
     }
 
@@ -1110,7 +1529,7 @@ pub mod tests {
         th.rxq.check_used_elem(1, 3, 0);
         th.rxq.check_used_elem(2, 4, 0);
         // Check that the frame wasn't deferred.
-        assert!(!th.net().rx_deferred_frame);
+        assert!(!th.net().queue_pairs[0].rx_deferred_frame);
         // Check that the frame has been written successfully to the valid Rx descriptor chain.
         th.rxq.check_used_elem(3, 5, frame.len() as u32);
         th.rxq.dtable[5].check_data(&frame);
@@ -1143,7 +1562,7 @@ pub mod tests {
         );
 
         // Check that the frame wasn't deferred.
-        assert!(!th.net().rx_deferred_frame);
+        assert!(!th.net().queue_pairs[0].rx_deferred_frame);
         // Check that the used queue has advanced.
         assert_eq!(th.rxq.used.idx.get(), 1);
         assert!(&th.net().irq_trigger.has_pending_irq(IrqType::Vring));
@@ -1182,7 +1601,7 @@ pub mod tests {
         );
 
         // Check that the frames weren't deferred.
-        assert!(!th.net().rx_deferred_frame);
+        assert!(!th.net().queue_pairs[0].rx_deferred_frame);
         // Check that the used queue has advanced.
         assert_eq!(th.rxq.used.idx.get(), 2);
         assert!(&th.net().irq_trigger.has_pending_irq(IrqType::Vring));
@@ -1200,7 +1619,8 @@ pub mod tests {
     fn test_tx_missing_queue_signal() {
         let mut th = TestHelper::default();
         th.activate_net();
-        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().tap));
+        let tap_traffic_simulator =
+            TapTrafficSimulator::new(if_index(&th.net().queue_pairs[0].tap));
 
         th.add_desc_chain(NetQueue::Tx, 0, &[(0, 4096, 0)]);
         th.net().queue_evts[TX_INDEX].read().unwrap();
@@ -1220,7 +1640,8 @@ pub mod tests {
     fn test_tx_writeable_descriptor() {
         let mut th = TestHelper::default();
         th.activate_net();
-        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().tap));
+        let tap_traffic_simulator =
+            TapTrafficSimulator::new(if_index(&th.net().queue_pairs[0].tap));
 
         let desc_list = [(0, 100, 0), (1, 100, VIRTQ_DESC_F_WRITE), (2, 500, 0)];
         th.add_desc_chain(NetQueue::Tx, 0, &desc_list);
@@ -1239,7 +1660,8 @@ pub mod tests {
     fn test_tx_short_frame() {
         let mut th = TestHelper::default();
         th.activate_net();
-        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().tap));
+        let tap_traffic_simulator =
+            TapTrafficSimulator::new(if_index(&th.net().queue_pairs[0].tap));
 
         // Send an invalid frame (too small, VNET header missing).
         th.add_desc_chain(NetQueue::Tx, 0, &[(0, 1, 0)]);
@@ -1261,7 +1683,8 @@ pub mod tests {
     fn test_tx_partial_read() {
         let mut th = TestHelper::default();
         th.activate_net();
-        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().tap));
+        let tap_traffic_simulator =
+            TapTrafficSimulator::new(if_index(&th.net().queue_pairs[0].tap));
 
         // The descriptor chain is created so that the last descriptor doesn't fit in the
         // guest memory.
@@ -1289,7 +1712,8 @@ pub mod tests {
     fn test_tx_retry() {
         let mut th = TestHelper::default();
         th.activate_net();
-        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().tap));
+        let tap_traffic_simulator =
+            TapTrafficSimulator::new(if_index(&th.net().queue_pairs[0].tap));
 
         // Add invalid descriptor chain - writeable descriptor.
         th.add_desc_chain(
@@ -1329,7 +1753,8 @@ pub mod tests {
     fn test_tx_complex_descriptor() {
         let mut th = TestHelper::default();
         th.activate_net();
-        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().tap));
+        let tap_traffic_simulator =
+            TapTrafficSimulator::new(if_index(&th.net().queue_pairs[0].tap));
 
         // Add gaps between the descriptor ids in order to ensure that we follow
         // the `next` field.
@@ -1357,7 +1782,8 @@ pub mod tests {
     fn test_tx_multiple_frame() {
         let mut th = TestHelper::default();
         th.activate_net();
-        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().tap));
+        let tap_traffic_simulator =
+            TapTrafficSimulator::new(if_index(&th.net().queue_pairs[0].tap));
 
         // Write the first frame to the Tx queue
         let desc_list = [(0, 50, 0), (1, 100, 0), (2, 150, 0)];
@@ -1439,9 +1865,8 @@ pub mod tests {
             1,
             assert!(Net::write_to_mmds_or_tap(
                 net.mmds_ns.as_mut(),
-                &mut net.tx_rate_limiter,
+                &mut net.queue_pairs[0],
                 &frame_buf[..frame_len],
-                &mut net.tap,
                 Some(src_mac),
             )
             .unwrap())
@@ -1451,7 +1876,7 @@ pub mod tests {
         check_metric_after_block!(
             &METRICS.mmds.tx_frames,
             1,
-            net.read_from_mmds_or_tap().unwrap()
+            net.read_from_mmds_or_tap(0).unwrap()
         );
     }
 
@@ -1473,9 +1898,8 @@ pub mod tests {
             0,
             Net::write_to_mmds_or_tap(
                 net.mmds_ns.as_mut(),
-                &mut net.tx_rate_limiter,
+                &mut net.queue_pairs[0],
                 &frame_buf[..frame_len],
-                &mut net.tap,
                 Some(guest_mac),
             )
         );
@@ -1486,9 +1910,8 @@ pub mod tests {
             1,
             Net::write_to_mmds_or_tap(
                 net.mmds_ns.as_mut(),
-                &mut net.tx_rate_limiter,
+                &mut net.queue_pairs[0],
                 &frame_buf[..frame_len],
-                &mut net.tap,
                 Some(not_guest_mac),
             )
         );
@@ -1526,7 +1949,7 @@ pub mod tests {
         th.net().mocks.set_read_tap(ReadTapMock::Failure);
 
         // The RX queue is empty and rx_deffered_frame is set.
-        th.net().rx_deferred_frame = true;
+        th.net().queue_pairs[0].rx_deferred_frame = true;
         check_metric_after_block!(
             &METRICS.net.no_rx_avail_buffer,
             1,
@@ -1547,7 +1970,7 @@ pub mod tests {
         let mut th = TestHelper::default();
         th.activate_net();
 
-        th.net().rx_rate_limiter = RateLimiter::new(0, 0, 0, 0, 0, 0).unwrap();
+        th.net().queue_pairs[0].rx_rate_limiter = RateLimiter::new(0, 0, 0, 0, 0, 0).unwrap();
         // There is no actual event on the rate limiter's timerfd.
         check_metric_after_block!(
             &METRICS.net.event_fails,
@@ -1561,7 +1984,7 @@ pub mod tests {
         let mut th = TestHelper::default();
         th.activate_net();
 
-        th.net().tx_rate_limiter = RateLimiter::new(0, 0, 0, 0, 0, 0).unwrap();
+        th.net().queue_pairs[0].tx_rate_limiter = RateLimiter::new(0, 0, 0, 0, 0, 0).unwrap();
         th.simulate_event(NetEvent::TxRateLimiter);
         // There is no actual event on the rate limiter's timerfd.
         check_metric_after_block!(
@@ -1584,7 +2007,7 @@ pub mod tests {
             assert!(rl.consume(0x1000, TokenType::Bytes));
 
             // set this tx rate limiter to be used
-            th.net().tx_rate_limiter = rl;
+            th.net().queue_pairs[0].tx_rate_limiter = rl;
 
             // try doing TX
             // following TX procedure should fail because of bandwidth rate limiting
@@ -1594,7 +2017,7 @@ pub mod tests {
                 th.simulate_event(NetEvent::TxQueue);
 
                 // assert that limiter is blocked
-                assert!(th.net().tx_rate_limiter.is_blocked());
+                assert!(th.net().queue_pairs[0].tx_rate_limiter.is_blocked());
                 assert_eq!(METRICS.net.tx_rate_limiter_throttled.count(), 1);
                 // make sure the data is still queued for processing
                 assert_eq!(th.txq.used.idx.get(), 0);
@@ -1613,7 +2036,7 @@ pub mod tests {
                     th.simulate_event(NetEvent::TxRateLimiter)
                 );
                 // validate the rate_limiter is no longer blocked
-                assert!(!th.net().tx_rate_limiter.is_blocked());
+                assert!(!th.net().queue_pairs[0].tx_rate_limiter.is_blocked());
                 // make sure the data queue advanced
                 assert_eq!(th.txq.used.idx.get(), 1);
             }
@@ -1627,10 +2050,10 @@ pub mod tests {
             assert!(rl.consume(0x1000, TokenType::Bytes));
 
             // set this rx rate limiter to be used
-            th.net().rx_rate_limiter = rl;
+            th.net().queue_pairs[0].rx_rate_limiter = rl;
 
             // set up RX
-            assert!(!th.net().rx_deferred_frame);
+            assert!(!th.net().queue_pairs[0].rx_deferred_frame);
             th.add_desc_chain(NetQueue::Rx, 0, &[(0, 4096, VIRTQ_DESC_F_WRITE)]);
 
             // following RX procedure should fail because of bandwidth rate limiting
@@ -1639,9 +2062,9 @@ pub mod tests {
                 th.simulate_event(NetEvent::Tap);
 
                 // assert that limiter is blocked
-                assert!(th.net().rx_rate_limiter.is_blocked());
+                assert!(th.net().queue_pairs[0].rx_rate_limiter.is_blocked());
                 assert_eq!(METRICS.net.rx_rate_limiter_throttled.count(), 1);
-                assert!(th.net().rx_deferred_frame);
+                assert!(th.net().queue_pairs[0].rx_deferred_frame);
                 // assert that no operation actually completed (limiter blocked it)
                 assert!(&th.net().irq_trigger.has_pending_irq(IrqType::Vring));
                 // make sure the data is still queued for processing
@@ -1662,7 +2085,7 @@ pub mod tests {
                     th.simulate_event(NetEvent::RxRateLimiter)
                 );
                 // validate the rate_limiter is no longer blocked
-                assert!(!th.net().rx_rate_limiter.is_blocked());
+                assert!(!th.net().queue_pairs[0].rx_rate_limiter.is_blocked());
                 // make sure the virtio queue operation completed this time
                 assert!(&th.net().irq_trigger.has_pending_irq(IrqType::Vring));
                 // make sure the data queue advanced
@@ -1686,7 +2109,7 @@ pub mod tests {
             assert!(rl.consume(1, TokenType::Ops));
 
             // set this tx rate limiter to be used
-            th.net().tx_rate_limiter = rl;
+            th.net().queue_pairs[0].tx_rate_limiter = rl;
 
             // try doing TX
             // following TX procedure should fail because of ops rate limiting
@@ -1700,7 +2123,7 @@ pub mod tests {
                 );
 
                 // assert that limiter is blocked
-                assert!(th.net().tx_rate_limiter.is_blocked());
+                assert!(th.net().queue_pairs[0].tx_rate_limiter.is_blocked());
                 // make sure the data is still queued for processing
                 assert_eq!(th.txq.used.idx.get(), 0);
             }
@@ -1718,7 +2141,7 @@ pub mod tests {
                     th.simulate_event(NetEvent::TxRateLimiter)
                 );
                 // validate the rate_limiter is no longer blocked
-                assert!(!th.net().tx_rate_limiter.is_blocked());
+                assert!(!th.net().queue_pairs[0].tx_rate_limiter.is_blocked());
                 // make sure the data queue advanced
                 assert_eq!(th.txq.used.idx.get(), 1);
             }
@@ -1732,10 +2155,10 @@ pub mod tests {
             assert!(rl.consume(1, TokenType::Ops));
 
             // set this rx rate limiter to be used
-            th.net().rx_rate_limiter = rl;
+            th.net().queue_pairs[0].rx_rate_limiter = rl;
 
             // set up RX
-            assert!(!th.net().rx_deferred_frame);
+            assert!(!th.net().queue_pairs[0].rx_deferred_frame);
             th.add_desc_chain(NetQueue::Rx, 0, &[(0, 4096, VIRTQ_DESC_F_WRITE)]);
 
             // following RX procedure should fail because of ops rate limiting
@@ -1748,9 +2171,9 @@ pub mod tests {
                 );
 
                 // assert that limiter is blocked
-                assert!(th.net().rx_rate_limiter.is_blocked());
+                assert!(th.net().queue_pairs[0].rx_rate_limiter.is_blocked());
                 assert!(METRICS.net.rx_rate_limiter_throttled.count() >= 1);
-                assert!(th.net().rx_deferred_frame);
+                assert!(th.net().queue_pairs[0].rx_deferred_frame);
                 // assert that no operation actually completed (limiter blocked it)
                 assert!(&th.net().irq_trigger.has_pending_irq(IrqType::Vring));
                 // make sure the data is still queued for processing
@@ -1787,8 +2210,8 @@ pub mod tests {
         let mut th = TestHelper::default();
         th.activate_net();
 
-        th.net().rx_rate_limiter = RateLimiter::new(10, 0, 10, 2, 0, 2).unwrap();
-        th.net().tx_rate_limiter = RateLimiter::new(10, 0, 10, 2, 0, 2).unwrap();
+        th.net().queue_pairs[0].rx_rate_limiter = RateLimiter::new(10, 0, 10, 2, 0, 2).unwrap();
+        th.net().queue_pairs[0].tx_rate_limiter = RateLimiter::new(10, 0, 10, 2, 0, 2).unwrap();
 
         let rx_bytes = TokenBucket::new(1000, 1001, 1002).unwrap();
         let rx_ops = TokenBucket::new(1003, 1004, 1005).unwrap();
@@ -1806,10 +2229,22 @@ pub mod tests {
             assert_eq!(a.one_time_burst(), b.one_time_burst());
             assert_eq!(a.refill_time_ms(), b.refill_time_ms());
         };
-        compare_buckets(th.net().rx_rate_limiter.bandwidth().unwrap(), &rx_bytes);
-        compare_buckets(th.net().rx_rate_limiter.ops().unwrap(), &rx_ops);
-        compare_buckets(th.net().tx_rate_limiter.bandwidth().unwrap(), &tx_bytes);
-        compare_buckets(th.net().tx_rate_limiter.ops().unwrap(), &tx_ops);
+        compare_buckets(
+            th.net().queue_pairs[0].rx_rate_limiter.bandwidth().unwrap(),
+            &rx_bytes,
+        );
+        compare_buckets(
+            th.net().queue_pairs[0].rx_rate_limiter.ops().unwrap(),
+            &rx_ops,
+        );
+        compare_buckets(
+            th.net().queue_pairs[0].tx_rate_limiter.bandwidth().unwrap(),
+            &tx_bytes,
+        );
+        compare_buckets(
+            th.net().queue_pairs[0].tx_rate_limiter.ops().unwrap(),
+            &tx_ops,
+        );
 
         th.net().patch_rate_limiters(
             BucketUpdate::Disabled,
@@ -1817,10 +2252,16 @@ pub mod tests {
             BucketUpdate::Disabled,
             BucketUpdate::Disabled,
         );
-        assert!(th.net().rx_rate_limiter.bandwidth().is_none());
-        assert!(th.net().rx_rate_limiter.ops().is_none());
-        assert!(th.net().tx_rate_limiter.bandwidth().is_none());
-        assert!(th.net().tx_rate_limiter.ops().is_none());
+        assert!(th.net().queue_pairs[0]
+            .rx_rate_limiter
+            .bandwidth()
+            .is_none());
+        assert!(th.net().queue_pairs[0].rx_rate_limiter.ops().is_none());
+        assert!(th.net().queue_pairs[0]
+            .tx_rate_limiter
+            .bandwidth()
+            .is_none());
+        assert!(th.net().queue_pairs[0].tx_rate_limiter.ops().is_none());
     }
 
     #[test]
@@ -1831,14 +2272,189 @@ pub mod tests {
 
         // Test queues count (TX and RX).
         let queues = net.queues();
-        assert_eq!(queues.len(), QUEUE_SIZES.len());
+        assert_eq!(queues.len(), 2);
         assert_eq!(queues[RX_INDEX].size, th.rxq.size());
         assert_eq!(queues[TX_INDEX].size, th.txq.size());
 
         // Test corresponding queues events.
-        assert_eq!(net.queue_events().len(), QUEUE_SIZES.len());
+        assert_eq!(net.queue_events().len(), 2);
 
         // Test interrupts.
         assert!(!&net.irq_trigger.has_pending_irq(IrqType::Vring));
     }
+
+    #[test]
+    fn test_multi_queue_device() {
+        assert!(Net::new_with_tap(
+            "net".to_string(),
+            "".to_string(),
+            None,
+            RateLimiter::default(),
+            RateLimiter::default(),
+            false,
+            MAX_QUEUE_PAIRS + 1,
+        )
+        .is_err());
+
+        let mut net = default_net_with_queue_pairs(4);
+        assert_eq!(net.num_queue_pairs(), 4);
+        assert_eq!(net.active_queue_pairs, 1);
+
+        // The RX/TX queues are followed by the control queue.
+        assert_eq!(net.queues().len(), 9);
+        assert_eq!(net.queue_events().len(), 9);
+        assert_ne!(net.avail_features() & (1 << VIRTIO_NET_F_MQ), 0);
+        assert_ne!(net.avail_features() & (1 << VIRTIO_NET_F_CTRL_VQ), 0);
+
+        // All the queue pairs are backed by the same interface.
+        assert!(net
+            .queue_pairs
+            .iter()
+            .all(|queue_pair| queue_pair.tap.if_name_as_str() == net.iface_name()));
+
+        // The maximum number of queue pairs follows the MAC address in the config space.
+        let mut max_virtqueue_pairs = [0u8; 2];
+        net.read_config(8, &mut max_virtqueue_pairs);
+        assert_eq!(u16::from_le_bytes(max_virtqueue_pairs), 4);
+        // And it is read-only.
+        net.write_config(8, &[1, 0]);
+        net.read_config(8, &mut max_virtqueue_pairs);
+        assert_eq!(u16::from_le_bytes(max_virtqueue_pairs), 4);
+
+        net.set_active_queue_pairs(3).unwrap();
+        assert_eq!(net.active_queue_pairs, 3);
+        net.set_active_queue_pairs(2).unwrap();
+        assert_eq!(net.active_queue_pairs, 2);
+        assert!(net.set_active_queue_pairs(0).is_err());
+        assert!(net.set_active_queue_pairs(5).is_err());
+        assert_eq!(net.active_queue_pairs, 2);
+
+        // The rate limiters of all the queue pairs are updated.
+        let tx_ops = TokenBucket::new(1009, 1010, 1011).unwrap();
+        net.patch_rate_limiters(
+            BucketUpdate::None,
+            BucketUpdate::None,
+            BucketUpdate::None,
+            BucketUpdate::Update(tx_ops),
+        );
+        for queue_pair in net.queue_pairs.iter() {
+            assert!(queue_pair.rx_rate_limiter.ops().is_none());
+            assert_eq!(queue_pair.tx_rate_limiter.ops().unwrap().capacity(), 1009);
+        }
+
+        // Only single queue pair devices can be backed by vhost-net.
+        assert!(!net.vhost_net_enabled());
+        match net.enable_vhost_net() {
+            Err(Error::InvalidNumQueuePairs(4)) => (),
+            _ => panic!("Unexpected result."),
+        }
+        assert!(!net.vhost_net_enabled());
+    }
+
+    // Places a command on the control queue, processes it and returns its acknowledgement.
+    fn send_ctrl_command(
+        net: &mut Net,
+        ctrlq: &VirtQueue,
+        mem: &GuestMemoryMmap,
+        command: &[u8],
+    ) -> u8 {
+        let cmd_addr = ctrlq.end().unchecked_align_up(VirtqDesc::ALIGNMENT);
+        let ack_addr = cmd_addr.unchecked_add(0x100);
+        mem.write_slice(command, cmd_addr).unwrap();
+        mem.write_obj(0xffu8, ack_addr).unwrap();
+        ctrlq.dtable[0].set(
+            cmd_addr.raw_value(),
+            command.len() as u32,
+            VIRTQ_DESC_F_NEXT,
+            1,
+        );
+        ctrlq.dtable[1].set(ack_addr.raw_value(), 1, VIRTQ_DESC_F_WRITE, 0);
+        let avail_idx = ctrlq.avail.idx.get();
+        ctrlq.avail.ring[(avail_idx % ctrlq.size()) as usize].set(0);
+        ctrlq.avail.idx.set(avail_idx + 1);
+
+        let ctrl_index = net.queues.len() - 1;
+        net.queue_evts[ctrl_index].write(1).unwrap();
+        net.process_ctrl_queue_event();
+        ctrlq.check_used_elem(avail_idx, 0, 1);
+        mem.read_obj(ack_addr).unwrap()
+    }
+
+    #[test]
+    fn test_ctrl_queue() {
+        let mut net = default_net_with_queue_pairs(2);
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let ctrlq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        let ctrl_index = net.queues.len() - 1;
+        net.queues[ctrl_index] = ctrlq.create_queue();
+        net.activate(mem.clone()).unwrap();
+
+        let mq = VIRTIO_NET_CTRL_MQ as u8;
+        let vq_pairs_set = VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET as u8;
+        let ack = send_ctrl_command(&mut net, &ctrlq, &mem, &[mq, vq_pairs_set, 2, 0]);
+        assert_eq!(ack, VIRTIO_NET_OK as u8);
+        assert_eq!(net.active_queue_pairs, 2);
+        assert!(net.irq_trigger.has_pending_irq(IrqType::Vring));
+
+        // Out of range number of queue pairs.
+        let ack = send_ctrl_command(&mut net, &ctrlq, &mem, &[mq, vq_pairs_set, 3, 0]);
+        assert_eq!(ack, VIRTIO_NET_ERR as u8);
+        assert_eq!(net.active_queue_pairs, 2);
+
+        // Unsupported command.
+        let ack = send_ctrl_command(&mut net, &ctrlq, &mem, &[0xff, 0, 0, 0]);
+        assert_eq!(ack, VIRTIO_NET_ERR as u8);
+
+        let ack = send_ctrl_command(&mut net, &ctrlq, &mem, &[mq, vq_pairs_set, 1, 0]);
+        assert_eq!(ack, VIRTIO_NET_OK as u8);
+        assert_eq!(net.active_queue_pairs, 1);
+
+        // The driver acknowledges the announcement of a new MAC.
+        net.acked_features = net.avail_features;
+        net.set_guest_mac(MacAddr::parse_str("11:22:33:44:55:66").unwrap())
+            .unwrap();
+        assert_ne!(net.config_space.status & VIRTIO_NET_S_ANNOUNCE as u16, 0);
+        let announce = VIRTIO_NET_CTRL_ANNOUNCE as u8;
+        let announce_ack = VIRTIO_NET_CTRL_ANNOUNCE_ACK as u8;
+        let ack = send_ctrl_command(&mut net, &ctrlq, &mem, &[announce, announce_ack]);
+        assert_eq!(ack, VIRTIO_NET_OK as u8);
+        assert_eq!(net.config_space.status & VIRTIO_NET_S_ANNOUNCE as u16, 0);
+    }
+
+    #[test]
+    fn test_link_state_and_mac_update() {
+        let mut net = default_net();
+        let mem = default_guest_memory();
+        assert!(net.link_up());
+
+        // The driver is not notified before activation.
+        net.set_link_up(false).unwrap();
+        assert!(!net.link_up());
+        assert!(!net.irq_trigger.has_pending_irq(IrqType::Config));
+
+        // The driver reads the MAC when it is probed.
+        let mac = MacAddr::parse_str("11:22:33:44:55:66").unwrap();
+        net.set_guest_mac(mac).unwrap();
+        assert_eq!(net.guest_mac(), Some(&mac));
+        let mut config_mac = [0u8; MAC_ADDR_LEN];
+        net.read_config(0, &mut config_mac);
+        assert_eq!(config_mac, mac.get_bytes());
+        assert!(!net.irq_trigger.has_pending_irq(IrqType::Config));
+
+        net.activate(mem).unwrap();
+        net.set_link_up(true).unwrap();
+        assert!(net.link_up());
+        assert!(net.irq_trigger.has_pending_irq(IrqType::Config));
+        net.irq_trigger.irq_status.store(0, Ordering::SeqCst);
+
+        // A single queue pair device doesn't offer announcements, so the driver can't pick
+        // up a new MAC once activated.
+        let new_mac = MacAddr::parse_str("11:22:33:44:55:77").unwrap();
+        assert_eq!(
+            format!("{:?}", net.set_guest_mac(new_mac)),
+            "Err(MacUpdateNotAnnounced)"
+        );
+        assert_eq!(net.guest_mac(), Some(&mac));
+        assert!(!net.irq_trigger.has_pending_irq(IrqType::Config));
+    }
 }
@@ -7,28 +7,86 @@ use event_manager::{EventOps, Events, MutEventSubscriber};
 use logger::{debug, error, warn, IncMetric, METRICS};
 use utils::epoll::EventSet;
 
-use crate::virtio::net::device::Net;
-use crate::virtio::{VirtioDevice, RX_INDEX, TX_INDEX};
+use crate::virtio::net::device::{rx_queue_index, tx_queue_index, Net};
+use crate::virtio::{VirtioDevice, RX_INDEX};
 
 impl Net {
     fn register_runtime_events(&self, ops: &mut EventOps) {
-        if let Err(e) = ops.add(Events::new(&self.queue_evts[RX_INDEX], EventSet::IN)) {
-            error!("Failed to register rx queue event: {}", e);
+        if let Some(vhost_net) = self.vhost_net.as_ref() {
+            // The queues and the tap are serviced by the vhost-net backend, which only
+            // notifies the used buffers.
+            for call_evt in vhost_net.call_evts.iter() {
+                if let Err(e) = ops.add(Events::new(call_evt, EventSet::IN)) {
+                    error!("Failed to register vhost-net call event: {}", e);
+                }
+            }
+            return;
         }
-        if let Err(e) = ops.add(Events::new(&self.queue_evts[TX_INDEX], EventSet::IN)) {
-            error!("Failed to register tx queue event: {}", e);
+
+        for (pair, queue_pair) in self.queue_pairs.iter().enumerate() {
+            if let Err(e) = ops.add(Events::new(
+                &self.queue_evts[rx_queue_index(pair)],
+                EventSet::IN,
+            )) {
+                error!("Failed to register rx queue event: {}", e);
+            }
+            if let Err(e) = ops.add(Events::new(
+                &self.queue_evts[tx_queue_index(pair)],
+                EventSet::IN,
+            )) {
+                error!("Failed to register tx queue event: {}", e);
+            }
+            if let Err(e) = ops.add(Events::new(&queue_pair.rx_rate_limiter, EventSet::IN)) {
+                error!("Failed to register rx queue event: {}", e);
+            }
+            if let Err(e) = ops.add(Events::new(&queue_pair.tx_rate_limiter, EventSet::IN)) {
+                error!("Failed to register tx queue event: {}", e);
+            }
         }
-        if let Err(e) = ops.add(Events::new(&self.rx_rate_limiter, EventSet::IN)) {
-            error!("Failed to register rx queue event: {}", e);
+        if self.num_queue_pairs() > 1 {
+            if let Err(e) = ops.add(Events::new(
+                &self.queue_evts[self.queues.len() - 1],
+                EventSet::IN,
+            )) {
+                error!("Failed to register ctrl queue event: {}", e);
+            }
         }
-        if let Err(e) = ops.add(Events::new(&self.tx_rate_limiter, EventSet::IN)) {
-            error!("Failed to register tx queue event: {}", e);
+        self.register_tap_events(ops, 0);
+    }
+
+    // Registers the taps of the active queue pairs starting with `first_pair`. The taps of
+    // the inactive queue pairs are detached, so they aren't monitored.
+    fn register_tap_events(&self, ops: &mut EventOps, first_pair: u16) {
+        for queue_pair in &self.queue_pairs[first_pair as usize..self.active_queue_pairs as usize] {
+            if let Err(e) = ops.add(Events::new(
+                &queue_pair.tap,
+                EventSet::IN | EventSet::EDGE_TRIGGERED,
+            )) {
+                error!("Failed to register tap event: {}", e);
+            }
         }
-        if let Err(e) = ops.add(Events::new(
-            &self.tap,
-            EventSet::IN | EventSet::EDGE_TRIGGERED,
-        )) {
-            error!("Failed to register tap event: {}", e);
+    }
+
+    // Unregisters the taps of the inactive queue pairs, up to `last_pair`.
+    fn unregister_tap_events(&self, ops: &mut EventOps, last_pair: u16) {
+        for queue_pair in &self.queue_pairs[self.active_queue_pairs as usize..last_pair as usize] {
+            if let Err(e) = ops.remove(Events::new(
+                &queue_pair.tap,
+                EventSet::IN | EventSet::EDGE_TRIGGERED,
+            )) {
+                error!("Failed to un-register tap event: {}", e);
+            }
+        }
+    }
+
+    fn process_ctrl_event(&mut self, ops: &mut EventOps) {
+        let active_queue_pairs = self.active_queue_pairs;
+        self.process_ctrl_queue_event();
+        // The number of active queue pairs may have been changed by the driver.
+        if self.active_queue_pairs > active_queue_pairs {
+            self.register_tap_events(ops, active_queue_pairs);
+        } else {
+            self.unregister_tap_events(ops, active_queue_pairs);
         }
     }
 
@@ -67,25 +125,68 @@ impl MutEventSubscriber for Net {
         }
 
         if self.is_activated() {
-            let virtq_rx_ev_fd = self.queue_evts[RX_INDEX].as_raw_fd();
-            let virtq_tx_ev_fd = self.queue_evts[TX_INDEX].as_raw_fd();
-            let rx_rate_limiter_fd = self.rx_rate_limiter.as_raw_fd();
-            let tx_rate_limiter_fd = self.tx_rate_limiter.as_raw_fd();
-            let tap_fd = self.tap.as_raw_fd();
-            let activate_fd = self.activate_evt.as_raw_fd();
-
-            // Looks better than C style if/else if/else.
-            match source {
-                _ if source == virtq_rx_ev_fd => self.process_rx_queue_event(),
-                _ if source == tap_fd => self.process_tap_rx_event(),
-                _ if source == virtq_tx_ev_fd => self.process_tx_queue_event(),
-                _ if source == rx_rate_limiter_fd => self.process_rx_rate_limiter_event(),
-                _ if source == tx_rate_limiter_fd => self.process_tx_rate_limiter_event(),
-                _ if activate_fd == source => self.process_activate_event(ops),
-                _ => {
-                    warn!("Net: Spurious event received: {:?}", source);
-                    METRICS.net.event_fails.inc();
+            let ctrl_queue_index = if self.num_queue_pairs() > 1 {
+                Some(self.queues.len() - 1)
+            } else {
+                None
+            };
+            if let Some(queue_index) = self.vhost_net.as_ref().and_then(|vhost_net| {
+                vhost_net
+                    .call_evts
+                    .iter()
+                    .position(|call_evt| call_evt.as_raw_fd() == source)
+            }) {
+                self.process_vhost_call_event(queue_index);
+                return;
+            }
+
+            if let Some(queue_index) = self
+                .queue_evts
+                .iter()
+                .position(|queue_evt| queue_evt.as_raw_fd() == source)
+            {
+                match queue_index {
+                    _ if Some(queue_index) == ctrl_queue_index => self.process_ctrl_event(ops),
+                    _ if queue_index % 2 == RX_INDEX => {
+                        self.process_rx_queue_event(queue_index / 2)
+                    }
+                    _ => self.process_tx_queue_event(queue_index / 2),
                 }
+                return;
+            }
+
+            if let Some(pair) = self
+                .queue_pairs
+                .iter()
+                .position(|queue_pair| queue_pair.tap.as_raw_fd() == source)
+            {
+                self.process_tap_rx_event(pair);
+                return;
+            }
+
+            if let Some(pair) = self
+                .queue_pairs
+                .iter()
+                .position(|queue_pair| queue_pair.rx_rate_limiter.as_raw_fd() == source)
+            {
+                self.process_rx_rate_limiter_event(pair);
+                return;
+            }
+
+            if let Some(pair) = self
+                .queue_pairs
+                .iter()
+                .position(|queue_pair| queue_pair.tx_rate_limiter.as_raw_fd() == source)
+            {
+                self.process_tx_rate_limiter_event(pair);
+                return;
+            }
+
+            if self.activate_evt.as_raw_fd() == source {
+                self.process_activate_event(ops);
+            } else {
+                warn!("Net: Spurious event received: {:?}", source);
+                METRICS.net.event_fails.inc();
             }
         } else {
             warn!(
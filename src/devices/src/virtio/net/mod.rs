@@ -5,11 +5,12 @@ use std::{io, result};
 
 pub const MAX_BUFFER_SIZE: usize = 65562;
 pub const QUEUE_SIZE: u16 = 256;
-pub const NUM_QUEUES: usize = 2;
-pub const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE; NUM_QUEUES];
-// The index of the rx queue from Net device queues/queues_evts vector.
+/// Maximum number of RX/TX queue pairs of a network device, bounded by the number of
+/// queue pairs for which metrics are reported.
+pub const MAX_QUEUE_PAIRS: u16 = logger::MAX_NET_QUEUE_PAIRS as u16;
+// The index of the rx queue of the first queue pair from Net device queues/queues_evts vector.
 pub const RX_INDEX: usize = 0;
-// The index of the tx queue from Net device queues/queues_evts vector.
+// The index of the tx queue of the first queue pair from Net device queues/queues_evts vector.
 pub const TX_INDEX: usize = 1;
 
 pub mod device;
@@ -18,10 +19,12 @@ pub mod persist;
 mod tap;
 #[cfg(test)]
 pub mod test_utils;
+mod vhost;
 
 pub use self::device::Net;
 pub use self::event_handler::*;
 pub use tap::Error as TapError;
+pub use vhost::Error as VhostNetError;
 
 #[derive(Debug)]
 pub enum Error {
@@ -33,12 +36,18 @@ pub enum Error {
     TapSetVnetHdrSize(TapError),
     /// Enabling tap interface failed.
     TapEnable(TapError),
+    /// Attaching or detaching a tap queue failed.
+    TapSetQueue(TapError),
     /// EventFd error.
     EventFd(io::Error),
     /// IO error.
     IO(io::Error),
     /// The VNET header is missing from the frame.
     VnetHeaderMissing,
+    /// The number of queue pairs is out of range.
+    InvalidNumQueuePairs(u16),
+    /// Setting up the vhost-net backend failed.
+    VhostNet(VhostNetError),
 }
 
 pub type Result<T> = result::Result<T, Error>;
@@ -9,25 +9,52 @@ use std::sync::Arc;
 
 use mmds::{ns::MmdsNetworkStack, persist::MmdsNetworkStackState};
 use rate_limiter::{persist::RateLimiterState, RateLimiter};
+use serde::Serialize;
 use snapshot::Persist;
 use utils::net::mac::{MacAddr, MAC_ADDR_LEN};
-use versionize::{VersionMap, Versionize, VersionizeResult};
+use versionize::{VersionMap, Versionize, VersionizeError, VersionizeResult};
 use versionize_derive::Versionize;
+use virtio_gen::virtio_net::VIRTIO_NET_S_LINK_UP;
 use vm_memory::GuestMemoryMmap;
 
-use super::device::{ConfigSpace, Net};
-use super::{NUM_QUEUES, QUEUE_SIZE};
+use super::device::Net;
+use super::QUEUE_SIZE;
 
 use crate::virtio::persist::{Error as VirtioStateError, VirtioDeviceState};
 use crate::virtio::{DeviceState, TYPE_NET};
 
-#[derive(Clone, Versionize)]
+#[derive(Clone, Debug, Serialize, Versionize)]
 // NOTICE: Any changes to this structure require a snapshot version bump.
 pub struct NetConfigSpaceState {
     guest_mac: [u8; MAC_ADDR_LEN],
+    #[version(start = 2, ser_fn = "status_ser", default_fn = "default_status")]
+    status: u16,
 }
 
-#[derive(Clone, Versionize)]
+impl NetConfigSpaceState {
+    fn status_ser(&mut self, target_version: u16) -> VersionizeResult<()> {
+        if target_version < 2 && self.status & VIRTIO_NET_S_LINK_UP as u16 == 0 {
+            return Err(VersionizeError::Semantic(
+                "Target version does not support net devices with the link down.".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn default_status(_source_version: u16) -> u16 {
+        VIRTIO_NET_S_LINK_UP as u16
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct NetQueuePairState {
+    rx_rate_limiter_state: RateLimiterState,
+    tx_rate_limiter_state: RateLimiterState,
+}
+
+#[derive(Clone, Debug, Serialize, Versionize)]
 // NOTICE: Any changes to this structure require a snapshot version bump.
 pub struct NetState {
     id: String,
@@ -37,10 +64,41 @@ pub struct NetState {
     mmds_ns: Option<MmdsNetworkStackState>,
     config_space: NetConfigSpaceState,
     virtio_state: VirtioDeviceState,
+    // The rate limiters of the first queue pair are saved above.
+    #[version(
+        start = 2,
+        ser_fn = "net_additional_queue_pairs_ser",
+        default_fn = "default_additional_queue_pairs"
+    )]
+    additional_queue_pairs: Vec<NetQueuePairState>,
+    #[version(start = 2, default_fn = "default_active_queue_pairs")]
+    active_queue_pairs: u16,
+}
+
+impl NetState {
+    fn net_additional_queue_pairs_ser(&mut self, target_version: u16) -> VersionizeResult<()> {
+        if target_version < 2 && !self.additional_queue_pairs.is_empty() {
+            return Err(VersionizeError::Semantic(
+                "Target version does not support multi-queue net devices.".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn default_additional_queue_pairs(_source_version: u16) -> Vec<NetQueuePairState> {
+        Vec::new()
+    }
+
+    fn default_active_queue_pairs(_source_version: u16) -> u16 {
+        1
+    }
 }
 
 pub struct NetConstructorArgs {
     pub mem: GuestMemoryMmap,
+    /// TAP device to attach the restored device to, instead of the one saved in the state.
+    pub tap_if_name: Option<String>,
 }
 
 #[derive(Debug)]
@@ -59,13 +117,22 @@ impl Persist<'_> for Net {
         NetState {
             id: self.id().clone(),
             tap_if_name: self.iface_name(),
-            rx_rate_limiter_state: self.rx_rate_limiter.save(),
-            tx_rate_limiter_state: self.tx_rate_limiter.save(),
+            rx_rate_limiter_state: self.queue_pairs[0].rx_rate_limiter.save(),
+            tx_rate_limiter_state: self.queue_pairs[0].tx_rate_limiter.save(),
             mmds_ns: self.mmds_ns.as_ref().map(|mmds| mmds.save()),
             config_space: NetConfigSpaceState {
                 guest_mac: self.config_space.guest_mac,
+                status: self.config_space.status,
             },
             virtio_state: VirtioDeviceState::from_device(self),
+            additional_queue_pairs: self.queue_pairs[1..]
+                .iter()
+                .map(|queue_pair| NetQueuePairState {
+                    rx_rate_limiter_state: queue_pair.rx_rate_limiter.save(),
+                    tx_rate_limiter_state: queue_pair.tx_rate_limiter.save(),
+                })
+                .collect(),
+            active_queue_pairs: self.active_queue_pairs,
         }
     }
 
@@ -78,33 +145,54 @@ impl Persist<'_> for Net {
             .map_err(Error::CreateRateLimiter)?;
         let tx_rate_limiter = RateLimiter::restore((), &state.tx_rate_limiter_state)
             .map_err(Error::CreateRateLimiter)?;
+        let tap_if_name = constructor_args
+            .tap_if_name
+            .unwrap_or_else(|| state.tap_if_name.clone());
         let mut net = Net::new_with_tap(
             state.id.clone(),
-            state.tap_if_name.clone(),
+            tap_if_name,
             None,
             rx_rate_limiter,
             tx_rate_limiter,
             state.mmds_ns.is_some(),
+            1 + state.additional_queue_pairs.len() as u16,
         )
         .map_err(Error::CreateNet)?;
 
+        for (queue_pair, queue_pair_state) in net.queue_pairs[1..]
+            .iter_mut()
+            .zip(state.additional_queue_pairs.iter())
+        {
+            queue_pair.rx_rate_limiter =
+                RateLimiter::restore((), &queue_pair_state.rx_rate_limiter_state)
+                    .map_err(Error::CreateRateLimiter)?;
+            queue_pair.tx_rate_limiter =
+                RateLimiter::restore((), &queue_pair_state.tx_rate_limiter_state)
+                    .map_err(Error::CreateRateLimiter)?;
+        }
+        net.set_active_queue_pairs(state.active_queue_pairs)
+            .map_err(Error::CreateNet)?;
+
         // Safe to unwrap because MmdsNetworkStack::restore() cannot fail.
         net.mmds_ns = state
             .mmds_ns
             .as_ref()
             .map(|mmds_state| MmdsNetworkStack::restore((), &mmds_state).unwrap());
 
+        // The device has a control queue after the RX/TX queues when it has more than
+        // one queue pair.
+        let num_queue_pairs = net.num_queue_pairs() as usize;
+        let num_queues = 2 * num_queue_pairs + usize::from(num_queue_pairs > 1);
         net.queues = state
             .virtio_state
-            .build_queues_checked(&constructor_args.mem, TYPE_NET, NUM_QUEUES, QUEUE_SIZE)
+            .build_queues_checked(&constructor_args.mem, TYPE_NET, num_queues, QUEUE_SIZE)
             .map_err(Error::VirtioState)?;
         net.irq_trigger.irq_status =
             Arc::new(AtomicUsize::new(state.virtio_state.interrupt_status));
         net.avail_features = state.virtio_state.avail_features;
         net.acked_features = state.virtio_state.acked_features;
-        net.config_space = ConfigSpace {
-            guest_mac: state.config_space.guest_mac,
-        };
+        net.config_space.guest_mac = state.config_space.guest_mac;
+        net.config_space.status = state.config_space.status;
 
         net.guest_mac = Some(MacAddr::from_bytes_unchecked(
             &state.config_space.guest_mac[..MAC_ADDR_LEN],
@@ -123,7 +211,9 @@ mod tests {
     use super::*;
     use crate::virtio::device::VirtioDevice;
 
-    use crate::virtio::net::test_utils::{default_guest_memory, default_net};
+    use crate::virtio::net::test_utils::{
+        default_guest_memory, default_net, default_net_with_queue_pairs,
+    };
     use std::sync::atomic::Ordering;
 
     #[test]
@@ -155,7 +245,10 @@ mod tests {
         // Deserialize and restore the net device.
         {
             let restored_net = Net::restore(
-                NetConstructorArgs { mem: guest_mem },
+                NetConstructorArgs {
+                    mem: guest_mem,
+                    tap_if_name: None,
+                },
                 &NetState::deserialize(&mut mem.as_slice(), &version_map, 1).unwrap(),
             )
             .unwrap();
@@ -174,8 +267,111 @@ mod tests {
             assert_eq!(&restored_net.id, &id);
             assert_eq!(&restored_net.iface_name(), &tap_if_name);
             assert_eq!(restored_net.mmds_ns.is_some(), allow_mmds_requests);
-            assert_eq!(restored_net.rx_rate_limiter, RateLimiter::default());
-            assert_eq!(restored_net.tx_rate_limiter, RateLimiter::default());
+            assert_eq!(restored_net.num_queue_pairs(), 1);
+            assert_eq!(
+                restored_net.queue_pairs[0].rx_rate_limiter,
+                RateLimiter::default()
+            );
+            assert_eq!(
+                restored_net.queue_pairs[0].tx_rate_limiter,
+                RateLimiter::default()
+            );
         }
     }
+
+    #[test]
+    fn test_default_queue_pairs() {
+        assert!(NetState::default_additional_queue_pairs(1).is_empty());
+        assert_eq!(NetState::default_active_queue_pairs(1), 1);
+    }
+
+    #[test]
+    fn test_multi_queue_persistence() {
+        let guest_mem = default_guest_memory();
+        let mut mem = vec![0; 4096];
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .set_type_version(NetState::type_id(), 2);
+
+        let mut net = default_net_with_queue_pairs(3);
+        net.queue_pairs[2].tx_rate_limiter = RateLimiter::new(0, 0, 0, 10, 0, 100).unwrap();
+        net.set_active_queue_pairs(2).unwrap();
+        let tap_if_name = net.iface_name();
+        let avail_features = net.avail_features();
+
+        // The first version of the net state doesn't describe multiple queue pairs.
+        assert!(<Net as Persist>::save(&net)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 1)
+            .is_err());
+
+        <Net as Persist>::save(&net)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 2)
+            .unwrap();
+        // The tap queues are released so that they can be opened again.
+        drop(net);
+
+        let restored_net = Net::restore(
+            NetConstructorArgs {
+                mem: guest_mem,
+                tap_if_name: None,
+            },
+            &NetState::deserialize(&mut mem.as_slice(), &version_map, 2).unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(restored_net.iface_name(), tap_if_name);
+        assert_eq!(restored_net.num_queue_pairs(), 3);
+        assert_eq!(restored_net.active_queue_pairs, 2);
+        assert_eq!(restored_net.queues().len(), 7);
+        assert_eq!(restored_net.avail_features(), avail_features);
+        assert!(restored_net.queue_pairs[1].tx_rate_limiter.ops().is_none());
+        assert_eq!(
+            restored_net.queue_pairs[2]
+                .tx_rate_limiter
+                .ops()
+                .unwrap()
+                .capacity(),
+            10
+        );
+    }
+
+    #[test]
+    fn test_link_state_persistence() {
+        assert_eq!(
+            NetConfigSpaceState::default_status(1),
+            VIRTIO_NET_S_LINK_UP as u16
+        );
+
+        let guest_mem = default_guest_memory();
+        let mut mem = vec![0; 4096];
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .set_type_version(NetState::type_id(), 2)
+            .set_type_version(NetConfigSpaceState::type_id(), 2);
+
+        let mut net = default_net();
+        net.set_link_up(false).unwrap();
+
+        // The first version of the net state doesn't describe the link state.
+        assert!(<Net as Persist>::save(&net)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 1)
+            .is_err());
+
+        <Net as Persist>::save(&net)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 2)
+            .unwrap();
+        drop(net);
+
+        let restored_net = Net::restore(
+            NetConstructorArgs {
+                mem: guest_mem,
+                tap_if_name: None,
+            },
+            &NetState::deserialize(&mut mem.as_slice(), &version_map, 2).unwrap(),
+        )
+        .unwrap();
+        assert!(!restored_net.link_up());
+    }
 }
@@ -36,6 +36,7 @@ const TUNTAP: ::std::os::raw::c_uint = 84;
 ioctl_iow_nr!(TUNSETIFF, TUNTAP, 202, ::std::os::raw::c_int);
 ioctl_iow_nr!(TUNSETOFFLOAD, TUNTAP, 208, ::std::os::raw::c_uint);
 ioctl_iow_nr!(TUNSETVNETHDRSZ, TUNTAP, 216, ::std::os::raw::c_int);
+ioctl_iow_nr!(TUNSETQUEUE, TUNTAP, 217, ::std::os::raw::c_int);
 
 /// Handle for a network tap interface.
 ///
@@ -105,8 +106,40 @@ impl Tap {
     ///
     /// * `if_name` - the name of the interface.
     pub fn open_named(if_name: &str) -> Result<Tap> {
-        let terminated_if_name = build_terminated_if_name(if_name)?;
+        Self::open_with_flags(
+            &build_terminated_if_name(if_name)?,
+            net_gen::IFF_TAP | net_gen::IFF_NO_PI | net_gen::IFF_VNET_HDR,
+        )
+    }
+
+    /// Create a multi-queue TUN/TAP device given the interface name, returning
+    /// one handle per queue.
+    /// # Arguments
+    ///
+    /// * `if_name` - the name of the interface.
+    /// * `num_queues` - the number of queues to open.
+    pub fn open_named_multi_queue(if_name: &str, num_queues: usize) -> Result<Vec<Tap>> {
+        let flags = net_gen::IFF_TAP
+            | net_gen::IFF_NO_PI
+            | net_gen::IFF_VNET_HDR
+            | net_gen::IFF_MULTI_QUEUE;
+        let mut terminated_if_name = build_terminated_if_name(if_name)?;
+        let mut taps = Vec::with_capacity(num_queues);
+        for _ in 0..num_queues {
+            let tap = Self::open_with_flags(&terminated_if_name, flags)?;
+            // Subsequent queues are attached to the interface created by the first one, which
+            // matters when the kernel picks the interface name.
+            terminated_if_name = tap.if_name;
+            taps.push(tap);
+        }
 
+        Ok(taps)
+    }
+
+    fn open_with_flags(
+        terminated_if_name: &[u8; IFACE_NAME_MAX_LEN],
+        flags: c_uint,
+    ) -> Result<Tap> {
         let fd = unsafe {
             // Open calls are safe because we give a constant null-terminated
             // string and verify the result.
@@ -122,8 +155,8 @@ impl Tap {
         let tuntap = unsafe { File::from_raw_fd(fd) };
 
         let ifreq = IfReqBuilder::new()
-            .if_name(&terminated_if_name)
-            .flags((net_gen::IFF_TAP | net_gen::IFF_NO_PI | net_gen::IFF_VNET_HDR) as i16)
+            .if_name(terminated_if_name)
+            .flags(flags as i16)
             .execute(&tuntap, TUNSETIFF())?;
 
         // Safe since only the name is accessed, and it's cloned out.
@@ -153,6 +186,20 @@ impl Tap {
         Ok(())
     }
 
+    /// Attach or detach the queue of a multi-queue tap interface.
+    pub fn set_queue_enabled(&self, enabled: bool) -> Result<()> {
+        let flags = if enabled {
+            net_gen::IFF_ATTACH_QUEUE
+        } else {
+            net_gen::IFF_DETACH_QUEUE
+        };
+        IfReqBuilder::new()
+            .flags(flags as i16)
+            .execute(&self.tap_file, TUNSETQUEUE())?;
+
+        Ok(())
+    }
+
     /// Set the size of the vnet hdr.
     pub fn set_vnet_hdr_size(&self, size: c_int) -> Result<()> {
         // ioctl is safe. Called with a valid tap fd, and we check the return.
@@ -252,6 +299,28 @@ pub mod tests {
         assert!(faulty_tap.set_offload(0).is_err());
     }
 
+    #[test]
+    fn test_tap_multi_queue() {
+        let taps = Tap::open_named_multi_queue("", 3).unwrap();
+        assert_eq!(taps.len(), 3);
+        // All the queues belong to the same interface.
+        assert!(taps.iter().all(|tap| tap.if_name == taps[0].if_name));
+
+        taps[1].set_queue_enabled(false).unwrap();
+        taps[1].set_queue_enabled(true).unwrap();
+
+        // 16 characters - too long.
+        match Tap::open_named_multi_queue("a123456789abcdef", 2) {
+            Err(Error::InvalidIfname) => (),
+            _ => panic!("Expected Error::InvalidIfname"),
+        };
+
+        // A single queue tap can't be reopened as a multi-queue one.
+        let tap = Tap::open_named("singlequeuetap").unwrap();
+        Tap::open_named_multi_queue(tap.if_name_as_str(), 2).unwrap_err();
+        assert!(tap.set_queue_enabled(false).is_err());
+    }
+
     #[test]
     fn test_raw_fd() {
         let tap = Tap::open_named("").unwrap();
@@ -26,6 +26,10 @@ pub type Result<T> = ::std::result::Result<T, Error>;
 static NEXT_INDEX: AtomicUsize = AtomicUsize::new(1);
 
 pub fn default_net() -> Net {
+    default_net_with_queue_pairs(1)
+}
+
+pub fn default_net_with_queue_pairs(num_queue_pairs: u16) -> Net {
     let next_tap = NEXT_INDEX.fetch_add(1, Ordering::SeqCst);
     let tap_dev_name = format!("net-device{}", next_tap);
 
@@ -38,9 +42,10 @@ pub fn default_net() -> Net {
         RateLimiter::default(),
         RateLimiter::default(),
         true,
+        num_queue_pairs,
     )
     .unwrap();
-    enable(&net.tap);
+    enable(&net.queue_pairs[0].tap);
 
     net
 }
@@ -233,7 +238,7 @@ pub fn enable(tap: &Tap) {
 #[cfg(test)]
 pub(crate) fn inject_tap_tx_frame(net: &Net, len: usize) -> Vec<u8> {
     assert!(len >= vnet_hdr_len());
-    let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&net.tap));
+    let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&net.queue_pairs[0].tap));
     let mut frame = utils::rand::rand_alphanumerics(len - vnet_hdr_len())
         .as_bytes()
         .to_vec();
@@ -357,11 +362,11 @@ pub mod test {
 
         pub fn simulate_event(&mut self, event: NetEvent) {
             match event {
-                NetEvent::RxQueue => self.net().process_rx_queue_event(),
-                NetEvent::RxRateLimiter => self.net().process_rx_rate_limiter_event(),
-                NetEvent::Tap => self.net().process_tap_rx_event(),
-                NetEvent::TxQueue => self.net().process_tx_queue_event(),
-                NetEvent::TxRateLimiter => self.net().process_tx_rate_limiter_event(),
+                NetEvent::RxQueue => self.net().process_rx_queue_event(0),
+                NetEvent::RxRateLimiter => self.net().process_rx_rate_limiter_event(0),
+                NetEvent::Tap => self.net().process_tap_rx_event(0),
+                NetEvent::TxQueue => self.net().process_tx_queue_event(0),
+                NetEvent::TxRateLimiter => self.net().process_tx_rate_limiter_event(0),
             };
         }
 
@@ -421,7 +426,7 @@ pub mod test {
                 self.event_manager.run_with_timeout(100).unwrap()
             );
             // Check that the frame has been deferred.
-            assert!(self.net().rx_deferred_frame);
+            assert!(self.net().queue_pairs[0].rx_deferred_frame);
             // Check that the descriptor chain has been discarded.
             assert_eq!(self.rxq.used.idx.get(), used_idx + 1);
             assert!(&self.net().irq_trigger.has_pending_irq(IrqType::Vring));
@@ -0,0 +1,304 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Handle for the in-kernel vhost-net data path.
+//!
+//! When a network device is backed by vhost-net, the kernel moves the frames between the
+//! guest virtqueues and the TAP interface. The device model only configures the backend on
+//! activation and forwards the used buffer notifications to the guest.
+
+use std::fs::{File, OpenOptions};
+use std::io::Error as IoError;
+use std::os::raw::{c_int, c_uint};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use utils::eventfd::EventFd;
+use utils::ioctl::{ioctl, ioctl_with_mut_ref, ioctl_with_ref};
+use utils::{ioctl_expr, ioctl_io_nr, ioctl_ioc_nr, ioctl_ior_nr, ioctl_iow_nr};
+use virtio_gen::virtio_net::VIRTIO_F_VERSION_1;
+use vm_memory::{Address, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
+
+use crate::virtio::Queue;
+
+// Path of the vhost-net control device.
+const VHOST_NET_PATH: &str = "/dev/vhost-net";
+// Maximum number of guest memory regions handed to the vhost-net backend.
+const MAX_MEMORY_REGIONS: usize = 8;
+
+/// List of errors the vhost-net backend can throw.
+#[derive(Debug)]
+pub enum Error {
+    /// EventFd error.
+    EventFd(IoError),
+    /// A queue is not correctly set up by the driver.
+    InvalidQueue(usize),
+    /// ioctl failed.
+    IoctlError(IoError),
+    /// The vhost-net backend doesn't support the features required by the device.
+    MissingFeatures(u64),
+    /// Couldn't open /dev/vhost-net.
+    OpenVhostNet(IoError),
+    /// The guest memory has more regions than what is handed to the backend.
+    TooManyMemoryRegions(usize),
+}
+
+pub type Result<T> = ::std::result::Result<T, Error>;
+
+const VHOST: c_uint = 0xAF;
+ioctl_ior_nr!(VHOST_GET_FEATURES, VHOST, 0x00, u64);
+ioctl_iow_nr!(VHOST_SET_FEATURES, VHOST, 0x00, u64);
+ioctl_io_nr!(VHOST_SET_OWNER, VHOST, 0x01);
+ioctl_iow_nr!(VHOST_SET_MEM_TABLE, VHOST, 0x03, VhostMemory);
+ioctl_iow_nr!(VHOST_SET_VRING_NUM, VHOST, 0x10, VhostVringState);
+ioctl_iow_nr!(VHOST_SET_VRING_ADDR, VHOST, 0x11, VhostVringAddr);
+ioctl_iow_nr!(VHOST_SET_VRING_BASE, VHOST, 0x12, VhostVringState);
+ioctl_iow_nr!(VHOST_SET_VRING_KICK, VHOST, 0x20, VhostVringFile);
+ioctl_iow_nr!(VHOST_SET_VRING_CALL, VHOST, 0x21, VhostVringFile);
+ioctl_iow_nr!(VHOST_NET_SET_BACKEND, VHOST, 0x30, VhostVringFile);
+
+// The structures below mirror the ones defined in the Linux UAPI:
+// https://elixir.bootlin.com/linux/v4.14/source/include/uapi/linux/vhost.h
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct VhostMemoryRegion {
+    guest_phys_addr: u64,
+    memory_size: u64,
+    userspace_addr: u64,
+    flags_padding: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct VhostMemory {
+    nregions: u32,
+    padding: u32,
+}
+
+// `struct vhost_memory` ends with a flexible array of regions.
+#[repr(C)]
+#[derive(Default)]
+struct VhostMemoryTable {
+    header: VhostMemory,
+    regions: [VhostMemoryRegion; MAX_MEMORY_REGIONS],
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct VhostVringState {
+    index: c_uint,
+    num: c_uint,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct VhostVringAddr {
+    index: c_uint,
+    flags: c_uint,
+    desc_user_addr: u64,
+    used_user_addr: u64,
+    avail_user_addr: u64,
+    log_guest_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct VhostVringFile {
+    index: c_uint,
+    fd: c_int,
+}
+
+/// Handle for a vhost-net backend instance.
+pub struct VhostNet {
+    vhost_file: File,
+    features: u64,
+    /// Eventfds signaled by the backend when it adds used buffers to a queue.
+    pub(crate) call_evts: Vec<EventFd>,
+}
+
+impl VhostNet {
+    /// Opens a vhost-net backend instance for a device with `num_queues` queues.
+    pub fn new(num_queues: usize) -> Result<VhostNet> {
+        let vhost_file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK | libc::O_CLOEXEC)
+            .open(VHOST_NET_PATH)
+            .map_err(Error::OpenVhostNet)?;
+
+        // ioctl is safe. Called with a valid vhost-net fd, and we check the return.
+        let ret = unsafe { ioctl(&vhost_file, VHOST_SET_OWNER()) };
+        if ret < 0 {
+            return Err(Error::IoctlError(IoError::last_os_error()));
+        }
+
+        let mut features = 0u64;
+        // ioctl is safe. Called with a valid vhost-net fd, and we check the return.
+        let ret = unsafe { ioctl_with_mut_ref(&vhost_file, VHOST_GET_FEATURES(), &mut features) };
+        if ret < 0 {
+            return Err(Error::IoctlError(IoError::last_os_error()));
+        }
+        if features & (1 << VIRTIO_F_VERSION_1) == 0 {
+            return Err(Error::MissingFeatures(1 << VIRTIO_F_VERSION_1));
+        }
+
+        let mut call_evts = Vec::with_capacity(num_queues);
+        for _ in 0..num_queues {
+            call_evts.push(EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd)?);
+        }
+
+        Ok(VhostNet {
+            vhost_file,
+            features,
+            call_evts,
+        })
+    }
+
+    /// Hands the queues of an activated device to the backend.
+    ///
+    /// # Arguments
+    ///
+    /// * `mem` - the guest memory, in which the queues live.
+    /// * `acked_features` - the features acked by the driver.
+    /// * `queues` - the device queues.
+    /// * `kick_evts` - the eventfds signaled when the driver notifies a queue.
+    /// * `tap_fd` - the TAP interface the frames are exchanged with.
+    pub fn activate(
+        &self,
+        mem: &GuestMemoryMmap,
+        acked_features: u64,
+        queues: &[Queue],
+        kick_evts: &[EventFd],
+        tap_fd: RawFd,
+    ) -> Result<()> {
+        let features = acked_features & self.features;
+        // ioctl is safe. Called with a valid vhost-net fd, and we check the return.
+        let ret = unsafe { ioctl_with_ref(&self.vhost_file, VHOST_SET_FEATURES(), &features) };
+        if ret < 0 {
+            return Err(Error::IoctlError(IoError::last_os_error()));
+        }
+
+        self.set_mem_table(mem)?;
+
+        for (index, queue) in queues.iter().enumerate() {
+            self.set_vring(index, queue, mem, &kick_evts[index])?;
+
+            let backend = VhostVringFile {
+                index: index as c_uint,
+                fd: tap_fd,
+            };
+            self.vring_ioctl(VHOST_NET_SET_BACKEND(), &backend)?;
+        }
+
+        Ok(())
+    }
+
+    fn set_mem_table(&self, mem: &GuestMemoryMmap) -> Result<()> {
+        let num_regions = mem.num_regions();
+        if num_regions > MAX_MEMORY_REGIONS {
+            return Err(Error::TooManyMemoryRegions(num_regions));
+        }
+
+        let mut table = VhostMemoryTable::default();
+        table.header.nregions = num_regions as u32;
+        let _: std::result::Result<(), ()> = mem.with_regions_mut(|index, region| {
+            table.regions[index] = VhostMemoryRegion {
+                guest_phys_addr: region.start_addr().raw_value(),
+                memory_size: region.len(),
+                // It's safe to unwrap because the guest address is valid.
+                userspace_addr: mem.get_host_address(region.start_addr()).unwrap() as u64,
+                flags_padding: 0,
+            };
+            Ok(())
+        });
+
+        // ioctl is safe. Called with a valid vhost-net fd, and we check the return.
+        let ret = unsafe { ioctl_with_ref(&self.vhost_file, VHOST_SET_MEM_TABLE(), &table) };
+        if ret < 0 {
+            return Err(Error::IoctlError(IoError::last_os_error()));
+        }
+
+        Ok(())
+    }
+
+    fn set_vring(
+        &self,
+        index: usize,
+        queue: &Queue,
+        mem: &GuestMemoryMmap,
+        kick_evt: &EventFd,
+    ) -> Result<()> {
+        if !queue.is_valid(mem) {
+            return Err(Error::InvalidQueue(index));
+        }
+
+        let num = VhostVringState {
+            index: index as c_uint,
+            num: c_uint::from(queue.actual_size()),
+        };
+        self.vring_ioctl(VHOST_SET_VRING_NUM(), &num)?;
+
+        let base = VhostVringState {
+            index: index as c_uint,
+            num: c_uint::from(queue.next_avail.0),
+        };
+        self.vring_ioctl(VHOST_SET_VRING_BASE(), &base)?;
+
+        // The queue was validated above, so the addresses are within the guest memory.
+        let addr = VhostVringAddr {
+            index: index as c_uint,
+            desc_user_addr: mem.get_host_address(queue.desc_table).unwrap() as u64,
+            used_user_addr: mem.get_host_address(queue.used_ring).unwrap() as u64,
+            avail_user_addr: mem.get_host_address(queue.avail_ring).unwrap() as u64,
+            ..Default::default()
+        };
+        self.vring_ioctl(VHOST_SET_VRING_ADDR(), &addr)?;
+
+        let kick = VhostVringFile {
+            index: index as c_uint,
+            fd: kick_evt.as_raw_fd(),
+        };
+        self.vring_ioctl(VHOST_SET_VRING_KICK(), &kick)?;
+
+        let call = VhostVringFile {
+            index: index as c_uint,
+            fd: self.call_evts[index].as_raw_fd(),
+        };
+        self.vring_ioctl(VHOST_SET_VRING_CALL(), &call)
+    }
+
+    fn vring_ioctl<T>(&self, request: u64, arg: &T) -> Result<()> {
+        // ioctl is safe. Called with a valid vhost-net fd and a structure matching the
+        // request, and we check the return.
+        let ret = unsafe { ioctl_with_ref(&self.vhost_file, request, arg) };
+        if ret < 0 {
+            return Err(Error::IoctlError(IoError::last_os_error()));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vhost_structs_layout() {
+        // The ioctl numbers encode the size of the UAPI structures.
+        assert_eq!(std::mem::size_of::<VhostMemory>(), 8);
+        assert_eq!(std::mem::size_of::<VhostMemoryRegion>(), 32);
+        assert_eq!(
+            std::mem::size_of::<VhostMemoryTable>(),
+            8 + 32 * MAX_MEMORY_REGIONS
+        );
+        assert_eq!(std::mem::size_of::<VhostVringState>(), 8);
+        assert_eq!(std::mem::size_of::<VhostVringAddr>(), 40);
+        assert_eq!(std::mem::size_of::<VhostVringFile>(), 8);
+
+        assert_eq!(VHOST_SET_OWNER(), 0xAF01);
+        assert_eq!(VHOST_GET_FEATURES(), 0x8008_AF00);
+        assert_eq!(VHOST_SET_VRING_ADDR(), 0x4028_AF11);
+        assert_eq!(VHOST_NET_SET_BACKEND(), 0x4008_AF30);
+    }
+}
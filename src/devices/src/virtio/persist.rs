@@ -6,6 +6,7 @@
 use super::device::*;
 use super::queue::*;
 use crate::virtio::MmioTransport;
+use serde::Serialize;
 use snapshot::Persist;
 use versionize::{VersionMap, Versionize, VersionizeResult};
 use versionize_derive::Versionize;
@@ -20,7 +21,7 @@ pub enum Error {
     InvalidInput,
 }
 
-#[derive(Clone, Debug, PartialEq, Versionize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Versionize)]
 // NOTICE: Any changes to this structure require a snapshot version bump.
 pub struct QueueState {
     /// The maximal size in elements offered by the device
@@ -81,7 +82,7 @@ impl Persist<'_> for Queue {
 }
 
 /// State of a VirtioDevice.
-#[derive(Clone, Debug, PartialEq, Versionize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Versionize)]
 // NOTICE: Any changes to this structure require a snapshot version bump.
 pub struct VirtioDeviceState {
     pub device_type: u32,
@@ -150,7 +151,7 @@ impl VirtioDeviceState {
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Versionize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Versionize)]
 // NOTICE: Any changes to this structure require a snapshot version bump.
 pub struct MmioTransportState {
     // The register where feature bits are stored.
@@ -202,6 +203,7 @@ mod tests {
     use crate::virtio::mmio::tests::DummyDevice;
     use crate::virtio::{net, Block, Net, Vsock, VsockUnixBackend};
 
+    use crate::virtio::block::io_engine::FileEngineType;
     use crate::virtio::block::test_utils::default_block_with_path;
     use crate::virtio::test_utils::default_mem;
     use utils::tempfile::TempFile;
@@ -375,7 +377,10 @@ mod tests {
         // Create backing file.
         let f = TempFile::new().unwrap();
         f.as_file().set_len(0x1000).unwrap();
-        let block = default_block_with_path(f.as_path().to_str().unwrap().to_string());
+        let block = default_block_with_path(
+            f.as_path().to_str().unwrap().to_string(),
+            FileEngineType::Sync,
+        );
         let block = Arc::new(Mutex::new(block));
         let mmio_transport = MmioTransport::new(mem.clone(), block.clone());
 
@@ -7,6 +7,7 @@ use std::sync::atomic::AtomicUsize;
 use std::sync::Arc;
 
 use super::*;
+use serde::Serialize;
 use snapshot::Persist;
 use versionize::{VersionMap, Versionize, VersionizeError, VersionizeResult};
 use versionize_derive::Versionize;
@@ -15,7 +16,7 @@ use vm_memory::GuestMemoryMmap;
 use crate::virtio::persist::VirtioDeviceState;
 use crate::virtio::{DeviceState, TYPE_VSOCK};
 
-#[derive(Clone, Versionize)]
+#[derive(Clone, Debug, Serialize, Versionize)]
 // NOTICE: Any changes to this structure require a snapshot version bump.
 pub struct VsockState {
     pub backend: VsockBackendState,
@@ -23,7 +24,7 @@ pub struct VsockState {
 }
 
 /// The Vsock serializable state.
-#[derive(Clone, Versionize)]
+#[derive(Clone, Debug, Serialize, Versionize)]
 // NOTICE: Any changes to this structure require a snapshot version bump.
 pub struct VsockFrontendState {
     pub cid: u64,
@@ -31,14 +32,14 @@ pub struct VsockFrontendState {
 }
 
 /// An enum for the serializable backend state types.
-#[derive(Clone, Versionize)]
+#[derive(Clone, Debug, Serialize, Versionize)]
 // NOTICE: Any changes to this structure require a snapshot version bump.
 pub enum VsockBackendState {
     Uds(VsockUdsState),
 }
 
 /// The Vsock Unix Backend serializable state.
-#[derive(Clone, Versionize)]
+#[derive(Clone, Debug, Serialize, Versionize)]
 // NOTICE: Any changes to this structure require a snapshot version bump.
 pub struct VsockUdsState {
     /// The path for the UDS socket.
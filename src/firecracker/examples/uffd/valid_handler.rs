@@ -0,0 +1,122 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Page fault handler serving the guest memory of a microVM restored from a snapshot with
+//! the `Uffd` memory backend.
+//!
+//! Usage: `uffd_valid_handler <socket path> <memory file path>`
+//!
+//! The handler listens on the socket for Firecracker to send the userfaultfd of the guest
+//! memory, along with the layout of the memory regions. Each missing page is then served
+//! from the memory file, or zeroed if the guest dropped it through the balloon device.
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixListener;
+use std::{env, ptr, slice};
+
+use utils::sock_ctrl_msg::ScmSocket;
+use utils::uffd::{Event, Uffd};
+use vmm::memory_snapshot::GuestRegionUffdMapping;
+
+struct PageFaultHandler<'a> {
+    uffd: Uffd,
+    mappings: Vec<GuestRegionUffdMapping>,
+    mem: &'a [u8],
+    page_size: u64,
+    // Pages dropped by the guest, which are zeroed instead of copied from the memory file.
+    removed_pages: HashSet<u64>,
+}
+
+impl<'a> PageFaultHandler<'a> {
+    fn run(&mut self) {
+        // Faults are deferred while events are pending on the userfaultfd.
+        let mut pending_faults = Vec::new();
+        loop {
+            match self
+                .uffd
+                .read_event()
+                .expect("Cannot read userfaultfd event")
+            {
+                Event::Pagefault { addr } => pending_faults.push(addr & !(self.page_size - 1)),
+                Event::Remove { start, end } => {
+                    self.removed_pages
+                        .extend((start..end).step_by(self.page_size as usize));
+                }
+            }
+            pending_faults.retain(|&page| !self.serve_page(page));
+        }
+    }
+
+    // Returns whether the fault on `page` was resolved.
+    fn serve_page(&mut self, page: u64) -> bool {
+        if self.removed_pages.contains(&page) {
+            let served = self
+                .uffd
+                .zero_page(page, self.page_size)
+                .expect("Cannot zero the page");
+            if served {
+                self.removed_pages.remove(&page);
+            }
+            return served;
+        }
+
+        let mapping = self
+            .mappings
+            .iter()
+            .find(|m| page >= m.base_host_virt_addr && page < m.base_host_virt_addr + m.size as u64)
+            .expect("Page fault outside of the guest memory");
+        let offset = (mapping.offset + page - mapping.base_host_virt_addr) as usize;
+        self.uffd
+            .copy(&self.mem[offset..offset + self.page_size as usize], page)
+            .expect("Cannot copy the page")
+    }
+}
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let socket_path = args.next().expect("Missing the socket path");
+    let mem_file_path = args.next().expect("Missing the memory file path");
+
+    let mem_file = File::open(mem_file_path).expect("Cannot open the memory file");
+    let mem_size = mem_file
+        .metadata()
+        .expect("Cannot get the memory file size")
+        .len() as usize;
+    // Safe because we check the return value. The mapping lives until the process exits.
+    let mem_addr = unsafe {
+        libc::mmap(
+            ptr::null_mut(),
+            mem_size,
+            libc::PROT_READ,
+            libc::MAP_PRIVATE,
+            mem_file.as_raw_fd(),
+            0,
+        )
+    };
+    assert_ne!(mem_addr, libc::MAP_FAILED, "Cannot map the memory file");
+    // Safe because the memory file is mapped above.
+    let mem = unsafe { slice::from_raw_parts(mem_addr as *const u8, mem_size) };
+
+    let listener = UnixListener::bind(socket_path).expect("Cannot bind to the socket");
+    let (stream, _) = listener.accept().expect("Cannot accept the connection");
+
+    let mut buf = [0u8; 4096];
+    let (len, file) = stream
+        .recv_with_fd(&mut buf)
+        .expect("Cannot receive the userfaultfd");
+    let mappings: Vec<GuestRegionUffdMapping> =
+        serde_json::from_slice(&buf[..len]).expect("Cannot deserialize the memory mappings");
+
+    // Safe because the call has no side effects.
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+    let mut handler = PageFaultHandler {
+        uffd: Uffd::from(file.expect("No userfaultfd received")),
+        mappings,
+        mem,
+        page_size,
+        removed_pages: HashSet::new(),
+    };
+    handler.run();
+}
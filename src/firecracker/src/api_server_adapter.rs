@@ -16,9 +16,10 @@ use mmds::MMDS;
 use seccompiler::BpfThreadMap;
 use utils::{epoll::EventSet, eventfd::EventFd};
 use vmm::{
+    migration::MigrationSender,
     resources::VmResources,
     rpc_interface::{PrebootApiController, RuntimeApiController, VmmAction},
-    vmm_config::instance_info::InstanceInfo,
+    vmm_config::{instance_info::InstanceInfo, migration::SendMigrationParams},
     EventManager, ExitCode, Vmm,
 };
 
@@ -27,6 +28,8 @@ struct ApiServerAdapter {
     from_api: Receiver<ApiRequest>,
     to_api: Sender<ApiResponse>,
     controller: RuntimeApiController,
+    // The migration being sent, whose response is deferred until it is done.
+    migration: Option<MigrationSender>,
 }
 
 impl ApiServerAdapter {
@@ -45,6 +48,7 @@ impl ApiServerAdapter {
             from_api,
             to_api,
             controller: RuntimeApiController::new(vm_resources, vmm.clone()),
+            migration: None,
         }));
         event_manager.add_subscriber(api_adapter);
         loop {
@@ -59,16 +63,64 @@ impl ApiServerAdapter {
 
     fn handle_request(&mut self, req_action: VmmAction) {
         let response = self.controller.handle_request(req_action);
+        self.send_response(response);
+    }
+
+    fn send_response(&self, response: ApiResponse) {
         // Send back the result.
         self.to_api
-            .send(Box::new(response))
+            .send(response)
             .map_err(|_| ())
             .expect("one-shot channel closed");
     }
+
+    // Unlike the other requests, the migration is advanced whenever its socket is writable, so
+    // that the devices keep being served while the guest memory is sent.
+    fn start_send_migration(&mut self, params: &SendMigrationParams, ops: &mut EventOps) {
+        let mut migration = match self.controller.start_send_migration(params) {
+            Ok(migration) => migration,
+            Err(err) => {
+                self.send_response(Box::new(Err(err)));
+                return;
+            }
+        };
+        if let Err(e) = ops.add(Events::new(&migration, EventSet::OUT)) {
+            error!("Failed to register the migration socket: {}", e);
+            // Send the whole migration at once instead.
+            let response = loop {
+                if let Some(result) = self.controller.send_migration_step(&mut migration) {
+                    break result;
+                }
+            };
+            self.send_response(Box::new(response));
+            return;
+        }
+        // Partial writes are then resumed once the socket is writable again, instead of
+        // blocking the event loop.
+        if let Err(e) = migration.set_nonblocking() {
+            error!("Failed to make the migration socket non-blocking: {}", e);
+        }
+        self.migration = Some(migration);
+    }
+
+    fn send_migration_step(&mut self, ops: &mut EventOps) {
+        let migration = self
+            .migration
+            .as_mut()
+            .expect("The migration socket is only registered during a migration");
+        if let Some(response) = self.controller.send_migration_step(migration) {
+            // The socket must be unregistered before the migration closes it.
+            if let Err(e) = ops.remove(Events::new(&*migration, EventSet::OUT)) {
+                error!("Failed to unregister the migration socket: {}", e);
+            }
+            self.migration = None;
+            self.send_response(Box::new(response));
+        }
+    }
 }
 impl MutEventSubscriber for ApiServerAdapter {
     /// Handle a read event (EPOLLIN).
-    fn process(&mut self, event: Events, _: &mut EventOps) {
+    fn process(&mut self, event: Events, ops: &mut EventOps) {
         let source = event.fd();
         let event_set = event.event_set();
 
@@ -76,7 +128,10 @@ impl MutEventSubscriber for ApiServerAdapter {
             match self.from_api.try_recv() {
                 Ok(api_request) => {
                     let request_is_pause = *api_request == VmmAction::Pause;
-                    self.handle_request(*api_request);
+                    match *api_request {
+                        VmmAction::SendMigration(params) => self.start_send_migration(&params, ops),
+                        request => self.handle_request(request),
+                    }
 
                     // If the latest req is a pause request, temporarily switch to a mode where we
                     // do blocking `recv`s on the `from_api` receiver in a loop, until we get
@@ -104,6 +159,12 @@ impl MutEventSubscriber for ApiServerAdapter {
                 }
             };
             let _ = self.api_event_fd.read();
+        } else if self
+            .migration
+            .as_ref()
+            .map_or(false, |migration| source == migration.as_raw_fd())
+        {
+            self.send_migration_step(ops);
         } else {
             error!("Spurious EventManager event for handler: ApiServerAdapter");
         }
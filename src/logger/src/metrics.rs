@@ -471,6 +471,10 @@ pub struct BlockDeviceMetrics {
     pub write_count: SharedIncMetric,
     /// Number of rate limiter throttling events.
     pub rate_limiter_throttled_events: SharedIncMetric,
+    /// Number of successful discard operations.
+    pub discard_count: SharedIncMetric,
+    /// Number of successful write zeroes operations.
+    pub write_zeroes_count: SharedIncMetric,
 }
 
 /// Metrics specific to the i8042 device.
@@ -587,6 +591,28 @@ pub struct NetDeviceMetrics {
     pub tx_rate_limiter_throttled: SharedIncMetric,
     /// Number of packets with a spoofed mac, sent by the guest.
     pub tx_spoofed_mac_count: SharedIncMetric,
+    /// Metrics of each RX/TX queue pair.
+    pub queue_pairs: [NetQueuePairMetrics; MAX_NET_QUEUE_PAIRS],
+}
+
+/// Maximum number of network device queue pairs for which metrics are reported.
+pub const MAX_NET_QUEUE_PAIRS: usize = 16;
+
+/// Metrics specific to a network device RX/TX queue pair.
+#[derive(Default, Serialize)]
+pub struct NetQueuePairMetrics {
+    /// Number of bytes received.
+    pub rx_bytes_count: SharedIncMetric,
+    /// Number of packets received.
+    pub rx_packets_count: SharedIncMetric,
+    /// Number of RX rate limiter throttling events.
+    pub rx_rate_limiter_throttled: SharedIncMetric,
+    /// Number of transmitted bytes.
+    pub tx_bytes_count: SharedIncMetric,
+    /// Number of transmitted packets.
+    pub tx_packets_count: SharedIncMetric,
+    /// Number of TX rate limiter throttling events.
+    pub tx_rate_limiter_throttled: SharedIncMetric,
 }
 
 /// Performance metrics related for the moment only to snapshots.
@@ -706,6 +732,10 @@ pub struct VcpuMetrics {
     pub failures: SharedIncMetric,
     /// Failures in configuring the CPUID.
     pub filter_cpuid: SharedIncMetric,
+    /// Number of vCPUs hot-added to the running microVM.
+    pub hotplug_count: SharedIncMetric,
+    /// Number of failed vCPU hotplug requests.
+    pub hotplug_fails: SharedIncMetric,
 }
 
 /// Metrics specific to the machine manager as a whole.
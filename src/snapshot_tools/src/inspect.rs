@@ -0,0 +1,163 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Decodes the microVM state saved in a snapshot into JSON, and compares two such states.
+//!
+//! The JSON is built from the `Serialize` implementations of the state structures:
+//! - structs become objects, keyed by field name, with the fields sorted by name;
+//! - `Some(value)` becomes the value and `None` becomes `null`;
+//! - variants holding values become an object holding their content, keyed by their name,
+//!   and unit variants become their name;
+//! - the KVM structures are converted field by field, without their padding, and their
+//!   unions according to the content they hold.
+
+use std::fmt::{Display, Formatter};
+
+use serde_json::Value;
+use vmm::persist::MicrovmState;
+
+/// Errors associated with decoding a microVM state.
+#[derive(Debug)]
+pub enum Error {
+    /// The state can't be converted to JSON.
+    Serialize(serde_json::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::Error::*;
+        match self {
+            Serialize(err) => write!(f, "Cannot decode the microVM state: {}", err),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Returns the JSON representation of `microvm_state`.
+pub fn microvm_state_to_json(microvm_state: &MicrovmState) -> Result<Value> {
+    serde_json::to_value(microvm_state).map_err(Error::Serialize)
+}
+
+/// A value that differs between two states.
+#[derive(Debug, PartialEq)]
+pub struct Difference {
+    /// Path of the value, e.g. `vcpu_states[0].regs.rip`.
+    pub path: String,
+    /// The value in the first state, if present.
+    pub left: Option<Value>,
+    /// The value in the second state, if present.
+    pub right: Option<Value>,
+}
+
+impl Display for Difference {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        let describe = |value: &Option<Value>| match value {
+            Some(value) => value.to_string(),
+            None => String::from("<missing>"),
+        };
+        write!(
+            f,
+            "{}: {} -> {}",
+            self.path,
+            describe(&self.left),
+            describe(&self.right)
+        )
+    }
+}
+
+/// Returns the values that differ between `left` and `right`, compared field by field.
+pub fn diff(left: &Value, right: &Value) -> Vec<Difference> {
+    let mut differences = Vec::new();
+    diff_values(String::new(), Some(left), Some(right), &mut differences);
+    differences
+}
+
+fn diff_values(
+    path: String,
+    left: Option<&Value>,
+    right: Option<&Value>,
+    differences: &mut Vec<Difference>,
+) {
+    match (left, right) {
+        (Some(Value::Object(left)), Some(Value::Object(right))) => {
+            let right_only = right.keys().filter(|key| !left.contains_key(*key));
+            for key in left.keys().chain(right_only) {
+                let field_path = if path.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", path, key)
+                };
+                diff_values(field_path, left.get(key), right.get(key), differences);
+            }
+        }
+        (Some(Value::Array(left)), Some(Value::Array(right))) => {
+            for index in 0..std::cmp::max(left.len(), right.len()) {
+                diff_values(
+                    format!("{}[{}]", path, index),
+                    left.get(index),
+                    right.get(index),
+                    differences,
+                );
+            }
+        }
+        (left, right) if left != right => differences.push(Difference {
+            path,
+            left: left.cloned(),
+            right: right.cloned(),
+        }),
+        _ => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    #[test]
+    fn test_diff() {
+        let left = json!({
+            "vcpu_states": [{"regs": {"rip": 1, "rsp": 2}}],
+            "devices": ["a", "b"],
+            "removed": 1
+        });
+        let right = json!({
+            "vcpu_states": [{"regs": {"rip": 3, "rsp": 2}}],
+            "devices": ["a"],
+            "added": null
+        });
+
+        assert!(diff(&left, &left).is_empty());
+        let differences: Vec<String> = diff(&left, &right)
+            .iter()
+            .map(|difference| difference.to_string())
+            .collect();
+        assert_eq!(
+            differences,
+            vec![
+                "devices[1]: \"b\" -> <missing>",
+                "removed: 1 -> <missing>",
+                "vcpu_states[0].regs.rip: 1 -> 3",
+                "added: <missing> -> null",
+            ]
+        );
+
+        // Values of different types differ as a whole.
+        assert_eq!(
+            diff(&json!({"a": [1]}), &json!({"a": {"0": 1}})),
+            vec![Difference {
+                path: String::from("a"),
+                left: Some(json!([1])),
+                right: Some(json!({"0": 1})),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_error_display() {
+        let err = Error::Serialize(serde_json::from_str::<Value>("{").unwrap_err());
+        let _ = format!("{}{:?}", err, err);
+    }
+}
@@ -0,0 +1,51 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Offline tools operating on the files of a Firecracker snapshot.
+
+pub mod inspect;
+pub mod merge;
+pub mod translate;
+
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use snapshot::Snapshot;
+use vmm::persist::MicrovmState;
+use vmm::version_map::VERSION_MAP;
+
+/// Errors associated with reading a microVM state file.
+#[derive(Debug)]
+pub enum Error {
+    /// Cannot open the state file.
+    Open(PathBuf, io::Error),
+    /// Cannot deserialize the microVM state.
+    Deserialize(PathBuf, snapshot::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::Error::*;
+        match self {
+            Open(path, err) => write!(f, "Cannot open the state file {:?}: {}", path, err),
+            Deserialize(path, err) => write!(
+                f,
+                "Cannot deserialize the microVM state from {:?}: {:?}",
+                path, err
+            ),
+        }
+    }
+}
+
+/// Reads the microVM state saved in the state file at `path`.
+pub fn load_microvm_state(path: &Path) -> Result<MicrovmState, Error> {
+    let mut file = File::open(path).map_err(|err| Error::Open(path.to_path_buf(), err))?;
+    let len = file
+        .metadata()
+        .map_err(|err| Error::Open(path.to_path_buf(), err))?
+        .len() as usize;
+    Snapshot::load(&mut file, len, VERSION_MAP.clone())
+        .map_err(|err| Error::Deserialize(path.to_path_buf(), err))
+}
@@ -0,0 +1,286 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Squashes a chain of memory snapshot files into a single full memory file.
+//!
+//! A full snapshot dumps the whole guest memory, while a diff snapshot writes a sparse file
+//! of the same size, in which only the pages dirtied since the previous snapshot hold data.
+//! Applying the data ranges of each diff file, in order, on top of the base file yields the
+//! memory of the last snapshot of the chain.
+
+use std::fmt::{Display, Formatter};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+use utils::seek_hole::SeekHole;
+use vmm::memory_snapshot::GuestMemoryState;
+
+// Size of the buffer through which the data ranges are copied.
+const COPY_BUFFER_SIZE: usize = 1 << 20;
+
+/// Errors associated with merging memory snapshot files.
+#[derive(Debug)]
+pub enum Error {
+    /// The memory regions are not laid out contiguously in the memory file.
+    InvalidLayout(usize),
+    /// The size of a memory file doesn't match the region layout.
+    LayerSize(PathBuf, u64, u64),
+    /// Cannot open a memory file.
+    Open(PathBuf, io::Error),
+    /// Cannot read a memory file.
+    Read(PathBuf, io::Error),
+    /// Cannot write the merged memory file.
+    Write(PathBuf, io::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::Error::*;
+        match self {
+            InvalidLayout(index) => write!(
+                f,
+                "Memory region {} doesn't follow the previous one in the memory file",
+                index
+            ),
+            LayerSize(path, expected, actual) => write!(
+                f,
+                "Memory file {:?} has {} bytes, while the memory regions span {} bytes",
+                path, actual, expected
+            ),
+            Open(path, err) => write!(f, "Cannot open the memory file {:?}: {}", path, err),
+            Read(path, err) => write!(f, "Cannot read the memory file {:?}: {}", path, err),
+            Write(path, err) => write!(f, "Cannot write the memory file {:?}: {}", path, err),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Returns the size of the memory file described by `mem_state`.
+pub fn memory_file_size(mem_state: &GuestMemoryState) -> Result<u64> {
+    let mut size = 0;
+    for (index, region) in mem_state.regions.iter().enumerate() {
+        if region.offset != size {
+            return Err(Error::InvalidLayout(index));
+        }
+        size += region.size as u64;
+    }
+    Ok(size)
+}
+
+/// Writes to `output` the memory obtained by applying the `diffs` files, in order, on top
+/// of the `base` full memory file.
+///
+/// Every file is checked against the region layout in `mem_state` before anything is
+/// written.
+pub fn merge_memory_files(
+    mem_state: &GuestMemoryState,
+    base: &Path,
+    diffs: &[PathBuf],
+    output: &Path,
+) -> Result<()> {
+    let size = memory_file_size(mem_state)?;
+    let mut base_file = open_layer(base, size)?;
+    let mut diff_files = diffs
+        .iter()
+        .map(|path| open_layer(path, size))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut output_file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(output)
+        .map_err(|err| Error::Open(output.to_path_buf(), err))?;
+    io::copy(&mut base_file, &mut output_file)
+        .map_err(|err| Error::Write(output.to_path_buf(), err))?;
+
+    let mut buf = vec![0u8; COPY_BUFFER_SIZE];
+    for (path, diff_file) in diffs.iter().zip(diff_files.iter_mut()) {
+        let mut offset = 0;
+        while let Some(data_start) = diff_file
+            .seek_data(offset)
+            .map_err(|err| Error::Read(path.clone(), err))?
+        {
+            // The end of the file is an implicit hole.
+            let data_end = diff_file
+                .seek_hole(data_start)
+                .map_err(|err| Error::Read(path.clone(), err))?
+                .unwrap_or(size);
+            copy_range(
+                diff_file,
+                path,
+                &output_file,
+                output,
+                data_start,
+                data_end,
+                &mut buf,
+            )?;
+            offset = data_end;
+        }
+    }
+
+    output_file
+        .sync_all()
+        .map_err(|err| Error::Write(output.to_path_buf(), err))
+}
+
+fn open_layer(path: &Path, size: u64) -> Result<File> {
+    let file = File::open(path).map_err(|err| Error::Open(path.to_path_buf(), err))?;
+    let len = file
+        .metadata()
+        .map_err(|err| Error::Open(path.to_path_buf(), err))?
+        .len();
+    if len != size {
+        return Err(Error::LayerSize(path.to_path_buf(), size, len));
+    }
+    Ok(file)
+}
+
+fn copy_range(
+    src: &File,
+    src_path: &Path,
+    dst: &File,
+    dst_path: &Path,
+    start: u64,
+    end: u64,
+    buf: &mut [u8],
+) -> Result<()> {
+    let mut offset = start;
+    while offset < end {
+        let len = std::cmp::min(buf.len() as u64, end - offset) as usize;
+        src.read_exact_at(&mut buf[..len], offset)
+            .map_err(|err| Error::Read(src_path.to_path_buf(), err))?;
+        dst.write_all_at(&buf[..len], offset)
+            .map_err(|err| Error::Write(dst_path.to_path_buf(), err))?;
+        offset += len as u64;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    use utils::tempfile::TempFile;
+    use vmm::memory_snapshot::GuestMemoryRegionState;
+
+    const PAGE_SIZE: usize = 4096;
+
+    fn mem_state(region_pages: &[usize]) -> GuestMemoryState {
+        let mut offset = 0;
+        let regions = region_pages
+            .iter()
+            .map(|&pages| {
+                let region = GuestMemoryRegionState {
+                    base_address: offset,
+                    size: pages * PAGE_SIZE,
+                    offset,
+                };
+                offset += (pages * PAGE_SIZE) as u64;
+                region
+            })
+            .collect();
+        GuestMemoryState {
+            regions,
+            ..Default::default()
+        }
+    }
+
+    // Creates a sparse memory file of `pages` pages, holding data only in `data_pages`.
+    fn memory_file(pages: usize, data_pages: &[(usize, u8)]) -> TempFile {
+        let file = TempFile::new().unwrap();
+        file.as_file().set_len((pages * PAGE_SIZE) as u64).unwrap();
+        for &(page, value) in data_pages {
+            file.as_file()
+                .write_all_at(&[value; PAGE_SIZE], (page * PAGE_SIZE) as u64)
+                .unwrap();
+        }
+        file
+    }
+
+    #[test]
+    fn test_memory_file_size() {
+        assert_eq!(memory_file_size(&mem_state(&[])).unwrap(), 0);
+        assert_eq!(
+            memory_file_size(&mem_state(&[2, 3])).unwrap(),
+            (5 * PAGE_SIZE) as u64
+        );
+
+        let mut state = mem_state(&[2, 3]);
+        state.regions[1].offset += PAGE_SIZE as u64;
+        match memory_file_size(&state) {
+            Err(Error::InvalidLayout(1)) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_merge_memory_files() {
+        let state = mem_state(&[1, 3]);
+        let base = memory_file(4, &[(0, 1), (1, 1), (2, 1), (3, 1)]);
+        let diffs = [memory_file(4, &[(1, 2)]), memory_file(4, &[(1, 3), (3, 3)])];
+        let diff_paths: Vec<_> = diffs.iter().map(|f| f.as_path().to_path_buf()).collect();
+        let output = TempFile::new().unwrap();
+
+        merge_memory_files(&state, base.as_path(), &diff_paths, output.as_path()).unwrap();
+
+        let merged = fs::read(output.as_path()).unwrap();
+        let pages: Vec<_> = merged.chunks(PAGE_SIZE).collect();
+        assert_eq!(pages.len(), 4);
+        for (page, &value) in pages.iter().zip([1u8, 3, 1, 3].iter()) {
+            assert!(page.iter().all(|&byte| byte == value));
+        }
+
+        // With no diff file, the output is a copy of the base.
+        merge_memory_files(&state, base.as_path(), &[], output.as_path()).unwrap();
+        assert_eq!(
+            fs::read(output.as_path()).unwrap(),
+            fs::read(base.as_path()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_merge_layer_size_mismatch() {
+        let state = mem_state(&[4]);
+        let base = memory_file(4, &[(0, 1)]);
+        let diff = memory_file(3, &[(1, 2)]);
+        let mut output = TempFile::new().unwrap();
+        output.remove().unwrap();
+
+        match merge_memory_files(
+            &state,
+            base.as_path(),
+            &[diff.as_path().to_path_buf()],
+            output.as_path(),
+        ) {
+            Err(Error::LayerSize(path, expected, actual)) => {
+                assert_eq!(path, diff.as_path());
+                assert_eq!(expected, (4 * PAGE_SIZE) as u64);
+                assert_eq!(actual, (3 * PAGE_SIZE) as u64);
+            }
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        // Nothing is written if a layer doesn't match the layout.
+        assert!(!output.as_path().exists());
+    }
+
+    #[test]
+    fn test_error_display() {
+        let path = PathBuf::from("mem");
+        let errors = [
+            Error::InvalidLayout(0),
+            Error::LayerSize(path.clone(), 0, 0),
+            Error::Open(path.clone(), io::Error::from_raw_os_error(0)),
+            Error::Read(path.clone(), io::Error::from_raw_os_error(0)),
+            Error::Write(path, io::Error::from_raw_os_error(0)),
+        ];
+        for err in errors.iter() {
+            let _ = format!("{}{:?}", err, err);
+        }
+    }
+}
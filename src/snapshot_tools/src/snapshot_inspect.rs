@@ -0,0 +1,85 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! snapshot-inspect prints the microVM state saved in a snapshot state file as JSON, or the
+//! fields that differ between the microVM states of two snapshot state files.
+
+use std::path::Path;
+use std::process;
+
+use serde_json::Value;
+use snapshot_tools::inspect::{diff, microvm_state_to_json};
+use snapshot_tools::load_microvm_state;
+use utils::arg_parser::{ArgParser, Argument};
+
+const SNAPSHOT_INSPECT_VERSION: &str = env!("CARGO_PKG_VERSION");
+const EXIT_CODE_ERROR: i32 = 1;
+
+fn build_arg_parser() -> ArgParser<'static> {
+    ArgParser::new()
+        .arg(
+            Argument::new("snapshot-path")
+                .required(true)
+                .takes_value(true)
+                .help("Path of the microVM state file to inspect."),
+        )
+        .arg(
+            Argument::new("diff-with")
+                .required(false)
+                .takes_value(true)
+                .help("Path of a microVM state file to compare with, field by field, instead of printing the state."),
+        )
+}
+
+fn state_to_json(path: &Path) -> Value {
+    let microvm_state = load_microvm_state(path).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(EXIT_CODE_ERROR);
+    });
+    microvm_state_to_json(&microvm_state).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(EXIT_CODE_ERROR);
+    })
+}
+
+fn main() {
+    let mut arg_parser = build_arg_parser();
+
+    if let Err(err) = arg_parser.parse_from_cmdline() {
+        eprintln!(
+            "Arguments parsing error: {} \n\n\
+             For more information try --help.",
+            err
+        );
+        process::exit(EXIT_CODE_ERROR);
+    }
+
+    let arguments = arg_parser.arguments();
+    if arguments.flag_present("help") {
+        println!("snapshot-inspect v{}\n", SNAPSHOT_INSPECT_VERSION);
+        println!("{}", arg_parser.formatted_help());
+        return;
+    }
+    if arguments.flag_present("version") {
+        println!("snapshot-inspect v{}\n", SNAPSHOT_INSPECT_VERSION);
+        return;
+    }
+
+    // It's safe to unwrap the required arguments, which the parser checked.
+    let state = state_to_json(Path::new(arguments.single_value("snapshot-path").unwrap()));
+
+    match arguments.single_value("diff-with") {
+        Some(other_path) => {
+            let other_state = state_to_json(Path::new(other_path));
+            let differences = diff(&state, &other_state);
+            if differences.is_empty() {
+                println!("The microVM states are identical.");
+            }
+            for difference in differences {
+                println!("{}", difference);
+            }
+        }
+        // It's safe to unwrap because JSON values can always be serialized.
+        None => println!("{}", serde_json::to_string_pretty(&state).unwrap()),
+    }
+}
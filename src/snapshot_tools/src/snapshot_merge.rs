@@ -0,0 +1,102 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! snapshot-merge squashes the memory file of a full snapshot and the memory files of the
+//! diff snapshots taken after it into a single full memory file.
+//!
+//! The resulting file can be loaded along with the microVM state file of the last diff
+//! snapshot of the chain, which also describes the guest memory layout that every memory
+//! file is checked against.
+
+use std::path::PathBuf;
+use std::process;
+
+use snapshot_tools::load_microvm_state;
+use snapshot_tools::merge::merge_memory_files;
+use utils::arg_parser::{ArgParser, Argument};
+
+const SNAPSHOT_MERGE_VERSION: &str = env!("CARGO_PKG_VERSION");
+const EXIT_CODE_ERROR: i32 = 1;
+
+fn build_arg_parser() -> ArgParser<'static> {
+    ArgParser::new()
+        .arg(
+            Argument::new("snapshot-path")
+                .required(true)
+                .takes_value(true)
+                .help("Path of the microVM state file of the last snapshot of the chain."),
+        )
+        .arg(
+            Argument::new("base-file")
+                .required(true)
+                .takes_value(true)
+                .help("Path of the memory file of the full snapshot the chain starts from."),
+        )
+        .arg(
+            Argument::new("diff-file")
+                .required(false)
+                .allow_multiple(true)
+                .help("Path of the memory file of a diff snapshot. Repeat the argument for each diff snapshot, from the oldest to the newest one."),
+        )
+        .arg(
+            Argument::new("output-file")
+                .required(true)
+                .takes_value(true)
+                .help("Path of the merged memory file."),
+        )
+}
+
+fn main() {
+    let mut arg_parser = build_arg_parser();
+
+    if let Err(err) = arg_parser.parse_from_cmdline() {
+        eprintln!(
+            "Arguments parsing error: {} \n\n\
+             For more information try --help.",
+            err
+        );
+        process::exit(EXIT_CODE_ERROR);
+    }
+
+    let arguments = arg_parser.arguments();
+    if arguments.flag_present("help") {
+        println!("snapshot-merge v{}\n", SNAPSHOT_MERGE_VERSION);
+        println!("{}", arg_parser.formatted_help());
+        return;
+    }
+    if arguments.flag_present("version") {
+        println!("snapshot-merge v{}\n", SNAPSHOT_MERGE_VERSION);
+        return;
+    }
+
+    // It's safe to unwrap the required arguments, which the parser checked.
+    let snapshot_path = PathBuf::from(arguments.single_value("snapshot-path").unwrap());
+    let base_file = PathBuf::from(arguments.single_value("base-file").unwrap());
+    let output_file = PathBuf::from(arguments.single_value("output-file").unwrap());
+    let diff_files: Vec<PathBuf> = arguments
+        .multiple_values("diff-file")
+        .unwrap_or_default()
+        .iter()
+        .map(PathBuf::from)
+        .collect();
+
+    let microvm_state = load_microvm_state(&snapshot_path).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(EXIT_CODE_ERROR);
+    });
+
+    if let Err(err) = merge_memory_files(
+        &microvm_state.memory_state,
+        &base_file,
+        &diff_files,
+        &output_file,
+    ) {
+        eprintln!("Cannot merge the memory files: {}", err);
+        process::exit(EXIT_CODE_ERROR);
+    }
+
+    println!(
+        "Memory files successfully merged into: {}",
+        output_file.display()
+    );
+}
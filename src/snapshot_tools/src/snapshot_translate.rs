@@ -0,0 +1,113 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! snapshot-translate rewrites a snapshot state file for the snapshot data version of
+//! another Firecracker version, so that the snapshot can be loaded by that version.
+//!
+//! The fields of the microVM state that the target version can't represent are reported,
+//! and the translated state file is only written if there are none, unless the loss is
+//! explicitly allowed.
+
+use std::path::PathBuf;
+use std::process;
+
+use snapshot_tools::load_microvm_state;
+use snapshot_tools::translate::{translate_microvm_state, write_state_file};
+use utils::arg_parser::{ArgParser, Argument};
+
+const SNAPSHOT_TRANSLATE_VERSION: &str = env!("CARGO_PKG_VERSION");
+const EXIT_CODE_ERROR: i32 = 1;
+
+fn build_arg_parser() -> ArgParser<'static> {
+    ArgParser::new()
+        .arg(
+            Argument::new("snapshot-path")
+                .required(true)
+                .takes_value(true)
+                .help("Path of the microVM state file to translate."),
+        )
+        .arg(
+            Argument::new("target-version")
+                .required(true)
+                .takes_value(true)
+                .help("Firecracker version which will load the translated state file, e.g. 0.24.0."),
+        )
+        .arg(
+            Argument::new("output-path")
+                .required(true)
+                .takes_value(true)
+                .help("Path of the translated microVM state file."),
+        )
+        .arg(
+            Argument::new("allow-lossy")
+                .takes_value(false)
+                .help("Write the translated state file even if some fields can't be represented in the target version."),
+        )
+}
+
+fn main() {
+    let mut arg_parser = build_arg_parser();
+
+    if let Err(err) = arg_parser.parse_from_cmdline() {
+        eprintln!(
+            "Arguments parsing error: {} \n\n\
+             For more information try --help.",
+            err
+        );
+        process::exit(EXIT_CODE_ERROR);
+    }
+
+    let arguments = arg_parser.arguments();
+    if arguments.flag_present("help") {
+        println!("snapshot-translate v{}\n", SNAPSHOT_TRANSLATE_VERSION);
+        println!("{}", arg_parser.formatted_help());
+        return;
+    }
+    if arguments.flag_present("version") {
+        println!("snapshot-translate v{}\n", SNAPSHOT_TRANSLATE_VERSION);
+        return;
+    }
+
+    // It's safe to unwrap the required arguments, which the parser checked.
+    let snapshot_path = PathBuf::from(arguments.single_value("snapshot-path").unwrap());
+    let target_version = arguments.single_value("target-version").unwrap();
+    let output_path = PathBuf::from(arguments.single_value("output-path").unwrap());
+
+    let microvm_state = load_microvm_state(&snapshot_path).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(EXIT_CODE_ERROR);
+    });
+    let translation =
+        translate_microvm_state(&microvm_state, target_version).unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            process::exit(EXIT_CODE_ERROR);
+        });
+
+    if !translation.lost_fields.is_empty() {
+        eprintln!(
+            "The following fields can't be represented in version {}, and are reset to their \
+             default values:",
+            target_version
+        );
+        for field in translation.lost_fields.iter() {
+            eprintln!("  {}", field);
+        }
+        if !arguments.flag_present("allow-lossy") {
+            eprintln!(
+                "The state file is not translated. Use --allow-lossy to translate it anyway."
+            );
+            process::exit(EXIT_CODE_ERROR);
+        }
+    }
+
+    if let Err(err) = write_state_file(&output_path, &translation) {
+        eprintln!("{}", err);
+        process::exit(EXIT_CODE_ERROR);
+    }
+
+    println!(
+        "State file translated to data version {}: {}",
+        translation.data_version,
+        output_path.display()
+    );
+}
@@ -0,0 +1,168 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Rewrites a microVM state for the snapshot data version of another Firecracker version.
+//!
+//! Serializing the state for an older data version fails if it holds values that the older
+//! version can't represent at all, e.g. multi-queue devices, and otherwise drops the fields
+//! that the older version doesn't know. The dropped fields are found by loading the
+//! translated state back, which restores them to their default values, and comparing it
+//! with the original state.
+
+use std::fmt::{Display, Formatter};
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use snapshot::Snapshot;
+use versionize::VersionizeError;
+use vmm::persist::{get_devices_snapshot_data_version, CreateSnapshotError, MicrovmState};
+use vmm::version_map::VERSION_MAP;
+
+use crate::inspect::{self, diff, microvm_state_to_json, Difference};
+
+/// Errors associated with translating a microVM state.
+#[derive(Debug)]
+pub enum Error {
+    /// Cannot decode a microVM state to compare it.
+    Inspect(inspect::Error),
+    /// Cannot load back the translated microVM state.
+    Deserialize(snapshot::Error),
+    /// Cannot serialize the microVM state for the target version.
+    Serialize(snapshot::Error),
+    /// The target version is not supported.
+    Version(CreateSnapshotError),
+    /// Cannot write the translated state file.
+    Write(PathBuf, io::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::Error::*;
+        match self {
+            Inspect(err) => write!(f, "{}", err),
+            Deserialize(err) => write!(
+                f,
+                "Cannot load back the translated microVM state: {:?}",
+                err
+            ),
+            Serialize(snapshot::Error::Versionize(VersionizeError::Semantic(msg))) => write!(
+                f,
+                "The microVM state can't be represented in the target version: {}",
+                msg
+            ),
+            Serialize(err) => write!(f, "Cannot serialize the microVM state: {:?}", err),
+            Version(err) => write!(f, "Invalid target version: {}", err),
+            Write(path, err) => write!(f, "Cannot write the state file {:?}: {}", path, err),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// A microVM state translated for another data version.
+pub struct Translation {
+    /// The snapshot data version of the translated state.
+    pub data_version: u16,
+    /// The serialized microVM state.
+    pub state_bytes: Vec<u8>,
+    /// The fields which lose their value in the translation.
+    pub lost_fields: Vec<Difference>,
+}
+
+/// Serializes `microvm_state` for the data version of the `target_version` Firecracker
+/// version, e.g. `0.24.0`.
+pub fn translate_microvm_state(
+    microvm_state: &MicrovmState,
+    target_version: &str,
+) -> Result<Translation> {
+    let devices = &microvm_state.device_states;
+    let used_irqs_count: usize = devices
+        .block_devices
+        .iter()
+        .map(|device| device.mmio_slot.irqs.len())
+        .chain(
+            devices
+                .net_devices
+                .iter()
+                .map(|device| device.mmio_slot.irqs.len()),
+        )
+        .chain(
+            devices
+                .vsock_device
+                .iter()
+                .map(|device| device.mmio_slot.irqs.len()),
+        )
+        .chain(
+            devices
+                .balloon_device
+                .iter()
+                .map(|device| device.mmio_slot.irqs.len()),
+        )
+        .sum();
+    let data_version = get_devices_snapshot_data_version(
+        &Some(target_version.to_string()),
+        &VERSION_MAP,
+        used_irqs_count,
+    )
+    .map_err(Error::Version)?;
+
+    let mut state_bytes = Vec::new();
+    Snapshot::new(VERSION_MAP.clone(), data_version)
+        .save(&mut state_bytes, microvm_state)
+        .map_err(Error::Serialize)?;
+
+    let translated_state: MicrovmState = Snapshot::load(
+        &mut state_bytes.as_slice(),
+        state_bytes.len(),
+        VERSION_MAP.clone(),
+    )
+    .map_err(Error::Deserialize)?;
+    let lost_fields = diff(
+        &microvm_state_to_json(microvm_state).map_err(Error::Inspect)?,
+        &microvm_state_to_json(&translated_state).map_err(Error::Inspect)?,
+    );
+
+    Ok(Translation {
+        data_version,
+        state_bytes,
+        lost_fields,
+    })
+}
+
+/// Writes the translated state to a new state file at `path`.
+pub fn write_state_file(path: &Path, translation: &Translation) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)
+        .map_err(|err| Error::Write(path.to_path_buf(), err))?;
+    file.write_all(&translation.state_bytes)
+        .and_then(|_| file.sync_all())
+        .map_err(|err| Error::Write(path.to_path_buf(), err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_display() {
+        let errors = [
+            Error::Inspect(inspect::Error::Serialize(
+                serde_json::from_str::<serde_json::Value>("{").unwrap_err(),
+            )),
+            Error::Deserialize(snapshot::Error::InvalidMagic(0)),
+            Error::Serialize(snapshot::Error::Versionize(VersionizeError::Semantic(
+                String::from("Target version does not support multi-queue net devices."),
+            ))),
+            Error::Serialize(snapshot::Error::Io(0)),
+            Error::Version(CreateSnapshotError::UnsupportedVersion),
+            Error::Write(PathBuf::from("state"), io::Error::from_raw_os_error(0)),
+        ];
+        for err in errors.iter() {
+            let _ = format!("{}{:?}", err, err);
+        }
+    }
+}
@@ -0,0 +1,266 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Minimal LZ77 block compression, tailored for guest memory contents.
+//!
+//! A compressed block is a series of sequences, each made of:
+//! - a token byte, holding the literal length in its high nibble and the match length
+//!   (minus `MIN_MATCH`) in its low nibble. A nibble of 15 means the length continues in
+//!   the following bytes, each adding up to 255, until a byte lower than 255;
+//! - the literal bytes;
+//! - the little endian 16 bit offset of the match, counted back from the current position,
+//!   followed by the extra match length bytes, if any.
+//!
+//! The last sequence only holds literals, and ends the block. The match of a sequence may
+//! overlap the bytes it produces, which encodes runs (e.g. zeroed pages) compactly.
+
+use std::fmt::{Display, Formatter};
+use std::result;
+
+use crate::byte_order::{read_le_u16, read_le_u32};
+
+const MIN_MATCH: usize = 4;
+const MAX_OFFSET: usize = u16::MAX as usize;
+const HASH_BITS: u32 = 12;
+const NIBBLE_MAX: usize = 15;
+
+/// Errors associated with decompressing a block.
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// The block ends in the middle of a sequence.
+    Truncated,
+    /// A match refers to bytes before the start of the output.
+    InvalidOffset(usize),
+    /// The block doesn't decompress to the expected size.
+    SizeMismatch,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::Error::*;
+        match self {
+            Truncated => write!(f, "The compressed block is truncated"),
+            InvalidOffset(offset) => write!(f, "Invalid match offset: {}", offset),
+            SizeMismatch => write!(f, "The block doesn't decompress to the expected size"),
+        }
+    }
+}
+
+type Result<T> = result::Result<T, Error>;
+
+/// Returns the maximum size of the compressed form of `len` bytes.
+pub fn max_compressed_len(len: usize) -> usize {
+    // Incompressible input is stored as one literal run.
+    len + len / 255 + 16
+}
+
+fn hash(sequence: u32) -> usize {
+    (sequence.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize
+}
+
+fn write_len_ext(out: &mut Vec<u8>, len: usize) {
+    let mut remaining = len - NIBBLE_MAX;
+    while remaining >= 255 {
+        out.push(255);
+        remaining -= 255;
+    }
+    out.push(remaining as u8);
+}
+
+fn write_sequence(out: &mut Vec<u8>, literals: &[u8], match_info: Option<(usize, usize)>) {
+    let literal_nibble = std::cmp::min(literals.len(), NIBBLE_MAX);
+    let match_len = match_info.map_or(0, |(_, len)| len - MIN_MATCH);
+    let match_nibble = std::cmp::min(match_len, NIBBLE_MAX);
+    out.push(((literal_nibble << 4) | match_nibble) as u8);
+    if literal_nibble == NIBBLE_MAX {
+        write_len_ext(out, literals.len());
+    }
+    out.extend_from_slice(literals);
+
+    if let Some((offset, _)) = match_info {
+        out.extend_from_slice(&(offset as u16).to_le_bytes());
+        if match_nibble == NIBBLE_MAX {
+            write_len_ext(out, match_len);
+        }
+    }
+}
+
+/// Compresses `src`, appending the compressed block to `out`.
+pub fn compress(src: &[u8], out: &mut Vec<u8>) {
+    // Last position, plus one, where each hashed 4 byte sequence was seen.
+    let mut table = vec![0usize; 1 << HASH_BITS];
+    let mut anchor = 0;
+    let mut pos = 0;
+
+    while pos + MIN_MATCH <= src.len() {
+        let sequence = read_le_u32(&src[pos..]);
+        let slot = &mut table[hash(sequence)];
+        let candidate = *slot;
+        *slot = pos + 1;
+
+        if candidate > 0 {
+            let candidate = candidate - 1;
+            if pos - candidate <= MAX_OFFSET && read_le_u32(&src[candidate..]) == sequence {
+                let mut len = MIN_MATCH;
+                while pos + len < src.len() && src[candidate + len] == src[pos + len] {
+                    len += 1;
+                }
+                write_sequence(out, &src[anchor..pos], Some((pos - candidate, len)));
+                pos += len;
+                anchor = pos;
+                continue;
+            }
+        }
+        pos += 1;
+    }
+
+    write_sequence(out, &src[anchor..], None);
+}
+
+// Reads the extra bytes of a length whose nibble is saturated.
+fn read_len(src: &[u8], pos: &mut usize, nibble: usize) -> Result<usize> {
+    let mut len = nibble;
+    if nibble == NIBBLE_MAX {
+        loop {
+            let byte = *src.get(*pos).ok_or(Error::Truncated)?;
+            *pos += 1;
+            len += byte as usize;
+            if byte < 255 {
+                break;
+            }
+        }
+    }
+    Ok(len)
+}
+
+/// Decompresses the `src` block into `dst`, which it must exactly fill.
+pub fn decompress(src: &[u8], dst: &mut [u8]) -> Result<()> {
+    let mut pos = 0;
+    let mut out = 0;
+
+    loop {
+        let token = *src.get(pos).ok_or(Error::Truncated)? as usize;
+        pos += 1;
+
+        let literal_len = read_len(src, &mut pos, token >> 4)?;
+        let literals = src.get(pos..pos + literal_len).ok_or(Error::Truncated)?;
+        dst.get_mut(out..out + literal_len)
+            .ok_or(Error::SizeMismatch)?
+            .copy_from_slice(literals);
+        pos += literal_len;
+        out += literal_len;
+
+        // The last sequence has no match.
+        if pos == src.len() {
+            break;
+        }
+
+        let offset = read_le_u16(src.get(pos..pos + 2).ok_or(Error::Truncated)?) as usize;
+        pos += 2;
+        let match_len = read_len(src, &mut pos, token & 0xF)? + MIN_MATCH;
+        if offset == 0 || offset > out {
+            return Err(Error::InvalidOffset(offset));
+        }
+        if out + match_len > dst.len() {
+            return Err(Error::SizeMismatch);
+        }
+        // The match may overlap the bytes it produces, so it's copied byte by byte.
+        for i in out..out + match_len {
+            dst[i] = dst[i - offset];
+        }
+        out += match_len;
+    }
+
+    if out != dst.len() {
+        return Err(Error::SizeMismatch);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(src: &[u8]) -> Vec<u8> {
+        let mut compressed = Vec::new();
+        compress(src, &mut compressed);
+        assert!(compressed.len() <= max_compressed_len(src.len()));
+
+        let mut decompressed = vec![0xFFu8; src.len()];
+        decompress(&compressed, &mut decompressed).unwrap();
+        assert_eq!(decompressed, src);
+        compressed
+    }
+
+    #[test]
+    fn test_round_trip() {
+        // Empty and tiny inputs are stored as literals.
+        assert_eq!(round_trip(&[]), vec![0]);
+        round_trip(&[1, 2, 3]);
+
+        // Runs collapse into a few bytes.
+        assert!(round_trip(&[0u8; 4096]).len() < 32);
+
+        // Repeated patterns, with long literal runs in between.
+        let mut src = Vec::new();
+        for i in 0..64u32 {
+            src.extend((0..300u32).map(|j| (i * 7 + j * 13) as u8));
+            src.extend_from_slice(b"firecracker");
+        }
+        round_trip(&src);
+
+        // Pseudo random input doesn't compress, but doesn't expand past the bound.
+        let mut state = 0x1234_5678u32;
+        let random: Vec<u8> = (0..65536)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect();
+        round_trip(&random);
+    }
+
+    #[test]
+    fn test_decompress_errors() {
+        let mut compressed = Vec::new();
+        compress(&[7u8; 1024], &mut compressed);
+
+        // The output size must match exactly.
+        assert_eq!(
+            decompress(&compressed, &mut [0u8; 1023]),
+            Err(Error::SizeMismatch)
+        );
+        assert_eq!(
+            decompress(&compressed, &mut [0u8; 1025]),
+            Err(Error::SizeMismatch)
+        );
+
+        assert_eq!(decompress(&[], &mut []), Err(Error::Truncated));
+        // Literal run longer than the block.
+        assert_eq!(decompress(&[0x30, 1], &mut [0u8; 3]), Err(Error::Truncated));
+        // Match before the start of the output.
+        assert_eq!(
+            decompress(&[0x10, 1, 2, 0, 0x00], &mut [0u8; 5]),
+            Err(Error::InvalidOffset(2))
+        );
+        assert_eq!(
+            decompress(&[0x10, 1, 0, 0, 0x00], &mut [0u8; 5]),
+            Err(Error::InvalidOffset(0))
+        );
+    }
+
+    #[test]
+    fn test_error_display() {
+        for err in [
+            Error::Truncated,
+            Error::InvalidOffset(0),
+            Error::SizeMismatch,
+        ]
+        .iter()
+        {
+            let _ = format!("{}{:?}", err, err);
+        }
+    }
+}
@@ -0,0 +1,348 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Minimal SHA-256 and HMAC-SHA256 implementations, as specified by FIPS 180-4 and RFC 2104.
+
+use crate::byte_order::read_be_u32;
+
+/// Size, in bytes, of a SHA-256 digest and of an HMAC-SHA256 tag.
+pub const DIGEST_LEN: usize = 32;
+const BLOCK_LEN: usize = 64;
+
+const INITIAL_STATE: [u32; 8] = [
+    0x6a09_e667,
+    0xbb67_ae85,
+    0x3c6e_f372,
+    0xa54f_f53a,
+    0x510e_527f,
+    0x9b05_688c,
+    0x1f83_d9ab,
+    0x5be0_cd19,
+];
+
+const ROUND_CONSTANTS: [u32; 64] = [
+    0x428a_2f98,
+    0x7137_4491,
+    0xb5c0_fbcf,
+    0xe9b5_dba5,
+    0x3956_c25b,
+    0x59f1_11f1,
+    0x923f_82a4,
+    0xab1c_5ed5,
+    0xd807_aa98,
+    0x1283_5b01,
+    0x2431_85be,
+    0x550c_7dc3,
+    0x72be_5d74,
+    0x80de_b1fe,
+    0x9bdc_06a7,
+    0xc19b_f174,
+    0xe49b_69c1,
+    0xefbe_4786,
+    0x0fc1_9dc6,
+    0x240c_a1cc,
+    0x2de9_2c6f,
+    0x4a74_84aa,
+    0x5cb0_a9dc,
+    0x76f9_88da,
+    0x983e_5152,
+    0xa831_c66d,
+    0xb003_27c8,
+    0xbf59_7fc7,
+    0xc6e0_0bf3,
+    0xd5a7_9147,
+    0x06ca_6351,
+    0x1429_2967,
+    0x27b7_0a85,
+    0x2e1b_2138,
+    0x4d2c_6dfc,
+    0x5338_0d13,
+    0x650a_7354,
+    0x766a_0abb,
+    0x81c2_c92e,
+    0x9272_2c85,
+    0xa2bf_e8a1,
+    0xa81a_664b,
+    0xc24b_8b70,
+    0xc76c_51a3,
+    0xd192_e819,
+    0xd699_0624,
+    0xf40e_3585,
+    0x106a_a070,
+    0x19a4_c116,
+    0x1e37_6c08,
+    0x2748_774c,
+    0x34b0_bcb5,
+    0x391c_0cb3,
+    0x4ed8_aa4a,
+    0x5b9c_ca4f,
+    0x682e_6ff3,
+    0x748f_82ee,
+    0x78a5_636f,
+    0x84c8_7814,
+    0x8cc7_0208,
+    0x90be_fffa,
+    0xa450_6ceb,
+    0xbef9_a3f7,
+    0xc671_78f2,
+];
+
+/// Incremental SHA-256 hasher.
+#[derive(Clone)]
+pub struct Sha256 {
+    state: [u32; 8],
+    // Data not yet hashed, always shorter than a block.
+    buffer: [u8; BLOCK_LEN],
+    buffer_len: usize,
+    // Total number of bytes fed to the hasher.
+    len: u64,
+}
+
+impl Default for Sha256 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Sha256 {
+    /// Creates a hasher with no data.
+    pub fn new() -> Self {
+        Sha256 {
+            state: INITIAL_STATE,
+            buffer: [0; BLOCK_LEN],
+            buffer_len: 0,
+            len: 0,
+        }
+    }
+
+    /// Feeds `data` to the hasher.
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.len = self.len.wrapping_add(data.len() as u64);
+
+        if self.buffer_len > 0 {
+            let count = std::cmp::min(BLOCK_LEN - self.buffer_len, data.len());
+            self.buffer[self.buffer_len..self.buffer_len + count].copy_from_slice(&data[..count]);
+            self.buffer_len += count;
+            data = &data[count..];
+            if self.buffer_len < BLOCK_LEN {
+                return;
+            }
+            let block = self.buffer;
+            self.compress(&block);
+            self.buffer_len = 0;
+        }
+
+        let mut blocks = data.chunks_exact(BLOCK_LEN);
+        for block in &mut blocks {
+            self.compress(block);
+        }
+        let remainder = blocks.remainder();
+        self.buffer[..remainder.len()].copy_from_slice(remainder);
+        self.buffer_len = remainder.len();
+    }
+
+    /// Returns the digest of all the data fed to the hasher.
+    pub fn finalize(mut self) -> [u8; DIGEST_LEN] {
+        let bit_len = self.len.wrapping_mul(8);
+
+        // The message is padded with a one bit, then zeros up to the last 8 bytes of a
+        // block, which hold its length in bits.
+        let mut padding = [0u8; BLOCK_LEN + 8];
+        padding[0] = 0x80;
+        let zeros = (BLOCK_LEN + BLOCK_LEN - 8 - 1 - self.buffer_len) % BLOCK_LEN;
+        let padding_len = 1 + zeros;
+        padding[padding_len..padding_len + 8].copy_from_slice(&bit_len.to_be_bytes());
+        self.update(&padding[..padding_len + 8]);
+        debug_assert_eq!(self.buffer_len, 0);
+
+        let mut digest = [0u8; DIGEST_LEN];
+        for (chunk, word) in digest.chunks_exact_mut(4).zip(self.state.iter()) {
+            chunk.copy_from_slice(&word.to_be_bytes());
+        }
+        digest
+    }
+
+    /// Returns the digest of `data`.
+    pub fn digest(data: &[u8]) -> [u8; DIGEST_LEN] {
+        let mut hasher = Sha256::new();
+        hasher.update(data);
+        hasher.finalize()
+    }
+
+    fn compress(&mut self, block: &[u8]) {
+        let mut schedule = [0u32; 64];
+        for (i, word) in block.chunks_exact(4).enumerate() {
+            schedule[i] = read_be_u32(word);
+        }
+        for i in 16..64 {
+            let s0 = schedule[i - 15].rotate_right(7)
+                ^ schedule[i - 15].rotate_right(18)
+                ^ (schedule[i - 15] >> 3);
+            let s1 = schedule[i - 2].rotate_right(17)
+                ^ schedule[i - 2].rotate_right(19)
+                ^ (schedule[i - 2] >> 10);
+            schedule[i] = schedule[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(schedule[i - 7])
+                .wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = self.state;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let temp1 = h
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(ROUND_CONSTANTS[i])
+                .wrapping_add(schedule[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let temp2 = s0.wrapping_add(maj);
+
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(temp1);
+            d = c;
+            c = b;
+            b = a;
+            a = temp1.wrapping_add(temp2);
+        }
+
+        for (word, value) in self.state.iter_mut().zip([a, b, c, d, e, f, g, h].iter()) {
+            *word = word.wrapping_add(*value);
+        }
+    }
+}
+
+/// Incremental HMAC-SHA256 computation.
+#[derive(Clone)]
+pub struct HmacSha256 {
+    inner: Sha256,
+    outer: Sha256,
+}
+
+impl HmacSha256 {
+    /// Starts an HMAC computation keyed with `key`.
+    pub fn new(key: &[u8]) -> Self {
+        // Keys longer than a block are hashed first.
+        let mut block_key = [0u8; BLOCK_LEN];
+        if key.len() > BLOCK_LEN {
+            block_key[..DIGEST_LEN].copy_from_slice(&Sha256::digest(key));
+        } else {
+            block_key[..key.len()].copy_from_slice(key);
+        }
+
+        let mut inner = Sha256::new();
+        let mut outer = Sha256::new();
+        let mut pad = [0u8; BLOCK_LEN];
+        for (pad_byte, key_byte) in pad.iter_mut().zip(block_key.iter()) {
+            *pad_byte = key_byte ^ 0x36;
+        }
+        inner.update(&pad);
+        for (pad_byte, key_byte) in pad.iter_mut().zip(block_key.iter()) {
+            *pad_byte = key_byte ^ 0x5c;
+        }
+        outer.update(&pad);
+
+        HmacSha256 { inner, outer }
+    }
+
+    /// Feeds `data` to the computation.
+    pub fn update(&mut self, data: &[u8]) {
+        self.inner.update(data);
+    }
+
+    /// Returns the tag of all the data fed to the computation.
+    pub fn finalize(self) -> [u8; DIGEST_LEN] {
+        let mut outer = self.outer;
+        outer.update(&self.inner.finalize());
+        outer.finalize()
+    }
+
+    /// Checks, in constant time, that the tag of all the data fed to the computation
+    /// is `tag`.
+    pub fn verify(self, tag: &[u8]) -> bool {
+        let expected = self.finalize();
+        if tag.len() != expected.len() {
+            return false;
+        }
+        expected
+            .iter()
+            .zip(tag.iter())
+            .fold(0u8, |acc, (a, b)| acc | (a ^ b))
+            == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn to_hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    #[test]
+    fn test_sha256() {
+        assert_eq!(
+            to_hex(&Sha256::digest(b"")),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            to_hex(&Sha256::digest(b"abc")),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            to_hex(&Sha256::digest(
+                b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"
+            )),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+
+        // Feeding the data in pieces of any size yields the same digest.
+        let data: Vec<u8> = (0..1000u32).map(|i| (i * 31) as u8).collect();
+        let expected = Sha256::digest(&data);
+        for piece_len in [1, 7, 63, 64, 65, 500].iter() {
+            let mut hasher = Sha256::new();
+            for piece in data.chunks(*piece_len) {
+                hasher.update(piece);
+            }
+            assert_eq!(hasher.finalize(), expected);
+        }
+    }
+
+    #[test]
+    fn test_hmac_sha256() {
+        // Test cases 1, 2 and 6 from RFC 4231.
+        let mut hmac = HmacSha256::new(&[0x0b; 20]);
+        hmac.update(b"Hi There");
+        assert_eq!(
+            to_hex(&hmac.finalize()),
+            "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7"
+        );
+
+        let mut hmac = HmacSha256::new(b"Jefe");
+        hmac.update(b"what do ya want ");
+        hmac.update(b"for nothing?");
+        assert_eq!(
+            to_hex(&hmac.finalize()),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+
+        let mut hmac = HmacSha256::new(&[0xaa; 131]);
+        hmac.update(b"Test Using Larger Than Block-Size Key - Hash Key First");
+        let tag = hmac.clone().finalize();
+        assert_eq!(
+            to_hex(&tag),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+
+        assert!(hmac.clone().verify(&tag));
+        assert!(!hmac.clone().verify(&tag[..DIGEST_LEN - 1]));
+        let mut bad_tag = tag;
+        bad_tag[DIGEST_LEN - 1] ^= 1;
+        assert!(!hmac.verify(&bad_tag));
+    }
+}
@@ -0,0 +1,572 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Minimal wrapper over the Linux `io_uring` asynchronous I/O interface.
+//!
+//! Only the subset needed by Firecracker is implemented: one submission/completion queue pair,
+//! file read, write and fsync operations and eventfd based completion notifications.
+
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::ptr::null_mut;
+use std::result;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+// The io_uring syscall numbers are the same on x86_64 and aarch64.
+const SYS_IO_URING_SETUP: libc::c_long = 425;
+const SYS_IO_URING_ENTER: libc::c_long = 426;
+const SYS_IO_URING_REGISTER: libc::c_long = 427;
+
+const IORING_OFF_SQ_RING: libc::off_t = 0;
+const IORING_OFF_CQ_RING: libc::off_t = 0x800_0000;
+const IORING_OFF_SQES: libc::off_t = 0x1000_0000;
+
+const IORING_ENTER_GETEVENTS: u32 = 1;
+const IORING_REGISTER_EVENTFD: u32 = 4;
+
+const IORING_OP_FSYNC: u8 = 3;
+const IORING_OP_READ: u8 = 22;
+const IORING_OP_WRITE: u8 = 23;
+
+/// Errors associated with io_uring operations.
+#[derive(Debug)]
+pub enum Error {
+    /// Cannot create the io_uring instance.
+    Setup(io::Error),
+    /// Cannot map one of the io_uring rings.
+    Mmap(io::Error),
+    /// Cannot register the completion eventfd.
+    RegisterEventfd(io::Error),
+    /// The `io_uring_enter` syscall failed.
+    Enter(io::Error),
+    /// There is no room left in the rings for another operation.
+    FullQueue,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::Error::*;
+        match self {
+            Setup(e) => write!(f, "Cannot set up io_uring: {}", e),
+            Mmap(e) => write!(f, "Cannot map io_uring rings: {}", e),
+            RegisterEventfd(e) => write!(f, "Cannot register io_uring eventfd: {}", e),
+            Enter(e) => write!(f, "io_uring_enter failed: {}", e),
+            FullQueue => write!(f, "The io_uring queue is full"),
+        }
+    }
+}
+
+type Result<T> = result::Result<T, Error>;
+
+// The structures below mirror the kernel ABI, so not all of their fields are used.
+#[allow(dead_code)]
+#[repr(C)]
+#[derive(Default)]
+struct SqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    resv2: u64,
+}
+
+#[allow(dead_code)]
+#[repr(C)]
+#[derive(Default)]
+struct CqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    resv2: u64,
+}
+
+#[allow(dead_code)]
+#[repr(C)]
+#[derive(Default)]
+struct Params {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: SqringOffsets,
+    cq_off: CqringOffsets,
+}
+
+/// Submission queue entry, as defined by `struct io_uring_sqe`.
+#[allow(dead_code)]
+#[repr(C)]
+#[derive(Default)]
+struct Sqe {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    off: u64,
+    addr: u64,
+    len: u32,
+    op_flags: u32,
+    user_data: u64,
+    buf_index: u16,
+    personality: u16,
+    splice_fd_in: i32,
+    pad: [u64; 2],
+}
+
+/// Completion queue entry, as defined by `struct io_uring_cqe`.
+#[allow(dead_code)]
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Cqe {
+    user_data: u64,
+    res: i32,
+    flags: u32,
+}
+
+/// An operation that can be pushed to the submission queue.
+pub struct Operation {
+    opcode: u8,
+    fd: RawFd,
+    addr: u64,
+    len: u32,
+    offset: u64,
+    user_data: u64,
+}
+
+impl Operation {
+    /// Read `len` bytes from `fd`, starting at `offset`, into the buffer at `addr`.
+    pub fn read(fd: RawFd, addr: *mut u8, len: u32, offset: u64, user_data: u64) -> Self {
+        Operation {
+            opcode: IORING_OP_READ,
+            fd,
+            addr: addr as u64,
+            len,
+            offset,
+            user_data,
+        }
+    }
+
+    /// Write `len` bytes from the buffer at `addr` to `fd`, starting at `offset`.
+    pub fn write(fd: RawFd, addr: *const u8, len: u32, offset: u64, user_data: u64) -> Self {
+        Operation {
+            opcode: IORING_OP_WRITE,
+            fd,
+            addr: addr as u64,
+            len,
+            offset,
+            user_data,
+        }
+    }
+
+    /// Sync the data and metadata of `fd` to the physical media.
+    pub fn fsync(fd: RawFd, user_data: u64) -> Self {
+        Operation {
+            opcode: IORING_OP_FSYNC,
+            fd,
+            addr: 0,
+            len: 0,
+            offset: 0,
+            user_data,
+        }
+    }
+
+    fn into_sqe(self) -> Sqe {
+        Sqe {
+            opcode: self.opcode,
+            fd: self.fd,
+            off: self.offset,
+            addr: self.addr,
+            len: self.len,
+            user_data: self.user_data,
+            ..Default::default()
+        }
+    }
+}
+
+/// The outcome of a completed operation.
+#[derive(Debug)]
+pub struct Completion {
+    user_data: u64,
+    res: i32,
+}
+
+impl Completion {
+    /// The user data of the operation that completed.
+    pub fn user_data(&self) -> u64 {
+        self.user_data
+    }
+
+    /// The number of bytes transferred by the operation, or the error it failed with.
+    pub fn result(&self) -> io::Result<u32> {
+        if self.res < 0 {
+            Err(io::Error::from_raw_os_error(-self.res))
+        } else {
+            Ok(self.res as u32)
+        }
+    }
+}
+
+/// A shared memory mapping of one of the io_uring rings.
+struct RingMmap {
+    addr: *mut u8,
+    len: usize,
+}
+
+impl RingMmap {
+    fn new(fd: RawFd, len: usize, offset: libc::off_t) -> Result<Self> {
+        // Safe because we check the return value and the kernel validates the fd and offset.
+        let addr = unsafe {
+            libc::mmap(
+                null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_POPULATE,
+                fd,
+                offset,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(Error::Mmap(io::Error::last_os_error()));
+        }
+
+        Ok(RingMmap {
+            addr: addr as *mut u8,
+            len,
+        })
+    }
+
+    // The offset must come from the kernel provided ring offsets, so it is always within bounds.
+    fn ptr_at<T>(&self, offset: u32) -> *mut T {
+        debug_assert!((offset as usize) < self.len);
+        // Safe because the offset is within the mapping.
+        unsafe { self.addr.add(offset as usize) as *mut T }
+    }
+
+    fn atomic_at(&self, offset: u32) -> &AtomicU32 {
+        // Safe because the ring head and tail are u32 values shared with the kernel, aligned
+        // and living as long as the mapping.
+        unsafe { &*self.ptr_at::<AtomicU32>(offset) }
+    }
+}
+
+impl Drop for RingMmap {
+    fn drop(&mut self) {
+        // Safe because we own the mapping.
+        unsafe {
+            libc::munmap(self.addr as *mut libc::c_void, self.len);
+        }
+    }
+}
+
+// Safe because the mapping is owned by the `RingMmap` and is only accessed through `IoUring`,
+// which requires `&mut self` for all the operations touching it.
+unsafe impl Send for RingMmap {}
+
+/// An io_uring instance with its submission and completion queues.
+pub struct IoUring {
+    fd: File,
+    sq_ring: RingMmap,
+    cq_ring: RingMmap,
+    sqes: RingMmap,
+    sq_off: SqringOffsets,
+    cq_off: CqringOffsets,
+    sq_entries: u32,
+    // Operations pushed to the submission queue but not yet handed to the kernel.
+    to_submit: u32,
+    // Operations pushed to the submission queue whose completion was not popped yet.
+    num_ops: u32,
+}
+
+impl IoUring {
+    /// Creates a new io_uring instance with room for `num_entries` in-flight operations.
+    pub fn new(num_entries: u32) -> Result<Self> {
+        let mut params = Params::default();
+        // Safe because we check the return value and `params` lives for the whole call.
+        let fd =
+            unsafe { libc::syscall(SYS_IO_URING_SETUP, num_entries, &mut params as *mut Params) };
+        if fd < 0 {
+            return Err(Error::Setup(io::Error::last_os_error()));
+        }
+        // Safe because the fd was just created and is owned by nobody else.
+        let fd = unsafe { File::from_raw_fd(fd as RawFd) };
+
+        let sq_ring = RingMmap::new(
+            fd.as_raw_fd(),
+            params.sq_off.array as usize + params.sq_entries as usize * mem::size_of::<u32>(),
+            IORING_OFF_SQ_RING,
+        )?;
+        let cq_ring = RingMmap::new(
+            fd.as_raw_fd(),
+            params.cq_off.cqes as usize + params.cq_entries as usize * mem::size_of::<Cqe>(),
+            IORING_OFF_CQ_RING,
+        )?;
+        let sqes = RingMmap::new(
+            fd.as_raw_fd(),
+            params.sq_entries as usize * mem::size_of::<Sqe>(),
+            IORING_OFF_SQES,
+        )?;
+
+        Ok(IoUring {
+            fd,
+            sq_ring,
+            cq_ring,
+            sqes,
+            sq_entries: params.sq_entries,
+            sq_off: params.sq_off,
+            cq_off: params.cq_off,
+            to_submit: 0,
+            num_ops: 0,
+        })
+    }
+
+    /// Requests that the kernel signals `fd` whenever an operation completes.
+    pub fn register_eventfd(&self, fd: RawFd) -> Result<()> {
+        // Safe because we check the return value and `fd` lives for the whole call.
+        let ret = unsafe {
+            libc::syscall(
+                SYS_IO_URING_REGISTER,
+                self.fd.as_raw_fd(),
+                IORING_REGISTER_EVENTFD,
+                &fd as *const RawFd,
+                1,
+            )
+        };
+        if ret < 0 {
+            return Err(Error::RegisterEventfd(io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+
+    /// Pushes an operation to the submission queue. It will only be handed to the kernel on
+    /// the next call to `submit` or `submit_and_wait_all`.
+    ///
+    /// # Safety
+    ///
+    /// The buffer described by the operation must stay valid until its completion is popped.
+    pub unsafe fn push(&mut self, op: Operation) -> Result<()> {
+        if self.num_ops >= self.sq_entries {
+            return Err(Error::FullQueue);
+        }
+
+        let head = self
+            .sq_ring
+            .atomic_at(self.sq_off.head)
+            .load(Ordering::Acquire);
+        let tail = self
+            .sq_ring
+            .atomic_at(self.sq_off.tail)
+            .load(Ordering::Relaxed);
+        if tail.wrapping_sub(head) >= self.sq_entries {
+            return Err(Error::FullQueue);
+        }
+
+        let mask = *self.sq_ring.ptr_at::<u32>(self.sq_off.ring_mask);
+        let index = tail & mask;
+        self.sqes
+            .ptr_at::<Sqe>(0)
+            .add(index as usize)
+            .write(op.into_sqe());
+        self.sq_ring
+            .ptr_at::<u32>(self.sq_off.array)
+            .add(index as usize)
+            .write(index);
+        // Publish the new entry to the kernel.
+        self.sq_ring
+            .atomic_at(self.sq_off.tail)
+            .store(tail.wrapping_add(1), Ordering::Release);
+
+        self.to_submit += 1;
+        self.num_ops += 1;
+        Ok(())
+    }
+
+    /// Hands all the pushed operations to the kernel, without waiting for them to complete.
+    pub fn submit(&mut self) -> Result<u32> {
+        self.enter(0)
+    }
+
+    /// Hands all the pushed operations to the kernel and waits until all the in-flight
+    /// operations are completed.
+    pub fn submit_and_wait_all(&mut self) -> Result<u32> {
+        self.enter(self.num_ops)
+    }
+
+    fn enter(&mut self, min_complete: u32) -> Result<u32> {
+        if self.to_submit == 0 && min_complete == 0 {
+            return Ok(0);
+        }
+        let flags = if min_complete > 0 {
+            IORING_ENTER_GETEVENTS
+        } else {
+            0
+        };
+
+        let submitted = loop {
+            // Safe because we check the return value and pass no signal mask.
+            let ret = unsafe {
+                libc::syscall(
+                    SYS_IO_URING_ENTER,
+                    self.fd.as_raw_fd(),
+                    self.to_submit,
+                    min_complete,
+                    flags,
+                    std::ptr::null::<libc::sigset_t>(),
+                    0,
+                )
+            };
+            if ret >= 0 {
+                break ret as u32;
+            }
+            let err = io::Error::last_os_error();
+            // A signal delivered while waiting for completions interrupts the call, which is
+            // then retried.
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(Error::Enter(err));
+            }
+        };
+        self.to_submit -= std::cmp::min(submitted, self.to_submit);
+        Ok(submitted)
+    }
+
+    /// Pops the next completed operation, if any.
+    pub fn pop(&mut self) -> Option<Completion> {
+        let head = self
+            .cq_ring
+            .atomic_at(self.cq_off.head)
+            .load(Ordering::Relaxed);
+        let tail = self
+            .cq_ring
+            .atomic_at(self.cq_off.tail)
+            .load(Ordering::Acquire);
+        if head == tail {
+            return None;
+        }
+
+        // Safe because the mask and the entries are provided by the kernel and `head` is masked
+        // to be within the completion queue.
+        let cqe = unsafe {
+            let mask = *self.cq_ring.ptr_at::<u32>(self.cq_off.ring_mask);
+            self.cq_ring
+                .ptr_at::<Cqe>(self.cq_off.cqes)
+                .add((head & mask) as usize)
+                .read()
+        };
+        // Hand the entry back to the kernel.
+        self.cq_ring
+            .atomic_at(self.cq_off.head)
+            .store(head.wrapping_add(1), Ordering::Release);
+
+        self.num_ops = self.num_ops.saturating_sub(1);
+        Some(Completion {
+            user_data: cqe.user_data,
+            res: cqe.res,
+        })
+    }
+
+    /// Returns the number of operations that were pushed and whose completion was not popped.
+    pub fn num_ops(&self) -> u32 {
+        self.num_ops
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{Read, Seek, SeekFrom};
+
+    use crate::eventfd::EventFd;
+    use crate::tempfile::TempFile;
+
+    #[test]
+    fn test_read_write_fsync() {
+        let mut ring = IoUring::new(4).unwrap();
+        let evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        ring.register_eventfd(evt.as_raw_fd()).unwrap();
+
+        let file = TempFile::new().unwrap();
+        let fd = file.as_file().as_raw_fd();
+        let mut src = [0xaau8; 512];
+        src[0] = 0x55;
+        let mut dst = [0u8; 512];
+
+        unsafe {
+            ring.push(Operation::write(fd, src.as_ptr(), 512, 512, 1))
+                .unwrap();
+        }
+        assert_eq!(ring.num_ops(), 1);
+        ring.submit_and_wait_all().unwrap();
+        let completion = ring.pop().unwrap();
+        assert_eq!(completion.user_data(), 1);
+        assert_eq!(completion.result().unwrap(), 512);
+        assert!(ring.pop().is_none());
+        assert_eq!(ring.num_ops(), 0);
+        assert!(evt.read().unwrap() >= 1);
+
+        unsafe {
+            ring.push(Operation::read(fd, dst.as_mut_ptr(), 512, 512, 2))
+                .unwrap();
+            ring.push(Operation::fsync(fd, 3)).unwrap();
+        }
+        ring.submit_and_wait_all().unwrap();
+        let mut user_data = vec![];
+        while let Some(completion) = ring.pop() {
+            completion.result().unwrap();
+            user_data.push(completion.user_data());
+        }
+        user_data.sort_unstable();
+        assert_eq!(user_data, vec![2, 3]);
+        assert_eq!(src[..], dst[..]);
+
+        let mut buf = vec![];
+        let mut file = file.as_file();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf.len(), 1024);
+        assert_eq!(buf[512..], src[..]);
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut ring = IoUring::new(1).unwrap();
+        let mut buf = [0u8; 16];
+
+        // Nothing to submit.
+        assert_eq!(ring.submit().unwrap(), 0);
+
+        // Reading from an invalid fd is reported in the completion.
+        unsafe {
+            ring.push(Operation::read(-1, buf.as_mut_ptr(), 16, 0, 7))
+                .unwrap();
+        }
+        // The ring only has room for one in-flight operation.
+        unsafe {
+            assert!(matches!(
+                ring.push(Operation::fsync(-1, 8)),
+                Err(Error::FullQueue)
+            ));
+        }
+        ring.submit_and_wait_all().unwrap();
+        let completion = ring.pop().unwrap();
+        assert_eq!(completion.user_data(), 7);
+        assert_eq!(
+            completion.result().unwrap_err().raw_os_error(),
+            Some(libc::EBADF)
+        );
+    }
+}
@@ -0,0 +1,352 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Minimal wrapper over the Linux `userfaultfd` interface.
+//!
+//! Only the subset needed to serve guest memory from a separate process is implemented:
+//! registering memory ranges for missing page faults, reading the fault and remove events
+//! and resolving the faults by copying or zeroing pages.
+
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io::{self, Read};
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::result;
+
+use crate::ioctl::ioctl_with_mut_ref;
+use crate::{ioctl_expr, ioctl_ioc_nr, ioctl_iowr_nr};
+
+const UFFD_API: u64 = 0xAA;
+
+const UFFDIO_REGISTER_MODE_MISSING: u64 = 1;
+
+const UFFD_EVENT_PAGEFAULT: u8 = 0x12;
+const UFFD_EVENT_REMOVE: u8 = 0x15;
+
+/// Asks the kernel to report the ranges dropped with `madvise(MADV_DONTNEED)` or
+/// `madvise(MADV_REMOVE)` as `Event::Remove`.
+pub const UFFD_FEATURE_EVENT_REMOVE: u64 = 1 << 3;
+
+/// Errors associated with userfaultfd operations.
+#[derive(Debug)]
+pub enum Error {
+    /// Cannot create the userfaultfd object.
+    Create(io::Error),
+    /// The API handshake failed.
+    Api(io::Error),
+    /// The kernel doesn't support the requested features.
+    MissingFeatures(u64),
+    /// Cannot register a memory range.
+    Register(io::Error),
+    /// Cannot read an event.
+    ReadEvent(io::Error),
+    /// Cannot copy a page to the faulting range.
+    Copy(io::Error),
+    /// Cannot zero a page of the faulting range.
+    ZeroPage(io::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::Error::*;
+        match self {
+            Create(e) => write!(f, "Cannot create userfaultfd: {}", e),
+            Api(e) => write!(f, "userfaultfd API handshake failed: {}", e),
+            MissingFeatures(features) => {
+                write!(f, "userfaultfd features {:#x} are not supported", features)
+            }
+            Register(e) => write!(f, "Cannot register memory with userfaultfd: {}", e),
+            ReadEvent(e) => write!(f, "Cannot read userfaultfd event: {}", e),
+            Copy(e) => write!(f, "UFFDIO_COPY failed: {}", e),
+            ZeroPage(e) => write!(f, "UFFDIO_ZEROPAGE failed: {}", e),
+        }
+    }
+}
+
+type Result<T> = result::Result<T, Error>;
+
+// The structures below mirror the kernel ABI:
+// https://elixir.bootlin.com/linux/v4.14/source/include/uapi/linux/userfaultfd.h
+#[repr(C)]
+#[derive(Default)]
+struct UffdioApi {
+    api: u64,
+    features: u64,
+    ioctls: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct UffdioRange {
+    start: u64,
+    len: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct UffdioRegister {
+    range: UffdioRange,
+    mode: u64,
+    ioctls: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct UffdioCopy {
+    dst: u64,
+    src: u64,
+    len: u64,
+    mode: u64,
+    copy: i64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct UffdioZeropage {
+    range: UffdioRange,
+    mode: u64,
+    zeropage: i64,
+}
+
+// `struct uffd_msg`, with the `arg` union flattened to the fields of its largest members.
+#[repr(C)]
+#[derive(Default)]
+struct UffdMsg {
+    event: u8,
+    reserved1: u8,
+    reserved2: u16,
+    reserved3: u32,
+    arg0: u64,
+    arg1: u64,
+    arg2: u64,
+}
+
+const UFFDIO: u32 = 0xAA;
+ioctl_iowr_nr!(UFFDIO_API, UFFDIO, 0x3F, UffdioApi);
+ioctl_iowr_nr!(UFFDIO_REGISTER, UFFDIO, 0x00, UffdioRegister);
+ioctl_iowr_nr!(UFFDIO_COPY, UFFDIO, 0x03, UffdioCopy);
+ioctl_iowr_nr!(UFFDIO_ZEROPAGE, UFFDIO, 0x04, UffdioZeropage);
+
+/// Event read from a userfaultfd.
+#[derive(Debug, PartialEq)]
+pub enum Event {
+    /// A thread faulted on the missing page at `addr`, and waits for it to be resolved.
+    Pagefault {
+        /// Faulting address.
+        addr: u64,
+    },
+    /// The pages in `[start, end)` were dropped, and have to be zero filled on the next fault.
+    Remove {
+        /// Start of the range.
+        start: u64,
+        /// End of the range.
+        end: u64,
+    },
+}
+
+/// A userfaultfd object.
+pub struct Uffd {
+    file: File,
+}
+
+impl Uffd {
+    /// Creates a userfaultfd object and enables the requested `features` on it.
+    ///
+    /// The file descriptor is blocking: reading an event waits for one to be available.
+    pub fn new(features: u64) -> Result<Self> {
+        // Safe because we check the return value.
+        let fd = unsafe { libc::syscall(libc::SYS_userfaultfd, libc::O_CLOEXEC) };
+        if fd < 0 {
+            return Err(Error::Create(io::Error::last_os_error()));
+        }
+        // Safe because the fd was just created and is owned by nobody else.
+        let file = unsafe { File::from_raw_fd(fd as RawFd) };
+
+        let mut api = UffdioApi {
+            api: UFFD_API,
+            features,
+            ..Default::default()
+        };
+        // Safe because we check the return value and `api` matches the request.
+        let ret = unsafe { ioctl_with_mut_ref(&file, UFFDIO_API(), &mut api) };
+        if ret < 0 {
+            let err = io::Error::last_os_error();
+            // The handshake is rejected as a whole when a feature is not supported.
+            if err.raw_os_error() == Some(libc::EINVAL) && features != 0 {
+                return Err(Error::MissingFeatures(features));
+            }
+            return Err(Error::Api(err));
+        }
+
+        Ok(Uffd { file })
+    }
+
+    /// Registers the `len` bytes starting at host virtual address `start` for missing
+    /// page faults.
+    pub fn register(&self, start: u64, len: u64) -> Result<()> {
+        let mut register = UffdioRegister {
+            range: UffdioRange { start, len },
+            mode: UFFDIO_REGISTER_MODE_MISSING,
+            ..Default::default()
+        };
+        // Safe because we check the return value and `register` matches the request.
+        let ret = unsafe { ioctl_with_mut_ref(&self.file, UFFDIO_REGISTER(), &mut register) };
+        if ret < 0 {
+            return Err(Error::Register(io::Error::last_os_error()));
+        }
+
+        Ok(())
+    }
+
+    /// Reads the next event. Events other than page faults and removals are skipped.
+    pub fn read_event(&mut self) -> Result<Event> {
+        loop {
+            let mut msg = UffdMsg::default();
+            // Safe because `UffdMsg` is a plain C struct, valid for any content.
+            let buf = unsafe {
+                std::slice::from_raw_parts_mut(
+                    &mut msg as *mut UffdMsg as *mut u8,
+                    mem::size_of::<UffdMsg>(),
+                )
+            };
+            self.file.read_exact(buf).map_err(Error::ReadEvent)?;
+
+            match msg.event {
+                // `struct uffd_pagefault` starts with the flags, followed by the address.
+                UFFD_EVENT_PAGEFAULT => return Ok(Event::Pagefault { addr: msg.arg1 }),
+                UFFD_EVENT_REMOVE => {
+                    return Ok(Event::Remove {
+                        start: msg.arg0,
+                        end: msg.arg1,
+                    })
+                }
+                _ => continue,
+            }
+        }
+    }
+
+    /// Resolves a fault by copying `src` at host virtual address `dst` of the registered
+    /// process, and waking up the faulting threads.
+    ///
+    /// Returns `Ok(false)` if the copy must be retried after the pending events are read,
+    /// as signaled by the kernel with `EAGAIN`.
+    pub fn copy(&self, src: &[u8], dst: u64) -> Result<bool> {
+        let mut copy = UffdioCopy {
+            dst,
+            src: src.as_ptr() as u64,
+            len: src.len() as u64,
+            ..Default::default()
+        };
+        // Safe because we check the return value and `copy` matches the request. The kernel
+        // only reads the `src` slice.
+        let ret = unsafe { ioctl_with_mut_ref(&self.file, UFFDIO_COPY(), &mut copy) };
+        Self::resolve_result(ret).map_err(Error::Copy)
+    }
+
+    /// Resolves a fault by zeroing the `len` bytes at host virtual address `dst` of the
+    /// registered process, and waking up the faulting threads.
+    ///
+    /// Returns `Ok(false)` if the operation must be retried after the pending events are
+    /// read, as signaled by the kernel with `EAGAIN`.
+    pub fn zero_page(&self, dst: u64, len: u64) -> Result<bool> {
+        let mut zeropage = UffdioZeropage {
+            range: UffdioRange { start: dst, len },
+            ..Default::default()
+        };
+        // Safe because we check the return value and `zeropage` matches the request.
+        let ret = unsafe { ioctl_with_mut_ref(&self.file, UFFDIO_ZEROPAGE(), &mut zeropage) };
+        Self::resolve_result(ret).map_err(Error::ZeroPage)
+    }
+
+    fn resolve_result(ret: i32) -> io::Result<bool> {
+        if ret < 0 {
+            let err = io::Error::last_os_error();
+            return match err.raw_os_error() {
+                // The page was already populated by a concurrent fault.
+                Some(libc::EEXIST) => Ok(true),
+                Some(libc::EAGAIN) => Ok(false),
+                _ => Err(err),
+            };
+        }
+
+        Ok(true)
+    }
+}
+
+impl From<File> for Uffd {
+    /// Wraps a userfaultfd received from another process.
+    fn from(file: File) -> Self {
+        Uffd { file }
+    }
+}
+
+impl AsRawFd for Uffd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_uffd_structs_layout() {
+        // The ioctl numbers encode the size of the UAPI structures.
+        assert_eq!(mem::size_of::<UffdioApi>(), 24);
+        assert_eq!(mem::size_of::<UffdioRegister>(), 32);
+        assert_eq!(mem::size_of::<UffdioCopy>(), 40);
+        assert_eq!(mem::size_of::<UffdioZeropage>(), 32);
+        assert_eq!(mem::size_of::<UffdMsg>(), 32);
+
+        assert_eq!(UFFDIO_API(), 0xC018_AA3F);
+        assert_eq!(UFFDIO_REGISTER(), 0xC020_AA00);
+        assert_eq!(UFFDIO_COPY(), 0xC028_AA03);
+        assert_eq!(UFFDIO_ZEROPAGE(), 0xC020_AA04);
+    }
+
+    #[test]
+    fn test_serve_missing_pages() {
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        // Safe because we check the return value and unmap the memory below.
+        let addr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                2 * page_size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(addr, libc::MAP_FAILED);
+        let start = addr as u64;
+
+        let mut uffd = Uffd::new(0).unwrap();
+        uffd.register(start, 2 * page_size as u64).unwrap();
+
+        // The first page is served with a pattern, the second one with zeroes.
+        let reader = std::thread::spawn(move || {
+            let first = start as *const u8;
+            // Safe because both pages are mapped.
+            unsafe { (*first, *first.add(page_size)) }
+        });
+        let pattern = vec![0xAAu8; page_size];
+        for _ in 0..2 {
+            match uffd.read_event().unwrap() {
+                Event::Pagefault { addr } if addr - start < page_size as u64 => {
+                    assert!(uffd.copy(&pattern, start).unwrap())
+                }
+                Event::Pagefault { .. } => assert!(uffd
+                    .zero_page(start + page_size as u64, page_size as u64)
+                    .unwrap()),
+                event => panic!("Unexpected event: {:?}", event),
+            }
+        }
+        assert_eq!(reader.join().unwrap(), (0xAA, 0));
+
+        // Safe because the memory was mapped above and is no longer used.
+        unsafe { libc::munmap(addr, 2 * page_size) };
+    }
+}
@@ -46,6 +46,9 @@ static MAX_ACCESS_CHUNK: usize = 4096;
 // The number of guard pages per region is a multiple of 2.
 const GUARD_NUMBER: usize = 2;
 
+/// The size of the huge pages used for hugetlbfs backed regions.
+pub const HUGE_PAGE_2M_SIZE: usize = 2 << 20;
+
 /// [`GuestMemoryRegion`](trait.GuestMemoryRegion.html) implementation that mmaps the guest's
 /// memory region in the current process.
 ///
@@ -115,7 +118,8 @@ impl GuestRegionMmap {
     /// * `prot` - The desired memory protection of the mapping.
     /// * `flags` - This argument determines whether updates to the mapping are visible to other
     ///             processes mapping the same region, and whether updates are carried through to
-    ///             the underlying file.
+    ///             the underlying file. With `MAP_HUGETLB`, the mapping is backed by 2M huge
+    ///             pages, so it is aligned to 2M and the guards are 2M wide.
     pub fn build_guarded(
         file_offset: Option<FileOffset>,
         size: usize,
@@ -123,9 +127,16 @@ impl GuestRegionMmap {
         flags: i32,
     ) -> Result<MmapRegion, MmapRegionError> {
         let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize };
-        // Create the guarded range size (received size + X pages),
-        // where X is defined as a constant GUARD_NUMBER.
-        let guarded_size = size + GUARD_NUMBER * page_size;
+        // A MAP_FIXED hugetlb mapping has to start on a huge page boundary.
+        let guard_size = if flags & libc::MAP_HUGETLB != 0 {
+            HUGE_PAGE_2M_SIZE
+        } else {
+            page_size
+        };
+        // Create the guarded range size (received size + X guards),
+        // where X is defined as a constant GUARD_NUMBER, plus the room
+        // needed to align the mapping to the guard size.
+        let guarded_size = size + GUARD_NUMBER * guard_size + (guard_size - page_size);
 
         // Map the guarded range to PROT_NONE
         let guard_addr = unsafe {
@@ -150,9 +161,10 @@ impl GuestRegionMmap {
             (-1, 0)
         };
 
-        let map_addr = guard_addr as usize + page_size * (GUARD_NUMBER / 2);
+        let aligned_guard_addr = (guard_addr as usize + guard_size - 1) & !(guard_size - 1);
+        let map_addr = aligned_guard_addr + guard_size * (GUARD_NUMBER / 2);
 
-        // Inside the protected range, starting with guard_addr + PAGE_SIZE,
+        // Inside the protected range, starting with the aligned guard_addr + guard_size,
         // map the requested range with received protection and flags
         let addr = unsafe {
             libc::mmap(
@@ -422,6 +434,34 @@ impl GuestMemoryMmap {
         )
     }
 
+    /// Creates a container and allocates anonymous memory backed by 2M huge pages for guest
+    /// memory regions. Each region is surrounded by guard pages. Allows the setting of dirty
+    /// page tracking.
+    ///
+    /// The huge pages have to be reserved on the host, e.g. through
+    /// `/proc/sys/vm/nr_hugepages`. The size of each region has to be a 2M multiple.
+    pub fn from_ranges_hugetlb_guarded(
+        ranges: &[(GuestAddress, usize)],
+        track_dirty_pages: bool,
+    ) -> result::Result<Self, Error> {
+        Self::from_ranges_with_files_helper(
+            ranges.iter().map(|r| (r.0, r.1, None)),
+            track_dirty_pages,
+            |_: Option<FileOffset>, size: usize| -> Result<MmapRegion, MmapRegionError> {
+                let prot = libc::PROT_READ | libc::PROT_WRITE;
+                // Without MAP_NORESERVE, the huge pages are reserved by the mmap call, which
+                // fails if there aren't enough of them, instead of the guest getting a SIGBUS
+                // on its first access to a missing page.
+                let flags = libc::MAP_PRIVATE
+                    | libc::MAP_ANONYMOUS
+                    | libc::MAP_HUGETLB
+                    | libc::MAP_HUGE_2MB;
+
+                GuestRegionMmap::build_guarded(None, size, prot, flags)
+            },
+        )
+    }
+
     /// Helper function for from_ranges_with_files.
     /// Allows setting a custom memory range build function.
     ///
@@ -1812,4 +1852,29 @@ mod tests {
             })
             .unwrap();
     }
+
+    #[test]
+    fn test_regions_hugetlb_guarded() {
+        let regions = vec![
+            (GuestAddress(0x0), HUGE_PAGE_2M_SIZE),
+            (GuestAddress(0x20_0000), 2 * HUGE_PAGE_2M_SIZE),
+        ];
+
+        // The host may not have any huge pages reserved.
+        let guest_memory = match GuestMemoryMmap::from_ranges_hugetlb_guarded(&regions, true) {
+            Ok(guest_memory) => guest_memory,
+            Err(Error::MmapRegion(MmapRegionError::Mmap(_))) => return,
+            Err(e) => panic!("{:?}", e),
+        };
+        guest_memory
+            .with_regions(|_, region| -> Result<(), Error> {
+                assert_eq!(region.as_ptr() as usize % HUGE_PAGE_2M_SIZE, 0);
+                assert!(region.flags() & libc::MAP_HUGETLB != 0);
+                // Dirty pages are still tracked at the granularity of the base pages.
+                assert!(region.dirty_bitmap().is_some());
+                validate_guard_region(region);
+                Ok(())
+            })
+            .unwrap();
+    }
 }
@@ -19,7 +19,9 @@ use vmm::persist::MicrovmState;
 use vmm::utilities::mock_resources::NOISY_KERNEL_IMAGE;
 use vmm::utilities::test_utils::create_vmm;
 use vmm::version_map::VERSION_MAP;
-use vmm::vmm_config::snapshot::{CreateSnapshotParams, SnapshotType};
+use vmm::vmm_config::snapshot::{
+    CreateSnapshotParams, MemFileFormat, SnapshotDestinationType, SnapshotType,
+};
 use vmm::{persist, FC_EXIT_CODE_OK};
 
 #[inline]
@@ -75,7 +77,10 @@ fn create_microvm_state(is_diff: bool) -> MicrovmState {
         snapshot_type,
         snapshot_path: snapshot_file.as_path().to_path_buf(),
         mem_file_path: memory_file.as_path().to_path_buf(),
+        destination_type: SnapshotDestinationType::File,
+        mem_file_format: MemFileFormat::Raw,
         version: None,
+        integrity_key: None,
     };
 
     {
@@ -3,10 +3,12 @@
 
 //! Enables pre-boot setup, instantiation and booting of a Firecracker VMM.
 
+use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::fmt::{Display, Formatter};
+use std::fs::File;
 use std::io::{self, Read, Seek, SeekFrom};
-use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::sync::{Arc, Mutex};
 
 #[cfg(target_arch = "aarch64")]
@@ -17,6 +19,8 @@ use crate::device_manager::mmio::MMIODeviceManager;
 use crate::device_manager::persist::MMIODevManagerConstructorArgs;
 use crate::persist::{MicrovmState, MicrovmStateError};
 use crate::vmm_config::boot_source::BootConfig;
+#[cfg(target_arch = "x86_64")]
+use crate::vstate::vcpu::{build_vcpu_cpuid, msr_modifier_entries};
 use crate::vstate::{
     system::KvmContext,
     vcpu::{Vcpu, VcpuConfig},
@@ -25,6 +29,8 @@ use crate::vstate::{
 use crate::{device_manager, Error, EventManager, Vmm, VmmEventsObserver};
 
 use crate::vmm_config::instance_info::InstanceInfo;
+use crate::vmm_config::machine_config::HugePageConfig;
+use crate::vmm_config::snapshot::NetworkOverride;
 use arch::InitrdConfig;
 #[cfg(target_arch = "x86_64")]
 use cpuid::common::is_same_model;
@@ -34,6 +40,8 @@ use devices::legacy::Serial;
 use devices::virtio::{Balloon, Block, MmioTransport, Net, VirtioDevice, Vsock, VsockUnixBackend};
 use event_manager::{MutEventSubscriber, SubscriberOps};
 use kernel::cmdline::Cmdline as KernelCmdline;
+#[cfg(target_arch = "x86_64")]
+use kvm_bindings::{kvm_msr_entry, CpuId};
 #[cfg(target_arch = "aarch64")]
 use logger::METRICS;
 use logger::{error, warn};
@@ -42,10 +50,13 @@ use snapshot::Persist;
 use utils::eventfd::EventFd;
 use utils::terminal::Terminal;
 use utils::time::TimestampUs;
-use vm_memory::{GuestAddress, GuestMemoryMmap};
+use vm_memory::{FileOffset, GuestAddress, GuestMemoryMmap};
 #[cfg(target_arch = "aarch64")]
 use vm_superio::RTC;
 
+// Name of the memfd backing the shared guest memory, as shown in /proc/<pid>/fd.
+const GUEST_MEMFD_NAME: &[u8] = b"guest_mem\0";
+
 /// Errors associated with starting the instance.
 #[derive(Debug)]
 pub enum StartMicrovmError {
@@ -57,6 +68,8 @@ pub enum StartMicrovmError {
     CreateNetDevice(devices::virtio::net::Error),
     /// Failed to create a `RateLimiter` object.
     CreateRateLimiter(io::Error),
+    /// Cannot create the memfd backing the shared guest memory.
+    GuestMemfd(io::Error),
     /// Memory regions are overlapping or mmap fails.
     GuestMemoryMmap(vm_memory::Error),
     /// Cannot load initrd due to an invalid memory configuration.
@@ -110,6 +123,11 @@ impl Display for StartMicrovmError {
 
                 write!(f, "Cannot create network device. {}", err_msg)
             }
+            GuestMemfd(err) => write!(
+                f,
+                "Cannot create the memfd backing the shared guest memory: {}",
+                err
+            ),
             GuestMemoryMmap(err) => {
                 // Remove imbricated quotes from error message.
                 let mut err_msg = format!("{:?}", err);
@@ -281,7 +299,11 @@ fn create_vmm_and_vcpus(
         shutdown_exit_code: None,
         vm,
         guest_memory,
+        uffd: None,
+        guest_memfd: None,
         vcpus_handles: Vec::new(),
+        plugged_vcpus: vcpu_count as usize,
+        ht_enabled: false,
         vcpus_exit_evt,
         mmio_device_manager,
         #[cfg(target_arch = "x86_64")]
@@ -312,13 +334,22 @@ pub fn build_microvm_for_boot(
     let boot_config = vm_resources.boot_source().ok_or(MissingKernelConfig)?;
 
     let track_dirty_pages = vm_resources.track_dirty_pages();
-    let guest_memory = create_guest_memory(
-        vm_resources
-            .vm_config()
-            .mem_size_mib
-            .ok_or(MissingMemSizeConfig)?,
-        track_dirty_pages,
-    )?;
+    let mem_size_mib = vm_resources
+        .vm_config()
+        .mem_size_mib
+        .ok_or(MissingMemSizeConfig)?;
+    let (guest_memory, guest_memfd) = if vm_resources.vm_config().shared_memory {
+        let (guest_memory, guest_memfd) =
+            create_shared_guest_memory(mem_size_mib, track_dirty_pages)?;
+        (guest_memory, Some(guest_memfd))
+    } else {
+        let guest_memory = create_guest_memory(
+            mem_size_mib,
+            track_dirty_pages,
+            vm_resources.vm_config().huge_pages,
+        )?;
+        (guest_memory, None)
+    };
     let vcpu_config = vm_resources.vcpu_config();
     let entry_addr = load_kernel(boot_config, &guest_memory)?;
     let initrd = load_initrd_from_config(boot_config, &guest_memory)?;
@@ -326,13 +357,18 @@ pub fn build_microvm_for_boot(
     #[allow(unused_mut)]
     let mut boot_cmdline = boot_config.cmdline.clone();
 
+    // The vCPUs that can be hot-added are created upfront, since vCPU threads can't be
+    // spawned once the VMM thread is sandboxed. They are kept paused until they are.
     let (mut vmm, mut vcpus) = create_vmm_and_vcpus(
         instance_info,
         event_manager,
         guest_memory,
         track_dirty_pages,
-        vcpu_config.vcpu_count,
+        vcpu_config.max_vcpu_count,
     )?;
+    vmm.guest_memfd = guest_memfd;
+    vmm.plugged_vcpus = vcpu_config.vcpu_count as usize;
+    vmm.ht_enabled = vcpu_config.ht_enabled;
 
     // The boot timer device needs to be the first device attached in order
     // to maintain the same MMIO address referenced in the documentation
@@ -364,6 +400,13 @@ pub fn build_microvm_for_boot(
     #[cfg(target_arch = "aarch64")]
     attach_legacy_devices_aarch64(event_manager, &mut vmm, &mut boot_cmdline).map_err(Internal)?;
 
+    // All the vCPUs are listed in the MP table, the guest is only told not to bring up the ones
+    // that aren't plugged yet.
+    #[cfg(target_arch = "x86_64")]
+    if vcpu_config.max_vcpu_count > vcpu_config.vcpu_count {
+        boot_cmdline.insert("maxcpus", vcpu_config.vcpu_count.to_string().as_str())?;
+    }
+
     configure_system_for_boot(
         &vmm,
         vcpus.as_mut(),
@@ -408,18 +451,25 @@ pub fn build_microvm_for_boot(
 ///
 /// An `Arc` reference of the built `Vmm` is also plugged in the `EventManager`, while another
 /// is returned.
+#[allow(clippy::too_many_arguments)]
 pub fn build_microvm_from_snapshot(
     instance_info: &InstanceInfo,
     event_manager: &mut EventManager,
     microvm_state: MicrovmState,
     guest_memory: GuestMemoryMmap,
     track_dirty_pages: bool,
+    net_overrides: &[NetworkOverride],
+    drive_path_overrides: &HashMap<String, String>,
     seccomp_filters: &BpfThreadMap,
 ) -> std::result::Result<Arc<Mutex<Vmm>>, StartMicrovmError> {
     use self::StartMicrovmError::*;
     let vcpu_count = u8::try_from(microvm_state.vcpu_states.len())
         .map_err(|_| MicrovmStateError::InvalidInput)
         .map_err(RestoreMicrovmState)?;
+    let plugged_vcpus = microvm_state.plugged_vcpu_count.unwrap_or(vcpu_count);
+    if plugged_vcpus > vcpu_count {
+        return Err(RestoreMicrovmState(MicrovmStateError::InvalidInput));
+    }
 
     // Build Vmm.
     let (mut vmm, vcpus) = create_vmm_and_vcpus(
@@ -429,6 +479,8 @@ pub fn build_microvm_from_snapshot(
         track_dirty_pages,
         vcpu_count,
     )?;
+    vmm.plugged_vcpus = plugged_vcpus as usize;
+    vmm.ht_enabled = microvm_state.ht_enabled;
 
     #[cfg(target_arch = "x86_64")]
     // Check if we need to scale the TSC.
@@ -487,6 +539,8 @@ pub fn build_microvm_from_snapshot(
         mem: guest_memory,
         vm: vmm.vm.fd(),
         event_manager,
+        net_overrides,
+        drive_path_overrides,
     };
     vmm.mmio_device_manager =
         MMIODeviceManager::restore(mmio_ctor_args, &microvm_state.device_states)
@@ -523,16 +577,65 @@ pub fn build_microvm_from_snapshot(
     Ok(vmm)
 }
 
-/// Creates GuestMemory of `mem_size_mib` MiB in size.
+/// Creates GuestMemory of `mem_size_mib` MiB in size, backed by `huge_pages`.
 pub fn create_guest_memory(
     mem_size_mib: usize,
     track_dirty_pages: bool,
+    huge_pages: HugePageConfig,
 ) -> std::result::Result<GuestMemoryMmap, StartMicrovmError> {
     let mem_size = mem_size_mib << 20;
     let arch_mem_regions = arch::arch_memory_regions(mem_size);
 
-    GuestMemoryMmap::from_ranges_guarded(&arch_mem_regions, track_dirty_pages)
-        .map_err(StartMicrovmError::GuestMemoryMmap)
+    match huge_pages {
+        HugePageConfig::None => {
+            GuestMemoryMmap::from_ranges_guarded(&arch_mem_regions, track_dirty_pages)
+        }
+        HugePageConfig::Hugetlbfs2M => {
+            GuestMemoryMmap::from_ranges_hugetlb_guarded(&arch_mem_regions, track_dirty_pages)
+        }
+    }
+    .map_err(StartMicrovmError::GuestMemoryMmap)
+}
+
+/// Creates GuestMemory of `mem_size_mib` MiB in size, backed by a memfd mapped as shared, so
+/// that other processes can access it. Returns the memfd along with the guest memory.
+///
+/// The regions are laid out back to back in the memfd, as in a full snapshot memory file.
+pub fn create_shared_guest_memory(
+    mem_size_mib: usize,
+    track_dirty_pages: bool,
+) -> std::result::Result<(GuestMemoryMmap, File), StartMicrovmError> {
+    use self::StartMicrovmError::GuestMemfd;
+
+    let mem_size = mem_size_mib << 20;
+    let arch_mem_regions = arch::arch_memory_regions(mem_size);
+
+    // Safe because the name is a valid C string and the result is checked.
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_memfd_create,
+            GUEST_MEMFD_NAME.as_ptr(),
+            libc::MFD_CLOEXEC,
+        )
+    };
+    if fd < 0 {
+        return Err(GuestMemfd(io::Error::last_os_error()));
+    }
+    // Safe because the fd was just created and is owned by nothing else.
+    let guest_memfd = unsafe { File::from_raw_fd(fd as RawFd) };
+    guest_memfd.set_len(mem_size as u64).map_err(GuestMemfd)?;
+
+    let mut offset = 0;
+    let mut ranges = Vec::with_capacity(arch_mem_regions.len());
+    for (guest_address, size) in arch_mem_regions {
+        let file = guest_memfd.try_clone().map_err(GuestMemfd)?;
+        ranges.push((guest_address, size, Some(FileOffset::new(file, offset))));
+        offset += size as u64;
+    }
+
+    let guest_memory = GuestMemoryMmap::from_ranges_with_files_guarded(ranges, track_dirty_pages)
+        .map_err(StartMicrovmError::GuestMemoryMmap)?;
+    Ok((guest_memory, guest_memfd))
 }
 
 fn load_kernel(
@@ -625,6 +728,52 @@ pub(crate) fn setup_kvm_vm(
     Ok(vm)
 }
 
+/// The CPU configuration of the guest vCPUs of a microVM booted from `VmResources`.
+#[cfg(target_arch = "x86_64")]
+pub struct GuestCpuConfig {
+    /// The CPUID of each vCPU.
+    pub cpuids: Vec<CpuId>,
+    /// The MSRs set on every vCPU, each one with the last value it's set to.
+    pub msrs: Vec<kvm_msr_entry>,
+}
+
+/// Computes the CPU configuration the guest vCPUs would get when booting a microVM with
+/// `vm_resources`, applying the CPU templates the same way, without creating the vCPUs.
+#[cfg(target_arch = "x86_64")]
+pub fn guest_cpu_config(
+    vm_resources: &super::resources::VmResources,
+) -> std::result::Result<GuestCpuConfig, StartMicrovmError> {
+    use self::StartMicrovmError::Internal;
+    let kvm = KvmContext::new()
+        .map_err(Error::KvmContext)
+        .map_err(Internal)?;
+    let vm = Vm::new(kvm.fd()).map_err(Error::Vm).map_err(Internal)?;
+    let vcpu_config = vm_resources.vcpu_config();
+
+    // The vCPUs that can be hot-added are included, since the guest sees them all.
+    let cpuids = (0..vcpu_config.max_vcpu_count)
+        .map(|cpu_index| build_vcpu_cpuid(cpu_index, &vcpu_config, vm.supported_cpuid().clone()))
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(Error::VcpuConfigure)
+        .map_err(Internal)?;
+
+    let mut msrs = arch::x86_64::msr::create_boot_msr_entries();
+    if let Some(template) = &vcpu_config.custom_cpu_template {
+        let entries = msr_modifier_entries(&template.msr_modifiers, vm.supported_msrs())
+            .map_err(Error::VcpuConfigure)
+            .map_err(Internal)?;
+        // The template MSRs are set after the boot ones, so they override them.
+        for entry in entries {
+            match msrs.iter_mut().find(|msr| msr.index == entry.index) {
+                Some(msr) => msr.data = entry.data,
+                None => msrs.push(entry),
+            }
+        }
+    }
+
+    Ok(GuestCpuConfig { cpuids, msrs })
+}
+
 /// Sets up the irqchip for a x86_64 microVM.
 #[cfg(target_arch = "x86_64")]
 pub fn setup_interrupt_controller(vm: &mut Vm) -> std::result::Result<(), StartMicrovmError> {
@@ -910,9 +1059,13 @@ pub mod tests {
     use std::io::Cursor;
 
     use super::*;
+    #[cfg(target_arch = "x86_64")]
+    use crate::resources::VmResources;
     use crate::vmm_config::balloon::{BalloonBuilder, BalloonDeviceConfig, BALLOON_DEV_ID};
     use crate::vmm_config::boot_source::DEFAULT_KERNEL_CMDLINE;
-    use crate::vmm_config::drive::{BlockBuilder, BlockDeviceConfig, CacheType};
+    use crate::vmm_config::drive::{BlockBuilder, BlockDeviceConfig, CacheType, FileEngineType};
+    #[cfg(target_arch = "x86_64")]
+    use crate::vmm_config::machine_config::VmConfig;
     use crate::vmm_config::net::{NetBuilder, NetworkInterfaceConfig};
     use crate::vmm_config::vsock::tests::default_config;
     use crate::vmm_config::vsock::{VsockBuilder, VsockDeviceConfig};
@@ -969,7 +1122,7 @@ pub mod tests {
     }
 
     pub(crate) fn default_vmm() -> Vmm {
-        let guest_memory = create_guest_memory(128, false).unwrap();
+        let guest_memory = create_guest_memory(128, false, HugePageConfig::None).unwrap();
 
         let vcpus_exit_evt = EventFd::new(libc::EFD_NONBLOCK)
             .map_err(Error::EventFd)
@@ -997,7 +1150,11 @@ pub mod tests {
             shutdown_exit_code: None,
             vm,
             guest_memory,
+            uffd: None,
+            guest_memfd: None,
             vcpus_handles: Vec::new(),
+            plugged_vcpus: 0,
+            ht_enabled: false,
             vcpus_exit_evt,
             mmio_device_manager,
             #[cfg(target_arch = "x86_64")]
@@ -1029,6 +1186,11 @@ pub mod tests {
                 is_read_only: custom_block_cfg.is_read_only,
                 cache_type: custom_block_cfg.cache_type,
                 rate_limiter: None,
+                io_engine: FileEngineType::Sync,
+                is_discard_enabled: false,
+                is_write_zeroes_enabled: false,
+                num_queues: 1,
+                overlay_path_on_host: None,
             };
             block_dev_configs.insert(block_device_config).unwrap();
         }
@@ -1158,21 +1320,63 @@ pub mod tests {
 
         // Case 1: create guest memory without dirty page tracking
         {
-            let guest_memory = create_guest_memory(mem_size, false).unwrap();
+            let guest_memory = create_guest_memory(mem_size, false, HugePageConfig::None).unwrap();
             assert!(!guest_memory.is_dirty_tracking_enabled());
         }
 
         // Case 2: create guest memory with dirty page tracking
         {
-            let guest_memory = create_guest_memory(mem_size, true).unwrap();
+            let guest_memory = create_guest_memory(mem_size, true, HugePageConfig::None).unwrap();
             assert!(guest_memory.is_dirty_tracking_enabled());
         }
+
+        // Case 3: create guest memory backed by huge pages, if the host has enough of them
+        // reserved.
+        {
+            use vm_memory::GuestMemory;
+
+            if let Ok(guest_memory) = create_guest_memory(4, true, HugePageConfig::Hugetlbfs2M) {
+                assert!(guest_memory.is_dirty_tracking_enabled());
+                guest_memory
+                    .with_regions(|_, region| -> std::result::Result<(), ()> {
+                        assert_eq!(region.as_ptr() as usize % (2 << 20), 0);
+                        Ok(())
+                    })
+                    .unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_is_guest_memfd() {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+        use std::path::PathBuf;
+
+        let mut vmm = default_vmm();
+        let (_, guest_memfd) = create_shared_guest_memory(2, false).unwrap();
+        let memfd_path = PathBuf::from(format!("/proc/self/fd/{}", guest_memfd.as_raw_fd()));
+        assert!(!vmm.is_guest_memfd(&memfd_path));
+
+        vmm.guest_memfd = Some(guest_memfd);
+        assert!(vmm.is_guest_memfd(&memfd_path));
+        let file = TempFile::new().unwrap();
+        assert!(!vmm.is_guest_memfd(file.as_path()));
+        assert!(!vmm.is_guest_memfd(&file.as_path().with_extension("missing")));
+
+        // Opening a FIFO would block until a writer shows up.
+        let mut fifo = TempFile::new().unwrap();
+        fifo.remove().unwrap();
+        let fifo_path = CString::new(fifo.as_path().as_os_str().as_bytes()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(fifo_path.as_ptr(), 0o600) }, 0);
+        assert!(!vmm.is_guest_memfd(fifo.as_path()));
+        std::fs::remove_file(fifo.as_path()).unwrap();
     }
 
     #[test]
     fn test_create_vcpus() {
         let vcpu_count = 2;
-        let guest_memory = create_guest_memory(128, false).unwrap();
+        let guest_memory = create_guest_memory(128, false, HugePageConfig::None).unwrap();
 
         #[allow(unused_mut)]
         let mut vm = setup_kvm_vm(&guest_memory, false).unwrap();
@@ -1185,6 +1389,40 @@ pub mod tests {
         assert_eq!(vcpu_vec.len(), vcpu_count as usize);
     }
 
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_guest_cpu_config() {
+        let mut vm_resources = VmResources::default();
+        vm_resources
+            .set_vm_config(&VmConfig {
+                vcpu_count: Some(2),
+                ..Default::default()
+            })
+            .unwrap();
+
+        let cpu_config = guest_cpu_config(&vm_resources).unwrap();
+        assert_eq!(cpu_config.cpuids.len(), 2);
+        assert_eq!(
+            cpu_config.msrs.len(),
+            arch::x86_64::msr::create_boot_msr_entries().len()
+        );
+        // Each vCPU gets its own APIC ID.
+        let apic_ids: Vec<u32> = cpu_config
+            .cpuids
+            .iter()
+            .map(|cpuid| {
+                cpuid
+                    .as_slice()
+                    .iter()
+                    .find(|entry| entry.function == 0x1)
+                    .unwrap()
+                    .ebx
+                    >> 24
+            })
+            .collect();
+        assert_eq!(apic_ids, vec![0, 1]);
+    }
+
     #[test]
     fn test_attach_net_devices() {
         let mut event_manager = EventManager::new().expect("Unable to create EventManager");
@@ -1197,6 +1435,8 @@ pub mod tests {
             rx_rate_limiter: None,
             tx_rate_limiter: None,
             allow_mmds_requests: true,
+            num_queue_pairs: 1,
+            vhost_net: false,
         };
 
         let mut cmdline = default_kernel_cmdline();
@@ -1453,6 +1693,9 @@ pub mod tests {
         let err = CreateRateLimiter(io::Error::from_raw_os_error(0));
         let _ = format!("{}{:?}", err, err);
 
+        let err = GuestMemfd(io::Error::from_raw_os_error(0));
+        let _ = format!("{}{:?}", err, err);
+
         let err = Internal(Error::Serial(io::Error::from_raw_os_error(0)));
         let _ = format!("{}{:?}", err, err);
 
@@ -3,11 +3,13 @@
 
 //! Provides functionality for saving/restoring the MMIO device manager and its devices.
 
-use std::io;
+use std::collections::HashMap;
 use std::result::Result;
 use std::sync::{Arc, Mutex};
 
 use super::mmio::*;
+use crate::vmm_config::snapshot::NetworkOverride;
+use crate::vmm_config::RateLimiterUpdate;
 use crate::EventManager;
 use logger::error;
 
@@ -15,7 +17,7 @@ use logger::error;
 use arch::DeviceType;
 use devices::virtio::balloon::persist::{BalloonConstructorArgs, BalloonState};
 use devices::virtio::balloon::{Balloon, Error as BalloonError};
-use devices::virtio::block::persist::{BlockConstructorArgs, BlockState};
+use devices::virtio::block::persist::{BlockConstructorArgs, BlockState, Error as BlockError};
 use devices::virtio::block::Block;
 use devices::virtio::net::persist::{Error as NetError, NetConstructorArgs, NetState};
 use devices::virtio::net::Net;
@@ -27,6 +29,7 @@ use devices::virtio::{
 };
 use event_manager::{MutEventSubscriber, SubscriberOps};
 use kvm_ioctls::VmFd;
+use serde::Serialize;
 use snapshot::Persist;
 use versionize::{VersionMap, Versionize, VersionizeError, VersionizeResult};
 use versionize_derive::Versionize;
@@ -36,17 +39,20 @@ use vm_memory::GuestMemoryMmap;
 #[derive(Debug)]
 pub enum Error {
     Balloon(BalloonError),
-    Block(io::Error),
+    Block(BlockError),
     DeviceManager(super::mmio::Error),
     MmioTransport,
     #[cfg(target_arch = "aarch64")]
     Legacy(crate::Error),
     Net(NetError),
+    NetOverride(devices::Error),
+    UnknownDriveOverride(String),
+    UnknownNetOverride(String),
     Vsock(VsockError),
     VsockUnixBackend(VsockUnixBackendError),
 }
 
-#[derive(Clone, Versionize)]
+#[derive(Clone, Debug, Serialize, Versionize)]
 /// Holds the state of a balloon device connected to the MMIO space.
 // NOTICE: Any changes to this structure require a snapshot version bump.
 pub struct ConnectedBalloonState {
@@ -60,7 +66,7 @@ pub struct ConnectedBalloonState {
     pub mmio_slot: MMIODeviceInfo,
 }
 
-#[derive(Clone, Versionize)]
+#[derive(Clone, Debug, Serialize, Versionize)]
 /// Holds the state of a block device connected to the MMIO space.
 // NOTICE: Any changes to this structure require a snapshot version bump.
 pub struct ConnectedBlockState {
@@ -74,7 +80,7 @@ pub struct ConnectedBlockState {
     pub mmio_slot: MMIODeviceInfo,
 }
 
-#[derive(Clone, Versionize)]
+#[derive(Clone, Debug, Serialize, Versionize)]
 /// Holds the state of a net device connected to the MMIO space.
 // NOTICE: Any changes to this structure require a snapshot version bump.
 pub struct ConnectedNetState {
@@ -88,7 +94,7 @@ pub struct ConnectedNetState {
     pub mmio_slot: MMIODeviceInfo,
 }
 
-#[derive(Clone, Versionize)]
+#[derive(Clone, Debug, Serialize, Versionize)]
 /// Holds the state of a vsock device connected to the MMIO space.
 // NOTICE: Any changes to this structure require a snapshot version bump.
 pub struct ConnectedVsockState {
@@ -103,7 +109,7 @@ pub struct ConnectedVsockState {
 }
 
 #[cfg(target_arch = "aarch64")]
-#[derive(Clone, Versionize)]
+#[derive(Clone, Debug, Serialize, Versionize)]
 /// Holds the state of a legacy device connected to the MMIO space.
 pub struct ConnectedLegacyState {
     /// Device identifier.
@@ -112,7 +118,7 @@ pub struct ConnectedLegacyState {
     pub mmio_slot: MMIODeviceInfo,
 }
 
-#[derive(Clone, Versionize)]
+#[derive(Clone, Debug, Serialize, Versionize)]
 /// Holds the device states.
 // NOTICE: Any changes to this structure require a snapshot version bump.
 pub struct DeviceStates {
@@ -146,6 +152,8 @@ pub struct MMIODevManagerConstructorArgs<'a> {
     pub mem: GuestMemoryMmap,
     pub vm: &'a VmFd,
     pub event_manager: &'a mut EventManager,
+    pub net_overrides: &'a [NetworkOverride],
+    pub drive_path_overrides: &'a HashMap<String, String>,
 }
 
 impl<'a> Persist<'a> for MMIODeviceManager {
@@ -204,11 +212,11 @@ impl<'a> Persist<'a> for MMIODeviceManager {
                     });
                 }
                 TYPE_BLOCK => {
-                    let block_state = locked_device
-                        .as_any()
-                        .downcast_ref::<Block>()
-                        .unwrap()
-                        .save();
+                    let block = locked_device.as_mut_any().downcast_mut::<Block>().unwrap();
+                    // Complete the in-flight async requests so that the saved queue
+                    // state and guest memory are consistent.
+                    block.prepare_save();
+                    let block_state = block.save();
                     states.block_devices.push(ConnectedBlockState {
                         device_id: devid.clone(),
                         device_state: block_state,
@@ -264,6 +272,28 @@ impl<'a> Persist<'a> for MMIODeviceManager {
         constructor_args: Self::ConstructorArgs,
         state: &Self::State,
     ) -> Result<Self, Self::Error> {
+        // Every override has to target a network interface saved in the snapshot.
+        if let Some(net_override) = constructor_args.net_overrides.iter().find(|net_override| {
+            !state
+                .net_devices
+                .iter()
+                .any(|net_state| net_state.device_id == net_override.iface_id)
+        }) {
+            return Err(Error::UnknownNetOverride(net_override.iface_id.clone()));
+        }
+        if let Some(drive_id) = constructor_args
+            .drive_path_overrides
+            .keys()
+            .find(|drive_id| {
+                !state
+                    .block_devices
+                    .iter()
+                    .any(|block_state| block_state.device_id == **drive_id)
+            })
+        {
+            return Err(Error::UnknownDriveOverride(drive_id.clone()));
+        }
+
         let mut dev_manager =
             MMIODeviceManager::new(arch::MMIO_MEM_START, (arch::IRQ_BASE, arch::IRQ_MAX));
         let mem = &constructor_args.mem;
@@ -276,7 +306,7 @@ impl<'a> Persist<'a> for MMIODeviceManager {
                     let serial = crate::builder::setup_serial_device(
                         constructor_args.event_manager,
                         Box::new(crate::builder::SerialStdin::get()),
-                        Box::new(io::stdout()),
+                        Box::new(std::io::stdout()),
                     )
                     .map_err(Error::Legacy)?;
 
@@ -340,7 +370,13 @@ impl<'a> Persist<'a> for MMIODeviceManager {
         for block_state in &state.block_devices {
             let device = Arc::new(Mutex::new(
                 Block::restore(
-                    BlockConstructorArgs { mem: mem.clone() },
+                    BlockConstructorArgs {
+                        mem: mem.clone(),
+                        disk_path: constructor_args
+                            .drive_path_overrides
+                            .get(&block_state.device_id)
+                            .cloned(),
+                    },
                     &block_state.device_state,
                 )
                 .map_err(Error::Block)?,
@@ -356,13 +392,34 @@ impl<'a> Persist<'a> for MMIODeviceManager {
             )?;
         }
         for net_state in &state.net_devices {
-            let device = Arc::new(Mutex::new(
-                Net::restore(
-                    NetConstructorArgs { mem: mem.clone() },
-                    &net_state.device_state,
-                )
-                .map_err(Error::Net)?,
-            ));
+            let net_override = constructor_args
+                .net_overrides
+                .iter()
+                .find(|net_override| net_override.iface_id == net_state.device_id);
+            let mut net = Net::restore(
+                NetConstructorArgs {
+                    mem: mem.clone(),
+                    tap_if_name: net_override
+                        .and_then(|net_override| net_override.host_dev_name.clone()),
+                },
+                &net_state.device_state,
+            )
+            .map_err(Error::Net)?;
+
+            if let Some(net_override) = net_override {
+                if let Some(guest_mac) = net_override.guest_mac {
+                    net.set_guest_mac(guest_mac).map_err(Error::NetOverride)?;
+                }
+                let rx_update = RateLimiterUpdate::from(net_override.rx_rate_limiter);
+                let tx_update = RateLimiterUpdate::from(net_override.tx_rate_limiter);
+                net.patch_rate_limiters(
+                    rx_update.bandwidth,
+                    rx_update.ops,
+                    tx_update.bandwidth,
+                    tx_update.ops,
+                );
+            }
+            let device = Arc::new(Mutex::new(net));
 
             restore_helper(
                 device.clone(),
@@ -411,7 +468,9 @@ mod tests {
     use crate::vmm_config::balloon::BalloonDeviceConfig;
     use crate::vmm_config::net::NetworkInterfaceConfig;
     use crate::vmm_config::vsock::VsockDeviceConfig;
+    use crate::vmm_config::{RateLimiterConfig, TokenBucketConfig};
     use devices::virtio::block::CacheType;
+    use utils::net::mac::MacAddr;
     use utils::tempfile::TempFile;
 
     impl PartialEq for ConnectedBalloonState {
@@ -574,6 +633,8 @@ mod tests {
                 rx_rate_limiter: None,
                 tx_rate_limiter: None,
                 allow_mmds_requests: true,
+                num_queue_pairs: 1,
+                vhost_net: false,
             };
             insert_net_device(
                 &mut vmm,
@@ -620,10 +681,111 @@ mod tests {
             mem: vmm.guest_memory().clone(),
             vm: vmm.vm.fd(),
             event_manager: &mut event_manager,
+            net_overrides: &[],
+            drive_path_overrides: &HashMap::new(),
         };
         let restored_dev_manager =
             MMIODeviceManager::restore(restore_args, &device_states).unwrap();
 
         assert_eq!(restored_dev_manager, original_mmio_device_manager);
     }
+
+    #[test]
+    fn test_device_overrides() {
+        let mut event_manager = EventManager::new().expect("Unable to create EventManager");
+        let device_states = {
+            let mut vmm = default_vmm();
+            let mut cmdline = default_kernel_cmdline();
+            let network_interface = NetworkInterfaceConfig {
+                iface_id: String::from("netif"),
+                host_dev_name: String::from("hostname"),
+                guest_mac: None,
+                rx_rate_limiter: None,
+                tx_rate_limiter: None,
+                allow_mmds_requests: false,
+                num_queue_pairs: 1,
+                vhost_net: false,
+            };
+            insert_net_device(
+                &mut vmm,
+                &mut cmdline,
+                &mut event_manager,
+                network_interface,
+            );
+            vmm.mmio_device_manager.save()
+        };
+
+        let vmm = default_vmm();
+        let rate_limiter = RateLimiterConfig {
+            bandwidth: Some(TokenBucketConfig {
+                size: 1000,
+                one_time_burst: None,
+                refill_time: 100,
+            }),
+            ops: None,
+        };
+        let guest_mac = MacAddr::parse_str("12:34:56:78:9a:bc").unwrap();
+
+        // Overrides of interfaces that are not in the snapshot are rejected.
+        let net_overrides = [NetworkOverride {
+            iface_id: String::from("other"),
+            ..Default::default()
+        }];
+        let restore_args = MMIODevManagerConstructorArgs {
+            mem: vmm.guest_memory().clone(),
+            vm: vmm.vm.fd(),
+            event_manager: &mut event_manager,
+            net_overrides: &net_overrides,
+            drive_path_overrides: &HashMap::new(),
+        };
+        match MMIODeviceManager::restore(restore_args, &device_states) {
+            Err(Error::UnknownNetOverride(iface_id)) => assert_eq!(iface_id, "other"),
+            _ => panic!("Unexpected result."),
+        }
+
+        // So are overrides of drives that are not in the snapshot.
+        let drive_path_overrides = vec![(String::from("rootfs"), String::from("/tmp/rootfs"))]
+            .into_iter()
+            .collect();
+        let restore_args = MMIODevManagerConstructorArgs {
+            mem: vmm.guest_memory().clone(),
+            vm: vmm.vm.fd(),
+            event_manager: &mut event_manager,
+            net_overrides: &[],
+            drive_path_overrides: &drive_path_overrides,
+        };
+        match MMIODeviceManager::restore(restore_args, &device_states) {
+            Err(Error::UnknownDriveOverride(drive_id)) => assert_eq!(drive_id, "rootfs"),
+            _ => panic!("Unexpected result."),
+        }
+
+        let net_overrides = [NetworkOverride {
+            iface_id: String::from("netif"),
+            host_dev_name: Some(String::from("hostname2")),
+            guest_mac: Some(guest_mac),
+            rx_rate_limiter: Some(rate_limiter),
+            tx_rate_limiter: None,
+        }];
+        let restore_args = MMIODevManagerConstructorArgs {
+            mem: vmm.guest_memory().clone(),
+            vm: vmm.vm.fd(),
+            event_manager: &mut event_manager,
+            net_overrides: &net_overrides,
+            drive_path_overrides: &HashMap::new(),
+        };
+        let restored_dev_manager =
+            MMIODeviceManager::restore(restore_args, &device_states).unwrap();
+        restored_dev_manager
+            .with_virtio_device_with_id(TYPE_NET, "netif", |net: &mut Net| {
+                assert_eq!(net.iface_name(), "hostname2");
+                assert_eq!(net.guest_mac(), Some(&guest_mac));
+                assert_eq!(RateLimiterConfig::from(net.rx_rate_limiter()), rate_limiter);
+                assert_eq!(
+                    RateLimiterConfig::from(net.tx_rate_limiter()),
+                    RateLimiterConfig::default()
+                );
+                Ok(())
+            })
+            .unwrap();
+    }
 }
@@ -14,6 +14,8 @@
 pub mod builder;
 pub(crate) mod device_manager;
 pub mod memory_snapshot;
+/// Live migration utilities.
+pub mod migration;
 /// Save/restore utilities.
 pub mod persist;
 /// Resource store for configured microVM resources.
@@ -34,8 +36,12 @@ mod vstate;
 
 use std::collections::HashMap;
 use std::fmt::{Display, Formatter};
+use std::fs::File;
 use std::io;
+use std::os::unix::fs::MetadataExt;
 use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
 use std::sync::mpsc::{RecvTimeoutError, TryRecvError};
 use std::sync::{Arc, Barrier, Mutex};
 use std::time::Duration;
@@ -45,7 +51,10 @@ use crate::device_manager::legacy::PortIODeviceManager;
 use crate::device_manager::mmio::MMIODeviceManager;
 use crate::memory_snapshot::SnapshotMemory;
 use crate::persist::{MicrovmState, MicrovmStateError, VmInfo};
+use crate::vmm_config::dirty_pages::RegionDirtyPageStats;
 use crate::vmm_config::instance_info::{InstanceInfo, VmState};
+use crate::vmm_config::shared_memory::GuestRegionMemfdMapping;
+use crate::vmm_config::vcpu_hotplug::VcpuHotplugError;
 use crate::vstate::vcpu::VcpuState;
 use crate::vstate::{
     vcpu::{Vcpu, VcpuEvent, VcpuHandle, VcpuResponse},
@@ -59,13 +68,16 @@ use devices::virtio::{
 };
 use devices::BusDevice;
 use event_manager::{EventManager as BaseEventManager, EventOps, Events, MutEventSubscriber};
-use logger::{error, info, warn, LoggerError, MetricsError, METRICS};
+use logger::{error, info, warn, IncMetric, LoggerError, MetricsError, METRICS};
 use rate_limiter::BucketUpdate;
 use seccompiler::BpfProgram;
 use snapshot::Persist;
 use utils::epoll::EventSet;
 use utils::eventfd::EventFd;
-use vm_memory::{GuestMemory, GuestMemoryMmap, GuestMemoryRegion, GuestRegionMmap};
+use utils::net::mac::MacAddr;
+use utils::sock_ctrl_msg::ScmSocket;
+use utils::uffd::Uffd;
+use vm_memory::{Address, GuestMemory, GuestMemoryMmap, GuestMemoryRegion, GuestRegionMmap};
 
 /// Shorthand type for the EventManager flavour used by Firecracker.
 pub type EventManager = BaseEventManager<Arc<Mutex<dyn MutEventSubscriber>>>;
@@ -134,6 +146,8 @@ pub enum Error {
     SeccompFilters(seccompiler::InstallationError),
     /// Write to the serial console failed.
     Serial(io::Error),
+    /// Cannot send the memfd backing the shared guest memory.
+    SharedMemory(io::Error),
     /// Cannot create Timer file descriptor.
     TimerFd(io::Error),
     /// Vcpu configuration error.
@@ -185,6 +199,7 @@ impl Display for Error {
             RegisterMMIODevice(e) => write!(f, "Cannot add a device to the MMIO Bus. {}", e),
             SeccompFilters(e) => write!(f, "Cannot install seccomp filters: {}", e),
             Serial(e) => write!(f, "Error writing to the serial console: {}", e),
+            SharedMemory(e) => write!(f, "Cannot send the shared guest memory: {}", e),
             TimerFd(e) => write!(f, "Error creating timer fd: {}", e),
             VcpuConfigure(e) => write!(f, "Error configuring the vcpu for boot: {}", e),
             VcpuCreate(e) => write!(f, "Error creating the vcpu: {}", e),
@@ -242,7 +257,18 @@ pub struct Vmm {
     // Guest VM core resources.
     vm: Vm,
     guest_memory: GuestMemoryMmap,
+    // Set when the guest memory is served by a page fault handler. It is only held so that
+    // the guest memory stays registered, which lasts as long as the userfaultfd is open.
+    #[allow(dead_code)]
+    uffd: Option<Uffd>,
+    // Set when the guest memory is shared, backed by this memfd.
+    guest_memfd: Option<File>,
     vcpus_handles: Vec<VcpuHandle>,
+    // Number of vCPUs the guest can run on, the first ones of `vcpus_handles`. The other
+    // ones are kept paused until they are hot-added.
+    plugged_vcpus: usize,
+    // Set when hyperthreading is enabled, in which case the vCPUs are hot-added in pairs.
+    ht_enabled: bool,
     // Used by Vcpus and devices to initiate teardown; Vmm should never write here.
     vcpus_exit_evt: EventFd,
 
@@ -307,12 +333,67 @@ impl Vmm {
         Ok(())
     }
 
-    // Checks that the vCPUs respond with the `_expected_response`.
+    /// Hot-adds vCPUs to the microVM, up to a total of `vcpu_count`, by letting the paused
+    /// ones run along with the others. The guest then brings them online on its own.
+    pub fn hotplug_vcpus(&mut self, vcpu_count: u8) -> std::result::Result<(), VcpuHotplugError> {
+        let result = self.plug_vcpus(vcpu_count as usize);
+        match result {
+            Ok(added) => METRICS.vcpu.hotplug_count.add(added),
+            Err(_) => METRICS.vcpu.hotplug_fails.inc(),
+        }
+        result.map(|_| ())
+    }
+
+    // Plugs vCPUs until `vcpu_count` of them are, and returns how many were added.
+    fn plug_vcpus(&mut self, vcpu_count: usize) -> std::result::Result<usize, VcpuHotplugError> {
+        use self::VcpuHotplugError::*;
+
+        // Counts are bounded by `MAX_SUPPORTED_VCPUS`.
+        if vcpu_count < self.plugged_vcpus {
+            return Err(VcpuRemoval(self.plugged_vcpus as u8, vcpu_count as u8));
+        }
+        if vcpu_count > self.vcpus_handles.len() {
+            return Err(TooManyVcpus(
+                vcpu_count as u8,
+                self.vcpus_handles.len() as u8,
+            ));
+        }
+        if self.ht_enabled && vcpu_count > 1 && vcpu_count % 2 == 1 {
+            return Err(InvalidVcpuCount(vcpu_count as u8));
+        }
+
+        let added = vcpu_count - self.plugged_vcpus;
+        // When the microVM is paused, the vCPUs are resumed along with the others.
+        if self.instance_info.state != VmState::Running {
+            self.plugged_vcpus = vcpu_count;
+            return Ok(added);
+        }
+        // The vCPUs are plugged one at a time, so that on failure the vCPUs that may already
+        // run are still paused, saved and stopped along with the others.
+        while self.plugged_vcpus < vcpu_count {
+            let handle = &self.vcpus_handles[self.plugged_vcpus];
+            handle
+                .send_event(VcpuEvent::Resume)
+                .map_err(|_| Internal(Error::VcpuResume))?;
+            self.plugged_vcpus += 1;
+            match handle
+                .response_receiver()
+                .recv_timeout(Duration::from_millis(1000))
+            {
+                Ok(VcpuResponse::Resumed) => (),
+                _ => return Err(Internal(Error::VcpuResume)),
+            }
+        }
+
+        Ok(added)
+    }
+
+    // Checks that the plugged vCPUs respond with the `_expected_response`.
     fn check_vcpus_response(
         &mut self,
         _expected_response: VcpuResponse,
     ) -> std::result::Result<(), ()> {
-        for handle in self.vcpus_handles.iter() {
+        for handle in self.vcpus_handles[..self.plugged_vcpus].iter() {
             match handle
                 .response_receiver()
                 .recv_timeout(Duration::from_millis(1000))
@@ -359,7 +440,26 @@ impl Vmm {
 
     /// Saves the state of a paused Microvm.
     pub fn save_state(&mut self) -> std::result::Result<MicrovmState, MicrovmStateError> {
-        use self::MicrovmStateError::SaveVmState;
+        use self::MicrovmStateError::{NotAllowed, SaveVmState};
+        // The data path state of the vhost-net devices is owned by the host kernel.
+        self.mmio_device_manager
+            .for_each_device(|devtype, id, _, bus_dev| {
+                if *devtype == DeviceType::Virtio(TYPE_NET) {
+                    let bus_dev = bus_dev.lock().expect("Poisoned lock");
+                    // Virtio devices are guaranteed MmioTransport.
+                    let mmio_dev = bus_dev.as_any().downcast_ref::<MmioTransport>().unwrap();
+                    let mut virtio = mmio_dev.locked_device();
+                    let net = virtio.as_mut_any().downcast_mut::<Net>().unwrap();
+                    if net.vhost_net_enabled() {
+                        return Err(NotAllowed(format!(
+                            "network interface {} is backed by vhost-net, which doesn't support \
+                             snapshotting",
+                            id
+                        )));
+                    }
+                }
+                Ok(())
+            })?;
         let vcpu_states = self.save_vcpu_states()?;
         let vm_state = {
             #[cfg(target_arch = "x86_64")]
@@ -384,6 +484,8 @@ impl Vmm {
             vm_state,
             vcpu_states,
             device_states,
+            plugged_vcpu_count: Some(self.plugged_vcpus as u8),
+            ht_enabled: self.ht_enabled,
         })
     }
 
@@ -426,7 +528,7 @@ impl Vmm {
         event: VcpuEvent,
         expected_response: VcpuResponse,
     ) -> Result<()> {
-        for handle in self.vcpus_handles.iter() {
+        for handle in self.vcpus_handles[..self.plugged_vcpus].iter() {
             handle
                 .send_event(event.clone())
                 .map_err(|_| Error::VcpuMessage)?;
@@ -495,6 +597,109 @@ impl Vmm {
         Ok(bitmap)
     }
 
+    /// Counts the pages of each guest memory region dirtied since the dirty page log was last
+    /// reset, either by the vCPUs or by the devices. The log is reset if `reset` is set, and
+    /// kept for the next diff snapshot otherwise.
+    pub fn dirty_page_stats(&self, reset: bool) -> Result<Vec<RegionDirtyPageStats>> {
+        // Reading the KVM dirty bitmap clears it, so the pages found dirty are moved to the
+        // Firecracker bitmap when the log is kept.
+        let dirty_bitmap = self.get_dirty_bitmap()?;
+        let page_size = arch::PAGE_SIZE;
+        let mut stats = Vec::new();
+
+        let _: std::result::Result<(), ()> = self.guest_memory.with_regions_mut(|slot, region| {
+            let kvm_bitmap = dirty_bitmap.get(&slot).map_or(&[][..], |b| b.as_slice());
+            let firecracker_bitmap = region.dirty_bitmap();
+            let total_pages = region.len() as usize / page_size;
+            let mut dirty_pages = 0;
+
+            for page in 0..total_pages {
+                let is_kvm_page_dirty = kvm_bitmap
+                    .get(page / 64)
+                    .map_or(false, |word| (word >> (page % 64)) & 1 != 0);
+                let is_firecracker_page_dirty =
+                    firecracker_bitmap.map_or(false, |b| b.is_addr_set(page * page_size));
+                if is_kvm_page_dirty || is_firecracker_page_dirty {
+                    dirty_pages += 1;
+                }
+                if is_kvm_page_dirty && !reset {
+                    if let Some(bitmap) = firecracker_bitmap {
+                        bitmap.set_addr_range(page * page_size, page_size);
+                    }
+                }
+            }
+            if reset {
+                if let Some(bitmap) = firecracker_bitmap {
+                    bitmap.reset();
+                }
+            }
+
+            stats.push(RegionDirtyPageStats {
+                base_address: region.start_addr().raw_value(),
+                total_pages: total_pages as u64,
+                dirty_pages,
+            });
+            Ok(())
+        });
+        Ok(stats)
+    }
+
+    /// Sends the memfd backing the shared guest memory to the process listening on the Unix
+    /// domain socket at `socket_path`, along with the layout of the guest memory regions in
+    /// the memfd.
+    pub fn share_guest_memory(&self, socket_path: &Path) -> Result<()> {
+        let guest_memfd = self.guest_memfd.as_ref().ok_or_else(|| {
+            Error::SharedMemory(io::Error::new(
+                io::ErrorKind::NotFound,
+                "the guest memory is not shared",
+            ))
+        })?;
+        let mappings: Vec<_> = self
+            .guest_memory
+            .describe()
+            .regions
+            .iter()
+            .map(|region| GuestRegionMemfdMapping {
+                base_address: region.base_address,
+                size: region.size,
+                offset: region.offset,
+            })
+            .collect();
+
+        // It's safe to unwrap because the mappings are plain structs.
+        let mappings = serde_json::to_string(&mappings).unwrap();
+        let socket = UnixStream::connect(socket_path).map_err(Error::SharedMemory)?;
+        socket
+            .send_with_fd(mappings.as_bytes(), guest_memfd.as_raw_fd())
+            .map_err(|err| Error::SharedMemory(io::Error::from_raw_os_error(err.errno())))?;
+        Ok(())
+    }
+
+    /// Returns whether `path` refers to the memfd backing the shared guest memory, e.g. through
+    /// `/proc/<pid>/fd/<fd>` of a process the memfd was sent to.
+    pub fn is_guest_memfd(&self, path: &Path) -> bool {
+        let guest_memfd = match self.guest_memfd.as_ref() {
+            Some(guest_memfd) => guest_memfd,
+            None => return false,
+        };
+        // The path isn't opened, which could block, e.g. on a FIFO.
+        match (guest_memfd.metadata(), std::fs::metadata(path)) {
+            (Ok(memfd_metadata), Ok(metadata)) => {
+                memfd_metadata.dev() == metadata.dev() && memfd_metadata.ino() == metadata.ino()
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns whether the guest memory is backed by huge pages.
+    pub fn is_hugetlb_backed(&self) -> bool {
+        self.guest_memory.map_and_fold(
+            false,
+            |(_, region)| region.flags() & libc::MAP_HUGETLB != 0,
+            |hugetlb, region_hugetlb| hugetlb || region_hugetlb,
+        )
+    }
+
     /// Enables or disables KVM dirty page tracking.
     pub fn set_dirty_page_tracking(&mut self, enable: bool) -> Result<()> {
         // This function _always_ results in an ioctl update. The VMM is stateless in the sense
@@ -507,13 +712,20 @@ impl Vmm {
             .map_err(Error::Vm)
     }
 
-    /// Updates the path of the host file backing the emulated block device with id `drive_id`.
+    /// Updates the path of the host file backing the emulated block device with id `drive_id`,
+    /// and/or the path of its overlay image. Paths which are not provided are kept.
     /// We update the disk image on the device and its virtio configuration.
-    pub fn update_block_device_path(&mut self, drive_id: &str, path_on_host: String) -> Result<()> {
+    pub fn update_block_device_path(
+        &mut self,
+        drive_id: &str,
+        path_on_host: Option<String>,
+        overlay_path_on_host: Option<String>,
+    ) -> Result<()> {
         self.mmio_device_manager
             .with_virtio_device_with_id(TYPE_BLOCK, drive_id, |block: &mut Block| {
+                let path_on_host = path_on_host.unwrap_or_else(|| block.file_path().clone());
                 block
-                    .update_disk_image(path_on_host)
+                    .update_disk_image(path_on_host, overlay_path_on_host)
                     .map_err(|e| e.to_string())
             })
             .map_err(Error::DeviceManager)
@@ -545,12 +757,40 @@ impl Vmm {
     ) -> Result<()> {
         self.mmio_device_manager
             .with_virtio_device_with_id(TYPE_NET, net_id, |net: &mut Net| {
+                if net.vhost_net_enabled() {
+                    return Err(
+                        "Rate limiting is not supported by vhost-net network interfaces."
+                            .to_string(),
+                    );
+                }
                 net.patch_rate_limiters(rx_bytes, rx_ops, tx_bytes, tx_ops);
                 Ok(())
             })
             .map_err(Error::DeviceManager)
     }
 
+    /// Updates the guest MAC address and/or the link state of the net device with `net_id` id.
+    /// The guest driver is notified of the changes.
+    pub fn update_net_link(
+        &mut self,
+        net_id: &str,
+        guest_mac: Option<MacAddr>,
+        link_up: Option<bool>,
+    ) -> Result<()> {
+        self.mmio_device_manager
+            .with_virtio_device_with_id(TYPE_NET, net_id, |net: &mut Net| {
+                if let Some(guest_mac) = guest_mac {
+                    net.set_guest_mac(guest_mac)
+                        .map_err(|e| format!("{:?}", e))?;
+                }
+                if let Some(link_up) = link_up {
+                    net.set_link_up(link_up).map_err(|e| format!("{:?}", e))?;
+                }
+                Ok(())
+            })
+            .map_err(Error::DeviceManager)
+    }
+
     /// Returns a reference to the balloon device if present.
     pub fn balloon_config(&self) -> std::result::Result<BalloonConfig, BalloonError> {
         if let Some(busdev) = self.get_bus_device(DeviceType::Virtio(TYPE_BALLOON), BALLOON_DEV_ID)
@@ -5,20 +5,67 @@
 
 use std::fmt::{Display, Formatter};
 use std::fs::File;
-use std::io::SeekFrom;
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
 
-use versionize::{VersionMap, Versionize, VersionizeResult};
+use serde::{Deserialize, Serialize};
+use versionize::crc::CRC64Writer;
+use versionize::{VersionMap, Versionize, VersionizeError, VersionizeResult};
 use versionize_derive::Versionize;
 use vm_memory::{
     Bytes, FileOffset, GuestAddress, GuestMemory, GuestMemoryError, GuestMemoryMmap,
     GuestMemoryRegion, GuestRegionMmap, MemoryRegionAddress,
 };
 
+use crate::vmm_config::snapshot::MemFileFormat;
 use crate::DirtyBitmap;
+use utils::byte_order::{read_le_u32, read_le_u64, write_le_u32, write_le_u64};
+use utils::compression::{compress, decompress, max_compressed_len};
 use utils::errno;
 
+// The compressed memory file format starts with a header:
+//
+//  |-----------------------------|
+//  |  64 bit COMPRESSED_MAGIC_ID |
+//  |-----------------------------|
+//  |   32 bit format version     |
+//  |-----------------------------|
+//  |   32 bit chunk size         |
+//  |-----------------------------|
+//  |   64 bit memory size        |
+//  |-----------------------------|
+//
+// followed by the chunks of each region, in order. Every chunk but the last one of a region
+// holds `chunk size` bytes of guest memory, and is stored as:
+//
+//  |-----------------------------|
+//  |   8 bit chunk kind          |
+//  |-----------------------------|
+//  |   32 bit payload length     |
+//  |-----------------------------|
+//  |   CRC64 of the fields above |
+//  |   and of the payload        |
+//  |-----------------------------|
+//  |   payload                   |
+//  |-----------------------------|
+//
+// All the fields are little endian.
+const COMPRESSED_MAGIC_ID: u64 = 0x0710_1984_C0DE_0001;
+const COMPRESSED_FORMAT_VERSION: u32 = 1;
+const COMPRESSED_HEADER_SIZE: usize = 24;
+const COMPRESSED_CHUNK_SIZE: usize = 64 << 10;
+// Upper bound of the chunk size accepted on restore.
+const MAX_COMPRESSED_CHUNK_SIZE: usize = 64 << 20;
+const CHUNK_HEADER_SIZE: usize = 13;
+
+// The chunk only holds zeroes, and has no payload.
+const CHUNK_KIND_ZERO: u8 = 0;
+// The payload is the chunk content.
+const CHUNK_KIND_RAW: u8 = 1;
+// The payload is the compressed chunk content.
+const CHUNK_KIND_COMPRESSED: u8 = 2;
+
 /// State of a guest memory region saved to file/buffer.
-#[derive(Debug, PartialEq, Versionize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Versionize)]
 // NOTICE: Any changes to this structure require a snapshot version bump.
 pub struct GuestMemoryRegionState {
     /// Base address.
@@ -29,12 +76,74 @@ pub struct GuestMemoryRegionState {
     pub offset: u64,
 }
 
+/// Format of the file the guest memory is saved to.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub enum MemFileFormatState {
+    /// The regions are saved one after the other.
+    Raw,
+    /// The regions are saved in the compressed format.
+    Compressed,
+}
+
+impl Default for MemFileFormatState {
+    fn default() -> MemFileFormatState {
+        MemFileFormatState::Raw
+    }
+}
+
+impl From<MemFileFormat> for MemFileFormatState {
+    fn from(mem_file_format: MemFileFormat) -> Self {
+        match mem_file_format {
+            MemFileFormat::Raw => MemFileFormatState::Raw,
+            MemFileFormat::Compressed => MemFileFormatState::Compressed,
+        }
+    }
+}
+
 /// Guest memory state.
-#[derive(Debug, Default, PartialEq, Versionize)]
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Versionize)]
 // NOTICE: Any changes to this structure require a snapshot version bump.
 pub struct GuestMemoryState {
     /// List of regions.
     pub regions: Vec<GuestMemoryRegionState>,
+    /// Format of the memory file. It's recorded rather than detected from the file, whose
+    /// content is controlled by the guest in the raw format.
+    #[version(
+        start = 2,
+        ser_fn = "file_format_ser",
+        default_fn = "default_file_format"
+    )]
+    pub file_format: MemFileFormatState,
+}
+
+impl GuestMemoryState {
+    fn file_format_ser(&mut self, target_version: u16) -> VersionizeResult<()> {
+        if target_version < 3 && self.file_format != MemFileFormatState::Raw {
+            return Err(VersionizeError::Semantic(
+                "Target version does not support compressed memory files.".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn default_file_format(_source_version: u16) -> MemFileFormatState {
+        MemFileFormatState::Raw
+    }
+}
+
+/// Describes how a guest memory region restored through userfaultfd is laid out, both in the
+/// address space of Firecracker and in the memory file. A list of such mappings is sent to the
+/// page fault handler, serialized as JSON, along with the userfaultfd.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct GuestRegionUffdMapping {
+    /// Host virtual address of the region in Firecracker.
+    pub base_host_virt_addr: u64,
+    /// Region size.
+    pub size: usize,
+    /// Offset in the memory file where the region is saved.
+    pub offset: u64,
 }
 
 /// Defines the interface for snapshotting memory.
@@ -45,7 +154,11 @@ where
     /// Describes GuestMemoryMmap through a GuestMemoryState struct.
     fn describe(&self) -> GuestMemoryState;
     /// Dumps all contents of GuestMemoryMmap to a writer.
+    ///
+    /// The contents are written sequentially, so the writer can be a stream, e.g. a socket.
     fn dump<T: std::io::Write>(&self, writer: &mut T) -> std::result::Result<(), Error>;
+    /// Dumps all contents of GuestMemoryMmap to a writer, in the compressed format.
+    fn dump_compressed<T: std::io::Write>(&self, writer: &mut T) -> std::result::Result<(), Error>;
     /// Dumps all pages of GuestMemoryMmap present in `dirty_bitmap` to a writer.
     fn dump_dirty<T: std::io::Write + std::io::Seek>(
         &self,
@@ -54,11 +167,20 @@ where
     ) -> std::result::Result<(), Error>;
     /// Creates a GuestMemoryMmap given a `file` containing the data
     /// and a `state` containing mapping information.
+    ///
+    /// The file is read in the format recorded in `state`.
     fn restore(
         file: &File,
         state: &GuestMemoryState,
         track_dirty_pages: bool,
     ) -> std::result::Result<Self, Error>;
+    /// Creates a GuestMemoryMmap backed by anonymous memory, and fills it with the data
+    /// read sequentially from `reader`, in the format recorded in `state`.
+    fn restore_from_reader<T: Read>(
+        reader: &mut T,
+        state: &GuestMemoryState,
+        track_dirty_pages: bool,
+    ) -> std::result::Result<Self, Error>;
 }
 
 /// Errors associated with dumping guest memory to file.
@@ -74,6 +196,12 @@ pub enum Error {
     PageSize(errno::Error),
     /// Cannot dump memory.
     WriteMemory(GuestMemoryError),
+    /// The header of the compressed memory file is invalid.
+    InvalidHeader(&'static str),
+    /// A chunk of the compressed memory file is corrupted.
+    CorruptChunk(usize),
+    /// A region of the memory file is saved before the end of the previous one.
+    RegionOffset(u64),
 }
 
 impl Display for Error {
@@ -85,6 +213,13 @@ impl Display for Error {
             CreateRegion(err) => write!(f, "Cannot create memory region: {:?}", err),
             PageSize(err) => write!(f, "Cannot fetch system's page size: {:?}", err),
             WriteMemory(err) => write!(f, "Cannot dump memory: {:?}", err),
+            InvalidHeader(msg) => write!(f, "Invalid compressed memory file header: {}", msg),
+            CorruptChunk(index) => write!(f, "Compressed memory chunk {} is corrupted", index),
+            RegionOffset(offset) => write!(
+                f,
+                "Memory region at offset {} overlaps the previous one",
+                offset
+            ),
         }
     }
 }
@@ -115,6 +250,46 @@ impl SnapshotMemory for GuestMemoryMmap {
         .map_err(Error::WriteMemory)
     }
 
+    /// Dumps all contents of GuestMemoryMmap to a writer, in the compressed format.
+    fn dump_compressed<T: std::io::Write>(&self, writer: &mut T) -> std::result::Result<(), Error> {
+        let mem_size = self
+            .describe()
+            .regions
+            .iter()
+            .map(|region| region.size as u64)
+            .sum();
+        let mut header = [0u8; COMPRESSED_HEADER_SIZE];
+        write_le_u64(&mut header[0..8], COMPRESSED_MAGIC_ID);
+        write_le_u32(&mut header[8..12], COMPRESSED_FORMAT_VERSION);
+        write_le_u32(&mut header[12..16], COMPRESSED_CHUNK_SIZE as u32);
+        write_le_u64(&mut header[16..24], mem_size);
+        writer.write_all(&header).map_err(Error::FileHandle)?;
+
+        let mut chunk = vec![0u8; COMPRESSED_CHUNK_SIZE];
+        let mut payload = Vec::with_capacity(max_compressed_len(COMPRESSED_CHUNK_SIZE));
+        self.with_regions_mut(|_, region| {
+            let mut offset = 0;
+            while offset < region.len() {
+                let len =
+                    std::cmp::min(COMPRESSED_CHUNK_SIZE as u64, region.len() - offset) as usize;
+                region
+                    .read_slice(&mut chunk[..len], MemoryRegionAddress(offset))
+                    .map_err(Error::WriteMemory)?;
+                let kind = encode_chunk(&chunk[..len], &mut payload);
+
+                let mut chunk_header = [0u8; CHUNK_HEADER_SIZE];
+                chunk_header[0] = kind;
+                write_le_u32(&mut chunk_header[1..5], payload.len() as u32);
+                write_le_u64(&mut chunk_header[5..13], chunk_checksum(kind, &payload));
+                writer.write_all(&chunk_header).map_err(Error::FileHandle)?;
+                writer.write_all(&payload).map_err(Error::FileHandle)?;
+
+                offset += len as u64;
+            }
+            Ok(())
+        })
+    }
+
     /// Dumps all pages of GuestMemoryMmap present in `dirty_bitmap` to a writer.
     fn dump_dirty<T: std::io::Write + std::io::Seek>(
         &self,
@@ -176,6 +351,12 @@ impl SnapshotMemory for GuestMemoryMmap {
         state: &GuestMemoryState,
         track_dirty_pages: bool,
     ) -> std::result::Result<Self, Error> {
+        if state.file_format == MemFileFormatState::Compressed {
+            let mut reader = BufReader::new(file);
+            reader.seek(SeekFrom::Start(0)).map_err(Error::FileHandle)?;
+            return Self::restore_from_reader(&mut reader, state, track_dirty_pages);
+        }
+
         let mut mmap_regions = Vec::new();
         for region in state.regions.iter() {
             let mmap_region = GuestRegionMmap::build_guarded(
@@ -202,6 +383,171 @@ impl SnapshotMemory for GuestMemoryMmap {
 
         Self::from_regions(mmap_regions).map_err(Error::CreateMemory)
     }
+
+    /// Creates a GuestMemoryMmap backed by anonymous memory, and fills it with the data
+    /// read sequentially from `reader`, in the format recorded in `state`.
+    fn restore_from_reader<T: Read>(
+        reader: &mut T,
+        state: &GuestMemoryState,
+        track_dirty_pages: bool,
+    ) -> std::result::Result<Self, Error> {
+        let ranges: Vec<_> = state
+            .regions
+            .iter()
+            .map(|region| (GuestAddress(region.base_address), region.size))
+            .collect();
+        let guest_memory = GuestMemoryMmap::from_ranges_guarded(&ranges, track_dirty_pages)
+            .map_err(Error::CreateMemory)?;
+
+        match state.file_format {
+            MemFileFormatState::Raw => read_raw(reader, state, &guest_memory)?,
+            MemFileFormatState::Compressed => read_compressed(reader, state, &guest_memory)?,
+        }
+
+        // Loading the content doesn't dirty the guest memory.
+        let _: std::result::Result<(), ()> = guest_memory.with_regions(|_, region| {
+            if let Some(bitmap) = region.dirty_bitmap() {
+                bitmap.reset();
+            }
+            Ok(())
+        });
+
+        Ok(guest_memory)
+    }
+}
+
+// Encodes `chunk` into `payload`, and returns the kind of the chunk.
+fn encode_chunk(chunk: &[u8], payload: &mut Vec<u8>) -> u8 {
+    payload.clear();
+    if chunk.iter().all(|&byte| byte == 0) {
+        return CHUNK_KIND_ZERO;
+    }
+
+    compress(chunk, payload);
+    if payload.len() < chunk.len() {
+        return CHUNK_KIND_COMPRESSED;
+    }
+
+    // Incompressible chunks are stored as they are.
+    payload.clear();
+    payload.extend_from_slice(chunk);
+    CHUNK_KIND_RAW
+}
+
+fn chunk_checksum(kind: u8, payload: &[u8]) -> u64 {
+    let mut crc_writer = CRC64Writer::new(io::sink());
+    // It's safe to unwrap because writing to a sink can't fail.
+    crc_writer.write_all(&[kind]).unwrap();
+    crc_writer
+        .write_all(&(payload.len() as u32).to_le_bytes())
+        .unwrap();
+    crc_writer.write_all(payload).unwrap();
+    crc_writer.checksum()
+}
+
+fn read_chunk_part<T: Read>(reader: &mut T, buf: &mut [u8], index: usize) -> Result<(), Error> {
+    reader.read_exact(buf).map_err(|err| match err.kind() {
+        io::ErrorKind::UnexpectedEof => Error::CorruptChunk(index),
+        _ => Error::FileHandle(err),
+    })
+}
+
+// Reads the regions saved one after the other.
+fn read_raw<T: Read>(
+    reader: &mut T,
+    state: &GuestMemoryState,
+    guest_memory: &GuestMemoryMmap,
+) -> Result<(), Error> {
+    let mut file_offset = 0;
+    for region in state.regions.iter() {
+        if region.offset < file_offset {
+            return Err(Error::RegionOffset(region.offset));
+        }
+        // Skip over the bytes preceding the region.
+        io::copy(
+            &mut reader.by_ref().take(region.offset - file_offset),
+            &mut io::sink(),
+        )
+        .map_err(Error::FileHandle)?;
+        guest_memory
+            .read_exact_from(GuestAddress(region.base_address), reader, region.size)
+            .map_err(Error::WriteMemory)?;
+        file_offset = region.offset + region.size as u64;
+    }
+    Ok(())
+}
+
+fn read_compressed<T: Read>(
+    reader: &mut T,
+    state: &GuestMemoryState,
+    guest_memory: &GuestMemoryMmap,
+) -> Result<(), Error> {
+    let mut header = [0u8; COMPRESSED_HEADER_SIZE];
+    reader
+        .read_exact(&mut header)
+        .map_err(|_| Error::InvalidHeader("the file is truncated"))?;
+    if read_le_u64(&header[0..8]) != COMPRESSED_MAGIC_ID {
+        return Err(Error::InvalidHeader(
+            "the file is not in the compressed format",
+        ));
+    }
+    if read_le_u32(&header[8..12]) != COMPRESSED_FORMAT_VERSION {
+        return Err(Error::InvalidHeader("unsupported format version"));
+    }
+    let chunk_size = read_le_u32(&header[12..16]) as usize;
+    if chunk_size == 0 || chunk_size > MAX_COMPRESSED_CHUNK_SIZE {
+        return Err(Error::InvalidHeader("invalid chunk size"));
+    }
+    let mem_size: u64 = state.regions.iter().map(|region| region.size as u64).sum();
+    if read_le_u64(&header[16..24]) != mem_size {
+        return Err(Error::InvalidHeader(
+            "the memory size doesn't match the microVM state",
+        ));
+    }
+
+    let mut chunk = vec![0u8; chunk_size];
+    let mut payload = Vec::new();
+    let mut index = 0;
+    for region in state.regions.iter() {
+        let mut offset = 0;
+        while offset < region.size {
+            let len = std::cmp::min(chunk_size, region.size - offset);
+            let mut chunk_header = [0u8; CHUNK_HEADER_SIZE];
+            read_chunk_part(reader, &mut chunk_header, index)?;
+            let kind = chunk_header[0];
+            let payload_len = read_le_u32(&chunk_header[1..5]) as usize;
+            if payload_len > max_compressed_len(len) {
+                return Err(Error::CorruptChunk(index));
+            }
+            payload.resize(payload_len, 0);
+            read_chunk_part(reader, &mut payload, index)?;
+            if chunk_checksum(kind, &payload) != read_le_u64(&chunk_header[5..13]) {
+                return Err(Error::CorruptChunk(index));
+            }
+
+            let addr = GuestAddress(region.base_address + offset as u64);
+            match kind {
+                // The guest memory is zeroed when created.
+                CHUNK_KIND_ZERO if payload_len == 0 => (),
+                CHUNK_KIND_RAW if payload_len == len => guest_memory
+                    .write_slice(&payload, addr)
+                    .map_err(Error::WriteMemory)?,
+                CHUNK_KIND_COMPRESSED => {
+                    decompress(&payload, &mut chunk[..len])
+                        .map_err(|_| Error::CorruptChunk(index))?;
+                    guest_memory
+                        .write_slice(&chunk[..len], addr)
+                        .map_err(Error::WriteMemory)?;
+                }
+                _ => return Err(Error::CorruptChunk(index)),
+            }
+
+            offset += len;
+            index += 1;
+        }
+    }
+
+    Ok(())
 }
 
 fn get_page_size() -> Result<usize, Error> {
@@ -244,6 +590,7 @@ mod tests {
                     offset: page_size as u64,
                 },
             ],
+            file_format: MemFileFormatState::Raw,
         };
 
         let actual_memory_state = guest_memory.describe();
@@ -269,12 +616,42 @@ mod tests {
                     offset: page_size as u64 * 3,
                 },
             ],
+            file_format: MemFileFormatState::Raw,
         };
 
         let actual_memory_state = guest_memory.describe();
         assert_eq!(expected_memory_state, actual_memory_state);
     }
 
+    #[test]
+    fn test_file_format_versionize() {
+        let version_map = crate::version_map::VERSION_MAP.clone();
+        let mut memory_state = GuestMemoryState {
+            regions: vec![],
+            file_format: MemFileFormatState::Compressed,
+        };
+
+        let mut buf = vec![0; 256];
+        memory_state
+            .serialize(&mut buf.as_mut_slice(), &version_map, 3)
+            .unwrap();
+        let restored_state =
+            GuestMemoryState::deserialize(&mut buf.as_slice(), &version_map, 3).unwrap();
+        assert_eq!(restored_state, memory_state);
+
+        // Older versions can only read raw memory files.
+        assert!(memory_state
+            .serialize(&mut buf.as_mut_slice(), &version_map, 2)
+            .is_err());
+        memory_state.file_format = MemFileFormatState::Raw;
+        memory_state
+            .serialize(&mut buf.as_mut_slice(), &version_map, 2)
+            .unwrap();
+        let restored_state =
+            GuestMemoryState::deserialize(&mut buf.as_slice(), &version_map, 2).unwrap();
+        assert_eq!(restored_state, memory_state);
+    }
+
     #[test]
     fn test_restore_memory() {
         let page_size: usize = get_page_size().unwrap();
@@ -304,6 +681,7 @@ mod tests {
             .unwrap();
 
         let memory_state = guest_memory.describe();
+        assert_eq!(memory_state.file_format, MemFileFormatState::Raw);
 
         // Case 1: dump the full memory.
         {
@@ -392,4 +770,200 @@ mod tests {
             assert_eq!(expected_first_region, diff_file_content);
         }
     }
+
+    #[test]
+    fn test_restore_compressed_memory() {
+        let page_size: usize = get_page_size().unwrap();
+
+        // The first region spans several chunks, the second one is smaller than a chunk.
+        let mem_regions = [
+            (GuestAddress(0), COMPRESSED_CHUNK_SIZE * 2 + page_size),
+            (
+                GuestAddress(COMPRESSED_CHUNK_SIZE as u64 * 4),
+                page_size * 2,
+            ),
+        ];
+        let guest_memory = GuestMemoryMmap::from_ranges(&mem_regions[..]).unwrap();
+
+        // Compressible, incompressible and zeroed chunks.
+        let mut state = 0x1234_5678u32;
+        let random: Vec<u8> = (0..COMPRESSED_CHUNK_SIZE)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect();
+        guest_memory.write(&random[..], GuestAddress(0)).unwrap();
+        guest_memory
+            .write(&[3u8; 100], GuestAddress(COMPRESSED_CHUNK_SIZE as u64 * 2))
+            .unwrap();
+        let second_region = vec![2u8; page_size * 2];
+        guest_memory
+            .write(&second_region[..], mem_regions[1].0)
+            .unwrap();
+
+        let mut memory_state = guest_memory.describe();
+        memory_state.file_format = MemFileFormatState::Compressed;
+        let memory_file = TempFile::new().unwrap();
+        guest_memory
+            .dump_compressed(&mut memory_file.as_file())
+            .unwrap();
+        let file_len = memory_file.as_file().metadata().unwrap().len();
+        assert!(file_len < (COMPRESSED_CHUNK_SIZE * 2) as u64);
+
+        let restored_guest_memory =
+            GuestMemoryMmap::restore(&memory_file.as_file(), &memory_state, true).unwrap();
+        for &(addr, size) in mem_regions.iter() {
+            let mut expected = vec![0u8; size];
+            let mut actual = vec![0u8; size];
+            guest_memory.read_slice(&mut expected, addr).unwrap();
+            restored_guest_memory.read_slice(&mut actual, addr).unwrap();
+            assert_eq!(expected, actual);
+        }
+        // Restoring the content leaves the Firecracker bitmap clean.
+        let _res: std::result::Result<(), Error> = restored_guest_memory.with_regions(|_, r| {
+            assert!(!r.dirty_bitmap().unwrap().is_bit_set(0));
+            Ok(())
+        });
+
+        // The memory size in the header must match the microVM state.
+        let mut other_state = memory_state.clone();
+        other_state.regions.pop();
+        match GuestMemoryMmap::restore(&memory_file.as_file(), &other_state, false) {
+            Err(Error::InvalidHeader(_)) => (),
+            res => panic!("Unexpected result: {:?}", res.map(|_| ())),
+        }
+
+        // A raw memory file isn't mistaken for a compressed one, even when it starts with the
+        // magic ID, and the other way around.
+        let raw_file = TempFile::new().unwrap();
+        guest_memory.dump(&mut raw_file.as_file()).unwrap();
+        match GuestMemoryMmap::restore(&raw_file.as_file(), &memory_state, false) {
+            Err(Error::InvalidHeader(_)) => (),
+            res => panic!("Unexpected result: {:?}", res.map(|_| ())),
+        }
+        guest_memory
+            .write_obj(COMPRESSED_MAGIC_ID.to_le(), GuestAddress(0))
+            .unwrap();
+        let raw_file = TempFile::new().unwrap();
+        guest_memory.dump(&mut raw_file.as_file()).unwrap();
+        let raw_state = guest_memory.describe();
+        let restored_guest_memory =
+            GuestMemoryMmap::restore(&raw_file.as_file(), &raw_state, false).unwrap();
+        assert_eq!(
+            restored_guest_memory
+                .read_obj::<u64>(GuestAddress(0))
+                .unwrap(),
+            COMPRESSED_MAGIC_ID.to_le()
+        );
+
+        // A corrupted payload fails the checksum of its chunk.
+        let mut content = std::fs::read(memory_file.as_path()).unwrap();
+        content[COMPRESSED_HEADER_SIZE + CHUNK_HEADER_SIZE + 1] ^= 0xFF;
+        let corrupted_file = TempFile::new().unwrap();
+        corrupted_file.as_file().write_all(&content).unwrap();
+        match GuestMemoryMmap::restore(&corrupted_file.as_file(), &memory_state, false) {
+            Err(Error::CorruptChunk(0)) => (),
+            res => panic!("Unexpected result: {:?}", res.map(|_| ())),
+        }
+
+        // A truncated file fails on its last chunk.
+        memory_file.as_file().set_len(file_len - 1).unwrap();
+        match GuestMemoryMmap::restore(&memory_file.as_file(), &memory_state, false) {
+            Err(Error::CorruptChunk(3)) => (),
+            res => panic!("Unexpected result: {:?}", res.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_restore_from_reader() {
+        let page_size: usize = get_page_size().unwrap();
+        let mem_regions = [
+            (GuestAddress(0), page_size * 2),
+            (GuestAddress(page_size as u64 * 3), page_size),
+        ];
+        let guest_memory = GuestMemoryMmap::from_ranges(&mem_regions[..]).unwrap();
+        guest_memory
+            .write(&vec![1u8; page_size * 2][..], GuestAddress(0))
+            .unwrap();
+        guest_memory
+            .write(&vec![2u8; page_size][..], mem_regions[1].0)
+            .unwrap();
+
+        let check_restored = |restored_guest_memory: &GuestMemoryMmap| {
+            for &(addr, size) in mem_regions.iter() {
+                let mut expected = vec![0u8; size];
+                let mut actual = vec![0u8; size];
+                guest_memory.read_slice(&mut expected, addr).unwrap();
+                restored_guest_memory.read_slice(&mut actual, addr).unwrap();
+                assert_eq!(expected, actual);
+            }
+            // Restoring the content leaves the Firecracker bitmap clean.
+            let _res: std::result::Result<(), Error> =
+                restored_guest_memory.with_regions(|_, r| {
+                    assert!(!r.dirty_bitmap().unwrap().is_bit_set(0));
+                    Ok(())
+                });
+        };
+
+        // Raw format.
+        let mut memory_state = guest_memory.describe();
+        let mut content = Vec::new();
+        guest_memory.dump(&mut content).unwrap();
+        let restored_guest_memory =
+            GuestMemoryMmap::restore_from_reader(&mut content.as_slice(), &memory_state, true)
+                .unwrap();
+        check_restored(&restored_guest_memory);
+
+        // The regions are read sequentially, so they can't overlap.
+        memory_state.regions[1].offset = 0;
+        match GuestMemoryMmap::restore_from_reader(&mut content.as_slice(), &memory_state, true) {
+            Err(Error::RegionOffset(0)) => (),
+            res => panic!("Unexpected result: {:?}", res.map(|_| ())),
+        }
+
+        // Compressed format.
+        let mut memory_state = guest_memory.describe();
+        memory_state.file_format = MemFileFormatState::Compressed;
+        let mut content = Vec::new();
+        guest_memory.dump_compressed(&mut content).unwrap();
+        let restored_guest_memory =
+            GuestMemoryMmap::restore_from_reader(&mut content.as_slice(), &memory_state, true)
+                .unwrap();
+        check_restored(&restored_guest_memory);
+    }
+
+    #[test]
+    fn test_dump_to_stream() {
+        let page_size: usize = get_page_size().unwrap();
+
+        let mem_regions = [
+            (GuestAddress(0), page_size * 2),
+            (GuestAddress(page_size as u64 * 3), page_size),
+        ];
+        let guest_memory = GuestMemoryMmap::from_ranges(&mem_regions[..]).unwrap();
+        guest_memory
+            .write(&vec![1u8; page_size * 2][..], GuestAddress(0))
+            .unwrap();
+        guest_memory
+            .write(&vec![2u8; page_size][..], mem_regions[1].0)
+            .unwrap();
+
+        // A socket can't seek, and is read concurrently so that it never fills up.
+        let (mut sender, mut receiver) = std::os::unix::net::UnixStream::pair().unwrap();
+        let reader = std::thread::spawn(move || {
+            let mut content = Vec::new();
+            receiver.read_to_end(&mut content).unwrap();
+            content
+        });
+        guest_memory.dump(&mut sender).unwrap();
+        drop(sender);
+        let streamed = reader.join().unwrap();
+
+        let memory_file = TempFile::new().unwrap();
+        guest_memory.dump(&mut memory_file.as_file()).unwrap();
+        assert_eq!(streamed, std::fs::read(memory_file.as_path()).unwrap());
+    }
 }
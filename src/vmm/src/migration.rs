@@ -0,0 +1,917 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Pre-copy live migration of a running microVM over a Unix domain socket.
+//!
+//! The source keeps the microVM running while it sends the whole guest memory to the
+//! destination, followed by rounds of the pages dirtied during the previous round. Once a
+//! round is small enough, or after a maximum number of rounds, the source pauses the microVM
+//! and sends the last dirty pages along with the microVM state, which the destination
+//! restores and resumes.
+
+use std::fmt::{Display, Formatter};
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::{Arc, Mutex};
+
+use logger::{error, info};
+use seccompiler::BpfThreadMap;
+use snapshot::Snapshot;
+use utils::byte_order::{read_le_u32, read_le_u64, write_le_u32, write_le_u64};
+use versionize::VersionMap;
+use vm_memory::{
+    Bytes, GuestAddress, GuestMemory, GuestMemoryError, GuestMemoryMmap, GuestMemoryRegion,
+    GuestRegionMmap, MemoryRegionAddress,
+};
+
+use crate::builder::{self, StartMicrovmError};
+use crate::memory_snapshot::{GuestMemoryState, SnapshotMemory};
+use crate::persist::{
+    get_snapshot_data_version, snapshot_state_sanity_check, CreateSnapshotError, LoadSnapshotError,
+    MicrovmState, MicrovmStateError,
+};
+use crate::vmm_config::instance_info::{InstanceInfo, VmState};
+use crate::vmm_config::migration::{ReceiveMigrationParams, SendMigrationParams};
+use crate::{DirtyBitmap, Error as VmmError, EventManager, Vmm};
+
+// Every message starts with a header:
+//
+//  |-----------------------------|
+//  |   32 bit message kind       |
+//  |-----------------------------|
+//  |   64 bit payload length     |
+//  |-----------------------------|
+//
+// followed by the payload. All the fields are little endian.
+const MESSAGE_HEADER_SIZE: usize = 12;
+
+// Sent first by the source. The payload holds the 64 bit MIGRATION_MAGIC_ID, the 32 bit
+// protocol version and the 32 bit number of guest memory regions, followed by the 64 bit
+// base address and size of each region.
+const MSG_START: u32 = 1;
+// The payload holds a 64 bit guest physical address, followed by the guest memory content
+// starting at that address.
+const MSG_MEMORY: u32 = 2;
+// The payload holds the serialized microVM state. It ends the migration on the source side.
+const MSG_STATE: u32 = 3;
+// Sent by the destination once the microVM is resumed. It has no payload.
+const MSG_COMPLETE: u32 = 4;
+// Sent by the destination when the migration fails. The payload holds the error message.
+const MSG_ERROR: u32 = 5;
+
+const MIGRATION_MAGIC_ID: u64 = 0x0710_1984_F11C_0001;
+const MIGRATION_PROTOCOL_VERSION: u32 = 1;
+// Upper bound of the payloads which are buffered instead of being streamed to guest memory.
+const MAX_BUFFERED_PAYLOAD_SIZE: u64 = 64 << 20;
+const DEFAULT_MAX_ITERATIONS: u32 = 5;
+// The microVM is paused once a round sends at most this many pages.
+const DIRTY_PAGES_THRESHOLD: usize = 256;
+// Upper bound of the guest memory pages buffered in a single step while the microVM is running.
+const MAX_STEP_PAGES: usize = (1 << 20) / arch::PAGE_SIZE;
+
+/// Errors associated with the live migration of a microVM.
+#[derive(Debug)]
+pub enum MigrationError {
+    /// Failed to build the microVM on the destination.
+    BuildMicroVm(StartMicrovmError),
+    /// Failed to create the guest memory on the destination.
+    CreateMemory(vm_memory::Error),
+    /// Failed to deserialize the microVM state.
+    DeserializeMicrovmState(snapshot::Error),
+    /// Failed to get the dirty bitmap.
+    DirtyBitmap(VmmError),
+    /// The guest memory of the source is backed by huge pages, which migration doesn't support.
+    HugePages,
+    /// The received microVM state failed sanity checks.
+    IncompatibleState(LoadSnapshotError),
+    /// The peer sent an unexpected message.
+    InvalidMessage(&'static str),
+    /// The microVM version of the destination is invalid.
+    InvalidVersion(CreateSnapshotError),
+    /// Failed to access the guest memory.
+    Memory(GuestMemoryError),
+    /// Failed to save the microVM state.
+    MicrovmState(MicrovmStateError),
+    /// Failed to pause the microVM on the source.
+    PauseMicroVm(VmmError),
+    /// The destination failed to restore the microVM.
+    Remote(String),
+    /// Failed to resume the microVM.
+    ResumeMicroVm(VmmError),
+    /// Failed to serialize the microVM state.
+    SerializeMicrovmState(snapshot::Error),
+    /// Failed to communicate over the migration socket.
+    Socket(io::Error),
+}
+
+impl Display for MigrationError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::MigrationError::*;
+        match self {
+            BuildMicroVm(err) => write!(f, "Cannot build the migrated microVM: {}", err),
+            CreateMemory(err) => write!(f, "Cannot create the guest memory: {:?}", err),
+            DeserializeMicrovmState(err) => {
+                write!(f, "Cannot deserialize the microVM state: {:?}", err)
+            }
+            DirtyBitmap(err) => write!(f, "Cannot get dirty bitmap: {}", err),
+            HugePages => write!(
+                f,
+                "Cannot migrate a microVM whose guest memory is backed by huge pages."
+            ),
+            IncompatibleState(err) => write!(f, "Invalid microVM state: {}", err),
+            InvalidMessage(msg) => write!(f, "Invalid migration message: {}", msg),
+            InvalidVersion(err) => write!(f, "Invalid microVM version: {}", err),
+            Memory(err) => write!(f, "Cannot access the guest memory: {}", err),
+            MicrovmState(err) => write!(f, "Cannot save the microVM state: {}", err),
+            PauseMicroVm(err) => write!(f, "Cannot pause the microVM: {}", err),
+            Remote(msg) => write!(f, "The destination failed: {}", msg),
+            ResumeMicroVm(err) => write!(f, "Cannot resume the microVM: {}", err),
+            SerializeMicrovmState(err) => {
+                write!(f, "Cannot serialize the microVM state: {:?}", err)
+            }
+            Socket(err) => write!(f, "Cannot use the migration socket: {}", err),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, MigrationError>;
+
+fn send_header<T: Write>(stream: &mut T, kind: u32, payload_len: u64) -> Result<()> {
+    let mut header = [0u8; MESSAGE_HEADER_SIZE];
+    write_le_u32(&mut header[0..4], kind);
+    write_le_u64(&mut header[4..12], payload_len);
+    stream.write_all(&header).map_err(MigrationError::Socket)
+}
+
+fn send_message<T: Write>(stream: &mut T, kind: u32, payload: &[u8]) -> Result<()> {
+    send_header(stream, kind, payload.len() as u64)?;
+    stream.write_all(payload).map_err(MigrationError::Socket)
+}
+
+// Writes as much of `buf` as `stream` accepts without blocking, and returns the number of
+// bytes written.
+fn write_available<T: Write>(stream: &mut T, buf: &[u8]) -> Result<usize> {
+    let mut written = 0;
+    while written < buf.len() {
+        match stream.write(&buf[written..]) {
+            Ok(0) => return Err(MigrationError::Socket(io::ErrorKind::WriteZero.into())),
+            Ok(len) => written += len,
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => (),
+            Err(err) => return Err(MigrationError::Socket(err)),
+        }
+    }
+    Ok(written)
+}
+
+fn recv_header<T: Read>(stream: &mut T) -> Result<(u32, u64)> {
+    let mut header = [0u8; MESSAGE_HEADER_SIZE];
+    stream
+        .read_exact(&mut header)
+        .map_err(MigrationError::Socket)?;
+    Ok((read_le_u32(&header[0..4]), read_le_u64(&header[4..12])))
+}
+
+fn recv_payload<T: Read>(stream: &mut T, payload_len: u64) -> Result<Vec<u8>> {
+    if payload_len > MAX_BUFFERED_PAYLOAD_SIZE {
+        return Err(MigrationError::InvalidMessage("payload too large"));
+    }
+    let mut payload = vec![0u8; payload_len as usize];
+    stream
+        .read_exact(&mut payload)
+        .map_err(MigrationError::Socket)?;
+    Ok(payload)
+}
+
+fn send_start<T: Write>(stream: &mut T, mem_state: &GuestMemoryState) -> Result<()> {
+    let mut payload = vec![0u8; 16 + mem_state.regions.len() * 16];
+    write_le_u64(&mut payload[0..8], MIGRATION_MAGIC_ID);
+    write_le_u32(&mut payload[8..12], MIGRATION_PROTOCOL_VERSION);
+    write_le_u32(&mut payload[12..16], mem_state.regions.len() as u32);
+    for (region, buf) in mem_state
+        .regions
+        .iter()
+        .zip(payload[16..].chunks_exact_mut(16))
+    {
+        write_le_u64(&mut buf[0..8], region.base_address);
+        write_le_u64(&mut buf[8..16], region.size as u64);
+    }
+    send_message(stream, MSG_START, &payload)
+}
+
+// Returns the guest memory ranges announced by the source.
+fn recv_start<T: Read>(stream: &mut T) -> Result<Vec<(GuestAddress, usize)>> {
+    let (kind, payload_len) = recv_header(stream)?;
+    if kind != MSG_START {
+        return Err(MigrationError::InvalidMessage("expected the start message"));
+    }
+    let payload = recv_payload(stream, payload_len)?;
+    if payload.len() < 16 || read_le_u64(&payload[0..8]) != MIGRATION_MAGIC_ID {
+        return Err(MigrationError::InvalidMessage("not a migration stream"));
+    }
+    if read_le_u32(&payload[8..12]) != MIGRATION_PROTOCOL_VERSION {
+        return Err(MigrationError::InvalidMessage(
+            "unsupported protocol version",
+        ));
+    }
+    let region_count = read_le_u32(&payload[12..16]) as usize;
+    if payload.len() != 16 + region_count * 16 {
+        return Err(MigrationError::InvalidMessage(
+            "invalid guest memory layout",
+        ));
+    }
+    Ok(payload[16..]
+        .chunks_exact(16)
+        .map(|buf| {
+            (
+                GuestAddress(read_le_u64(&buf[0..8])),
+                read_le_u64(&buf[8..16]) as usize,
+            )
+        })
+        .collect())
+}
+
+fn send_range<T: Write>(
+    stream: &mut T,
+    region: &GuestRegionMmap,
+    offset: u64,
+    len: usize,
+) -> Result<()> {
+    send_header(stream, MSG_MEMORY, 8 + len as u64)?;
+    stream
+        .write_all(&(region.start_addr().0 + offset).to_le_bytes())
+        .map_err(MigrationError::Socket)?;
+    region
+        .write_all_to(MemoryRegionAddress(offset), stream, len)
+        .map_err(MigrationError::Memory)
+}
+
+// The guest memory pages of a round, along with the position the round reached.
+struct Round {
+    // One bitmap per guest memory region.
+    pages: Vec<Vec<u64>>,
+    region: usize,
+    page: usize,
+    sent_pages: usize,
+}
+
+impl Round {
+    // Builds a round of the pages set in either the KVM or the Firecracker dirty bitmap, and
+    // clears the latter. The round holds every page when `dirty_bitmap` is `None`.
+    fn new(guest_memory: &GuestMemoryMmap, dirty_bitmap: Option<&DirtyBitmap>) -> Self {
+        let page_size = arch::PAGE_SIZE;
+        let mut pages = Vec::new();
+
+        let _: std::result::Result<(), ()> = guest_memory.with_regions_mut(|slot, region| {
+            let firecracker_bitmap = region.dirty_bitmap();
+            let page_count = region.len() as usize / page_size;
+            let mut bitmap = vec![0u64; (page_count + 63) / 64];
+
+            for page in 0..page_count {
+                let is_page_dirty = match dirty_bitmap {
+                    None => true,
+                    Some(dirty_bitmap) => {
+                        let is_kvm_page_dirty = dirty_bitmap
+                            .get(&slot)
+                            .and_then(|b| b.get(page / 64))
+                            .map_or(false, |word| (word >> (page % 64)) & 1 != 0);
+                        is_kvm_page_dirty
+                            || firecracker_bitmap.map_or(false, |b| b.is_addr_set(page * page_size))
+                    }
+                };
+                if is_page_dirty {
+                    bitmap[page / 64] |= 1 << (page % 64);
+                }
+            }
+
+            if let Some(bitmap) = firecracker_bitmap {
+                bitmap.reset();
+            }
+            pages.push(bitmap);
+            Ok(())
+        });
+
+        Round {
+            pages,
+            region: 0,
+            page: 0,
+            sent_pages: 0,
+        }
+    }
+
+    fn is_page_set(&self, region: usize, page: usize) -> bool {
+        (self.pages[region][page / 64] >> (page % 64)) & 1 != 0
+    }
+
+    fn is_complete(&self) -> bool {
+        self.region == self.pages.len()
+    }
+
+    // Sends at most `max_pages` of the pages left in the round. Returns whether the round is
+    // complete.
+    fn send_pages<T: Write>(
+        &mut self,
+        stream: &mut T,
+        guest_memory: &GuestMemoryMmap,
+        max_pages: usize,
+    ) -> Result<bool> {
+        let page_size = arch::PAGE_SIZE;
+        let mut budget = max_pages;
+
+        guest_memory.with_regions_mut(|index, region| {
+            if index < self.region || budget == 0 {
+                return Ok(());
+            }
+            let page_count = region.len() as usize / page_size;
+            let mut page = self.page;
+            let mut batch_start = page;
+            let mut batch_pages = 0;
+
+            while page < page_count && batch_pages < budget {
+                if self.is_page_set(index, page) {
+                    if batch_pages == 0 {
+                        batch_start = page;
+                    }
+                    batch_pages += 1;
+                } else if batch_pages > 0 {
+                    send_range(
+                        stream,
+                        region,
+                        (batch_start * page_size) as u64,
+                        batch_pages * page_size,
+                    )?;
+                    self.sent_pages += batch_pages;
+                    budget -= batch_pages;
+                    batch_pages = 0;
+                }
+                page += 1;
+            }
+            if batch_pages > 0 {
+                send_range(
+                    stream,
+                    region,
+                    (batch_start * page_size) as u64,
+                    batch_pages * page_size,
+                )?;
+                self.sent_pages += batch_pages;
+                budget -= batch_pages;
+            }
+
+            if page < page_count {
+                self.region = index;
+                self.page = page;
+            } else {
+                self.region = index + 1;
+                self.page = 0;
+            }
+            Ok(())
+        })?;
+
+        Ok(self.is_complete())
+    }
+}
+
+fn reset_dirty_bitmaps(guest_memory: &GuestMemoryMmap) {
+    let _: std::result::Result<(), ()> = guest_memory.with_regions(|_, region| {
+        if let Some(bitmap) = region.dirty_bitmap() {
+            bitmap.reset();
+        }
+        Ok(())
+    });
+}
+
+/// A live migration in progress on the source.
+///
+/// The guest memory is sent in steps while the microVM keeps running, so that the caller can
+/// serve other events in between. Each step buffers the next pages, and writes what the
+/// socket accepts; once it is non-blocking, the rest is written by the next steps. Once the
+/// pre-copy rounds are over, `finish` pauses the microVM and sends the rest.
+pub struct MigrationSender {
+    stream: UnixStream,
+    // Messages buffered by a step, the first `written` bytes of which were sent.
+    pending: Vec<u8>,
+    written: usize,
+    snapshot_data_version: u16,
+    version_map: VersionMap,
+    max_iterations: u32,
+    // Number of rounds of dirty pages started so far, the first round sending the whole
+    // guest memory.
+    iteration: u32,
+    round: Round,
+}
+
+impl MigrationSender {
+    /// Connects to the destination listening on `params.socket_path`, and starts sending the
+    /// guest memory of the microVM.
+    pub fn new(
+        vmm: &mut Vmm,
+        params: &SendMigrationParams,
+        version_map: VersionMap,
+    ) -> Result<Self> {
+        // Fail early from invalid target version.
+        let snapshot_data_version = get_snapshot_data_version(&params.version, &version_map, vmm)
+            .map_err(MigrationError::InvalidVersion)?;
+        // The destination restores the guest memory into regular pages.
+        if vmm.is_hugetlb_backed() {
+            return Err(MigrationError::HugePages);
+        }
+        let mut stream =
+            UnixStream::connect(&params.socket_path).map_err(MigrationError::Socket)?;
+
+        send_start(&mut stream, &vmm.guest_memory().describe())?;
+        // Start tracking the dirty pages from a clean slate, right before the first round.
+        vmm.get_dirty_bitmap()
+            .map_err(MigrationError::DirtyBitmap)?;
+        let round = Round::new(vmm.guest_memory(), None);
+
+        Ok(MigrationSender {
+            stream,
+            pending: Vec::new(),
+            written: 0,
+            snapshot_data_version,
+            version_map,
+            max_iterations: params.max_iterations.unwrap_or(DEFAULT_MAX_ITERATIONS),
+            iteration: 0,
+            round,
+        })
+    }
+
+    /// Puts the migration socket in non-blocking mode, so that the steps only write what it
+    /// accepts. The caller is then expected to only step the migration when it is writable.
+    pub fn set_nonblocking(&mut self) -> Result<()> {
+        self.stream
+            .set_nonblocking(true)
+            .map_err(MigrationError::Socket)
+    }
+
+    /// Sends the next guest memory pages of the current round, and starts the next round once
+    /// it is complete. Returns `true` when the pre-copy rounds are over.
+    pub fn send_step(&mut self, vmm: &mut Vmm) -> Result<bool> {
+        // The pages buffered by the previous steps are written first.
+        if !self.write_pending()? {
+            return Ok(false);
+        }
+        if !self.round.is_complete() {
+            self.round
+                .send_pages(&mut self.pending, vmm.guest_memory(), MAX_STEP_PAGES)?;
+            self.write_pending()?;
+            return Ok(false);
+        }
+
+        if self.iteration > 0 {
+            info!(
+                "Migration round {} sent {} dirty pages.",
+                self.iteration, self.round.sent_pages
+            );
+            if self.round.sent_pages <= DIRTY_PAGES_THRESHOLD {
+                return Ok(true);
+            }
+        }
+        if self.iteration == self.max_iterations {
+            return Ok(true);
+        }
+
+        let dirty_bitmap = vmm
+            .get_dirty_bitmap()
+            .map_err(MigrationError::DirtyBitmap)?;
+        self.round = Round::new(vmm.guest_memory(), Some(&dirty_bitmap));
+        self.iteration += 1;
+        Ok(false)
+    }
+
+    // Writes the buffered messages, and returns whether they were all written.
+    fn write_pending(&mut self) -> Result<bool> {
+        self.written += write_available(&mut self.stream, &self.pending[self.written..])?;
+        if self.written < self.pending.len() {
+            return Ok(false);
+        }
+        self.pending.clear();
+        self.written = 0;
+        Ok(true)
+    }
+
+    /// Pauses the microVM, and sends the last dirty pages along with the microVM state.
+    ///
+    /// On success, the microVM is left paused, and should not be resumed since it now runs on
+    /// the destination. If the migration fails, the microVM is resumed if it was running.
+    pub fn finish(&mut self, vmm: &mut Vmm) -> Result<()> {
+        // The microVM is paused while the rest is sent, so the socket can block.
+        self.stream
+            .set_nonblocking(false)
+            .map_err(MigrationError::Socket)?;
+        self.stream
+            .write_all(&self.pending[self.written..])
+            .map_err(MigrationError::Socket)?;
+        self.pending.clear();
+        self.written = 0;
+
+        let was_running = vmm.instance_info().state == VmState::Running;
+        if was_running {
+            vmm.pause_vm().map_err(MigrationError::PauseMicroVm)?;
+        }
+        let result = self.send_final_state(vmm);
+        if result.is_err() && was_running {
+            // The guest keeps running on the source.
+            if let Err(err) = vmm.resume_vm() {
+                error!(
+                    "Cannot resume the microVM after a failed migration: {}",
+                    err
+                );
+            }
+        }
+        result
+    }
+
+    fn send_final_state(&mut self, vmm: &mut Vmm) -> Result<()> {
+        // Saving the state quiesces the devices, which write the completed requests to the
+        // guest memory, so the last dirty pages are only collected afterwards.
+        let microvm_state = vmm.save_state().map_err(MigrationError::MicrovmState)?;
+        let dirty_bitmap = vmm
+            .get_dirty_bitmap()
+            .map_err(MigrationError::DirtyBitmap)?;
+        Round::new(vmm.guest_memory(), Some(&dirty_bitmap)).send_pages(
+            &mut self.stream,
+            vmm.guest_memory(),
+            usize::MAX,
+        )?;
+
+        let mut state = Vec::new();
+        Snapshot::new(self.version_map.clone(), self.snapshot_data_version)
+            .save(&mut state, &microvm_state)
+            .map_err(MigrationError::SerializeMicrovmState)?;
+        send_message(&mut self.stream, MSG_STATE, &state)?;
+
+        let (kind, payload_len) = recv_header(&mut self.stream)?;
+        match kind {
+            MSG_COMPLETE => Ok(()),
+            MSG_ERROR => {
+                let payload = recv_payload(&mut self.stream, payload_len)?;
+                Err(MigrationError::Remote(
+                    String::from_utf8_lossy(&payload).into_owned(),
+                ))
+            }
+            _ => Err(MigrationError::InvalidMessage(
+                "expected the completion message",
+            )),
+        }
+    }
+}
+
+impl AsRawFd for MigrationSender {
+    fn as_raw_fd(&self) -> RawFd {
+        self.stream.as_raw_fd()
+    }
+}
+
+/// Receives a microVM from a source connecting to `params.socket_path`, and resumes it.
+pub fn receive_migration(
+    instance_info: &InstanceInfo,
+    event_manager: &mut EventManager,
+    seccomp_filters: &BpfThreadMap,
+    params: &ReceiveMigrationParams,
+    version_map: VersionMap,
+) -> Result<Arc<Mutex<Vmm>>> {
+    let listener = UnixListener::bind(&params.socket_path).map_err(MigrationError::Socket)?;
+    let accepted = listener.accept();
+    // A single source is accepted.
+    drop(listener);
+    if let Err(err) = std::fs::remove_file(&params.socket_path) {
+        error!("Cannot remove the migration socket: {}", err);
+    }
+    let (mut stream, _) = accepted.map_err(MigrationError::Socket)?;
+
+    match receive_microvm(
+        &mut stream,
+        instance_info,
+        event_manager,
+        seccomp_filters,
+        params,
+        version_map,
+    ) {
+        Ok(vmm) => {
+            if let Err(err) = send_message(&mut stream, MSG_COMPLETE, &[]) {
+                error!("Cannot notify the source of the migration: {}", err);
+            }
+            Ok(vmm)
+        }
+        Err(err) => {
+            // Let the source know, so that it resumes the microVM.
+            let _ = send_message(&mut stream, MSG_ERROR, err.to_string().as_bytes());
+            Err(err)
+        }
+    }
+}
+
+// Writes the received memory to `guest_memory` and returns the serialized microVM state.
+fn receive_memory<T: Read>(stream: &mut T, guest_memory: &GuestMemoryMmap) -> Result<Vec<u8>> {
+    loop {
+        let (kind, payload_len) = recv_header(stream)?;
+        match kind {
+            MSG_MEMORY => {
+                if payload_len < 8 {
+                    return Err(MigrationError::InvalidMessage("memory message too short"));
+                }
+                let mut addr = [0u8; 8];
+                stream
+                    .read_exact(&mut addr)
+                    .map_err(MigrationError::Socket)?;
+                guest_memory
+                    .read_exact_from(
+                        GuestAddress(read_le_u64(&addr)),
+                        stream,
+                        (payload_len - 8) as usize,
+                    )
+                    .map_err(MigrationError::Memory)?;
+            }
+            MSG_STATE => return recv_payload(stream, payload_len),
+            _ => return Err(MigrationError::InvalidMessage("unexpected message")),
+        }
+    }
+}
+
+fn receive_microvm(
+    stream: &mut UnixStream,
+    instance_info: &InstanceInfo,
+    event_manager: &mut EventManager,
+    seccomp_filters: &BpfThreadMap,
+    params: &ReceiveMigrationParams,
+    version_map: VersionMap,
+) -> Result<Arc<Mutex<Vmm>>> {
+    let track_dirty_pages = params.enable_diff_snapshots;
+    let ranges = recv_start(stream)?;
+    let guest_memory = GuestMemoryMmap::from_ranges_guarded(&ranges, track_dirty_pages)
+        .map_err(MigrationError::CreateMemory)?;
+
+    let state = receive_memory(stream, &guest_memory)?;
+    let microvm_state: MicrovmState =
+        Snapshot::load(&mut state.as_slice(), state.len(), version_map)
+            .map_err(MigrationError::DeserializeMicrovmState)?;
+    snapshot_state_sanity_check(&microvm_state).map_err(MigrationError::IncompatibleState)?;
+    if !microvm_state
+        .memory_state
+        .regions
+        .iter()
+        .map(|region| (GuestAddress(region.base_address), region.size))
+        .eq(ranges.into_iter())
+    {
+        return Err(MigrationError::InvalidMessage(
+            "the guest memory layout doesn't match the microVM state",
+        ));
+    }
+    // Receiving the content doesn't dirty the guest memory.
+    reset_dirty_bitmaps(&guest_memory);
+
+    let vmm = builder::build_microvm_from_snapshot(
+        instance_info,
+        event_manager,
+        microvm_state,
+        guest_memory,
+        track_dirty_pages,
+        &params.network_overrides,
+        &params.drive_path_overrides,
+        seccomp_filters,
+    )
+    .map_err(MigrationError::BuildMicroVm)?;
+    vmm.lock()
+        .expect("Poisoned lock")
+        .resume_vm()
+        .map_err(MigrationError::ResumeMicroVm)?;
+
+    Ok(vmm)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+
+    const PAGE_SIZE: usize = arch::PAGE_SIZE;
+
+    fn guest_memory(track_dirty_pages: bool) -> GuestMemoryMmap {
+        let ranges = [
+            (GuestAddress(0), PAGE_SIZE * 4),
+            (GuestAddress(PAGE_SIZE as u64 * 8), PAGE_SIZE * 2),
+        ];
+        GuestMemoryMmap::from_ranges_guarded(&ranges, track_dirty_pages).unwrap()
+    }
+
+    fn read_page(guest_memory: &GuestMemoryMmap, page: u64) -> Vec<u8> {
+        let mut buf = vec![0u8; PAGE_SIZE];
+        guest_memory
+            .read_slice(&mut buf, GuestAddress(page * PAGE_SIZE as u64))
+            .unwrap();
+        buf
+    }
+
+    #[test]
+    fn test_start_message() {
+        let src_memory = guest_memory(false);
+        let mut stream = Vec::new();
+        send_start(&mut stream, &src_memory.describe()).unwrap();
+
+        let ranges = recv_start(&mut stream.as_slice()).unwrap();
+        assert_eq!(
+            ranges,
+            vec![
+                (GuestAddress(0), PAGE_SIZE * 4),
+                (GuestAddress(PAGE_SIZE as u64 * 8), PAGE_SIZE * 2),
+            ]
+        );
+
+        // Corrupt the magic ID.
+        stream[MESSAGE_HEADER_SIZE] ^= 0xFF;
+        match recv_start(&mut stream.as_slice()) {
+            Err(MigrationError::InvalidMessage(_)) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+
+        // Another message kind.
+        let mut stream = Vec::new();
+        send_message(&mut stream, MSG_COMPLETE, &[]).unwrap();
+        match recv_start(&mut stream.as_slice()) {
+            Err(MigrationError::InvalidMessage(_)) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_send_memory() {
+        let src_memory = guest_memory(true);
+        for page in [0u64, 1, 3, 8, 9].iter() {
+            src_memory
+                .write_slice(
+                    &[*page as u8 + 1; PAGE_SIZE],
+                    GuestAddress(page * PAGE_SIZE as u64),
+                )
+                .unwrap();
+        }
+
+        // The first round sends the whole memory.
+        let mut stream = Vec::new();
+        let mut round = Round::new(&src_memory, None);
+        assert!(round
+            .send_pages(&mut stream, &src_memory, usize::MAX)
+            .unwrap());
+        assert_eq!(round.sent_pages, 6);
+        send_message(&mut stream, MSG_STATE, b"state").unwrap();
+        let dst_memory = guest_memory(false);
+        let state = receive_memory(&mut stream.as_slice(), &dst_memory).unwrap();
+        assert_eq!(state, b"state");
+        for page in [0u64, 1, 2, 3, 8, 9].iter() {
+            assert_eq!(read_page(&src_memory, *page), read_page(&dst_memory, *page));
+        }
+
+        // Then only the pages dirtied by the guest or by the devices are sent.
+        src_memory
+            .write_slice(&[0xAA; PAGE_SIZE], GuestAddress(PAGE_SIZE as u64 * 2))
+            .unwrap();
+        let mut dirty_bitmap: DirtyBitmap = HashMap::new();
+        // KVM reports the second page of the first region and the first page of the second one.
+        dirty_bitmap.insert(0, vec![0b0010]);
+        dirty_bitmap.insert(1, vec![0b01]);
+        src_memory
+            .write_slice(&[0xBB; PAGE_SIZE], GuestAddress(PAGE_SIZE as u64))
+            .unwrap();
+        src_memory
+            .write_slice(&[0xCC; PAGE_SIZE], GuestAddress(PAGE_SIZE as u64 * 8))
+            .unwrap();
+
+        let mut stream = Vec::new();
+        let mut round = Round::new(&src_memory, Some(&dirty_bitmap));
+        assert!(round
+            .send_pages(&mut stream, &src_memory, usize::MAX)
+            .unwrap());
+        assert_eq!(round.sent_pages, 3);
+        // The pages are sent in two batches, one per region.
+        assert_eq!(stream.len(), 2 * (MESSAGE_HEADER_SIZE + 8) + 3 * PAGE_SIZE);
+        send_message(&mut stream, MSG_STATE, &[]).unwrap();
+        receive_memory(&mut stream.as_slice(), &dst_memory).unwrap();
+        for page in [0u64, 1, 2, 3, 8, 9].iter() {
+            assert_eq!(read_page(&src_memory, *page), read_page(&dst_memory, *page));
+        }
+
+        // The Firecracker bitmap was cleared.
+        let mut stream = Vec::new();
+        let mut round = Round::new(&src_memory, Some(&HashMap::new()));
+        assert!(round
+            .send_pages(&mut stream, &src_memory, usize::MAX)
+            .unwrap());
+        assert_eq!(round.sent_pages, 0);
+        assert!(stream.is_empty());
+    }
+
+    #[test]
+    fn test_send_memory_in_steps() {
+        let src_memory = guest_memory(true);
+        for page in [0u64, 1, 2, 3, 8, 9].iter() {
+            src_memory
+                .write_slice(
+                    &[*page as u8 + 1; PAGE_SIZE],
+                    GuestAddress(page * PAGE_SIZE as u64),
+                )
+                .unwrap();
+        }
+
+        let mut stream = Vec::new();
+        let mut round = Round::new(&src_memory, None);
+        // A step stops in the middle of a region once it sent the maximum page count.
+        assert!(!round.send_pages(&mut stream, &src_memory, 3).unwrap());
+        assert_eq!(round.sent_pages, 3);
+        // The next one resumes from there.
+        assert!(round.send_pages(&mut stream, &src_memory, 3).unwrap());
+        assert_eq!(round.sent_pages, 6);
+        assert_eq!(stream.len(), 3 * (MESSAGE_HEADER_SIZE + 8) + 6 * PAGE_SIZE);
+
+        send_message(&mut stream, MSG_STATE, &[]).unwrap();
+        let dst_memory = guest_memory(false);
+        receive_memory(&mut stream.as_slice(), &dst_memory).unwrap();
+        for page in [0u64, 1, 2, 3, 8, 9].iter() {
+            assert_eq!(read_page(&src_memory, *page), read_page(&dst_memory, *page));
+        }
+    }
+
+    // Accepts `capacity` bytes, after which writing would block.
+    struct FullSocket {
+        buf: Vec<u8>,
+        capacity: usize,
+    }
+
+    impl Write for FullSocket {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let len = std::cmp::min(buf.len(), self.capacity - self.buf.len());
+            if len == 0 {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            self.buf.extend_from_slice(&buf[..len]);
+            Ok(len)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_write_available() {
+        let mut socket = FullSocket {
+            buf: Vec::new(),
+            capacity: 4,
+        };
+        assert_eq!(write_available(&mut socket, b"foobar").unwrap(), 4);
+        assert_eq!(write_available(&mut socket, b"ar").unwrap(), 0);
+
+        // The write resumes from where it stopped once the socket has room.
+        socket.capacity = 6;
+        assert_eq!(write_available(&mut socket, b"ar").unwrap(), 2);
+        assert_eq!(socket.buf, b"foobar");
+
+        match write_available(&mut &mut [0u8; 0][..], b"foo") {
+            Err(MigrationError::Socket(_)) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_receive_invalid_memory() {
+        let dst_memory = guest_memory(false);
+
+        // Memory outside of the guest memory.
+        let mut stream = Vec::new();
+        send_header(&mut stream, MSG_MEMORY, 8 + PAGE_SIZE as u64).unwrap();
+        stream.extend_from_slice(&(PAGE_SIZE as u64 * 5).to_le_bytes());
+        stream.extend_from_slice(&[1u8; PAGE_SIZE]);
+        match receive_memory(&mut stream.as_slice(), &dst_memory) {
+            Err(MigrationError::Memory(_)) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+
+        // Truncated stream.
+        let mut stream = Vec::new();
+        send_header(&mut stream, MSG_MEMORY, 4).unwrap();
+        match receive_memory(&mut stream.as_slice(), &dst_memory) {
+            Err(MigrationError::InvalidMessage(_)) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        match receive_memory(&mut &[0u8; 4][..], &dst_memory) {
+            Err(MigrationError::Socket(_)) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_error_display() {
+        let errors = [
+            MigrationError::HugePages,
+            MigrationError::InvalidMessage("foo"),
+            MigrationError::PauseMicroVm(VmmError::VcpuPause),
+            MigrationError::Remote(String::from("foo")),
+            MigrationError::ResumeMicroVm(VmmError::VcpuResume),
+            MigrationError::Socket(io::Error::from_raw_os_error(0)),
+        ];
+        for err in errors.iter() {
+            let _ = format!("{}{:?}", err, err);
+        }
+    }
+}
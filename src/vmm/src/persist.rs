@@ -5,42 +5,61 @@
 
 use std::fmt::{Display, Formatter};
 use std::fs::{File, OpenOptions};
-use std::io::{self, Write};
-use std::path::Path;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 
 use crate::builder::{self, StartMicrovmError};
 use crate::device_manager::persist::Error as DevicePersistError;
 use crate::mem_size_mib;
 use crate::vmm_config::machine_config::MAX_SUPPORTED_VCPUS;
-use crate::vmm_config::snapshot::{CreateSnapshotParams, LoadSnapshotParams, SnapshotType};
+use crate::vmm_config::snapshot::{
+    CheckSnapshotParams, CreateSnapshotParams, LoadSnapshotParams, MemBackendType, MemFileFormat,
+    SnapshotDestinationType, SnapshotType,
+};
+#[cfg(target_arch = "x86_64")]
+use crate::vstate::system::KvmContext;
 use crate::vstate::{self, vcpu::VcpuState, vm::VmState};
 
 use crate::device_manager::persist::DeviceStates;
 use crate::memory_snapshot;
-use crate::memory_snapshot::{GuestMemoryState, SnapshotMemory};
+use crate::memory_snapshot::{
+    GuestMemoryState, GuestRegionUffdMapping, MemFileFormatState, SnapshotMemory,
+};
 use crate::version_map::FC_VERSION_TO_SNAP_VERSION;
 use crate::{Error as VmmError, EventManager, Vmm};
 #[cfg(target_arch = "x86_64")]
 use cpuid::common::{get_vendor_id_from_cpuid, get_vendor_id_from_host};
+#[cfg(target_arch = "x86_64")]
+use cpuid::compat::{missing_cpuid_bits, MissingCpuidBits};
+#[cfg(target_arch = "x86_64")]
+use kvm_bindings::{CpuId, MsrList, KVM_MAX_CPUID_ENTRIES};
 
 use crate::vmm_config::instance_info::InstanceInfo;
 #[cfg(target_arch = "aarch64")]
 use arch::regs::{get_manufacturer_id_from_host, get_manufacturer_id_from_state};
 use logger::{error, info};
 use seccompiler::BpfThreadMap;
+use serde::Serialize;
 use snapshot::Snapshot;
+use utils::hmac::{HmacSha256, DIGEST_LEN};
+use utils::sock_ctrl_msg::ScmSocket;
+use utils::uffd::{Error as UffdError, Uffd, UFFD_FEATURE_EVENT_REMOVE};
 use versionize::{VersionMap, Versionize, VersionizeResult};
 use versionize_derive::Versionize;
-use vm_memory::GuestMemoryMmap;
+use vm_memory::{GuestAddress, GuestMemory, GuestMemoryMmap};
 
 #[cfg(target_arch = "x86_64")]
 const FC_V0_23_SNAP_VERSION: u16 = 1;
 #[cfg(target_arch = "x86_64")]
 const FC_V0_23_MAX_DEVICES: u32 = 11;
+// Size of the buffer through which the memory file is fed to the HMAC computation.
+const INTEGRITY_READ_BUFFER_SIZE: usize = 1 << 20;
 
 /// Holds information related to the VM that is not part of VmState.
-#[derive(Debug, PartialEq, Versionize)]
+#[derive(Debug, PartialEq, Serialize, Versionize)]
 // NOTICE: Any changes to this structure require a snapshot version bump.
 pub struct VmInfo {
     /// Guest memory size.
@@ -48,7 +67,7 @@ pub struct VmInfo {
 }
 
 /// Contains the necesary state for saving/restoring a microVM.
-#[derive(Versionize)]
+#[derive(Debug, Serialize, Versionize)]
 // NOTICE: Any changes to this structure require a snapshot version bump.
 pub struct MicrovmState {
     /// Miscellaneous VM info.
@@ -61,6 +80,25 @@ pub struct MicrovmState {
     pub vcpu_states: Vec<VcpuState>,
     /// Device states.
     pub device_states: DeviceStates,
+    /// Number of vCPUs plugged into the guest, the first ones of `vcpu_states`. The other
+    /// ones can still be hot-added.
+    #[version(start = 2, default_fn = "default_plugged_vcpu_count")]
+    pub plugged_vcpu_count: Option<u8>,
+    /// Whether hyperthreading is enabled, in which case the vCPUs are hot-added in pairs.
+    #[version(start = 2, default_fn = "default_ht_enabled")]
+    pub ht_enabled: bool,
+}
+
+impl MicrovmState {
+    // v0.24 and older versions do not support vCPU hotplug, so all the vCPUs are plugged.
+    fn default_plugged_vcpu_count(_: u16) -> Option<u8> {
+        None
+    }
+
+    // No vCPU can be hot-added to the microVMs of v0.24 and older versions.
+    fn default_ht_enabled(_: u16) -> bool {
+        false
+    }
 }
 
 /// Errors related to saving and restoring Microvm state.
@@ -111,6 +149,13 @@ impl Display for MicrovmStateError {
 pub enum CreateSnapshotError {
     /// Failed to get dirty bitmap.
     DirtyBitmap(VmmError),
+    /// The memory file is the memfd backing the shared guest memory, which can only be
+    /// referenced by a full snapshot in the raw format.
+    GuestMemfdReference,
+    /// The guest memory is backed by huge pages, which snapshots don't support.
+    HugePages,
+    /// The integrity key is not a valid hex string.
+    InvalidIntegrityKey,
     /// Invalid microVM version format
     InvalidVersionFormat,
     /// MicroVM version does not support snapshot.
@@ -135,6 +180,19 @@ impl Display for CreateSnapshotError {
         use self::CreateSnapshotError::*;
         match self {
             DirtyBitmap(err) => write!(f, "Cannot get dirty bitmap: {}", err),
+            GuestMemfdReference => write!(
+                f,
+                "The memfd backing the shared guest memory can only be the memory file of a \
+                 full snapshot in the raw format."
+            ),
+            HugePages => write!(
+                f,
+                "Cannot snapshot a microVM whose guest memory is backed by huge pages."
+            ),
+            InvalidIntegrityKey => write!(
+                f,
+                "The integrity key must be a non-empty string of hex encoded bytes."
+            ),
             InvalidVersionFormat => write!(f, "Invalid microVM version format"),
             UnsupportedVersion => write!(
                 f,
@@ -166,6 +224,26 @@ impl Display for CreateSnapshotError {
     }
 }
 
+/// A CPU feature used by a snapshot that the host doesn't support.
+#[cfg(target_arch = "x86_64")]
+#[derive(Clone, Debug, PartialEq)]
+pub enum MissingCpuFeature {
+    /// CPUID feature flags exposed to the guest.
+    Cpuid(MissingCpuidBits),
+    /// An MSR saved in the vCPU state.
+    Msr(u32),
+}
+
+#[cfg(target_arch = "x86_64")]
+impl Display for MissingCpuFeature {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            MissingCpuFeature::Cpuid(bits) => write!(f, "{}", bits),
+            MissingCpuFeature::Msr(index) => write!(f, "MSR {:#x}", index),
+        }
+    }
+}
+
 /// Errors associated with loading a snapshot.
 #[derive(Debug)]
 pub enum LoadSnapshotError {
@@ -183,8 +261,28 @@ pub enum LoadSnapshotError {
     SnapshotBackingFile(&'static str, io::Error),
     /// Snapshot cpu vendor differs than host cpu vendor.
     CpuVendorCheck(String),
+    /// Failed to retrieve the CPU features supported by the host.
+    #[cfg(target_arch = "x86_64")]
+    CpuFeatureCheck(String),
+    /// The host doesn't support some of the CPU features used by the snapshot.
+    #[cfg(target_arch = "x86_64")]
+    MissingCpuFeatures(Vec<MissingCpuFeature>),
     /// Snapshot failed sanity checks.
     InvalidSnapshot(String),
+    /// The guest memory backend is not correctly specified.
+    InvalidMemoryBackend,
+    /// The integrity key is not a valid hex string.
+    InvalidIntegrityKey,
+    /// The snapshot files don't match their HMAC tag.
+    IntegrityCheck,
+    /// The integrity of the guest memory can't be verified with the chosen backend.
+    IntegrityCheckNotSupported,
+    /// Failed to register the guest memory with userfaultfd.
+    Uffd(UffdError),
+    /// The memory file is compressed, so the page fault handler can't serve it.
+    UffdCompressedMemory,
+    /// Failed to send the userfaultfd to the page fault handler.
+    UffdHandler(io::Error),
 }
 
 impl Display for LoadSnapshotError {
@@ -208,7 +306,50 @@ impl Display for LoadSnapshotError {
                 action, err
             ),
             CpuVendorCheck(err) => write!(f, "CPU vendor check failed: {}", err),
+            #[cfg(target_arch = "x86_64")]
+            CpuFeatureCheck(err) => write!(f, "CPU feature check failed: {}", err),
+            #[cfg(target_arch = "x86_64")]
+            MissingCpuFeatures(features) => {
+                let features: Vec<String> = features.iter().map(|f| f.to_string()).collect();
+                write!(
+                    f,
+                    "The host doesn't support the CPU features used by the snapshot: {}",
+                    features.join(", ")
+                )
+            }
             InvalidSnapshot(err) => write!(f, "Snapshot sanity check failed: {}", err),
+            InvalidMemoryBackend => write!(
+                f,
+                "Exactly one of mem_file_path and mem_backend must be specified."
+            ),
+            InvalidIntegrityKey => write!(
+                f,
+                "The integrity key must be a non-empty string of hex encoded bytes."
+            ),
+            IntegrityCheck => write!(
+                f,
+                "The snapshot files don't match their integrity tag. They are either \
+                 corrupted, tampered with, or were not created with the same key."
+            ),
+            IntegrityCheckNotSupported => write!(
+                f,
+                "The integrity of the snapshot files can't be verified with a Uffd memory \
+                 backend."
+            ),
+            Uffd(err) => write!(
+                f,
+                "Cannot register the guest memory with userfaultfd: {}",
+                err
+            ),
+            UffdCompressedMemory => write!(
+                f,
+                "Compressed memory files can't be served by a Uffd memory backend."
+            ),
+            UffdHandler(err) => write!(
+                f,
+                "Cannot send the userfaultfd to the page fault handler: {}",
+                err
+            ),
         }
     }
 }
@@ -219,42 +360,66 @@ pub fn create_snapshot(
     params: &CreateSnapshotParams,
     version_map: VersionMap,
 ) -> std::result::Result<(), CreateSnapshotError> {
+    use self::CreateSnapshotError::*;
     // Fail early from invalid target version.
     let snapshot_data_version = get_snapshot_data_version(&params.version, &version_map, &vmm)?;
+    // The memory file would be restored into, or served through userfaultfd to, regular
+    // pages, so the huge pages the microVM was configured with would be silently dropped.
+    if vmm.is_hugetlb_backed() {
+        return Err(HugePages);
+    }
+    let integrity_key = match &params.integrity_key {
+        Some(key) => Some(parse_integrity_key(key).ok_or(InvalidIntegrityKey)?),
+        None => None,
+    };
 
-    let microvm_state = vmm
-        .save_state()
-        .map_err(CreateSnapshotError::MicrovmState)?;
+    let mut microvm_state = vmm.save_state().map_err(MicrovmState)?;
+    microvm_state.memory_state.file_format = params.mem_file_format.into();
 
-    snapshot_state_to_file(
-        &microvm_state,
-        &params.snapshot_path,
-        snapshot_data_version,
-        version_map,
+    let mut state_bytes = Vec::new();
+    Snapshot::new(version_map, snapshot_data_version)
+        .save(&mut state_bytes, &microvm_state)
+        .map_err(SerializeMicrovmState)?;
+
+    if params.destination_type == SnapshotDestinationType::UnixSocket {
+        snapshot_state_to_socket(&state_bytes, &params.snapshot_path)?;
+        return snapshot_memory_to_socket(vmm, &params.mem_file_path, params.mem_file_format);
+    }
+
+    snapshot_memory_to_file(
+        vmm,
+        &params.mem_file_path,
+        &params.snapshot_type,
+        params.mem_file_format,
     )?;
 
-    snapshot_memory_to_file(vmm, &params.mem_file_path, &params.snapshot_type)?;
+    snapshot_state_to_file(&state_bytes, &params.snapshot_path)?;
+
+    // The tags cover the memory file as written, so they're computed last.
+    if let Some(key) = integrity_key {
+        let tags = snapshot_tags(&key, &state_bytes, &params.mem_file_path)
+            .map_err(|e| MemoryBackingFile("read", e))?;
+        snapshot_state_to_file(&tags, &integrity_tag_path(&params.snapshot_path))?;
+    }
 
     Ok(())
 }
 
 fn snapshot_state_to_file(
-    microvm_state: &MicrovmState,
+    state_bytes: &[u8],
     snapshot_path: &Path,
-    snapshot_data_version: u16,
-    version_map: VersionMap,
 ) -> std::result::Result<(), CreateSnapshotError> {
     use self::CreateSnapshotError::*;
     let mut snapshot_file = OpenOptions::new()
         .create(true)
         .write(true)
+        .truncate(true)
         .open(snapshot_path)
         .map_err(|e| SnapshotBackingFile("open", e))?;
 
-    let mut snapshot = Snapshot::new(version_map, snapshot_data_version);
-    snapshot
-        .save(&mut snapshot_file, microvm_state)
-        .map_err(SerializeMicrovmState)?;
+    snapshot_file
+        .write_all(state_bytes)
+        .map_err(|e| SnapshotBackingFile("write", e))?;
     snapshot_file
         .flush()
         .map_err(|e| SnapshotBackingFile("flush", e))?;
@@ -263,12 +428,52 @@ fn snapshot_state_to_file(
         .map_err(|e| SnapshotBackingFile("sync_all", e))
 }
 
+fn snapshot_state_to_socket(
+    state_bytes: &[u8],
+    socket_path: &Path,
+) -> std::result::Result<(), CreateSnapshotError> {
+    use self::CreateSnapshotError::*;
+    let mut stream =
+        UnixStream::connect(socket_path).map_err(|e| SnapshotBackingFile("connect", e))?;
+    stream
+        .write_all(state_bytes)
+        .map_err(|e| SnapshotBackingFile("write", e))
+}
+
+// Only full snapshots are streamed, as diff snapshots need to seek over the
+// clean pages.
+fn snapshot_memory_to_socket(
+    vmm: &Vmm,
+    socket_path: &Path,
+    mem_file_format: MemFileFormat,
+) -> std::result::Result<(), CreateSnapshotError> {
+    use self::CreateSnapshotError::*;
+    let stream = UnixStream::connect(socket_path).map_err(|e| MemoryBackingFile("connect", e))?;
+    let mut writer = BufWriter::new(&stream);
+    match mem_file_format {
+        MemFileFormat::Compressed => vmm.guest_memory().dump_compressed(&mut writer),
+        MemFileFormat::Raw => vmm.guest_memory().dump(&mut writer),
+    }
+    .map_err(Memory)?;
+    writer.flush().map_err(|e| MemoryBackingFile("flush", e))
+}
+
 fn snapshot_memory_to_file(
     vmm: &Vmm,
     mem_file_path: &Path,
     snapshot_type: &SnapshotType,
+    mem_file_format: MemFileFormat,
 ) -> std::result::Result<(), CreateSnapshotError> {
     use self::CreateSnapshotError::*;
+    // The memfd backing the shared guest memory is laid out as a full snapshot memory file,
+    // so it is referenced as such instead of being copied.
+    if vmm.is_guest_memfd(mem_file_path) {
+        if *snapshot_type != SnapshotType::Full || mem_file_format != MemFileFormat::Raw {
+            return Err(GuestMemfdReference);
+        }
+        return Ok(());
+    }
+
     let mut file = OpenOptions::new()
         .write(true)
         .create(true)
@@ -276,6 +481,18 @@ fn snapshot_memory_to_file(
         .open(mem_file_path)
         .map_err(|e| MemoryBackingFile("open", e))?;
 
+    if mem_file_format == MemFileFormat::Compressed {
+        // The compressed file is written sequentially, in small records.
+        let mut writer = BufWriter::new(&file);
+        vmm.guest_memory()
+            .dump_compressed(&mut writer)
+            .map_err(Memory)?;
+        writer.flush().map_err(|e| MemoryBackingFile("flush", e))?;
+        return file
+            .sync_all()
+            .map_err(|e| MemoryBackingFile("sync_all", e));
+    }
+
     // Set the length of the file to the full size of the memory area.
     let mem_size_mib = mem_size_mib(vmm.guest_memory());
     file.set_len((mem_size_mib * 1024 * 1024) as u64)
@@ -299,7 +516,21 @@ fn snapshot_memory_to_file(
 pub fn get_snapshot_data_version(
     version: &Option<String>,
     version_map: &VersionMap,
-    _vmm: &Vmm,
+    vmm: &Vmm,
+) -> std::result::Result<u16, CreateSnapshotError> {
+    get_devices_snapshot_data_version(
+        version,
+        version_map,
+        vmm.mmio_device_manager.used_irqs_count(),
+    )
+}
+
+/// Validate the microVM version and translate it to its corresponding snapshot data format,
+/// for a microVM whose MMIO devices use `used_irqs_count` interrupt lines.
+pub fn get_devices_snapshot_data_version(
+    version: &Option<String>,
+    version_map: &VersionMap,
+    _used_irqs_count: usize,
 ) -> std::result::Result<u16, CreateSnapshotError> {
     if version.is_none() {
         return Ok(version_map.latest_version());
@@ -308,7 +539,7 @@ pub fn get_snapshot_data_version(
     match FC_VERSION_TO_SNAP_VERSION.get(version.as_ref().unwrap()) {
         #[cfg(target_arch = "x86_64")]
         Some(&FC_V0_23_SNAP_VERSION) => {
-            validate_devices_number(_vmm.mmio_device_manager.used_irqs_count())?;
+            validate_devices_number(_used_irqs_count)?;
             Ok(FC_V0_23_SNAP_VERSION)
         }
         Some(data_version) => Ok(*data_version),
@@ -345,6 +576,61 @@ pub fn validate_cpu_vendor(
     Ok(())
 }
 
+/// Validates that the host supports the CPU features used by the snapshot, i.e. the
+/// CPUID feature flags exposed to the guest and the MSRs saved in the vCPU states.
+#[cfg(target_arch = "x86_64")]
+pub fn validate_cpu_features(
+    microvm_state: &MicrovmState,
+) -> std::result::Result<(), LoadSnapshotError> {
+    use self::LoadSnapshotError::{CpuFeatureCheck, MissingCpuFeatures};
+
+    let kvm = KvmContext::new().map_err(|e| CpuFeatureCheck(e.to_string()))?;
+    let host_cpuid = kvm
+        .fd()
+        .get_supported_cpuid(KVM_MAX_CPUID_ENTRIES)
+        .map_err(|e| CpuFeatureCheck(e.to_string()))?;
+    let host_msrs = arch::x86_64::msr::supported_guest_msrs(kvm.fd())
+        .map_err(|e| CpuFeatureCheck(format!("{:?}", e)))?;
+
+    let missing = missing_cpu_features(microvm_state, &host_cpuid, &host_msrs);
+    if !missing.is_empty() {
+        let err = MissingCpuFeatures(missing);
+        error!("{}", err);
+        return Err(err);
+    }
+
+    Ok(())
+}
+
+// Collects the CPU features of all the vCPU states that are missing on the host, each
+// feature being reported once.
+#[cfg(target_arch = "x86_64")]
+fn missing_cpu_features(
+    microvm_state: &MicrovmState,
+    host_cpuid: &CpuId,
+    host_msrs: &MsrList,
+) -> Vec<MissingCpuFeature> {
+    let mut missing = Vec::new();
+    for state in &microvm_state.vcpu_states {
+        let cpuid_features = missing_cpuid_bits(&state.cpuid, host_cpuid)
+            .into_iter()
+            .map(MissingCpuFeature::Cpuid);
+        let msr_features = state
+            .msrs
+            .as_slice()
+            .iter()
+            .filter(|entry| !host_msrs.as_slice().contains(&entry.index))
+            .map(|entry| MissingCpuFeature::Msr(entry.index));
+
+        for feature in cpuid_features.chain(msr_features) {
+            if !missing.contains(&feature) {
+                missing.push(feature);
+            }
+        }
+    }
+    missing
+}
+
 /// Validate that Snapshot Manufacturer ID matches
 /// the one from the Host
 ///
@@ -398,13 +684,26 @@ pub fn snapshot_state_sanity_check(
     }
 
     #[cfg(target_arch = "x86_64")]
-    validate_cpu_vendor(&microvm_state)?;
+    {
+        validate_cpu_vendor(&microvm_state)?;
+        validate_cpu_features(&microvm_state)?;
+    }
     #[cfg(target_arch = "aarch64")]
     validate_cpu_manufacturer_id(&microvm_state)?;
 
     Ok(())
 }
 
+/// Runs the sanity checks of a snapshot restore against the state file, without
+/// loading the snapshot.
+pub fn check_snapshot(
+    params: &CheckSnapshotParams,
+    version_map: VersionMap,
+) -> std::result::Result<(), LoadSnapshotError> {
+    let microvm_state = snapshot_state_from_file(&params.snapshot_path, version_map)?;
+    snapshot_state_sanity_check(&microvm_state)
+}
+
 /// Loads a Microvm snapshot producing a 'paused' Microvm.
 pub fn restore_from_snapshot(
     instance_info: &InstanceInfo,
@@ -415,25 +714,88 @@ pub fn restore_from_snapshot(
 ) -> std::result::Result<Arc<Mutex<Vmm>>, LoadSnapshotError> {
     use self::LoadSnapshotError::*;
     let track_dirty_pages = params.enable_diff_snapshots;
-    let microvm_state = snapshot_state_from_file(&params.snapshot_path, version_map)?;
+    let (microvm_state, verified_guest_memory) = match &params.integrity_key {
+        Some(key) => {
+            let mem_file_path = match (&params.mem_file_path, &params.mem_backend) {
+                (Some(mem_file_path), None) => mem_file_path,
+                (None, Some(mem_backend)) if mem_backend.backend_type == MemBackendType::File => {
+                    &mem_backend.backend_path
+                }
+                // The memory file of a Uffd backend is only read by the page fault handler.
+                (None, Some(_)) => return Err(IntegrityCheckNotSupported),
+                _ => return Err(InvalidMemoryBackend),
+            };
+            let (microvm_state, guest_memory) = verified_snapshot_from_files(
+                &params.snapshot_path,
+                mem_file_path,
+                key,
+                track_dirty_pages,
+                version_map,
+            )?;
+            (microvm_state, Some(guest_memory))
+        }
+        None => (
+            snapshot_state_from_file(&params.snapshot_path, version_map)?,
+            None,
+        ),
+    };
 
     // Some sanity checks before building the microvm.
     snapshot_state_sanity_check(&microvm_state)?;
 
-    let guest_memory = guest_memory_from_file(
+    let (guest_memory, uffd) = match (
+        verified_guest_memory,
         &params.mem_file_path,
-        &microvm_state.memory_state,
-        track_dirty_pages,
-    )?;
-    builder::build_microvm_from_snapshot(
+        &params.mem_backend,
+    ) {
+        // The verified content of the memory file was already copied to the guest memory.
+        (Some(guest_memory), _, _) => (guest_memory, None),
+        (None, Some(mem_file_path), None) => (
+            guest_memory_from_file(
+                mem_file_path,
+                &microvm_state.memory_state,
+                track_dirty_pages,
+            )?,
+            None,
+        ),
+        (None, None, Some(mem_backend)) => match mem_backend.backend_type {
+            MemBackendType::File => (
+                guest_memory_from_file(
+                    &mem_backend.backend_path,
+                    &microvm_state.memory_state,
+                    track_dirty_pages,
+                )?,
+                None,
+            ),
+            MemBackendType::Uffd => {
+                if microvm_state.memory_state.file_format == MemFileFormatState::Compressed {
+                    return Err(UffdCompressedMemory);
+                }
+                let (guest_memory, uffd) = guest_memory_from_uffd(
+                    &mem_backend.backend_path,
+                    &microvm_state.memory_state,
+                    track_dirty_pages,
+                )?;
+                (guest_memory, Some(uffd))
+            }
+        },
+        _ => return Err(InvalidMemoryBackend),
+    };
+    let vmm = builder::build_microvm_from_snapshot(
         instance_info,
         event_manager,
         microvm_state,
         guest_memory,
         track_dirty_pages,
+        &params.network_overrides,
+        &params.drive_path_overrides,
         seccomp_filters,
     )
-    .map_err(BuildMicroVm)
+    .map_err(BuildMicroVm)?;
+    // The guest memory is served by the handler for as long as the userfaultfd is open.
+    vmm.lock().expect("Poisoned lock").uffd = uffd;
+
+    Ok(vmm)
 }
 
 fn snapshot_state_from_file(
@@ -449,6 +811,119 @@ fn snapshot_state_from_file(
     Snapshot::load(&mut snapshot_reader, snapshot_len, version_map).map_err(DeserializeMicrovmState)
 }
 
+/// Returns the path of the file holding the integrity tags of the snapshot whose microVM
+/// state file is `snapshot_path`.
+pub fn integrity_tag_path(snapshot_path: &Path) -> PathBuf {
+    let mut path = snapshot_path.as_os_str().to_owned();
+    path.push(".hmac");
+    PathBuf::from(path)
+}
+
+// Checks the snapshot files against their integrity tags. The microVM state is only
+// deserialized once it matches its tag. The memory file is then read once, into anonymous
+// guest memory, so that the content which is checked is the one the microVM runs on.
+fn verified_snapshot_from_files(
+    snapshot_path: &Path,
+    mem_file_path: &Path,
+    integrity_key: &str,
+    track_dirty_pages: bool,
+    version_map: VersionMap,
+) -> std::result::Result<(MicrovmState, GuestMemoryMmap), LoadSnapshotError> {
+    use self::LoadSnapshotError::*;
+    let key = parse_integrity_key(integrity_key).ok_or(InvalidIntegrityKey)?;
+    let tags = std::fs::read(integrity_tag_path(snapshot_path))
+        .map_err(|e| SnapshotBackingFile("read", e))?;
+    if tags.len() != 2 * DIGEST_LEN {
+        return Err(IntegrityCheck);
+    }
+    let (state_tag, mem_tag) = tags.split_at(DIGEST_LEN);
+
+    let state_bytes = std::fs::read(snapshot_path).map_err(|e| SnapshotBackingFile("read", e))?;
+    if !state_hmac(&key, &state_bytes).verify(state_tag) {
+        return Err(IntegrityCheck);
+    }
+    let microvm_state: MicrovmState =
+        Snapshot::load(&mut state_bytes.as_slice(), state_bytes.len(), version_map)
+            .map_err(DeserializeMicrovmState)?;
+
+    let mut hmac = HmacSha256::new(&key);
+    hmac.update(state_tag);
+    let mem_file = File::open(mem_file_path).map_err(MemoryBackingFile)?;
+    let mut mem_reader = HmacReader {
+        reader: BufReader::new(mem_file),
+        hmac: &mut hmac,
+    };
+    let guest_memory = GuestMemoryMmap::restore_from_reader(
+        &mut mem_reader,
+        &microvm_state.memory_state,
+        track_dirty_pages,
+    )
+    .map_err(DeserializeMemory);
+    // The tag covers the whole memory file, which is read to its end even when its content
+    // doesn't match the state, so that tampering is reported as such.
+    io::copy(&mut mem_reader, &mut io::sink()).map_err(MemoryBackingFile)?;
+
+    if !hmac.verify(mem_tag) {
+        return Err(IntegrityCheck);
+    }
+    Ok((microvm_state, guest_memory?))
+}
+
+// Feeds the bytes read through it to an HMAC computation.
+struct HmacReader<'a, T> {
+    reader: T,
+    hmac: &'a mut HmacSha256,
+}
+
+impl<T: Read> Read for HmacReader<'_, T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.reader.read(buf)?;
+        self.hmac.update(&buf[..count]);
+        Ok(count)
+    }
+}
+
+// Decodes a hex encoded integrity key.
+fn parse_integrity_key(key: &str) -> Option<Vec<u8>> {
+    if key.is_empty() || key.len() % 2 != 0 || !key.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..key.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&key[i..i + 2], 16).ok())
+        .collect()
+}
+
+// Feeds the serialized microVM state, prefixed by its length, to an HMAC computation keyed
+// with `key`.
+fn state_hmac(key: &[u8], state_bytes: &[u8]) -> HmacSha256 {
+    let mut hmac = HmacSha256::new(key);
+    hmac.update(&(state_bytes.len() as u64).to_le_bytes());
+    hmac.update(state_bytes);
+    hmac
+}
+
+// Returns the tag of the microVM state, followed by the tag of the memory file. The latter
+// covers the state tag before the content of the memory file, so that both files are bound
+// together.
+fn snapshot_tags(key: &[u8], state_bytes: &[u8], mem_file_path: &Path) -> io::Result<Vec<u8>> {
+    let mut tags = state_hmac(key, state_bytes).finalize().to_vec();
+    let mut hmac = HmacSha256::new(key);
+    hmac.update(&tags);
+
+    let mut mem_file = File::open(mem_file_path)?;
+    let mut buf = vec![0u8; INTEGRITY_READ_BUFFER_SIZE];
+    loop {
+        let count = mem_file.read(&mut buf)?;
+        if count == 0 {
+            break;
+        }
+        hmac.update(&buf[..count]);
+    }
+    tags.extend_from_slice(&hmac.finalize());
+    Ok(tags)
+}
+
 fn guest_memory_from_file(
     mem_file_path: &Path,
     mem_state: &GuestMemoryState,
@@ -459,6 +934,50 @@ fn guest_memory_from_file(
     GuestMemoryMmap::restore(&mem_file, mem_state, track_dirty_pages).map_err(DeserializeMemory)
 }
 
+fn guest_memory_from_uffd(
+    socket_path: &Path,
+    mem_state: &GuestMemoryState,
+    track_dirty_pages: bool,
+) -> std::result::Result<(GuestMemoryMmap, Uffd), LoadSnapshotError> {
+    use self::LoadSnapshotError::{DeserializeMemory, Uffd as UffdErr, UffdHandler};
+
+    // The guest memory starts out as anonymous memory, populated by the handler on the
+    // first access to each page.
+    let ranges: Vec<_> = mem_state
+        .regions
+        .iter()
+        .map(|region| (GuestAddress(region.base_address), region.size))
+        .collect();
+    let guest_memory = GuestMemoryMmap::from_ranges_guarded(&ranges, track_dirty_pages)
+        .map_err(|err| DeserializeMemory(memory_snapshot::Error::CreateMemory(err)))?;
+
+    // Balloon inflation drops guest pages, which the handler then has to serve zeroed.
+    let uffd = Uffd::new(UFFD_FEATURE_EVENT_REMOVE).map_err(UffdErr)?;
+    let mut mappings = Vec::with_capacity(mem_state.regions.len());
+    for region in mem_state.regions.iter() {
+        // It's safe to unwrap because the region was just created at this address.
+        let host_base_addr = guest_memory
+            .get_host_address(GuestAddress(region.base_address))
+            .unwrap() as u64;
+        uffd.register(host_base_addr, region.size as u64)
+            .map_err(UffdErr)?;
+        mappings.push(GuestRegionUffdMapping {
+            base_host_virt_addr: host_base_addr,
+            size: region.size,
+            offset: region.offset,
+        });
+    }
+
+    // It's safe to unwrap because the mappings are plain structs.
+    let mappings = serde_json::to_string(&mappings).unwrap();
+    let socket = UnixStream::connect(socket_path).map_err(UffdHandler)?;
+    socket
+        .send_with_fd(mappings.as_bytes(), uffd.as_raw_fd())
+        .map_err(|err| UffdHandler(io::Error::from_raw_os_error(err.errno())))?;
+
+    Ok((guest_memory, uffd))
+}
+
 #[cfg(target_arch = "x86_64")]
 fn validate_devices_number(device_number: usize) -> std::result::Result<(), CreateSnapshotError> {
     use self::CreateSnapshotError::TooManyDevices;
@@ -538,6 +1057,8 @@ mod tests {
             rx_rate_limiter: None,
             tx_rate_limiter: None,
             allow_mmds_requests: true,
+            num_queue_pairs: 1,
+            vhost_net: false,
         };
         insert_net_device(
             &mut vmm,
@@ -581,6 +1102,8 @@ mod tests {
             vm_state: vmm.vm.save_state(&mpidrs).unwrap(),
             #[cfg(target_arch = "x86_64")]
             vm_state: vmm.vm.save_state().unwrap(),
+            plugged_vcpu_count: Some(1),
+            ht_enabled: true,
         };
 
         let mut buf = vec![0; 10000];
@@ -604,7 +1127,75 @@ mod tests {
         assert_eq!(
             restored_microvm_state.device_states,
             microvm_state.device_states
-        )
+        );
+        // Older versions don't hold the number of plugged vCPUs.
+        assert!(restored_microvm_state.plugged_vcpu_count.is_none());
+        assert!(!restored_microvm_state.ht_enabled);
+
+        version_map
+            .new_version()
+            .set_type_version(MicrovmState::type_id(), 2);
+        microvm_state
+            .serialize(&mut buf.as_mut_slice(), &version_map, 3)
+            .unwrap();
+        let restored_microvm_state =
+            MicrovmState::deserialize(&mut buf.as_slice(), &version_map, 3).unwrap();
+        assert_eq!(restored_microvm_state.plugged_vcpu_count, Some(1));
+        assert!(restored_microvm_state.ht_enabled);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_missing_cpu_features() {
+        use cpuid::custom::CpuidRegister;
+        use kvm_bindings::{kvm_msr_entry, Msrs};
+
+        let vmm = default_vmm_with_devices();
+        let mut vcpu_state = VcpuState::default();
+        vcpu_state.cpuid.as_mut_slice()[0].function = 0x7;
+        vcpu_state.cpuid.as_mut_slice()[0].ebx = 0x0000_01ff;
+        vcpu_state.msrs = Msrs::from_entries(&[
+            kvm_msr_entry {
+                index: 0x10a,
+                ..Default::default()
+            },
+            kvm_msr_entry {
+                index: 0x174,
+                ..Default::default()
+            },
+        ])
+        .unwrap();
+        let microvm_state = MicrovmState {
+            device_states: vmm.mmio_device_manager.save(),
+            memory_state: vmm.guest_memory().describe(),
+            vcpu_states: vec![vcpu_state.clone(), vcpu_state],
+            vm_info: VmInfo { mem_size_mib: 1u64 },
+            vm_state: vmm.vm.save_state().unwrap(),
+            plugged_vcpu_count: None,
+            ht_enabled: false,
+        };
+
+        let mut host_cpuid = CpuId::new(1).unwrap();
+        host_cpuid.as_mut_slice()[0].function = 0x7;
+        host_cpuid.as_mut_slice()[0].ebx = 0x0000_00ff;
+        let host_msrs = MsrList::from_entries(&[0x10a, 0x174]).unwrap();
+        assert!(missing_cpu_features(&microvm_state, &host_cpuid, &host_msrs).is_empty());
+
+        // The features missing on several vCPUs are reported once.
+        host_cpuid.as_mut_slice()[0].ebx = 0x0000_000f;
+        let host_msrs = MsrList::from_entries(&[0x10a]).unwrap();
+        assert_eq!(
+            missing_cpu_features(&microvm_state, &host_cpuid, &host_msrs),
+            vec![
+                MissingCpuFeature::Cpuid(MissingCpuidBits {
+                    leaf: 0x7,
+                    subleaf: 0,
+                    register: CpuidRegister::Ebx,
+                    bits: 0x0000_01f0,
+                }),
+                MissingCpuFeature::Msr(0x174),
+            ]
+        );
     }
 
     #[test]
@@ -647,6 +1238,19 @@ mod tests {
         assert!(get_snapshot_data_version(&Some("0.24.0".to_string()), &VERSION_MAP, &vmm).is_ok());
     }
 
+    #[test]
+    fn test_parse_integrity_key() {
+        assert_eq!(
+            parse_integrity_key("00ff7Fa0").unwrap(),
+            vec![0x00, 0xff, 0x7f, 0xa0]
+        );
+        assert!(parse_integrity_key("").is_none());
+        assert!(parse_integrity_key("abc").is_none());
+        assert!(parse_integrity_key("+f").is_none());
+        assert!(parse_integrity_key("0g").is_none());
+        assert!(parse_integrity_key("é0").is_none());
+    }
+
     #[test]
     fn test_create_snapshot_error_display() {
         use crate::persist::CreateSnapshotError::*;
@@ -655,6 +1259,15 @@ mod tests {
         let err = DirtyBitmap(VmmError::DirtyBitmap(kvm_ioctls::Error::new(20)));
         let _ = format!("{}{:?}", err, err);
 
+        let err = GuestMemfdReference;
+        let _ = format!("{}{:?}", err, err);
+
+        let err = HugePages;
+        let _ = format!("{}{:?}", err, err);
+
+        let err = InvalidIntegrityKey;
+        let _ = format!("{}{:?}", err, err);
+
         let err = InvalidVersionFormat;
         let _ = format!("{}{:?}", err, err);
 
@@ -708,6 +1321,33 @@ mod tests {
 
         let err = CpuVendorCheck(String::new());
         let _ = format!("{}{:?}", err, err);
+
+        #[cfg(target_arch = "x86_64")]
+        {
+            let err = CpuFeatureCheck(String::new());
+            let _ = format!("{}{:?}", err, err);
+
+            let err = MissingCpuFeatures(vec![MissingCpuFeature::Msr(0x10a)]);
+            let _ = format!("{}{:?}", err, err);
+        }
+
+        let err = InvalidMemoryBackend;
+        let _ = format!("{}{:?}", err, err);
+
+        let err = InvalidIntegrityKey;
+        let _ = format!("{}{:?}", err, err);
+
+        let err = IntegrityCheck;
+        let _ = format!("{}{:?}", err, err);
+
+        let err = IntegrityCheckNotSupported;
+        let _ = format!("{}{:?}", err, err);
+
+        let err = Uffd(UffdError::MissingFeatures(0));
+        let _ = format!("{}{:?}", err, err);
+
+        let err = UffdHandler(io::Error::from_raw_os_error(0));
+        let _ = format!("{}{:?}", err, err);
     }
 
     #[test]
@@ -14,11 +14,15 @@ use crate::vmm_config::mmds::{MmdsConfig, MmdsConfigError};
 use crate::vmm_config::net::*;
 use crate::vmm_config::vsock::*;
 use crate::vstate::vcpu::VcpuConfig;
+#[cfg(target_arch = "x86_64")]
+use cpuid::custom::CustomCpuTemplate;
 use mmds::ns::MmdsNetworkStack;
 use utils::net::ipv4addr::is_link_local_valid;
 
 use serde::{Deserialize, Serialize};
 use std::convert::From;
+#[cfg(target_arch = "x86_64")]
+use std::path::Path;
 
 type Result<E> = std::result::Result<(), E>;
 
@@ -76,6 +80,9 @@ pub struct VmmConfig {
 pub struct VmResources {
     /// The vCpu and memory configuration for this microVM.
     vm_config: VmConfig,
+    /// The custom CPU template loaded from `vm_config`.
+    #[cfg(target_arch = "x86_64")]
+    custom_cpu_template: Option<CustomCpuTemplate>,
     /// The boot configuration for this microVM.
     boot_config: Option<BootConfig>,
     /// The block devices.
@@ -157,13 +164,22 @@ impl VmResources {
     pub fn vcpu_config(&self) -> VcpuConfig {
         // The unwraps are ok to use because the values are initialized using defaults if not
         // supplied by the user.
+        let vcpu_count = self.vm_config().vcpu_count.unwrap();
         VcpuConfig {
-            vcpu_count: self.vm_config().vcpu_count.unwrap(),
+            vcpu_count,
+            max_vcpu_count: self.vm_config().max_vcpu_count.unwrap_or(vcpu_count),
             ht_enabled: self.vm_config().ht_enabled.unwrap(),
             cpu_template: self.vm_config().cpu_template,
+            #[cfg(target_arch = "x86_64")]
+            custom_cpu_template: self.custom_cpu_template.clone(),
         }
     }
 
+    /// Updates the vcpu count once vcpus are hot-added to the running microVM.
+    pub fn set_hotplugged_vcpu_count(&mut self, vcpu_count: u8) {
+        self.vm_config.vcpu_count = Some(vcpu_count);
+    }
+
     /// Returns whether dirty page tracking is enabled or not.
     pub fn track_dirty_pages(&self) -> bool {
         self.vm_config().track_dirty_pages
@@ -205,6 +221,22 @@ impl VmResources {
             return Err(VmConfigError::IncompatibleBalloonSize);
         }
 
+        // The balloon inflates 4K pages, which can't be reclaimed from huge pages.
+        if self.balloon.get().is_some() && machine_config.huge_pages.is_hugetlbfs() {
+            return Err(VmConfigError::IncompatibleBalloonHugePages);
+        }
+
+        let mem_size_mib = machine_config
+            .mem_size_mib
+            .unwrap_or_else(|| self.vm_config.mem_size_mib.unwrap());
+        if !machine_config.huge_pages.is_valid_mem_size(mem_size_mib) {
+            return Err(VmConfigError::InvalidHugePageMemorySize);
+        }
+
+        if machine_config.shared_memory && machine_config.huge_pages.is_hugetlbfs() {
+            return Err(VmConfigError::IncompatibleSharedMemoryHugePages);
+        }
+
         let ht_enabled = machine_config
             .ht_enabled
             .unwrap_or_else(|| self.vm_config.ht_enabled.unwrap());
@@ -219,10 +251,38 @@ impl VmResources {
             return Err(VmConfigError::InvalidVcpuCount);
         }
 
+        let max_vcpu_count = machine_config
+            .max_vcpu_count
+            .or(self.vm_config.max_vcpu_count);
+        if let Some(max_vcpu_count) = max_vcpu_count {
+            // The vCPUs are hot-added in pairs when hyperthreading is enabled.
+            if max_vcpu_count < vcpu_count_value
+                || (ht_enabled && max_vcpu_count > 1 && max_vcpu_count % 2 == 1)
+            {
+                return Err(VmConfigError::InvalidMaxVcpuCount);
+            }
+            #[cfg(target_arch = "aarch64")]
+            {
+                if max_vcpu_count > vcpu_count_value {
+                    return Err(VmConfigError::VcpuHotplugNotSupported);
+                }
+            }
+        }
+
+        #[cfg(target_arch = "x86_64")]
+        let custom_cpu_template = machine_config
+            .custom_cpu_template_path
+            .as_ref()
+            .map(|path| load_custom_cpu_template(path.as_path()))
+            .transpose()?;
+
         // Update all the fields that have a new value.
         self.vm_config.vcpu_count = Some(vcpu_count_value);
+        self.vm_config.max_vcpu_count = max_vcpu_count;
         self.vm_config.ht_enabled = Some(ht_enabled);
         self.vm_config.track_dirty_pages = machine_config.track_dirty_pages;
+        self.vm_config.huge_pages = machine_config.huge_pages;
+        self.vm_config.shared_memory = machine_config.shared_memory;
 
         if machine_config.mem_size_mib.is_some() {
             self.vm_config.mem_size_mib = machine_config.mem_size_mib;
@@ -232,6 +292,15 @@ impl VmResources {
             self.vm_config.cpu_template = machine_config.cpu_template;
         }
 
+        if machine_config.custom_cpu_template_path.is_some() {
+            self.vm_config.custom_cpu_template_path =
+                machine_config.custom_cpu_template_path.clone();
+            #[cfg(target_arch = "x86_64")]
+            {
+                self.custom_cpu_template = custom_cpu_template;
+            }
+        }
+
         Ok(())
     }
 
@@ -257,6 +326,10 @@ impl VmResources {
             return Err(BalloonConfigError::TooManyPagesRequested);
         }
 
+        if self.vm_config.huge_pages.is_hugetlbfs() {
+            return Err(BalloonConfigError::HugePagesNotSupported);
+        }
+
         self.balloon.set(config)
     }
 
@@ -346,6 +419,17 @@ impl From<&VmResources> for VmmConfig {
     }
 }
 
+// Reads and parses the JSON file holding a custom CPU template. Whether the host supports
+// the template is only known when the vCPUs are configured.
+#[cfg(target_arch = "x86_64")]
+fn load_custom_cpu_template(path: &Path) -> std::result::Result<CustomCpuTemplate, VmConfigError> {
+    let template = std::fs::read(path).map_err(|e| {
+        VmConfigError::InvalidCustomCpuTemplate(format!("{}: {}", path.display(), e))
+    })?;
+    serde_json::from_slice(&template)
+        .map_err(|e| VmConfigError::InvalidCustomCpuTemplate(format!("{}: {}", path.display(), e)))
+}
+
 #[cfg(test)]
 mod tests {
     use std::fs::File;
@@ -354,8 +438,10 @@ mod tests {
     use super::*;
     use crate::resources::VmResources;
     use crate::vmm_config::boot_source::{BootConfig, BootSourceConfig, DEFAULT_KERNEL_CMDLINE};
-    use crate::vmm_config::drive::{BlockBuilder, BlockDeviceConfig};
-    use crate::vmm_config::machine_config::{CpuFeaturesTemplate, VmConfig, VmConfigError};
+    use crate::vmm_config::drive::{BlockBuilder, BlockDeviceConfig, FileEngineType};
+    use crate::vmm_config::machine_config::{
+        CpuFeaturesTemplate, HugePageConfig, VmConfig, VmConfigError,
+    };
     use crate::vmm_config::net::{NetBuilder, NetworkInterfaceConfig};
     use crate::vmm_config::vsock::tests::default_config;
     use crate::vmm_config::RateLimiterConfig;
@@ -378,6 +464,8 @@ mod tests {
             rx_rate_limiter: Some(RateLimiterConfig::default()),
             tx_rate_limiter: Some(RateLimiterConfig::default()),
             allow_mmds_requests: false,
+            num_queue_pairs: 1,
+            vhost_net: false,
         }
     }
 
@@ -399,6 +487,11 @@ mod tests {
                 cache_type: CacheType::Unsafe,
                 is_read_only: false,
                 rate_limiter: Some(RateLimiterConfig::default()),
+                io_engine: FileEngineType::Sync,
+                is_discard_enabled: false,
+                is_write_zeroes_enabled: false,
+                num_queues: 1,
+                overlay_path_on_host: None,
             },
             tmp_file,
         )
@@ -426,6 +519,8 @@ mod tests {
     fn default_vm_resources() -> VmResources {
         VmResources {
             vm_config: VmConfig::default(),
+            #[cfg(target_arch = "x86_64")]
+            custom_cpu_template: None,
             boot_config: Some(default_boot_cfg()),
             block: default_blocks(),
             vsock: Default::default(),
@@ -772,8 +867,11 @@ mod tests {
         let vm_resources = default_vm_resources();
         let expected_vcpu_config = VcpuConfig {
             vcpu_count: vm_resources.vm_config().vcpu_count.unwrap(),
+            max_vcpu_count: vm_resources.vm_config().vcpu_count.unwrap(),
             ht_enabled: vm_resources.vm_config().ht_enabled.unwrap(),
             cpu_template: vm_resources.vm_config().cpu_template,
+            #[cfg(target_arch = "x86_64")]
+            custom_cpu_template: None,
         };
 
         let vcpu_config = vm_resources.vcpu_config();
@@ -793,10 +891,14 @@ mod tests {
         let mut vm_resources = default_vm_resources();
         let mut aux_vm_config = VmConfig {
             vcpu_count: Some(32),
+            max_vcpu_count: None,
             mem_size_mib: Some(512),
             ht_enabled: Some(true),
             cpu_template: Some(CpuFeaturesTemplate::T2),
+            custom_cpu_template_path: None,
             track_dirty_pages: false,
+            huge_pages: HugePageConfig::None,
+            shared_memory: false,
         };
 
         assert_ne!(vm_resources.vm_config, aux_vm_config);
@@ -816,6 +918,37 @@ mod tests {
         );
         aux_vm_config.vcpu_count = Some(32);
 
+        // Invalid max vcpu count.
+        aux_vm_config.vcpu_count = Some(4);
+        aux_vm_config.max_vcpu_count = Some(2);
+        assert_eq!(
+            vm_resources.set_vm_config(&aux_vm_config),
+            Err(VmConfigError::InvalidMaxVcpuCount)
+        );
+        aux_vm_config.max_vcpu_count = Some(7);
+        assert_eq!(
+            vm_resources.set_vm_config(&aux_vm_config),
+            Err(VmConfigError::InvalidMaxVcpuCount)
+        );
+        aux_vm_config.max_vcpu_count = Some(8);
+        #[cfg(target_arch = "x86_64")]
+        {
+            vm_resources.set_vm_config(&aux_vm_config).unwrap();
+            assert_eq!(vm_resources.vcpu_config().max_vcpu_count, 8);
+            // The maximum is kept when it's not part of the update.
+            aux_vm_config.max_vcpu_count = None;
+            vm_resources.set_vm_config(&aux_vm_config).unwrap();
+            assert_eq!(vm_resources.vm_config.max_vcpu_count, Some(8));
+            vm_resources.vm_config.max_vcpu_count = None;
+        }
+        #[cfg(target_arch = "aarch64")]
+        assert_eq!(
+            vm_resources.set_vm_config(&aux_vm_config),
+            Err(VmConfigError::VcpuHotplugNotSupported)
+        );
+        aux_vm_config.vcpu_count = Some(32);
+        aux_vm_config.max_vcpu_count = None;
+
         // Invalid mem_size_mib.
         aux_vm_config.mem_size_mib = Some(0);
         assert_eq!(
@@ -823,6 +956,29 @@ mod tests {
             Err(VmConfigError::InvalidMemorySize)
         );
 
+        // mem_size_mib not a multiple of the huge page size.
+        aux_vm_config.mem_size_mib = Some(129);
+        aux_vm_config.huge_pages = HugePageConfig::Hugetlbfs2M;
+        assert_eq!(
+            vm_resources.set_vm_config(&aux_vm_config),
+            Err(VmConfigError::InvalidHugePageMemorySize)
+        );
+        aux_vm_config.mem_size_mib = Some(128);
+        vm_resources.set_vm_config(&aux_vm_config).unwrap();
+        assert_eq!(vm_resources.vm_config, aux_vm_config);
+
+        // Huge pages can't back shared memory.
+        aux_vm_config.shared_memory = true;
+        assert_eq!(
+            vm_resources.set_vm_config(&aux_vm_config),
+            Err(VmConfigError::IncompatibleSharedMemoryHugePages)
+        );
+        aux_vm_config.huge_pages = HugePageConfig::None;
+        vm_resources.set_vm_config(&aux_vm_config).unwrap();
+        assert!(vm_resources.vm_config.shared_memory);
+        aux_vm_config.shared_memory = false;
+        vm_resources.set_vm_config(&aux_vm_config).unwrap();
+
         // Incompatible mem_size_mib with balloon size.
         vm_resources.vm_config.mem_size_mib = Some(128);
         vm_resources
@@ -841,12 +997,77 @@ mod tests {
         // mem_size_mib compatible with balloon size.
         aux_vm_config.mem_size_mib = Some(256);
         assert!(vm_resources.set_vm_config(&aux_vm_config).is_ok());
+
+        // Huge pages incompatible with the balloon device.
+        aux_vm_config.huge_pages = HugePageConfig::Hugetlbfs2M;
+        assert_eq!(
+            vm_resources.set_vm_config(&aux_vm_config),
+            Err(VmConfigError::IncompatibleBalloonHugePages)
+        );
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_set_custom_cpu_template() {
+        use std::io::Write;
+
+        let mut vm_resources = default_vm_resources();
+        let template_file = TempFile::new().unwrap();
+        let mut aux_vm_config = VmConfig {
+            custom_cpu_template_path: Some(template_file.as_path().to_path_buf()),
+            ..Default::default()
+        };
+
+        // The file doesn't hold a template.
+        match vm_resources.set_vm_config(&aux_vm_config) {
+            Err(VmConfigError::InvalidCustomCpuTemplate(_)) => (),
+            _ => panic!("Test failed."),
+        }
+        assert!(vm_resources.vm_config.custom_cpu_template_path.is_none());
+        assert!(vm_resources.vcpu_config().custom_cpu_template.is_none());
+
+        template_file
+            .as_file()
+            .write_all(
+                br#"{
+                    "cpuid_modifiers": [
+                        {
+                            "leaf": "0x1",
+                            "modifiers": [{ "register": "ecx", "bitmask": 1, "value": 0 }]
+                        }
+                    ],
+                    "msr_modifiers": [{ "index": "0x10a", "value": 0 }]
+                }"#,
+            )
+            .unwrap();
+        vm_resources.set_vm_config(&aux_vm_config).unwrap();
+        assert_eq!(
+            vm_resources.vm_config.custom_cpu_template_path,
+            aux_vm_config.custom_cpu_template_path
+        );
+        let template = vm_resources.vcpu_config().custom_cpu_template.unwrap();
+        assert_eq!(template.cpuid_modifiers.len(), 1);
+        assert_eq!(template.msr_modifiers[0].index, 0x10a);
+
+        // The template is kept when the machine configuration is updated without one.
+        aux_vm_config.custom_cpu_template_path = None;
+        vm_resources.set_vm_config(&aux_vm_config).unwrap();
+        assert!(vm_resources.vcpu_config().custom_cpu_template.is_some());
+
+        // The file doesn't exist.
+        aux_vm_config.custom_cpu_template_path = Some("/invalid/path".into());
+        match vm_resources.set_vm_config(&aux_vm_config) {
+            Err(VmConfigError::InvalidCustomCpuTemplate(_)) => (),
+            _ => panic!("Test failed."),
+        }
     }
 
     #[test]
     fn test_set_balloon_device() {
         let mut vm_resources = VmResources {
             vm_config: VmConfig::default(),
+            #[cfg(target_arch = "x86_64")]
+            custom_cpu_template: None,
             boot_config: Some(default_boot_cfg()),
             block: default_blocks(),
             vsock: Default::default(),
@@ -878,6 +1099,8 @@ mod tests {
 
         vm_resources = VmResources {
             vm_config: VmConfig::default(),
+            #[cfg(target_arch = "x86_64")]
+            custom_cpu_template: None,
             boot_config: Some(default_boot_cfg()),
             block: default_blocks(),
             vsock: Default::default(),
@@ -887,7 +1110,17 @@ mod tests {
             boot_timer: false,
         };
         new_balloon_cfg.amount_mib = 256;
-        assert!(vm_resources.set_balloon_device(new_balloon_cfg).is_err());
+        assert!(vm_resources
+            .set_balloon_device(new_balloon_cfg.clone())
+            .is_err());
+
+        // The balloon can't be used with huge pages.
+        new_balloon_cfg.amount_mib = 100;
+        vm_resources.vm_config.huge_pages = HugePageConfig::Hugetlbfs2M;
+        match vm_resources.set_balloon_device(new_balloon_cfg) {
+            Err(BalloonConfigError::HugePagesNotSupported) => (),
+            _ => unreachable!(),
+        }
     }
 
     #[test]
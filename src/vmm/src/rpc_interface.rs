@@ -8,9 +8,11 @@ use std::sync::{Arc, Mutex};
 use super::Error as VmmError;
 #[cfg(not(test))]
 use super::{
-    builder::build_microvm_for_boot, persist::create_snapshot, persist::restore_from_snapshot,
+    builder::build_microvm_for_boot, migration::receive_migration, migration::MigrationSender,
+    persist::check_snapshot, persist::create_snapshot, persist::restore_from_snapshot,
     resources::VmResources, Vmm,
 };
+use crate::migration::MigrationError;
 use crate::persist::{CreateSnapshotError, LoadSnapshotError};
 use crate::resources::VmmConfig;
 use crate::version_map::VERSION_MAP;
@@ -19,16 +21,23 @@ use crate::vmm_config::balloon::{
     BalloonUpdateStatsConfig,
 };
 use crate::vmm_config::boot_source::{BootSourceConfig, BootSourceConfigError};
+use crate::vmm_config::dirty_pages::{DirtyPageStats, DirtyPageStatsParams};
 use crate::vmm_config::drive::{BlockDeviceConfig, BlockDeviceUpdateConfig, DriveError};
 use crate::vmm_config::instance_info::InstanceInfo;
 use crate::vmm_config::logger::{LoggerConfig, LoggerConfigError};
 use crate::vmm_config::machine_config::{VmConfig, VmConfigError};
 use crate::vmm_config::metrics::{MetricsConfig, MetricsConfigError};
+use crate::vmm_config::migration::{ReceiveMigrationParams, SendMigrationParams};
 use crate::vmm_config::mmds::{MmdsConfig, MmdsConfigError};
 use crate::vmm_config::net::{
     NetworkInterfaceConfig, NetworkInterfaceError, NetworkInterfaceUpdateConfig,
 };
-use crate::vmm_config::snapshot::{CreateSnapshotParams, LoadSnapshotParams, SnapshotType};
+use crate::vmm_config::shared_memory::SharedMemoryParams;
+use crate::vmm_config::snapshot::{
+    CheckSnapshotParams, CreateSnapshotParams, LoadSnapshotParams, MemFileFormat,
+    SnapshotDestinationType, SnapshotType,
+};
+use crate::vmm_config::vcpu_hotplug::{VcpuHotplugConfig, VcpuHotplugError};
 use crate::vmm_config::vsock::{VsockConfigError, VsockDeviceConfig};
 use crate::vmm_config::{self, RateLimiterUpdate};
 use crate::{builder::StartMicrovmError, EventManager};
@@ -37,7 +46,8 @@ use logger::{info, update_metric_with_elapsed_time, METRICS};
 use seccompiler::BpfThreadMap;
 #[cfg(test)]
 use tests::{
-    build_microvm_for_boot, create_snapshot, restore_from_snapshot, MockVmRes as VmResources,
+    build_microvm_for_boot, check_snapshot, create_snapshot, receive_migration,
+    restore_from_snapshot, MockMigrationSender as MigrationSender, MockVmRes as VmResources,
     MockVmm as Vmm,
 };
 
@@ -45,6 +55,9 @@ use tests::{
 /// bits of information (ids, paths, etc.).
 #[derive(PartialEq)]
 pub enum VmmAction {
+    /// Check that the host supports the snapshot using as input the `CheckSnapshotParams`,
+    /// without loading it. This action can only be called before the microVM has booted.
+    CheckSnapshot(CheckSnapshotParams),
     /// Configure the boot source of the microVM using as input the `ConfigureBootSource`. This
     /// action can only be called before the microVM has booted.
     ConfigureBootSource(BootSourceConfig),
@@ -59,6 +72,10 @@ pub enum VmmAction {
     CreateSnapshot(CreateSnapshotParams),
     /// Get the balloon device configuration.
     GetBalloonConfig,
+    /// Get the number of guest memory pages dirtied since the dirty page log was last reset,
+    /// using as input the `DirtyPageStatsParams`. This action can only be called after the
+    /// microVM has booted, with dirty page tracking enabled.
+    GetDirtyPageStats(DirtyPageStatsParams),
     /// Get the ballon device latest statistics.
     GetBalloonStats,
     /// Get complete microVM configuration in JSON format.
@@ -69,6 +86,10 @@ pub enum VmmAction {
     GetVmInstanceInfo,
     /// Flush the metrics. This action can only be called after the logger has been configured.
     FlushMetrics,
+    /// Hot-add vCPUs to the running microVM, using as input the `VcpuHotplugConfig`. This action
+    /// can only be called after the microVM has booted, with a maximum vCPU count higher than
+    /// the boot one.
+    HotplugVcpus(VcpuHotplugConfig),
     /// Add a new block device or update one that already exists using the `BlockDeviceConfig` as
     /// input. This action can only be called before the microVM has booted.
     InsertBlockDevice(BlockDeviceConfig),
@@ -82,6 +103,10 @@ pub enum VmmAction {
     LoadSnapshot(LoadSnapshotParams),
     /// Pause the guest, by pausing the microVM VCPUs.
     Pause,
+    /// Receive a microVM sent by another Firecracker process, using as input the
+    /// `ReceiveMigrationParams`. This action can only be called before the microVM has booted.
+    /// If this action is successful, the received microVM is running.
+    ReceiveMigration(ReceiveMigrationParams),
     /// Resume the guest, by resuming the microVM VCPUs.
     Resume,
     /// Set the balloon device or update the one that already exists using the
@@ -99,6 +124,14 @@ pub enum VmmAction {
     SetVmConfiguration(VmConfig),
     /// Launch the microVM. This action can only be called before the microVM has booted.
     StartMicroVm,
+    /// Send the running microVM to another Firecracker process, using as input the
+    /// `SendMigrationParams`. This action can only be called after the microVM has booted.
+    /// If this action is successful, the microVM is left in `Paused` state.
+    SendMigration(SendMigrationParams),
+    /// Send the memfd backing the shared guest memory to another process, using as input the
+    /// `SharedMemoryParams`. This action can only be called after the microVM has booted,
+    /// with shared guest memory.
+    ShareGuestMemory(SharedMemoryParams),
     /// Send CTRL+ALT+DEL to the microVM, using the i8042 keyboard function. If an AT-keyboard
     /// driver is listening on the guest end, this can be used to shut down the microVM gracefully.
     #[cfg(target_arch = "x86_64")]
@@ -107,7 +140,8 @@ pub enum VmmAction {
     UpdateBalloon(BalloonUpdateConfig),
     /// Update the balloon statistics polling interval, after microVM start.
     UpdateBalloonStatistics(BalloonUpdateStatsConfig),
-    /// Update existing block device properties such as `path_on_host` or `rate_limiter`.
+    /// Update existing block device properties such as `path_on_host`, `overlay_path_on_host`
+    /// or `rate_limiter`.
     UpdateBlockDevice(BlockDeviceUpdateConfig),
     /// Update a network interface, after microVM start. Currently, the only updatable properties
     /// are the RX and TX rate limiters.
@@ -138,6 +172,10 @@ pub enum VmmActionError {
     MachineConfig(VmConfigError),
     /// The action `ConfigureMetrics` failed because of bad user input.
     Metrics(MetricsConfigError),
+    /// One of the actions `SendMigration` or `ReceiveMigration` failed.
+    Migration(MigrationError),
+    /// Receiving a microVM migration not allowed after configuring boot-specific resources.
+    MigrationNotAllowed,
     /// The action `SetMmdsConfiguration` failed because of bad user input.
     MmdsConfig(MmdsConfigError),
     /// The action `InsertNetworkDevice` failed because of bad user input.
@@ -150,6 +188,8 @@ pub enum VmmActionError {
     OperationNotSupportedPreBoot,
     /// The action `StartMicroVm` failed because of an internal error.
     StartMicrovm(StartMicrovmError),
+    /// The action `HotplugVcpus` failed.
+    VcpuHotplug(VcpuHotplugError),
     /// The action `SetVsockDevice` failed because of bad user input.
     VsockConfig(VsockConfigError),
 }
@@ -175,6 +215,12 @@ impl Display for VmmActionError {
                 Logger(err) => err.to_string(),
                 MachineConfig(err) => err.to_string(),
                 Metrics(err) => err.to_string(),
+                Migration(err) => format!("Live migration error: {}", err),
+                MigrationNotAllowed => {
+                    "Receiving a microVM migration not allowed after configuring boot-specific \
+                     resources."
+                        .to_string()
+                }
                 MmdsConfig(err) => err.to_string(),
                 NetworkConfig(err) => err.to_string(),
                 NotSupported(err) => format!("The requested operation is not supported: {}", err),
@@ -187,6 +233,7 @@ impl Display for VmmActionError {
                         .to_string()
                 }
                 StartMicrovm(err) => err.to_string(),
+                VcpuHotplug(err) => err.to_string(),
                 // The action `SetVsockDevice` failed because of bad user input.
                 VsockConfig(err) => err.to_string(),
             }
@@ -202,6 +249,8 @@ pub enum VmmData {
     BalloonConfig(BalloonDeviceConfig),
     /// The latest balloon device statistics.
     BalloonStats(BalloonStats),
+    /// The number of guest memory pages dirtied during the current window.
+    DirtyPageStats(DirtyPageStats),
     /// No data is sent on the channel.
     Empty,
     /// The complete microVM configuration in JSON format.
@@ -304,6 +353,9 @@ impl<'a> PrebootApiController<'a> {
 
         match request {
             // Supported operations allowed pre-boot.
+            CheckSnapshot(config) => check_snapshot(&config, VERSION_MAP.clone())
+                .map(|()| VmmData::Empty)
+                .map_err(VmmActionError::LoadSnapshot),
             ConfigureBootSource(config) => self.set_boot_source(config),
             ConfigureLogger(logger_cfg) => {
                 vmm_config::logger::init_logger(logger_cfg, &self.instance_info)
@@ -322,6 +374,7 @@ impl<'a> PrebootApiController<'a> {
             InsertBlockDevice(config) => self.insert_block_device(config),
             InsertNetworkDevice(config) => self.insert_net_device(config),
             LoadSnapshot(config) => self.load_snapshot(&config),
+            ReceiveMigration(config) => self.receive_migration(&config),
             SetBalloonDevice(config) => self.set_balloon_device(config),
             SetVsockDevice(config) => self.set_vsock_device(config),
             SetVmConfiguration(config) => self.set_vm_config(config),
@@ -332,7 +385,11 @@ impl<'a> PrebootApiController<'a> {
             | FlushMetrics
             | Pause
             | Resume
+            | SendMigration(_)
+            | ShareGuestMemory(_)
             | GetBalloonStats
+            | GetDirtyPageStats(_)
+            | HotplugVcpus(_)
             | UpdateBalloon(_)
             | UpdateBalloonStatistics(_)
             | UpdateBlockDevice(_)
@@ -468,12 +525,57 @@ impl<'a> PrebootApiController<'a> {
 
         result
     }
+
+    // On success, this command will end the pre-boot stage and this controller
+    // will be replaced by a runtime controller.
+    fn receive_migration(&mut self, params: &ReceiveMigrationParams) -> ActionResult {
+        let receive_start_us = utils::time::get_time_us(utils::time::ClockType::Monotonic);
+
+        if self.boot_path {
+            let err = VmmActionError::MigrationNotAllowed;
+            info!("{}", err);
+            return Err(err);
+        }
+
+        if params.enable_diff_snapshots {
+            self.vm_resources.set_track_dirty_pages(true);
+        }
+
+        let result = receive_migration(
+            &self.instance_info,
+            &mut self.event_manager,
+            self.seccomp_filters,
+            params,
+            VERSION_MAP.clone(),
+        )
+        .map(|vmm| {
+            self.built_vmm = Some(vmm);
+            VmmData::Empty
+        })
+        .map_err(|e| {
+            // The process is too dirty to recover at this point.
+            self.fatal_error = Some(FC_EXIT_CODE_BAD_CONFIGURATION);
+            VmmActionError::Migration(e)
+        });
+
+        info!(
+            "'receive migration' VMM action took {} us.",
+            utils::time::get_time_us(utils::time::ClockType::Monotonic) - receive_start_us
+        );
+
+        result
+    }
 }
 
 /// Enables RPC interaction with a running Firecracker VMM.
 pub struct RuntimeApiController {
     vmm: Arc<Mutex<Vmm>>,
     vm_resources: VmResources,
+    // Start time of the window over which the dirty pages are counted, which is
+    // restarted whenever the dirty page log is reset.
+    dirty_page_window_start_us: u64,
+    // Start time of the migration being sent.
+    send_migration_start_us: u64,
 }
 
 impl RuntimeApiController {
@@ -498,6 +600,7 @@ impl RuntimeApiController {
                 .latest_balloon_stats()
                 .map(VmmData::BalloonStats)
                 .map_err(|e| VmmActionError::BalloonConfig(BalloonConfigError::from(e))),
+            GetDirtyPageStats(params) => self.dirty_page_stats(params),
             GetFullVmConfig => Ok(VmmData::FullVmConfig((&self.vm_resources).into())),
             GetVmMachineConfig => Ok(VmmData::MachineConfiguration(
                 self.vm_resources.vm_config().clone(),
@@ -505,10 +608,13 @@ impl RuntimeApiController {
             GetVmInstanceInfo => Ok(VmmData::InstanceInformation(
                 self.vmm.lock().expect("Poisoned lock").instance_info(),
             )),
+            HotplugVcpus(cfg) => self.hotplug_vcpus(cfg),
             Pause => self.pause(),
             Resume => self.resume(),
+            SendMigration(params) => self.send_migration(&params),
             #[cfg(target_arch = "x86_64")]
             SendCtrlAltDel => self.send_ctrl_alt_del(),
+            ShareGuestMemory(params) => self.share_guest_memory(&params),
             UpdateBalloon(balloon_update) => self
                 .vmm
                 .lock()
@@ -524,15 +630,17 @@ impl RuntimeApiController {
                 .map(|_| VmmData::Empty)
                 .map_err(|e| VmmActionError::BalloonConfig(BalloonConfigError::from(e))),
             UpdateBlockDevice(new_cfg) => self.update_block_device(new_cfg),
-            UpdateNetworkInterface(netif_update) => self.update_net_rate_limiters(netif_update),
+            UpdateNetworkInterface(netif_update) => self.update_net_device(netif_update),
 
             // Operations not allowed post-boot.
-            ConfigureBootSource(_)
+            CheckSnapshot(_)
+            | ConfigureBootSource(_)
             | ConfigureLogger(_)
             | ConfigureMetrics(_)
             | InsertBlockDevice(_)
             | InsertNetworkDevice(_)
             | LoadSnapshot(_)
+            | ReceiveMigration(_)
             | SetBalloonDevice(_)
             | SetVsockDevice(_)
             | SetMmdsConfiguration(_)
@@ -543,7 +651,60 @@ impl RuntimeApiController {
 
     /// Creates a new `RuntimeApiController`.
     pub fn new(vm_resources: VmResources, vmm: Arc<Mutex<Vmm>>) -> Self {
-        Self { vmm, vm_resources }
+        Self {
+            vmm,
+            vm_resources,
+            dirty_page_window_start_us: utils::time::get_time_us(utils::time::ClockType::Monotonic),
+            send_migration_start_us: 0,
+        }
+    }
+
+    /// Starts sending the microVM to another Firecracker process, like the `SendMigration`
+    /// request does, but leaves it to the caller to advance the migration with
+    /// `send_migration_step`. The caller can then keep serving the devices while the guest
+    /// memory is sent, by only stepping the migration when its socket is writable.
+    pub fn start_send_migration(
+        &mut self,
+        params: &SendMigrationParams,
+    ) -> result::Result<MigrationSender, VmmActionError> {
+        if !self.vm_resources.track_dirty_pages() {
+            return Err(VmmActionError::NotSupported(
+                "Live migration is not allowed on uVMs with dirty page tracking disabled."
+                    .to_string(),
+            ));
+        }
+
+        self.send_migration_start_us = utils::time::get_time_us(utils::time::ClockType::Monotonic);
+        MigrationSender::new(
+            &mut self.vmm.lock().expect("Poisoned lock"),
+            params,
+            VERSION_MAP.clone(),
+        )
+        .map_err(VmmActionError::Migration)
+    }
+
+    /// Sends the next guest memory pages of `migration`. Once the pre-copy rounds are over,
+    /// pauses the microVM to send the rest, and returns the outcome of the migration.
+    pub fn send_migration_step(&mut self, migration: &mut MigrationSender) -> Option<ActionResult> {
+        let mut locked_vmm = self.vmm.lock().expect("Poisoned lock");
+        let result = match migration.send_step(&mut locked_vmm) {
+            Ok(false) => return None,
+            Ok(true) => migration.finish(&mut locked_vmm),
+            Err(err) => Err(err),
+        };
+        // The migration consumes the dirty page log, even when it fails.
+        self.dirty_page_window_start_us =
+            utils::time::get_time_us(utils::time::ClockType::Monotonic);
+        if let Err(err) = result {
+            return Some(Err(VmmActionError::Migration(err)));
+        }
+
+        info!(
+            "'send migration' VMM action took {} us.",
+            utils::time::get_time_us(utils::time::ClockType::Monotonic)
+                - self.send_migration_start_us
+        );
+        Some(Ok(VmmData::Empty))
     }
 
     /// Pauses the microVM by pausing the vCPUs.
@@ -593,6 +754,63 @@ impl RuntimeApiController {
             .map_err(VmmActionError::InternalVmm)
     }
 
+    /// Counts the guest memory pages dirtied since the dirty page log was last reset.
+    fn dirty_page_stats(&mut self, params: DirtyPageStatsParams) -> ActionResult {
+        if !self.vm_resources.track_dirty_pages() {
+            return Err(VmmActionError::NotSupported(
+                "Dirty page statistics are not available on uVMs with dirty page tracking \
+                 disabled."
+                    .to_string(),
+            ));
+        }
+
+        let regions = self
+            .vmm
+            .lock()
+            .expect("Poisoned lock")
+            .dirty_page_stats(params.reset)
+            .map_err(VmmActionError::InternalVmm)?;
+        let now_us = utils::time::get_time_us(utils::time::ClockType::Monotonic);
+        let window_ms = (now_us - self.dirty_page_window_start_us) / 1000;
+        if params.reset {
+            self.dirty_page_window_start_us = now_us;
+        }
+
+        Ok(VmmData::DirtyPageStats(DirtyPageStats::new(
+            window_ms,
+            arch::PAGE_SIZE as u64,
+            regions,
+        )))
+    }
+
+    /// Sends the memfd backing the shared guest memory to another process.
+    fn share_guest_memory(&mut self, params: &SharedMemoryParams) -> ActionResult {
+        if !self.vm_resources.vm_config().shared_memory {
+            return Err(VmmActionError::NotSupported(
+                "Shared guest memory is not enabled on this uVM.".to_string(),
+            ));
+        }
+
+        self.vmm
+            .lock()
+            .expect("Poisoned lock")
+            .share_guest_memory(&params.socket_path)
+            .map(|()| VmmData::Empty)
+            .map_err(VmmActionError::InternalVmm)
+    }
+
+    /// Hot-adds vCPUs to the microVM, up to the requested vCPU count.
+    fn hotplug_vcpus(&mut self, cfg: VcpuHotplugConfig) -> ActionResult {
+        self.vmm
+            .lock()
+            .expect("Poisoned lock")
+            .hotplug_vcpus(cfg.vcpu_count)
+            .map_err(VmmActionError::VcpuHotplug)?;
+        self.vm_resources.set_hotplugged_vcpu_count(cfg.vcpu_count);
+
+        Ok(VmmData::Empty)
+    }
+
     /// Injects CTRL+ALT+DEL keystroke combo to the inner Vmm (if present).
     #[cfg(target_arch = "x86_64")]
     fn send_ctrl_alt_del(&mut self) -> ActionResult {
@@ -613,6 +831,25 @@ impl RuntimeApiController {
                     .to_string(),
             ));
         }
+        if create_params.snapshot_type == SnapshotType::Diff
+            && create_params.mem_file_format == MemFileFormat::Compressed
+        {
+            return Err(VmmActionError::NotSupported(
+                "Diff snapshots cannot use the compressed memory file format.".to_string(),
+            ));
+        }
+        if create_params.destination_type == SnapshotDestinationType::UnixSocket {
+            if create_params.snapshot_type == SnapshotType::Diff {
+                return Err(VmmActionError::NotSupported(
+                    "Diff snapshots cannot be streamed to a Unix socket.".to_string(),
+                ));
+            }
+            if create_params.integrity_key.is_some() {
+                return Err(VmmActionError::NotSupported(
+                    "Snapshots streamed to a Unix socket cannot be authenticated.".to_string(),
+                ));
+            }
+        }
 
         let mut locked_vmm = self.vmm.lock().unwrap();
         let create_start_us = utils::time::get_time_us(utils::time::ClockType::Monotonic);
@@ -640,22 +877,38 @@ impl RuntimeApiController {
                     "'create diff snapshot' VMM action took {} us.",
                     elapsed_time_us
                 );
+                // The diff snapshot consumed the dirty page log.
+                self.dirty_page_window_start_us =
+                    utils::time::get_time_us(utils::time::ClockType::Monotonic);
             }
         }
         Ok(VmmData::Empty)
     }
 
+    fn send_migration(&mut self, params: &SendMigrationParams) -> ActionResult {
+        let mut migration = self.start_send_migration(params)?;
+        loop {
+            if let Some(result) = self.send_migration_step(&mut migration) {
+                return result;
+            }
+        }
+    }
+
     /// Updates block device properties:
-    ///  - path of the host file backing the emulated block device,
-    ///    update the disk image on the device and its virtio configuration
+    ///  - path of the host file backing the emulated block device and/or of its
+    ///    overlay image, update the disk image on the device and its virtio configuration
     ///  - rate limiter configuration.
     fn update_block_device(&mut self, new_cfg: BlockDeviceUpdateConfig) -> ActionResult {
         let mut vmm = self.vmm.lock().expect("Poisoned lock");
-        if let Some(new_path) = new_cfg.path_on_host {
-            vmm.update_block_device_path(&new_cfg.drive_id, new_path)
-                .map(|()| VmmData::Empty)
-                .map_err(DriveError::DeviceUpdate)
-                .map_err(VmmActionError::DriveConfig)?;
+        if new_cfg.path_on_host.is_some() || new_cfg.overlay_path_on_host.is_some() {
+            vmm.update_block_device_path(
+                &new_cfg.drive_id,
+                new_cfg.path_on_host,
+                new_cfg.overlay_path_on_host,
+            )
+            .map(|()| VmmData::Empty)
+            .map_err(DriveError::DeviceUpdate)
+            .map_err(VmmActionError::DriveConfig)?;
         }
         if new_cfg.rate_limiter.is_some() {
             vmm.update_block_rate_limiter(
@@ -671,20 +924,25 @@ impl RuntimeApiController {
     }
 
     /// Updates configuration for an emulated net device as described in `new_cfg`.
-    fn update_net_rate_limiters(&mut self, new_cfg: NetworkInterfaceUpdateConfig) -> ActionResult {
-        self.vmm
-            .lock()
-            .expect("Poisoned lock")
-            .update_net_rate_limiters(
+    fn update_net_device(&mut self, new_cfg: NetworkInterfaceUpdateConfig) -> ActionResult {
+        let mut vmm = self.vmm.lock().expect("Poisoned lock");
+        if new_cfg.rx_rate_limiter.is_some() || new_cfg.tx_rate_limiter.is_some() {
+            vmm.update_net_rate_limiters(
                 &new_cfg.iface_id,
                 RateLimiterUpdate::from(new_cfg.rx_rate_limiter).bandwidth,
                 RateLimiterUpdate::from(new_cfg.rx_rate_limiter).ops,
                 RateLimiterUpdate::from(new_cfg.tx_rate_limiter).bandwidth,
                 RateLimiterUpdate::from(new_cfg.tx_rate_limiter).ops,
             )
-            .map(|()| VmmData::Empty)
             .map_err(NetworkInterfaceError::DeviceUpdate)
-            .map_err(VmmActionError::NetworkConfig)
+            .map_err(VmmActionError::NetworkConfig)?;
+        }
+        if new_cfg.guest_mac.is_some() || new_cfg.link_up.is_some() {
+            vmm.update_net_link(&new_cfg.iface_id, new_cfg.guest_mac, new_cfg.link_up)
+                .map_err(NetworkInterfaceError::DeviceUpdate)
+                .map_err(VmmActionError::NetworkConfig)?;
+        }
+        Ok(VmmData::Empty)
     }
 }
 
@@ -692,14 +950,18 @@ impl RuntimeApiController {
 mod tests {
     use super::*;
     use crate::vmm_config::balloon::BalloonBuilder;
-    use crate::vmm_config::drive::CacheType;
+    use crate::vmm_config::dirty_pages::RegionDirtyPageStats;
+    use crate::vmm_config::drive::{CacheType, FileEngineType};
     use crate::vmm_config::logger::LoggerLevel;
     use crate::vmm_config::vsock::VsockBuilder;
     use devices::virtio::balloon::{BalloonConfig, Error as BalloonError};
     use devices::virtio::VsockError;
     use seccompiler::BpfThreadMap;
 
-    use std::path::PathBuf;
+    use std::collections::HashMap;
+    use std::io;
+    use std::path::{Path, PathBuf};
+    use utils::net::mac::MacAddr;
 
     impl PartialEq for VmmActionError {
         fn eq(&self, other: &VmmActionError) -> bool {
@@ -713,6 +975,8 @@ mod tests {
                     | (InternalVmm(_), InternalVmm(_))
                     | (LoadSnapshot(_), LoadSnapshot(_))
                     | (LoadSnapshotNotAllowed, LoadSnapshotNotAllowed)
+                    | (Migration(_), Migration(_))
+                    | (MigrationNotAllowed, MigrationNotAllowed)
                     | (Logger(_), Logger(_))
                     | (MachineConfig(_), MachineConfig(_))
                     | (Metrics(_), Metrics(_))
@@ -722,6 +986,7 @@ mod tests {
                     | (OperationNotSupportedPostBoot, OperationNotSupportedPostBoot)
                     | (OperationNotSupportedPreBoot, OperationNotSupportedPreBoot)
                     | (StartMicrovm(_), StartMicrovm(_))
+                    | (VcpuHotplug(_), VcpuHotplug(_))
                     | (VsockConfig(_), VsockConfig(_))
             )
         }
@@ -766,6 +1031,10 @@ mod tests {
             self.vm_config.track_dirty_pages = dirty_page_tracking;
         }
 
+        pub fn set_hotplugged_vcpu_count(&mut self, vcpu_count: u8) {
+            self.vm_config.vcpu_count = Some(vcpu_count);
+        }
+
         pub fn set_vm_config(&mut self, machine_config: &VmConfig) -> Result<(), VmConfigError> {
             if self.force_errors {
                 return Err(VmConfigError::InvalidVcpuCount);
@@ -847,14 +1116,18 @@ mod tests {
     pub struct MockVmm {
         pub balloon_config_called: bool,
         pub latest_balloon_stats_called: bool,
+        pub dirty_page_stats_called: bool,
+        pub hotplug_vcpus_called: bool,
         pub pause_called: bool,
         pub resume_called: bool,
         #[cfg(target_arch = "x86_64")]
         pub send_ctrl_alt_del_called: bool,
+        pub share_guest_memory_called: bool,
         pub update_balloon_config_called: bool,
         pub update_balloon_stats_config_called: bool,
         pub update_block_device_path_called: bool,
         pub update_net_rate_limiters_called: bool,
+        pub update_net_link_called: bool,
         // when `true`, all self methods are forced to fail
         pub force_errors: bool,
     }
@@ -903,6 +1176,34 @@ mod tests {
             Ok(BalloonStats::default())
         }
 
+        pub fn dirty_page_stats(&mut self, _: bool) -> Result<Vec<RegionDirtyPageStats>, VmmError> {
+            if self.force_errors {
+                return Err(VmmError::VcpuPause);
+            }
+            self.dirty_page_stats_called = true;
+            Ok(vec![RegionDirtyPageStats {
+                base_address: 0,
+                total_pages: 4,
+                dirty_pages: 1,
+            }])
+        }
+
+        pub fn hotplug_vcpus(&mut self, _: u8) -> Result<(), VcpuHotplugError> {
+            if self.force_errors {
+                return Err(VcpuHotplugError::Internal(VmmError::VcpuResume));
+            }
+            self.hotplug_vcpus_called = true;
+            Ok(())
+        }
+
+        pub fn share_guest_memory(&mut self, _: &Path) -> Result<(), VmmError> {
+            if self.force_errors {
+                return Err(VmmError::SharedMemory(io::Error::from_raw_os_error(0)));
+            }
+            self.share_guest_memory_called = true;
+            Ok(())
+        }
+
         pub fn update_balloon_config(&mut self, _: u32) -> Result<(), BalloonError> {
             if self.force_errors {
                 return Err(BalloonError::DeviceNotFound);
@@ -919,7 +1220,12 @@ mod tests {
             Ok(())
         }
 
-        pub fn update_block_device_path(&mut self, _: &str, _: String) -> Result<(), VmmError> {
+        pub fn update_block_device_path(
+            &mut self,
+            _: &str,
+            _: Option<String>,
+            _: Option<String>,
+        ) -> Result<(), VmmError> {
             if self.force_errors {
                 return Err(VmmError::DeviceManager(
                     crate::device_manager::mmio::Error::IncorrectDeviceType,
@@ -955,6 +1261,21 @@ mod tests {
             Ok(())
         }
 
+        pub fn update_net_link(
+            &mut self,
+            _: &str,
+            _: Option<MacAddr>,
+            _: Option<bool>,
+        ) -> Result<(), VmmError> {
+            if self.force_errors {
+                return Err(VmmError::DeviceManager(
+                    crate::device_manager::mmio::Error::IncorrectDeviceType,
+                ));
+            }
+            self.update_net_link_called = true;
+            Ok(())
+        }
+
         pub fn instance_info(&self) -> InstanceInfo {
             InstanceInfo::default()
         }
@@ -993,6 +1314,52 @@ mod tests {
         Ok(Arc::new(Mutex::new(MockVmm::default())))
     }
 
+    // Need to redefine this since the non-test one checks a real snapshot
+    // against the host.
+    pub fn check_snapshot(
+        _: &CheckSnapshotParams,
+        _: versionize::VersionMap,
+    ) -> std::result::Result<(), LoadSnapshotError> {
+        Ok(())
+    }
+
+    // Need to redefine this since the non-test one uses real Vmm
+    // instead of our mocks.
+    pub struct MockMigrationSender {
+        steps: u32,
+    }
+
+    impl MockMigrationSender {
+        pub fn new(
+            _: &mut Vmm,
+            _: &SendMigrationParams,
+            _: versionize::VersionMap,
+        ) -> std::result::Result<Self, MigrationError> {
+            Ok(MockMigrationSender { steps: 0 })
+        }
+
+        pub fn send_step(&mut self, _: &mut Vmm) -> std::result::Result<bool, MigrationError> {
+            self.steps += 1;
+            Ok(self.steps == 2)
+        }
+
+        pub fn finish(&mut self, _: &mut Vmm) -> std::result::Result<(), MigrationError> {
+            Ok(())
+        }
+    }
+
+    // Need to redefine this since the non-test one uses real Vmm
+    // instead of our mocks.
+    pub fn receive_migration(
+        _: &InstanceInfo,
+        _: &mut EventManager,
+        _: &BpfThreadMap,
+        _: &ReceiveMigrationParams,
+        _: versionize::VersionMap,
+    ) -> Result<Arc<Mutex<Vmm>>, MigrationError> {
+        Ok(Arc::new(Mutex::new(MockVmm::default())))
+    }
+
     fn default_preboot<'a>(
         vm_resources: &'a mut VmResources,
         event_manager: &'a mut EventManager,
@@ -1111,6 +1478,11 @@ mod tests {
             is_read_only: false,
             drive_id: String::new(),
             rate_limiter: None,
+            io_engine: FileEngineType::Sync,
+            is_discard_enabled: false,
+            is_write_zeroes_enabled: false,
+            num_queues: 1,
+            overlay_path_on_host: None,
         });
         check_preboot_request(req, |result, vm_res| {
             assert_eq!(result, Ok(VmmData::Empty));
@@ -1125,6 +1497,11 @@ mod tests {
             is_read_only: false,
             drive_id: String::new(),
             rate_limiter: None,
+            io_engine: FileEngineType::Sync,
+            is_discard_enabled: false,
+            is_write_zeroes_enabled: false,
+            num_queues: 1,
+            overlay_path_on_host: None,
         });
         check_preboot_request_err(
             req,
@@ -1141,6 +1518,8 @@ mod tests {
             rx_rate_limiter: None,
             tx_rate_limiter: None,
             allow_mmds_requests: false,
+            num_queue_pairs: 1,
+            vhost_net: false,
         });
         check_preboot_request(req, |result, vm_res| {
             assert_eq!(result, Ok(VmmData::Empty));
@@ -1154,6 +1533,8 @@ mod tests {
             rx_rate_limiter: None,
             tx_rate_limiter: None,
             allow_mmds_requests: false,
+            num_queue_pairs: 1,
+            vhost_net: false,
         });
         check_preboot_request_err(
             req,
@@ -1214,9 +1595,13 @@ mod tests {
         // Without resume.
         let req = VmmAction::LoadSnapshot(LoadSnapshotParams {
             snapshot_path: PathBuf::new(),
-            mem_file_path: PathBuf::new(),
+            mem_file_path: Some(PathBuf::new()),
+            mem_backend: None,
             enable_diff_snapshots: false,
             resume_vm: false,
+            network_overrides: vec![],
+            drive_path_overrides: HashMap::new(),
+            integrity_key: None,
         });
         // Request should succeed.
         preboot.handle_preboot_request(req).unwrap();
@@ -1227,9 +1612,13 @@ mod tests {
         // With resume.
         let req = VmmAction::LoadSnapshot(LoadSnapshotParams {
             snapshot_path: PathBuf::new(),
-            mem_file_path: PathBuf::new(),
+            mem_file_path: Some(PathBuf::new()),
+            mem_backend: None,
             enable_diff_snapshots: false,
             resume_vm: true,
+            network_overrides: vec![],
+            drive_path_overrides: HashMap::new(),
+            integrity_key: None,
         });
         // Request should succeed.
         preboot.handle_preboot_request(req).unwrap();
@@ -1240,6 +1629,59 @@ mod tests {
         assert!(!vmm.pause_called);
     }
 
+    #[test]
+    fn test_preboot_check_snapshot() {
+        let mut vm_resources = MockVmRes::default();
+        let mut evmgr = EventManager::new().unwrap();
+        let seccomp_filters = BpfThreadMap::new();
+        let mut preboot = default_preboot(&mut vm_resources, &mut evmgr, &seccomp_filters);
+
+        let req = VmmAction::CheckSnapshot(CheckSnapshotParams {
+            snapshot_path: PathBuf::new(),
+        });
+        assert_eq!(preboot.handle_preboot_request(req), Ok(VmmData::Empty));
+        // Checking a snapshot neither builds a microVM nor prevents loading one.
+        assert!(preboot.built_vmm.is_none());
+        assert!(!preboot.boot_path);
+    }
+
+    #[test]
+    fn test_preboot_receive_migration() {
+        let mut vm_resources = MockVmRes::default();
+        let mut evmgr = EventManager::new().unwrap();
+        let seccomp_filters = BpfThreadMap::new();
+        let mut preboot = default_preboot(&mut vm_resources, &mut evmgr, &seccomp_filters);
+
+        let req = VmmAction::ReceiveMigration(ReceiveMigrationParams {
+            socket_path: PathBuf::new(),
+            enable_diff_snapshots: true,
+            network_overrides: vec![],
+            drive_path_overrides: HashMap::new(),
+        });
+        // Request should succeed.
+        preboot.handle_preboot_request(req).unwrap();
+        // Should have built default mock vmm.
+        assert!(preboot.built_vmm.is_some());
+        assert!(vm_resources.track_dirty_pages());
+
+        // Receiving a migration isn't allowed after configuring boot-specific resources.
+        let mut vm_resources = MockVmRes::default();
+        let mut preboot = default_preboot(&mut vm_resources, &mut evmgr, &seccomp_filters);
+        preboot
+            .handle_preboot_request(VmmAction::ConfigureBootSource(BootSourceConfig::default()))
+            .unwrap();
+        let req = VmmAction::ReceiveMigration(ReceiveMigrationParams {
+            socket_path: PathBuf::new(),
+            enable_diff_snapshots: false,
+            network_overrides: vec![],
+            drive_path_overrides: HashMap::new(),
+        });
+        assert_eq!(
+            preboot.handle_preboot_request(req),
+            Err(VmmActionError::MigrationNotAllowed)
+        );
+    }
+
     #[test]
     fn test_preboot_disallowed() {
         check_preboot_request_err(
@@ -1258,6 +1700,20 @@ mod tests {
             VmmAction::GetBalloonStats,
             VmmActionError::OperationNotSupportedPreBoot,
         );
+        check_preboot_request_err(
+            VmmAction::GetDirtyPageStats(DirtyPageStatsParams::default()),
+            VmmActionError::OperationNotSupportedPreBoot,
+        );
+        check_preboot_request_err(
+            VmmAction::HotplugVcpus(VcpuHotplugConfig { vcpu_count: 2 }),
+            VmmActionError::OperationNotSupportedPreBoot,
+        );
+        check_preboot_request_err(
+            VmmAction::ShareGuestMemory(SharedMemoryParams {
+                socket_path: PathBuf::new(),
+            }),
+            VmmActionError::OperationNotSupportedPreBoot,
+        );
         check_preboot_request_err(
             VmmAction::UpdateBalloon(BalloonUpdateConfig { amount_mib: 0 }),
             VmmActionError::OperationNotSupportedPreBoot,
@@ -1273,11 +1729,7 @@ mod tests {
             VmmActionError::OperationNotSupportedPreBoot,
         );
         check_preboot_request_err(
-            VmmAction::UpdateNetworkInterface(NetworkInterfaceUpdateConfig {
-                iface_id: String::new(),
-                rx_rate_limiter: None,
-                tx_rate_limiter: None,
-            }),
+            VmmAction::UpdateNetworkInterface(NetworkInterfaceUpdateConfig::default()),
             VmmActionError::OperationNotSupportedPreBoot,
         );
         check_preboot_request_err(
@@ -1285,6 +1737,17 @@ mod tests {
                 snapshot_type: SnapshotType::Full,
                 snapshot_path: PathBuf::new(),
                 mem_file_path: PathBuf::new(),
+                destination_type: SnapshotDestinationType::File,
+                mem_file_format: MemFileFormat::Raw,
+                version: None,
+                integrity_key: None,
+            }),
+            VmmActionError::OperationNotSupportedPreBoot,
+        );
+        check_preboot_request_err(
+            VmmAction::SendMigration(SendMigrationParams {
+                socket_path: PathBuf::new(),
+                max_iterations: None,
                 version: None,
             }),
             VmmActionError::OperationNotSupportedPreBoot,
@@ -1444,6 +1907,105 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_runtime_dirty_page_stats() {
+        // Dirty page statistics rely on dirty page tracking.
+        let req = VmmAction::GetDirtyPageStats(DirtyPageStatsParams::default());
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(result, Err(VmmActionError::NotSupported(String::new())));
+            assert!(!vmm.dirty_page_stats_called)
+        });
+
+        let mut vm_resources = MockVmRes::default();
+        vm_resources.set_track_dirty_pages(true);
+        let vmm = Arc::new(Mutex::new(MockVmm::default()));
+        let mut runtime = RuntimeApiController::new(vm_resources, vmm.clone());
+        let window_start_us = runtime.dirty_page_window_start_us;
+        match runtime.handle_request(VmmAction::GetDirtyPageStats(DirtyPageStatsParams {
+            reset: false,
+        })) {
+            Ok(VmmData::DirtyPageStats(stats)) => {
+                assert_eq!(stats.page_size, arch::PAGE_SIZE as u64);
+                assert_eq!(stats.dirty_pages, 1);
+                assert_eq!(stats.regions.len(), 1);
+            }
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        assert!(vmm.lock().unwrap().dirty_page_stats_called);
+        assert_eq!(runtime.dirty_page_window_start_us, window_start_us);
+
+        // Resetting the log starts a new window.
+        runtime
+            .handle_request(VmmAction::GetDirtyPageStats(DirtyPageStatsParams {
+                reset: true,
+            }))
+            .unwrap();
+        assert!(runtime.dirty_page_window_start_us >= window_start_us);
+
+        vmm.lock().unwrap().force_errors = true;
+        assert_eq!(
+            runtime.handle_request(VmmAction::GetDirtyPageStats(DirtyPageStatsParams {
+                reset: true,
+            })),
+            Err(VmmActionError::InternalVmm(VmmError::VcpuPause))
+        );
+    }
+
+    #[test]
+    fn test_runtime_hotplug_vcpus() {
+        let vmm = Arc::new(Mutex::new(MockVmm::default()));
+        let mut runtime = RuntimeApiController::new(MockVmRes::default(), vmm.clone());
+        let req = VmmAction::HotplugVcpus(VcpuHotplugConfig { vcpu_count: 4 });
+        assert_eq!(runtime.handle_request(req), Ok(VmmData::Empty));
+        assert!(vmm.lock().unwrap().hotplug_vcpus_called);
+        // The machine configuration reports the hot-added vCPUs.
+        assert_eq!(runtime.vm_resources.vm_config().vcpu_count, Some(4));
+
+        let req = VmmAction::HotplugVcpus(VcpuHotplugConfig { vcpu_count: 4 });
+        check_runtime_request_err(
+            req,
+            VmmActionError::VcpuHotplug(VcpuHotplugError::Internal(VmmError::VcpuResume)),
+        );
+    }
+
+    #[test]
+    fn test_runtime_share_guest_memory() {
+        // Only shared guest memory can be handed to other processes.
+        let req = VmmAction::ShareGuestMemory(SharedMemoryParams {
+            socket_path: PathBuf::new(),
+        });
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(result, Err(VmmActionError::NotSupported(String::new())));
+            assert!(!vmm.share_guest_memory_called)
+        });
+
+        let mut vm_resources = MockVmRes::default();
+        vm_resources
+            .set_vm_config(&VmConfig {
+                shared_memory: true,
+                ..Default::default()
+            })
+            .unwrap();
+        let vmm = Arc::new(Mutex::new(MockVmm::default()));
+        let mut runtime = RuntimeApiController::new(vm_resources, vmm.clone());
+        let req = VmmAction::ShareGuestMemory(SharedMemoryParams {
+            socket_path: PathBuf::new(),
+        });
+        assert_eq!(runtime.handle_request(req), Ok(VmmData::Empty));
+        assert!(vmm.lock().unwrap().share_guest_memory_called);
+
+        vmm.lock().unwrap().force_errors = true;
+        let req = VmmAction::ShareGuestMemory(SharedMemoryParams {
+            socket_path: PathBuf::new(),
+        });
+        assert_eq!(
+            runtime.handle_request(req),
+            Err(VmmActionError::InternalVmm(VmmError::SharedMemory(
+                io::Error::from_raw_os_error(0)
+            )))
+        );
+    }
+
     #[test]
     fn test_runtime_update_balloon_config() {
         let req = VmmAction::UpdateBalloon(BalloonUpdateConfig { amount_mib: 0 });
@@ -1489,6 +2051,15 @@ mod tests {
             assert!(vmm.update_block_device_path_called)
         });
 
+        let req = VmmAction::UpdateBlockDevice(BlockDeviceUpdateConfig {
+            overlay_path_on_host: Some(String::new()),
+            ..Default::default()
+        });
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(result, Ok(VmmData::Empty));
+            assert!(vmm.update_block_device_path_called)
+        });
+
         let req = VmmAction::UpdateBlockDevice(BlockDeviceUpdateConfig {
             path_on_host: Some(String::new()),
             ..Default::default()
@@ -1504,19 +2075,18 @@ mod tests {
     #[test]
     fn test_runtime_update_net_rate_limiters() {
         let req = VmmAction::UpdateNetworkInterface(NetworkInterfaceUpdateConfig {
-            iface_id: String::new(),
-            rx_rate_limiter: None,
-            tx_rate_limiter: None,
+            rx_rate_limiter: Some(vmm_config::RateLimiterConfig::default()),
+            ..Default::default()
         });
         check_runtime_request(req, |result, vmm| {
             assert_eq!(result, Ok(VmmData::Empty));
-            assert!(vmm.update_net_rate_limiters_called)
+            assert!(vmm.update_net_rate_limiters_called);
+            assert!(!vmm.update_net_link_called);
         });
 
         let req = VmmAction::UpdateNetworkInterface(NetworkInterfaceUpdateConfig {
-            iface_id: String::new(),
-            rx_rate_limiter: None,
-            tx_rate_limiter: None,
+            tx_rate_limiter: Some(vmm_config::RateLimiterConfig::default()),
+            ..Default::default()
         });
         check_runtime_request_err(
             req,
@@ -1526,8 +2096,90 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_runtime_update_net_link() {
+        let req = VmmAction::UpdateNetworkInterface(NetworkInterfaceUpdateConfig {
+            link_up: Some(false),
+            ..Default::default()
+        });
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(result, Ok(VmmData::Empty));
+            assert!(vmm.update_net_link_called);
+            assert!(!vmm.update_net_rate_limiters_called);
+        });
+
+        let req = VmmAction::UpdateNetworkInterface(NetworkInterfaceUpdateConfig {
+            guest_mac: Some(MacAddr::parse_str("12:34:56:78:9A:BC").unwrap()),
+            ..Default::default()
+        });
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(result, Ok(VmmData::Empty));
+            assert!(vmm.update_net_link_called);
+        });
+
+        let req = VmmAction::UpdateNetworkInterface(NetworkInterfaceUpdateConfig {
+            link_up: Some(true),
+            ..Default::default()
+        });
+        check_runtime_request_err(
+            req,
+            VmmActionError::NetworkConfig(NetworkInterfaceError::DeviceUpdate(
+                VmmError::DeviceManager(crate::device_manager::mmio::Error::IncorrectDeviceType),
+            )),
+        );
+    }
+
+    #[test]
+    fn test_runtime_send_migration() {
+        let params = SendMigrationParams {
+            socket_path: PathBuf::new(),
+            max_iterations: Some(1),
+            version: None,
+        };
+
+        // Live migration relies on dirty page tracking.
+        let req = VmmAction::SendMigration(params);
+        check_runtime_request_err(req, VmmActionError::NotSupported(String::new()));
+
+        let params = SendMigrationParams {
+            socket_path: PathBuf::new(),
+            max_iterations: Some(1),
+            version: None,
+        };
+        let mut vm_resources = MockVmRes::default();
+        vm_resources.set_track_dirty_pages(true);
+        let vmm = Arc::new(Mutex::new(MockVmm::default()));
+        let mut runtime = RuntimeApiController::new(vm_resources, vmm);
+        assert_eq!(
+            runtime.handle_request(VmmAction::SendMigration(params)),
+            Ok(VmmData::Empty)
+        );
+
+        // The migration can also be advanced one step at a time.
+        let params = SendMigrationParams {
+            socket_path: PathBuf::new(),
+            max_iterations: Some(1),
+            version: None,
+        };
+        let mut migration = runtime.start_send_migration(&params).unwrap();
+        let window_start_us = runtime.dirty_page_window_start_us;
+        assert!(runtime.send_migration_step(&mut migration).is_none());
+        assert_eq!(runtime.dirty_page_window_start_us, window_start_us);
+        assert_eq!(
+            runtime.send_migration_step(&mut migration),
+            Some(Ok(VmmData::Empty))
+        );
+        assert!(runtime.dirty_page_window_start_us >= window_start_us);
+    }
+
     #[test]
     fn test_runtime_disallowed() {
+        check_runtime_request_err(
+            VmmAction::CheckSnapshot(CheckSnapshotParams {
+                snapshot_path: PathBuf::new(),
+            }),
+            VmmActionError::OperationNotSupportedPostBoot,
+        );
         check_runtime_request_err(
             VmmAction::ConfigureBootSource(BootSourceConfig::default()),
             VmmActionError::OperationNotSupportedPostBoot,
@@ -1556,6 +2208,11 @@ mod tests {
                 is_read_only: false,
                 drive_id: String::new(),
                 rate_limiter: None,
+                io_engine: FileEngineType::Sync,
+                is_discard_enabled: false,
+                is_write_zeroes_enabled: false,
+                num_queues: 1,
+                overlay_path_on_host: None,
             }),
             VmmActionError::OperationNotSupportedPostBoot,
         );
@@ -1567,6 +2224,8 @@ mod tests {
                 rx_rate_limiter: None,
                 tx_rate_limiter: None,
                 allow_mmds_requests: false,
+                num_queue_pairs: 1,
+                vhost_net: false,
             }),
             VmmActionError::OperationNotSupportedPostBoot,
         );
@@ -1601,9 +2260,22 @@ mod tests {
         check_runtime_request_err(
             VmmAction::LoadSnapshot(LoadSnapshotParams {
                 snapshot_path: PathBuf::new(),
-                mem_file_path: PathBuf::new(),
+                mem_file_path: Some(PathBuf::new()),
+                mem_backend: None,
                 enable_diff_snapshots: false,
                 resume_vm: false,
+                network_overrides: vec![],
+                drive_path_overrides: HashMap::new(),
+                integrity_key: None,
+            }),
+            VmmActionError::OperationNotSupportedPostBoot,
+        );
+        check_runtime_request_err(
+            VmmAction::ReceiveMigration(ReceiveMigrationParams {
+                socket_path: PathBuf::new(),
+                enable_diff_snapshots: false,
+                network_overrides: vec![],
+                drive_path_overrides: HashMap::new(),
             }),
             VmmActionError::OperationNotSupportedPostBoot,
         );
@@ -1620,9 +2292,13 @@ mod tests {
         // Load snapshot should no longer be allowed.
         let req = VmmAction::LoadSnapshot(LoadSnapshotParams {
             snapshot_path: PathBuf::new(),
-            mem_file_path: PathBuf::new(),
+            mem_file_path: Some(PathBuf::new()),
+            mem_backend: None,
             enable_diff_snapshots: false,
             resume_vm: false,
+            network_overrides: vec![],
+            drive_path_overrides: HashMap::new(),
+            integrity_key: None,
         });
         let err = preboot.handle_preboot_request(req);
         assert_eq!(
@@ -1647,6 +2323,11 @@ mod tests {
             is_read_only: false,
             drive_id: String::new(),
             rate_limiter: None,
+            io_engine: FileEngineType::Sync,
+            is_discard_enabled: false,
+            is_write_zeroes_enabled: false,
+            num_queues: 1,
+            overlay_path_on_host: None,
         });
         verify_load_snap_disallowed_after_boot_resources(req, "InsertBlockDevice");
 
@@ -1657,6 +2338,8 @@ mod tests {
             rx_rate_limiter: None,
             tx_rate_limiter: None,
             allow_mmds_requests: false,
+            num_queue_pairs: 1,
+            vhost_net: false,
         });
         verify_load_snap_disallowed_after_boot_resources(req, "InsertNetworkDevice");
 
@@ -6,7 +6,7 @@ use std::path::PathBuf;
 
 use crate::resources::VmResources;
 use crate::vmm_config::boot_source::BootSourceConfig;
-use crate::vmm_config::machine_config::VmConfig;
+use crate::vmm_config::machine_config::{HugePageConfig, VmConfig};
 
 pub const DEFAULT_BOOT_ARGS: &str = "reboot=k panic=1 pci=off";
 #[cfg(target_arch = "x86_64")]
@@ -95,6 +95,26 @@ impl MockVmConfig {
         self.0.track_dirty_pages = true;
         self
     }
+
+    pub fn with_huge_pages(mut self) -> Self {
+        self.0.huge_pages = HugePageConfig::Hugetlbfs2M;
+        self
+    }
+
+    pub fn with_vcpu_count(mut self, vcpu_count: u8) -> Self {
+        self.0.vcpu_count = Some(vcpu_count);
+        self
+    }
+
+    pub fn with_max_vcpu_count(mut self, max_vcpu_count: u8) -> Self {
+        self.0.max_vcpu_count = Some(max_vcpu_count);
+        self
+    }
+
+    pub fn with_ht_enabled(mut self) -> Self {
+        self.0.ht_enabled = Some(true);
+        self
+    }
 }
 
 generate_from!(MockBootSourceConfig, BootSourceConfig);
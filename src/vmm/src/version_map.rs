@@ -6,9 +6,12 @@
 use std::collections::HashMap;
 
 use crate::device_manager::persist::DeviceStates;
+use crate::memory_snapshot::GuestMemoryState;
+use crate::persist::MicrovmState;
 #[cfg(target_arch = "x86_64")]
 use crate::vstate::vcpu::VcpuState;
 use devices::virtio::block::persist::BlockState;
+use devices::virtio::net::persist::{NetConfigSpaceState, NetState};
 
 use lazy_static::lazy_static;
 use versionize::VersionMap;
@@ -26,8 +29,12 @@ lazy_static! {
 
         // v0.25 state change mappings.
         version_map.new_version().set_type_version(BlockState::type_id(), 2);
+        version_map.set_type_version(NetState::type_id(), 2);
+        version_map.set_type_version(NetConfigSpaceState::type_id(), 2);
         #[cfg(target_arch = "x86_64")]
         version_map.set_type_version(VcpuState::type_id(), 2);
+        version_map.set_type_version(MicrovmState::type_id(), 2);
+        version_map.set_type_version(GuestMemoryState::type_id(), 2);
 
         version_map
     };
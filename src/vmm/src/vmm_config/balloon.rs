@@ -24,6 +24,8 @@ pub enum BalloonConfigError {
     InvalidStatsUpdate,
     /// Amount of pages requested is too large.
     TooManyPagesRequested,
+    /// The guest memory is backed by huge pages, which the balloon can't reclaim.
+    HugePagesNotSupported,
     /// The user polled the statistics of a balloon device that
     /// does not have the statistics enabled.
     StatsNotFound,
@@ -44,6 +46,11 @@ impl fmt::Display for BalloonConfigError {
             ),
             InvalidStatsUpdate => write!(f, "Cannot enable/disable the statistics after boot."),
             TooManyPagesRequested => write!(f, "Amount of pages requested is too large."),
+            HugePagesNotSupported => write!(
+                f,
+                "The balloon device can't be used with huge pages backed guest memory, \
+                 since it inflates 4K pages."
+            ),
             StatsNotFound => write!(f, "Statistics for the balloon device are not enabled"),
             CreateFailure(e) => write!(f, "Error creating the balloon device: {:?}", e),
             UpdateFailure(e) => write!(
@@ -245,6 +252,9 @@ pub(crate) mod tests {
         let err = TooManyPagesRequested;
         let _ = format!("{}{:?}", err, err);
 
+        let err = HugePagesNotSupported;
+        let _ = format!("{}{:?}", err, err);
+
         let err = StatsNotFound;
         let _ = format!("{}{:?}", err, err);
     }
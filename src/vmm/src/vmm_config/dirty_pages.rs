@@ -0,0 +1,53 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Configurations used for reporting the dirty guest memory pages.
+
+use serde::{Deserialize, Serialize};
+
+/// Stores the configuration that will be used for getting the dirty page statistics.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct DirtyPageStatsParams {
+    /// Setting this flag resets the dirty page log after the pages are counted,
+    /// which starts a new window.
+    #[serde(default)]
+    pub reset: bool,
+}
+
+/// The dirty pages of a guest memory region.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct RegionDirtyPageStats {
+    /// Guest physical address of the start of the region.
+    pub base_address: u64,
+    /// Number of pages in the region.
+    pub total_pages: u64,
+    /// Number of pages of the region dirtied during the window.
+    pub dirty_pages: u64,
+}
+
+/// The dirty pages of the guest memory, over the window since the dirty page log
+/// was last reset.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct DirtyPageStats {
+    /// Length of the window, in milliseconds.
+    pub window_ms: u64,
+    /// Size of a page, in bytes.
+    pub page_size: u64,
+    /// Number of pages dirtied during the window, in all the regions.
+    pub dirty_pages: u64,
+    /// The dirty pages of each region, ordered by address.
+    pub regions: Vec<RegionDirtyPageStats>,
+}
+
+impl DirtyPageStats {
+    /// Gathers the dirty pages of `regions`, over a window of `window_ms` milliseconds.
+    pub fn new(window_ms: u64, page_size: u64, regions: Vec<RegionDirtyPageStats>) -> Self {
+        DirtyPageStats {
+            window_ms,
+            page_size,
+            dirty_pages: regions.iter().map(|region| region.dirty_pages).sum(),
+            regions,
+        }
+    }
+}
@@ -11,9 +11,10 @@ use std::sync::{Arc, Mutex};
 
 use super::RateLimiterConfig;
 use crate::Error as VmmError;
+use devices::virtio::block::MAX_NUM_QUEUES;
 use devices::virtio::Block;
 
-pub use devices::virtio::CacheType;
+pub use devices::virtio::{CacheType, FileEngineType};
 
 use serde::{Deserialize, Serialize};
 use std::ops::Deref;
@@ -34,10 +35,18 @@ pub enum DriveError {
     DeviceUpdate(VmmError),
     /// The block device path is invalid.
     InvalidBlockDevicePath,
+    /// The number of queues is out of range.
+    InvalidNumQueues(u16),
+    /// The overlay image path is invalid.
+    InvalidOverlayImagePath,
     /// Cannot open block device due to invalid permissions or path.
     OpenBlockDevice(io::Error),
+    /// Discard or write zeroes requests are enabled on a read-only drive.
+    ReadOnlyDiscardWriteZeroes,
     /// A root block device was already added.
     RootBlockDeviceAlreadyAdded,
+    /// Overlay images are not supported by the async I/O engine.
+    UnsupportedOverlayIoEngine,
 }
 
 impl Display for DriveError {
@@ -54,12 +63,26 @@ impl Display for DriveError {
             CreateRateLimiter(e) => write!(f, "Cannot create RateLimiter: {}", e),
             DeviceUpdate(e) => write!(f, "Error during drive update (patch): {}", e),
             InvalidBlockDevicePath => write!(f, "Invalid block device path!"),
+            InvalidNumQueues(num_queues) => write!(
+                f,
+                "Invalid number of queues: {}. It must be between 1 and {}.",
+                num_queues, MAX_NUM_QUEUES
+            ),
+            InvalidOverlayImagePath => write!(f, "Invalid overlay image path!"),
             OpenBlockDevice(e) => write!(
                 f,
                 "Cannot open block device. Invalid permission/path: {}",
                 e
             ),
+            ReadOnlyDiscardWriteZeroes => write!(
+                f,
+                "Discard and write zeroes requests can't be enabled on a read-only drive."
+            ),
             RootBlockDeviceAlreadyAdded => write!(f, "A root block device already exists!"),
+            UnsupportedOverlayIoEngine => write!(
+                f,
+                "Overlay images are not supported by the Async I/O engine."
+            ),
         }
     }
 }
@@ -88,6 +111,28 @@ pub struct BlockDeviceConfig {
     pub cache_type: CacheType,
     /// Rate Limiter for I/O operations.
     pub rate_limiter: Option<RateLimiterConfig>,
+    /// The type of I/O engine used for executing the requests on the backing file.
+    #[serde(default = "FileEngineType::default")]
+    pub io_engine: FileEngineType,
+    /// If set to true, the guest driver can discard ranges of the drive, which
+    /// deallocates them in the backing file.
+    #[serde(default)]
+    pub is_discard_enabled: bool,
+    /// If set to true, the guest driver can zero ranges of the drive without
+    /// transferring the data.
+    #[serde(default)]
+    pub is_write_zeroes_enabled: bool,
+    /// Number of virtqueues of the drive. Using more than one queue lets the
+    /// guest driver submit requests from multiple vCPUs in parallel.
+    #[serde(default = "default_num_queues")]
+    pub num_queues: u16,
+    /// Path of a copy-on-write overlay image. When set, the drive at `path_on_host`
+    /// is only used as a read-only base image, and all the writes go to the overlay.
+    pub overlay_path_on_host: Option<String>,
+}
+
+fn default_num_queues() -> u16 {
+    1
 }
 
 impl From<&Block> for BlockDeviceConfig {
@@ -101,6 +146,11 @@ impl From<&Block> for BlockDeviceConfig {
             is_read_only: block.is_read_only(),
             cache_type: block.cache_type(),
             rate_limiter: rl.into_option(),
+            io_engine: block.file_engine_type(),
+            is_discard_enabled: block.is_discard_enabled(),
+            is_write_zeroes_enabled: block.is_write_zeroes_enabled(),
+            num_queues: block.num_queues(),
+            overlay_path_on_host: block.overlay_path().cloned(),
         }
     }
 }
@@ -114,6 +164,8 @@ pub struct BlockDeviceUpdateConfig {
     pub drive_id: String,
     /// New block file path on the host. Only provided data will be updated.
     pub path_on_host: Option<String>,
+    /// New overlay image path on the host. Only provided data will be updated.
+    pub overlay_path_on_host: Option<String>,
     /// New rate limiter config.
     pub rate_limiter: Option<RateLimiterConfig>,
 }
@@ -201,6 +253,27 @@ impl BlockBuilder {
             return Err(DriveError::InvalidBlockDevicePath);
         }
 
+        if block_device_config.num_queues == 0 || block_device_config.num_queues > MAX_NUM_QUEUES {
+            return Err(DriveError::InvalidNumQueues(block_device_config.num_queues));
+        }
+
+        // Both requests modify the backing file, which is opened read-only.
+        if block_device_config.is_read_only
+            && (block_device_config.is_discard_enabled
+                || block_device_config.is_write_zeroes_enabled)
+        {
+            return Err(DriveError::ReadOnlyDiscardWriteZeroes);
+        }
+
+        if let Some(ref overlay_path) = block_device_config.overlay_path_on_host {
+            if !PathBuf::from(overlay_path).exists() {
+                return Err(DriveError::InvalidOverlayImagePath);
+            }
+            if block_device_config.io_engine == FileEngineType::Async {
+                return Err(DriveError::UnsupportedOverlayIoEngine);
+            }
+        }
+
         let rate_limiter = block_device_config
             .rate_limiter
             .map(super::RateLimiterConfig::try_into)
@@ -216,6 +289,11 @@ impl BlockBuilder {
             block_device_config.is_read_only,
             block_device_config.is_root_device,
             rate_limiter.unwrap_or_default(),
+            block_device_config.io_engine,
+            block_device_config.is_discard_enabled,
+            block_device_config.is_write_zeroes_enabled,
+            block_device_config.num_queues,
+            block_device_config.overlay_path_on_host,
         )
         .map_err(DriveError::CreateBlockDevice)
     }
@@ -254,6 +332,11 @@ mod tests {
                 is_read_only: self.is_read_only,
                 drive_id: self.drive_id.clone(),
                 rate_limiter: None,
+                io_engine: self.io_engine,
+                is_discard_enabled: self.is_discard_enabled,
+                is_write_zeroes_enabled: self.is_write_zeroes_enabled,
+                num_queues: self.num_queues,
+                overlay_path_on_host: self.overlay_path_on_host.clone(),
             }
         }
     }
@@ -277,6 +360,11 @@ mod tests {
             is_read_only: false,
             drive_id: dummy_id.clone(),
             rate_limiter: None,
+            io_engine: FileEngineType::Sync,
+            is_discard_enabled: false,
+            is_write_zeroes_enabled: false,
+            num_queues: 1,
+            overlay_path_on_host: None,
         };
 
         let mut block_devs = BlockBuilder::new();
@@ -307,6 +395,11 @@ mod tests {
             is_read_only: true,
             drive_id: String::from("1"),
             rate_limiter: None,
+            io_engine: FileEngineType::Sync,
+            is_discard_enabled: false,
+            is_write_zeroes_enabled: false,
+            num_queues: 1,
+            overlay_path_on_host: None,
         };
 
         let mut block_devs = BlockBuilder::new();
@@ -334,6 +427,11 @@ mod tests {
             is_read_only: false,
             drive_id: String::from("1"),
             rate_limiter: None,
+            io_engine: FileEngineType::Sync,
+            is_discard_enabled: false,
+            is_write_zeroes_enabled: false,
+            num_queues: 1,
+            overlay_path_on_host: None,
         };
 
         let dummy_file_2 = TempFile::new().unwrap();
@@ -346,6 +444,11 @@ mod tests {
             is_read_only: false,
             drive_id: String::from("2"),
             rate_limiter: None,
+            io_engine: FileEngineType::Sync,
+            is_discard_enabled: false,
+            is_write_zeroes_enabled: false,
+            num_queues: 1,
+            overlay_path_on_host: None,
         };
 
         let mut block_devs = BlockBuilder::new();
@@ -369,6 +472,11 @@ mod tests {
             is_read_only: false,
             drive_id: String::from("1"),
             rate_limiter: None,
+            io_engine: FileEngineType::Sync,
+            is_discard_enabled: false,
+            is_write_zeroes_enabled: false,
+            num_queues: 1,
+            overlay_path_on_host: None,
         };
 
         let dummy_file_2 = TempFile::new().unwrap();
@@ -381,6 +489,11 @@ mod tests {
             is_read_only: false,
             drive_id: String::from("2"),
             rate_limiter: None,
+            io_engine: FileEngineType::Sync,
+            is_discard_enabled: false,
+            is_write_zeroes_enabled: false,
+            num_queues: 1,
+            overlay_path_on_host: None,
         };
 
         let dummy_file_3 = TempFile::new().unwrap();
@@ -393,6 +506,11 @@ mod tests {
             is_read_only: false,
             drive_id: String::from("3"),
             rate_limiter: None,
+            io_engine: FileEngineType::Sync,
+            is_discard_enabled: false,
+            is_write_zeroes_enabled: false,
+            num_queues: 1,
+            overlay_path_on_host: None,
         };
 
         let mut block_devs = BlockBuilder::new();
@@ -430,6 +548,11 @@ mod tests {
             is_read_only: false,
             drive_id: String::from("1"),
             rate_limiter: None,
+            io_engine: FileEngineType::Sync,
+            is_discard_enabled: false,
+            is_write_zeroes_enabled: false,
+            num_queues: 1,
+            overlay_path_on_host: None,
         };
 
         let dummy_file_2 = TempFile::new().unwrap();
@@ -442,6 +565,11 @@ mod tests {
             is_read_only: false,
             drive_id: String::from("2"),
             rate_limiter: None,
+            io_engine: FileEngineType::Sync,
+            is_discard_enabled: false,
+            is_write_zeroes_enabled: false,
+            num_queues: 1,
+            overlay_path_on_host: None,
         };
 
         let dummy_file_3 = TempFile::new().unwrap();
@@ -454,6 +582,11 @@ mod tests {
             is_read_only: false,
             drive_id: String::from("3"),
             rate_limiter: None,
+            io_engine: FileEngineType::Sync,
+            is_discard_enabled: false,
+            is_write_zeroes_enabled: false,
+            num_queues: 1,
+            overlay_path_on_host: None,
         };
 
         let mut block_devs = BlockBuilder::new();
@@ -492,6 +625,11 @@ mod tests {
             is_read_only: false,
             drive_id: String::from("1"),
             rate_limiter: None,
+            io_engine: FileEngineType::Sync,
+            is_discard_enabled: false,
+            is_write_zeroes_enabled: false,
+            num_queues: 1,
+            overlay_path_on_host: None,
         };
 
         let dummy_file_2 = TempFile::new().unwrap();
@@ -504,6 +642,11 @@ mod tests {
             is_read_only: false,
             drive_id: String::from("2"),
             rate_limiter: None,
+            io_engine: FileEngineType::Sync,
+            is_discard_enabled: false,
+            is_write_zeroes_enabled: false,
+            num_queues: 1,
+            overlay_path_on_host: None,
         };
 
         let mut block_devs = BlockBuilder::new();
@@ -563,6 +706,11 @@ mod tests {
             is_read_only: false,
             drive_id: String::from("1"),
             rate_limiter: None,
+            io_engine: FileEngineType::Sync,
+            is_discard_enabled: false,
+            is_write_zeroes_enabled: false,
+            num_queues: 1,
+            overlay_path_on_host: None,
         };
         // Switch roots and add a PARTUUID for the new one.
         let mut root_block_device_old = root_block_device;
@@ -575,6 +723,11 @@ mod tests {
             is_read_only: false,
             drive_id: String::from("2"),
             rate_limiter: None,
+            io_engine: FileEngineType::Sync,
+            is_discard_enabled: false,
+            is_write_zeroes_enabled: false,
+            num_queues: 1,
+            overlay_path_on_host: None,
         };
         assert!(block_devs.insert(root_block_device_old).is_ok());
         let root_block_id = root_block_device_new.drive_id.clone();
@@ -596,6 +749,11 @@ mod tests {
             is_read_only: true,
             drive_id: String::from("1"),
             rate_limiter: None,
+            io_engine: FileEngineType::Sync,
+            is_discard_enabled: false,
+            is_write_zeroes_enabled: false,
+            num_queues: 1,
+            overlay_path_on_host: None,
         };
 
         let mut block_devs = BlockBuilder::new();
@@ -605,4 +763,159 @@ mod tests {
         assert_eq!(configs.len(), 1);
         assert_eq!(configs.first().unwrap(), &dummy_block_device);
     }
+
+    #[test]
+    fn test_block_io_engine() {
+        let dummy_file = TempFile::new().unwrap();
+        let path_on_host = dummy_file.as_path().to_str().unwrap().to_string();
+
+        // The I/O engine defaults to `Sync` when not specified.
+        let json = format!(
+            r#"{{
+                "drive_id": "1",
+                "path_on_host": "{}",
+                "is_root_device": false,
+                "is_read_only": false
+            }}"#,
+            path_on_host
+        );
+        let config: BlockDeviceConfig = serde_json::from_str(&json).unwrap();
+        assert_eq!(config.io_engine, FileEngineType::Sync);
+
+        let mut block_devs = BlockBuilder::new();
+        let mut async_config = config;
+        async_config.io_engine = FileEngineType::Async;
+        assert!(block_devs.insert(async_config.clone()).is_ok());
+        assert_eq!(
+            block_devs.list[0].lock().unwrap().file_engine_type(),
+            FileEngineType::Async
+        );
+        assert_eq!(block_devs.configs().first().unwrap(), &async_config);
+    }
+
+    #[test]
+    fn test_block_discard_write_zeroes() {
+        let dummy_file = TempFile::new().unwrap();
+        let path_on_host = dummy_file.as_path().to_str().unwrap().to_string();
+
+        // Discard and write zeroes requests are disabled when not specified.
+        let json = format!(
+            r#"{{
+                "drive_id": "1",
+                "path_on_host": "{}",
+                "is_root_device": false,
+                "is_read_only": false
+            }}"#,
+            path_on_host
+        );
+        let config: BlockDeviceConfig = serde_json::from_str(&json).unwrap();
+        assert!(!config.is_discard_enabled);
+        assert!(!config.is_write_zeroes_enabled);
+
+        let mut block_devs = BlockBuilder::new();
+        let mut config = config;
+        config.is_discard_enabled = true;
+        config.is_write_zeroes_enabled = true;
+
+        // Neither can be enabled on a read-only drive.
+        config.is_read_only = true;
+        assert_eq!(
+            block_devs.insert(config.clone()),
+            Err(DriveError::ReadOnlyDiscardWriteZeroes)
+        );
+        config.is_discard_enabled = false;
+        assert_eq!(
+            block_devs.insert(config.clone()),
+            Err(DriveError::ReadOnlyDiscardWriteZeroes)
+        );
+        config.is_discard_enabled = true;
+        config.is_read_only = false;
+
+        assert!(block_devs.insert(config.clone()).is_ok());
+        {
+            let block = block_devs.list[0].lock().unwrap();
+            assert!(block.is_discard_enabled());
+            assert!(block.is_write_zeroes_enabled());
+        }
+        assert_eq!(block_devs.configs().first().unwrap(), &config);
+    }
+
+    #[test]
+    fn test_block_num_queues() {
+        let dummy_file = TempFile::new().unwrap();
+        let mut config = BlockDeviceConfig {
+            path_on_host: dummy_file.as_path().to_str().unwrap().to_string(),
+            is_root_device: false,
+            partuuid: None,
+            cache_type: CacheType::Unsafe,
+            is_read_only: false,
+            drive_id: String::from("1"),
+            rate_limiter: None,
+            io_engine: FileEngineType::Sync,
+            is_discard_enabled: false,
+            is_write_zeroes_enabled: false,
+            num_queues: 0,
+            overlay_path_on_host: None,
+        };
+
+        let mut block_devs = BlockBuilder::new();
+        assert_eq!(
+            block_devs.insert(config.clone()),
+            Err(DriveError::InvalidNumQueues(0))
+        );
+        config.num_queues = MAX_NUM_QUEUES + 1;
+        assert_eq!(
+            block_devs.insert(config.clone()),
+            Err(DriveError::InvalidNumQueues(MAX_NUM_QUEUES + 1))
+        );
+
+        config.num_queues = 4;
+        assert!(block_devs.insert(config.clone()).is_ok());
+        assert_eq!(block_devs.list[0].lock().unwrap().num_queues(), 4);
+        assert_eq!(block_devs.configs().first().unwrap(), &config);
+    }
+
+    #[test]
+    fn test_block_overlay() {
+        let base_file = TempFile::new().unwrap();
+        base_file.as_file().set_len(0x1000).unwrap();
+        let overlay_file = TempFile::new().unwrap();
+        let overlay_path = overlay_file.as_path().to_str().unwrap().to_string();
+
+        let mut config = BlockDeviceConfig {
+            path_on_host: base_file.as_path().to_str().unwrap().to_string(),
+            is_root_device: false,
+            partuuid: None,
+            cache_type: CacheType::Unsafe,
+            is_read_only: false,
+            drive_id: String::from("1"),
+            rate_limiter: None,
+            io_engine: FileEngineType::Sync,
+            is_discard_enabled: false,
+            is_write_zeroes_enabled: false,
+            num_queues: 1,
+            overlay_path_on_host: Some(String::from("/invalid/overlay/path")),
+        };
+
+        let mut block_devs = BlockBuilder::new();
+        assert_eq!(
+            block_devs.insert(config.clone()),
+            Err(DriveError::InvalidOverlayImagePath)
+        );
+
+        config.overlay_path_on_host = Some(overlay_path.clone());
+        config.io_engine = FileEngineType::Async;
+        assert_eq!(
+            block_devs.insert(config.clone()),
+            Err(DriveError::UnsupportedOverlayIoEngine)
+        );
+
+        config.io_engine = FileEngineType::Sync;
+        assert!(block_devs.insert(config.clone()).is_ok());
+        assert_eq!(
+            block_devs.list[0].lock().unwrap().overlay_path(),
+            Some(&overlay_path)
+        );
+        assert_eq!(block_devs.configs().first().unwrap(), &config);
+    }
 }
@@ -3,18 +3,32 @@
 
 use serde::{de, Deserialize, Serialize};
 use std::fmt;
+use std::path::PathBuf;
 
 /// The default memory size of the VM, in MiB.
 pub const DEFAULT_MEM_SIZE_MIB: usize = 128;
 /// Firecracker aims to support small scale workloads only, so limit the maximum
 /// vCPUs supported.
 pub const MAX_SUPPORTED_VCPUS: u8 = 32;
+/// The size of a 2M huge page, in MiB.
+const HUGE_PAGE_2M_SIZE_MIB: usize = 2;
 
 /// Errors associated with configuring the microVM.
 #[derive(Debug, PartialEq)]
 pub enum VmConfigError {
     /// The memory size is smaller than the target size set in the balloon device configuration.
     IncompatibleBalloonSize,
+    /// The balloon device can't be used with huge pages backed guest memory.
+    IncompatibleBalloonHugePages,
+    /// Huge pages can't back shared guest memory.
+    IncompatibleSharedMemoryHugePages,
+    /// The custom CPU template can't be loaded.
+    InvalidCustomCpuTemplate(String),
+    /// The memory size is not a multiple of the huge page size.
+    InvalidHugePageMemorySize,
+    /// The maximum vcpu count is invalid. It can't be lower than the vcpu count and, when
+    /// hyperthreading is enabled, it must be either 1 or an even number.
+    InvalidMaxVcpuCount,
     /// The memory size is invalid. The memory can only be an unsigned integer.
     InvalidMemorySize,
     /// The vcpu count is invalid. When hyperthreading is enabled, the `cpu_count` must be either
@@ -23,6 +37,8 @@ pub enum VmConfigError {
     /// Could not get the config of the balloon device from the VM resources, even though a
     /// balloon device was previously installed.
     InvalidVmState,
+    /// vCPUs can't be hot-added on this architecture.
+    VcpuHotplugNotSupported,
 }
 
 impl fmt::Display for VmConfigError {
@@ -34,6 +50,26 @@ impl fmt::Display for VmConfigError {
                 "The memory size (MiB) is smaller than the previously \
                  set balloon device target size.",
             ),
+            IncompatibleBalloonHugePages => write!(
+                f,
+                "Huge pages can't be enabled when a balloon device is \
+                 configured, since the balloon inflates 4K pages.",
+            ),
+            IncompatibleSharedMemoryHugePages => {
+                write!(f, "Huge pages can't be enabled for shared guest memory.",)
+            }
+            InvalidCustomCpuTemplate(ref e) => {
+                write!(f, "The custom CPU template can't be loaded: {}", e)
+            }
+            InvalidHugePageMemorySize => write!(
+                f,
+                "The memory size (MiB) must be a multiple of the huge page size.",
+            ),
+            InvalidMaxVcpuCount => write!(
+                f,
+                "The maximum vCPU number is invalid! It can't be lower than the vCPU \
+                 number, and can only be 1 or an even number when hyperthreading is enabled.",
+            ),
             InvalidMemorySize => write!(f, "The memory size (MiB) is invalid.",),
             InvalidVcpuCount => write!(
                 f,
@@ -45,6 +81,9 @@ impl fmt::Display for VmConfigError {
                 "Could not get the configuration of the previously \
                  installed balloon device to validate the memory size.",
             ),
+            VcpuHotplugNotSupported => {
+                write!(f, "vCPU hotplug is only supported on x86_64.",)
+            }
         }
     }
 }
@@ -61,6 +100,13 @@ pub struct VmConfig {
         deserialize_with = "validate_vcpu_num"
     )]
     pub vcpu_count: Option<u8>,
+    /// Maximum number of vcpus, including the ones that can be hot-added after boot.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "validate_vcpu_num"
+    )]
+    pub max_vcpu_count: Option<u8>,
     /// The memory size in MiB.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub mem_size_mib: Option<usize>,
@@ -70,19 +116,33 @@ pub struct VmConfig {
     /// A CPU template that it is used to filter the CPU features exposed to the guest.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub cpu_template: Option<CpuFeaturesTemplate>,
+    /// Path to a JSON file holding a user-defined CPU template, applied after `cpu_template`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub custom_cpu_template_path: Option<PathBuf>,
     /// Enables or disables dirty page tracking. Enabling allows incremental snapshots.
     #[serde(default)]
     pub track_dirty_pages: bool,
+    /// The size of the huge pages backing the guest memory.
+    #[serde(default)]
+    pub huge_pages: HugePageConfig,
+    /// Backs the guest memory with a memfd mapped as shared, which can be handed to other
+    /// processes.
+    #[serde(default)]
+    pub shared_memory: bool,
 }
 
 impl Default for VmConfig {
     fn default() -> Self {
         VmConfig {
             vcpu_count: Some(1),
+            max_vcpu_count: None,
             mem_size_mib: Some(DEFAULT_MEM_SIZE_MIB),
             ht_enabled: Some(false),
             cpu_template: None,
+            custom_cpu_template_path: None,
             track_dirty_pages: false,
+            huge_pages: HugePageConfig::None,
+            shared_memory: false,
         }
     }
 }
@@ -90,16 +150,30 @@ impl Default for VmConfig {
 impl fmt::Display for VmConfig {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let vcpu_count = self.vcpu_count.unwrap_or(1);
+        let max_vcpu_count = self.max_vcpu_count.unwrap_or(vcpu_count);
         let mem_size = self.mem_size_mib.unwrap_or(DEFAULT_MEM_SIZE_MIB);
         let ht_enabled = self.ht_enabled.unwrap_or(false);
         let cpu_template = self
             .cpu_template
             .map_or("Uninitialized".to_string(), |c| c.to_string());
+        let custom_cpu_template_path = self
+            .custom_cpu_template_path
+            .as_ref()
+            .map_or("Uninitialized".to_string(), |p| p.display().to_string());
         write!(
             f,
-            "{{ \"vcpu_count\": {:?}, \"mem_size_mib\": {:?}, \"ht_enabled\": {:?}, \
-             \"cpu_template\": {:?}, \"track_dirty_pages\": {:?} }}",
-            vcpu_count, mem_size, ht_enabled, cpu_template, self.track_dirty_pages
+            "{{ \"vcpu_count\": {:?}, \"max_vcpu_count\": {:?}, \"mem_size_mib\": {:?}, \
+             \"ht_enabled\": {:?}, \"cpu_template\": {:?}, \"custom_cpu_template_path\": {:?}, \
+             \"track_dirty_pages\": {:?}, \"huge_pages\": {:?}, \"shared_memory\": {:?} }}",
+            vcpu_count,
+            max_vcpu_count,
+            mem_size,
+            ht_enabled,
+            cpu_template,
+            custom_cpu_template_path,
+            self.track_dirty_pages,
+            self.huge_pages.to_string(),
+            self.shared_memory
         )
     }
 }
@@ -121,13 +195,15 @@ where
 }
 
 /// Template types available for configuring the CPU features that map
-/// to EC2 instances.
+/// to EC2 instances or to a CPU generation.
 #[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
 pub enum CpuFeaturesTemplate {
     /// C3 Template.
     C3,
     /// T2 Template.
     T2,
+    /// Zen 1 Template, only available on AMD hosts.
+    Zen1,
 }
 
 impl fmt::Display for CpuFeaturesTemplate {
@@ -135,6 +211,47 @@ impl fmt::Display for CpuFeaturesTemplate {
         match self {
             CpuFeaturesTemplate::C3 => write!(f, "C3"),
             CpuFeaturesTemplate::T2 => write!(f, "T2"),
+            CpuFeaturesTemplate::Zen1 => write!(f, "Zen1"),
+        }
+    }
+}
+
+/// Huge page sizes available for backing the guest memory.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum HugePageConfig {
+    /// The guest memory is backed by regular pages.
+    None,
+    /// The guest memory is backed by 2M hugetlbfs pages.
+    #[serde(rename = "2M")]
+    Hugetlbfs2M,
+}
+
+impl HugePageConfig {
+    /// Returns whether the guest memory is backed by huge pages.
+    pub fn is_hugetlbfs(self) -> bool {
+        self != HugePageConfig::None
+    }
+
+    /// Checks that a guest memory of `mem_size_mib` MiB can be backed by these pages.
+    pub fn is_valid_mem_size(self, mem_size_mib: usize) -> bool {
+        match self {
+            HugePageConfig::None => true,
+            HugePageConfig::Hugetlbfs2M => mem_size_mib % HUGE_PAGE_2M_SIZE_MIB == 0,
+        }
+    }
+}
+
+impl Default for HugePageConfig {
+    fn default() -> Self {
+        HugePageConfig::None
+    }
+}
+
+impl fmt::Display for HugePageConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HugePageConfig::None => write!(f, "None"),
+            HugePageConfig::Hugetlbfs2M => write!(f, "2M"),
         }
     }
 }
@@ -147,6 +264,7 @@ mod tests {
     fn test_display_cpu_features_template() {
         assert_eq!(CpuFeaturesTemplate::C3.to_string(), "C3".to_string());
         assert_eq!(CpuFeaturesTemplate::T2.to_string(), "T2".to_string());
+        assert_eq!(CpuFeaturesTemplate::Zen1.to_string(), "Zen1".to_string());
     }
 
     #[test]
@@ -157,5 +275,44 @@ mod tests {
 
         let expected_str = "The memory size (MiB) is invalid.";
         assert_eq!(VmConfigError::InvalidMemorySize.to_string(), expected_str);
+
+        let expected_str = "vCPU hotplug is only supported on x86_64.";
+        assert_eq!(
+            VmConfigError::VcpuHotplugNotSupported.to_string(),
+            expected_str
+        );
+    }
+
+    #[test]
+    fn test_max_vcpu_count() {
+        let config: VmConfig =
+            serde_json::from_str(r#"{"vcpu_count": 2, "max_vcpu_count": 8}"#).unwrap();
+        assert_eq!(config.max_vcpu_count, Some(8));
+        assert!(config.to_string().contains(r#""max_vcpu_count": 8"#));
+        assert!(serde_json::from_str::<VmConfig>(r#"{"max_vcpu_count": 33}"#).is_err());
+
+        // The maximum defaults to the vCPU count.
+        let config: VmConfig = serde_json::from_str(r#"{"vcpu_count": 2}"#).unwrap();
+        assert_eq!(config.max_vcpu_count, None);
+        assert!(config.to_string().contains(r#""max_vcpu_count": 2"#));
+    }
+
+    #[test]
+    fn test_huge_page_config() {
+        assert_eq!(HugePageConfig::default(), HugePageConfig::None);
+        assert_eq!(HugePageConfig::Hugetlbfs2M.to_string(), "2M".to_string());
+        assert!(!HugePageConfig::None.is_hugetlbfs());
+        assert!(HugePageConfig::Hugetlbfs2M.is_hugetlbfs());
+
+        assert!(HugePageConfig::None.is_valid_mem_size(129));
+        assert!(HugePageConfig::Hugetlbfs2M.is_valid_mem_size(128));
+        assert!(!HugePageConfig::Hugetlbfs2M.is_valid_mem_size(129));
+
+        let config: VmConfig = serde_json::from_str(
+            r#"{"vcpu_count": 1, "mem_size_mib": 128, "ht_enabled": false, "huge_pages": "2M"}"#,
+        )
+        .unwrap();
+        assert_eq!(config.huge_pages, HugePageConfig::Hugetlbfs2M);
+        assert!(serde_json::from_str::<VmConfig>(r#"{"huge_pages": "1G"}"#).is_err());
     }
 }
@@ -0,0 +1,47 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Configurations used in the live migration context.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use super::snapshot::NetworkOverride;
+
+/// Stores the configuration that will be used for sending a running microVM
+/// to another Firecracker process.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct SendMigrationParams {
+    /// Path to the Unix domain socket the destination listens on.
+    pub socket_path: PathBuf,
+    /// Maximum number of rounds of dirty pages sent while the microVM is running.
+    /// The default value is 5.
+    pub max_iterations: Option<u32>,
+    /// Optional field for the microVM version of the destination. The default
+    /// value is the current version.
+    pub version: Option<String>,
+}
+
+/// Stores the configuration that will be used for receiving a microVM
+/// sent by another Firecracker process.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReceiveMigrationParams {
+    /// Path to the Unix domain socket to listen on for the source.
+    pub socket_path: PathBuf,
+    /// Setting this flag will enable KVM dirty page tracking and will
+    /// allow taking subsequent incremental snapshots or migrations.
+    #[serde(default)]
+    pub enable_diff_snapshots: bool,
+    /// Host-side configuration of the network interfaces that differs from the
+    /// one of the source.
+    #[serde(default)]
+    pub network_overrides: Vec<NetworkOverride>,
+    /// Host paths of the drive backing files that differ from the ones of the
+    /// source, indexed by drive ID.
+    #[serde(default)]
+    pub drive_path_overrides: HashMap<String, String>,
+}
@@ -16,6 +16,8 @@ use rate_limiter::{BucketUpdate, RateLimiter, TokenBucket};
 pub mod balloon;
 /// Wrapper for configuring the microVM boot source.
 pub mod boot_source;
+/// Wrapper for reporting the dirty guest memory pages.
+pub mod dirty_pages;
 /// Wrapper for configuring the block devices.
 pub mod drive;
 /// Wrapper over the microVM general information attached to the microVM.
@@ -26,12 +28,18 @@ pub mod logger;
 pub mod machine_config;
 /// Wrapper for configuring the metrics.
 pub mod metrics;
+/// Wrapper for configuring the live migration of the microVM.
+pub mod migration;
 /// Wrapper for configuring the MMDS.
 pub mod mmds;
 /// Wrapper for configuring the network devices attached to the microVM.
 pub mod net;
+/// Wrapper for handing the shared guest memory to other processes.
+pub mod shared_memory;
 /// Wrapper for configuring microVM snapshots and the microVM state.
 pub mod snapshot;
+/// Wrapper for hot-adding vCPUs to the running microVM.
+pub mod vcpu_hotplug;
 /// Wrapper for configuring the vsock devices attached to the microVM.
 pub mod vsock;
 
@@ -8,7 +8,7 @@ use std::sync::{Arc, Mutex};
 
 use super::RateLimiterConfig;
 use crate::Error as VmmError;
-use devices::virtio::net::TapError;
+use devices::virtio::net::{TapError, MAX_QUEUE_PAIRS};
 use devices::virtio::Net;
 use utils::net::mac::MacAddr;
 
@@ -37,6 +37,15 @@ pub struct NetworkInterfaceConfig {
     /// same address are intercepted by the device model, and do not reach
     /// the associated TAP device.
     pub allow_mmds_requests: bool,
+    /// Number of RX/TX queue pairs of the guest network interface. Each queue pair is
+    /// backed by a queue of the host multi-queue TAP device, and rate limited separately.
+    #[serde(default = "default_num_queue_pairs")]
+    pub num_queue_pairs: u16,
+    /// If this field is set, the frames are moved between the guest and the TAP device
+    /// by the in-kernel vhost-net backend instead of the device model. MMDS, rate limiting
+    /// and snapshotting are not supported for such interfaces.
+    #[serde(default)]
+    pub vhost_net: bool,
 }
 
 impl From<&Net> for NetworkInterfaceConfig {
@@ -50,6 +59,8 @@ impl From<&Net> for NetworkInterfaceConfig {
             rx_rate_limiter: rx_rl.into_option(),
             tx_rate_limiter: tx_rl.into_option(),
             allow_mmds_requests: net.mmds_enabled(),
+            num_queue_pairs: net.num_queue_pairs(),
+            vhost_net: net.vhost_net_enabled(),
         }
     }
 }
@@ -61,9 +72,13 @@ fn default_allow_mmds_requests() -> bool {
     false
 }
 
-/// The data fed into a network iface update request. Currently, only the RX and TX rate limiters
-/// can be updated.
-#[derive(Debug, Deserialize, PartialEq, Clone)]
+fn default_num_queue_pairs() -> u16 {
+    1
+}
+
+/// The data fed into a network iface update request. The RX and TX rate limiters, the guest MAC
+/// address and the link state can be updated.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
 #[serde(deny_unknown_fields)]
 pub struct NetworkInterfaceUpdateConfig {
     /// The net iface ID, as provided by the user at iface creation time.
@@ -74,6 +89,10 @@ pub struct NetworkInterfaceUpdateConfig {
     /// New TX rate limiter config. Only provided data will be updated. I.e. if any optional data
     /// is missing, it will not be nullified, but left unchanged.
     pub tx_rate_limiter: Option<RateLimiterConfig>,
+    /// New guest MAC address, which the guest driver is asked to announce to the network.
+    pub guest_mac: Option<MacAddr>,
+    /// New link state reported to the guest driver.
+    pub link_up: Option<bool>,
 }
 
 /// Errors associated with `NetworkInterfaceConfig`.
@@ -87,8 +106,12 @@ pub enum NetworkInterfaceError {
     GuestMacAddressInUse(String),
     /// Error during interface update (patch).
     DeviceUpdate(VmmError),
+    /// The number of queue pairs is out of range.
+    InvalidNumQueuePairs(u16),
     /// Cannot open/create tap device.
     OpenTap(TapError),
+    /// The requested feature is not supported by vhost-net interfaces.
+    VhostNetUnsupported(&'static str),
 }
 
 impl fmt::Display for NetworkInterfaceError {
@@ -103,6 +126,11 @@ impl fmt::Display for NetworkInterfaceError {
                 format!("The guest MAC address {} is already in use.", mac_addr)
             ),
             DeviceUpdate(e) => write!(f, "Error during interface update (patch): {}", e),
+            InvalidNumQueuePairs(num_queue_pairs) => write!(
+                f,
+                "Invalid number of queue pairs: {}. It must be between 1 and {}.",
+                num_queue_pairs, MAX_QUEUE_PAIRS
+            ),
             OpenTap(e) => {
                 // We are propagating the Tap Error. This error can contain
                 // imbricated quotes which would result in an invalid json.
@@ -116,6 +144,11 @@ impl fmt::Display for NetworkInterfaceError {
                     tap_err
                 )
             }
+            VhostNetUnsupported(feature) => write!(
+                f,
+                "{} is not supported by vhost-net network interfaces.",
+                feature
+            ),
         }
     }
 }
@@ -184,6 +217,33 @@ impl NetBuilder {
 
     /// Creates a Net device from a NetworkInterfaceConfig.
     pub fn create_net(cfg: NetworkInterfaceConfig) -> Result<Net> {
+        if cfg.num_queue_pairs == 0 || cfg.num_queue_pairs > MAX_QUEUE_PAIRS {
+            return Err(NetworkInterfaceError::InvalidNumQueuePairs(
+                cfg.num_queue_pairs,
+            ));
+        }
+
+        if cfg.vhost_net {
+            // The frames don't go through the device model, which implements these features.
+            if cfg.allow_mmds_requests {
+                return Err(NetworkInterfaceError::VhostNetUnsupported("MMDS"));
+            }
+            if cfg
+                .rx_rate_limiter
+                .and_then(RateLimiterConfig::into_option)
+                .is_some()
+                || cfg
+                    .tx_rate_limiter
+                    .and_then(RateLimiterConfig::into_option)
+                    .is_some()
+            {
+                return Err(NetworkInterfaceError::VhostNetUnsupported("Rate limiting"));
+            }
+            if cfg.num_queue_pairs > 1 {
+                return Err(NetworkInterfaceError::VhostNetUnsupported("Multi-queue"));
+            }
+        }
+
         let rx_rate_limiter = cfg
             .rx_rate_limiter
             .map(super::RateLimiterConfig::try_into)
@@ -196,15 +256,22 @@ impl NetBuilder {
             .map_err(NetworkInterfaceError::CreateRateLimiter)?;
 
         // Create and return the Net device
-        devices::virtio::net::Net::new_with_tap(
+        let mut net = devices::virtio::net::Net::new_with_tap(
             cfg.iface_id,
             cfg.host_dev_name.clone(),
             cfg.guest_mac.as_ref(),
             rx_rate_limiter.unwrap_or_default(),
             tx_rate_limiter.unwrap_or_default(),
             cfg.allow_mmds_requests,
+            cfg.num_queue_pairs,
         )
-        .map_err(NetworkInterfaceError::CreateNetworkDevice)
+        .map_err(NetworkInterfaceError::CreateNetworkDevice)?;
+        if cfg.vhost_net {
+            net.enable_vhost_net()
+                .map_err(NetworkInterfaceError::CreateNetworkDevice)?;
+        }
+
+        Ok(net)
     }
 
     /// Returns a vec with the structures used to configure the net devices.
@@ -241,6 +308,8 @@ mod tests {
             rx_rate_limiter: RateLimiterConfig::default().into_option(),
             tx_rate_limiter: RateLimiterConfig::default().into_option(),
             allow_mmds_requests: false,
+            num_queue_pairs: 1,
+            vhost_net: false,
         }
     }
 
@@ -253,6 +322,8 @@ mod tests {
                 rx_rate_limiter: None,
                 tx_rate_limiter: None,
                 allow_mmds_requests: self.allow_mmds_requests,
+                num_queue_pairs: self.num_queue_pairs,
+                vhost_net: self.vhost_net,
             }
         }
     }
@@ -365,11 +436,21 @@ mod tests {
             NetworkInterfaceError::DeviceUpdate(VmmError::VcpuExit),
             NetworkInterfaceError::DeviceUpdate(VmmError::VcpuExit)
         );
+        let _ = format!(
+            "{}{:?}",
+            NetworkInterfaceError::InvalidNumQueuePairs(0),
+            NetworkInterfaceError::InvalidNumQueuePairs(0)
+        );
         let _ = format!(
             "{}{:?}",
             NetworkInterfaceError::OpenTap(TapError::InvalidIfname),
             NetworkInterfaceError::OpenTap(TapError::InvalidIfname)
         );
+        let _ = format!(
+            "{}{:?}",
+            NetworkInterfaceError::VhostNetUnsupported("MMDS"),
+            NetworkInterfaceError::VhostNetUnsupported("MMDS")
+        );
     }
 
     #[test]
@@ -393,4 +474,98 @@ mod tests {
         assert_eq!(configs.len(), 1);
         assert_eq!(configs.first().unwrap(), &net_if_cfg);
     }
+
+    #[test]
+    fn test_multi_queue_net_config() {
+        let mut net_builder = NetBuilder::new();
+        let mut net_if_cfg = create_netif("id", "mqdev", "01:23:45:67:89:0c");
+
+        net_if_cfg.num_queue_pairs = 0;
+        assert_eq!(
+            net_builder
+                .build(net_if_cfg.clone())
+                .err()
+                .unwrap()
+                .to_string(),
+            NetworkInterfaceError::InvalidNumQueuePairs(0).to_string()
+        );
+        net_if_cfg.num_queue_pairs = MAX_QUEUE_PAIRS + 1;
+        assert_eq!(
+            net_builder
+                .build(net_if_cfg.clone())
+                .err()
+                .unwrap()
+                .to_string(),
+            NetworkInterfaceError::InvalidNumQueuePairs(MAX_QUEUE_PAIRS + 1).to_string()
+        );
+        assert!(net_builder.is_empty());
+
+        net_if_cfg.num_queue_pairs = 4;
+        assert!(net_builder.build(net_if_cfg.clone()).is_ok());
+        let configs = net_builder.configs();
+        assert_eq!(configs.first().unwrap().num_queue_pairs, 4);
+
+        // The number of queue pairs defaults to 1.
+        let json = r#"{
+            "iface_id": "id",
+            "host_dev_name": "mqdev"
+        }"#;
+        let cfg: NetworkInterfaceConfig = serde_json::from_str(json).unwrap();
+        assert_eq!(cfg.num_queue_pairs, 1);
+    }
+
+    #[test]
+    fn test_vhost_net_config() {
+        let mut net_builder = NetBuilder::new();
+        let mut net_if_cfg = create_netif("id", "vhostdev", "01:23:45:67:89:0d");
+        net_if_cfg.vhost_net = true;
+
+        net_if_cfg.allow_mmds_requests = true;
+        assert_eq!(
+            net_builder
+                .build(net_if_cfg.clone())
+                .err()
+                .unwrap()
+                .to_string(),
+            NetworkInterfaceError::VhostNetUnsupported("MMDS").to_string()
+        );
+        net_if_cfg.allow_mmds_requests = false;
+
+        let mut rate_limited_cfg = net_if_cfg.clone();
+        rate_limited_cfg.tx_rate_limiter = Some(RateLimiterConfig {
+            bandwidth: Some(super::super::TokenBucketConfig {
+                size: 1000,
+                one_time_burst: None,
+                refill_time: 1000,
+            }),
+            ops: None,
+        });
+        assert_eq!(
+            net_builder
+                .build(rate_limited_cfg)
+                .err()
+                .unwrap()
+                .to_string(),
+            NetworkInterfaceError::VhostNetUnsupported("Rate limiting").to_string()
+        );
+
+        net_if_cfg.num_queue_pairs = 2;
+        assert_eq!(
+            net_builder
+                .build(net_if_cfg.clone())
+                .err()
+                .unwrap()
+                .to_string(),
+            NetworkInterfaceError::VhostNetUnsupported("Multi-queue").to_string()
+        );
+        assert!(net_builder.is_empty());
+
+        // The data path is handled by the device model by default.
+        let json = r#"{
+            "iface_id": "id",
+            "host_dev_name": "vhostdev"
+        }"#;
+        let cfg: NetworkInterfaceConfig = serde_json::from_str(json).unwrap();
+        assert!(!cfg.vhost_net);
+    }
 }
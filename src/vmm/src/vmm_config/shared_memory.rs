@@ -0,0 +1,30 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Configurations used for handing the shared guest memory to other processes.
+
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+/// Stores the configuration that will be used for sending the memfd backing the
+/// shared guest memory to another process.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct SharedMemoryParams {
+    /// Path to the Unix domain socket the receiving process listens on.
+    pub socket_path: PathBuf,
+}
+
+/// Describes where a guest memory region lies in the memfd backing the shared guest memory.
+/// A list of such mappings is sent to the receiving process, serialized as JSON, along with
+/// the memfd.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct GuestRegionMemfdMapping {
+    /// Guest physical address of the start of the region.
+    pub base_address: u64,
+    /// Region size.
+    pub size: usize,
+    /// Offset in the memfd where the region starts.
+    pub offset: u64,
+}
@@ -3,9 +3,13 @@
 
 //! Configurations used in the snapshotting context.
 
+use std::collections::HashMap;
 use std::path::PathBuf;
 
 use serde::{Deserialize, Serialize};
+use utils::net::mac::MacAddr;
+
+use super::RateLimiterConfig;
 
 /// The snapshot type options that are available when
 /// creating a new snapshot.
@@ -23,6 +27,38 @@ impl Default for SnapshotType {
     }
 }
 
+/// The memory file formats that are available when creating a new snapshot.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum MemFileFormat {
+    /// The guest memory is dumped as it is.
+    Raw,
+    /// The guest memory is dumped in checksummed chunks, compressing their
+    /// content and skipping the zeroed ones.
+    Compressed,
+}
+
+impl Default for MemFileFormat {
+    fn default() -> MemFileFormat {
+        MemFileFormat::Raw
+    }
+}
+
+/// The destinations the snapshot can be written to.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum SnapshotDestinationType {
+    /// The snapshot is written to regular files.
+    File,
+    /// The snapshot is streamed to Unix domain sockets which Firecracker
+    /// connects to.
+    UnixSocket,
+}
+
+impl Default for SnapshotDestinationType {
+    fn default() -> SnapshotDestinationType {
+        SnapshotDestinationType::File
+    }
+}
+
 /// Stores the configuration that will be used for creating a snapshot.
 #[derive(Debug, Deserialize, PartialEq, Serialize)]
 #[serde(deny_unknown_fields)]
@@ -35,9 +71,60 @@ pub struct CreateSnapshotParams {
     pub snapshot_path: PathBuf,
     /// Path to the file that will contain the guest memory.
     pub mem_file_path: PathBuf,
+    /// Type of the destination of the snapshot. The default value is `File`.
+    /// With `UnixSocket`, `snapshot_path` and `mem_file_path` are the paths of
+    /// the sockets the microVM state and the guest memory are streamed to.
+    /// Only full snapshots can be streamed.
+    #[serde(default = "SnapshotDestinationType::default")]
+    pub destination_type: SnapshotDestinationType,
+    /// Format of the memory file. The default value is `Raw`.
+    /// Only full snapshots can use the `Compressed` format.
+    #[serde(default = "MemFileFormat::default")]
+    pub mem_file_format: MemFileFormat,
     /// Optional field for the microVM version. The default
     /// value is the current version.
     pub version: Option<String>,
+    /// Hex encoded key used to write the HMAC-SHA256 tags of the microVM state
+    /// and of the guest memory next to the snapshot file.
+    pub integrity_key: Option<String>,
+}
+
+/// Overrides the host-side configuration of a network interface saved in a snapshot.
+/// The fields that are not set keep the values saved in the snapshot.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct NetworkOverride {
+    /// ID of the guest network interface to reconfigure.
+    pub iface_id: String,
+    /// Host level path of the TAP device the interface is attached to.
+    pub host_dev_name: Option<String>,
+    /// Guest MAC address.
+    pub guest_mac: Option<MacAddr>,
+    /// Rate Limiter for received packages.
+    pub rx_rate_limiter: Option<RateLimiterConfig>,
+    /// Rate Limiter for transmitted packages.
+    pub tx_rate_limiter: Option<RateLimiterConfig>,
+}
+
+/// The guest memory backend options that are available when
+/// loading a snapshot.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum MemBackendType {
+    /// The guest memory is mapped from the memory file.
+    File,
+    /// The guest memory is populated on demand by a userfaultfd page fault handler.
+    Uffd,
+}
+
+/// Stores the configuration of the guest memory backend used when loading a snapshot.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct MemBackendConfig {
+    /// Path to the memory file for the `File` backend, or to the Unix socket the
+    /// page fault handler listens on for the `Uffd` backend.
+    pub backend_path: PathBuf,
+    /// The type of the backend.
+    pub backend_type: MemBackendType,
 }
 
 /// Stores the configuration that will be used for loading a snapshot.
@@ -47,7 +134,11 @@ pub struct LoadSnapshotParams {
     /// Path to the file that contains the microVM state to be loaded.
     pub snapshot_path: PathBuf,
     /// Path to the file that contains the guest memory to be loaded.
-    pub mem_file_path: PathBuf,
+    /// Cannot be used together with `mem_backend`.
+    pub mem_file_path: Option<PathBuf>,
+    /// Backend of the guest memory to be loaded.
+    /// Cannot be used together with `mem_file_path`.
+    pub mem_backend: Option<MemBackendConfig>,
     /// Setting this flag will enable KVM dirty page tracking and will
     /// allow taking subsequent incremental snapshots.
     #[serde(default)]
@@ -56,6 +147,26 @@ pub struct LoadSnapshotParams {
     /// is successful.
     #[serde(default)]
     pub resume_vm: bool,
+    /// Host-side configuration of the network interfaces that differs from the
+    /// one saved in the snapshot.
+    #[serde(default)]
+    pub network_overrides: Vec<NetworkOverride>,
+    /// Host paths of the drive backing files that differ from the ones saved in the
+    /// snapshot, indexed by drive ID.
+    #[serde(default)]
+    pub drive_path_overrides: HashMap<String, String>,
+    /// Hex encoded key used to verify the HMAC-SHA256 tags of the snapshot
+    /// files before loading them.
+    pub integrity_key: Option<String>,
+}
+
+/// Stores the configuration that will be used for checking that a snapshot can be
+/// loaded on the host.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct CheckSnapshotParams {
+    /// Path to the file that contains the microVM state to be checked.
+    pub snapshot_path: PathBuf,
 }
 
 /// The microVM state options.
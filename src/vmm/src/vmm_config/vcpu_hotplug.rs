@@ -0,0 +1,82 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Configurations used for hot-adding vCPUs to a running microVM.
+
+use std::fmt::{Display, Formatter, Result};
+
+use serde::{Deserialize, Serialize};
+
+/// Stores the number of vCPUs the running microVM should have after the hotplug.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct VcpuHotplugConfig {
+    /// Total number of vCPUs, including the ones the microVM already has.
+    pub vcpu_count: u8,
+}
+
+/// Errors associated with hot-adding vCPUs.
+#[derive(Debug)]
+pub enum VcpuHotplugError {
+    /// vCPUs can't be removed from a running microVM.
+    VcpuRemoval(u8, u8),
+    /// The requested vCPU count is higher than the maximum one.
+    TooManyVcpus(u8, u8),
+    /// The requested vCPU count is odd while hyperthreading is enabled.
+    InvalidVcpuCount(u8),
+    /// Failed to resume the new vCPUs.
+    Internal(crate::Error),
+}
+
+impl Display for VcpuHotplugError {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        use self::VcpuHotplugError::*;
+        match self {
+            VcpuRemoval(current, requested) => write!(
+                f,
+                "Cannot remove vCPUs from a running microVM: it has {} vCPUs, but {} were \
+                 requested. Offline them from the guest instead.",
+                current, requested
+            ),
+            TooManyVcpus(requested, max) => write!(
+                f,
+                "Cannot hot-add vCPUs up to {}, the maximum vCPU number is {}.",
+                requested, max
+            ),
+            InvalidVcpuCount(requested) => write!(
+                f,
+                "Cannot hot-add vCPUs up to {}, the vCPU number can only be 1 or an even \
+                 number when hyperthreading is enabled.",
+                requested
+            ),
+            Internal(e) => write!(f, "Failed to hot-add the vCPUs: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vcpu_hotplug_error_display() {
+        assert_eq!(
+            VcpuHotplugError::VcpuRemoval(4, 2).to_string(),
+            "Cannot remove vCPUs from a running microVM: it has 4 vCPUs, but 2 were requested. \
+             Offline them from the guest instead."
+        );
+        assert_eq!(
+            VcpuHotplugError::TooManyVcpus(8, 4).to_string(),
+            "Cannot hot-add vCPUs up to 8, the maximum vCPU number is 4."
+        );
+        assert_eq!(
+            VcpuHotplugError::InvalidVcpuCount(3).to_string(),
+            "Cannot hot-add vCPUs up to 3, the vCPU number can only be 1 or an even number when \
+             hyperthreading is enabled."
+        );
+        assert_eq!(
+            VcpuHotplugError::Internal(crate::Error::VcpuResume).to_string(),
+            "Failed to hot-add the vCPUs: Failed to resume the vCPUs."
+        );
+    }
+}
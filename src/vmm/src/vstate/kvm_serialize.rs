@@ -0,0 +1,434 @@
+// Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Serializes the KVM structures of the VM and vCPU states, which don't implement
+//! `Serialize`. The padding and reserved fields are left out, and the unions are converted
+//! according to the content they hold.
+
+use kvm_bindings::kvm_mp_state;
+#[cfg(target_arch = "aarch64")]
+use kvm_bindings::kvm_one_reg;
+#[cfg(target_arch = "x86_64")]
+use kvm_bindings::{
+    kvm_clock_data, kvm_debugregs, kvm_dtable, kvm_ioapic_state, kvm_irqchip, kvm_lapic_state,
+    kvm_pic_state, kvm_pit_state2, kvm_regs, kvm_segment, kvm_sregs, kvm_vcpu_events, kvm_xcrs,
+    kvm_xsave, CpuId, Msrs, KVM_IRQCHIP_IOAPIC, KVM_IRQCHIP_PIC_MASTER, KVM_IRQCHIP_PIC_SLAVE,
+};
+use serde::{Serialize, Serializer};
+use serde_json::{Map, Value};
+
+type Result<S> = std::result::Result<<S as Serializer>::Ok, <S as Serializer>::Error>;
+
+fn object(fields: Vec<(&str, Value)>) -> Value {
+    Value::Object(
+        fields
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value))
+            .collect::<Map<String, Value>>(),
+    )
+}
+
+pub(crate) fn serialize_mp_state<S: Serializer>(
+    mp_state: &kvm_mp_state,
+    serializer: S,
+) -> Result<S> {
+    object(vec![("mp_state", mp_state.mp_state.into())]).serialize(serializer)
+}
+
+#[cfg(target_arch = "aarch64")]
+pub(crate) fn serialize_one_regs<S: Serializer>(regs: &[kvm_one_reg], serializer: S) -> Result<S> {
+    Value::Array(
+        regs.iter()
+            .map(|reg| object(vec![("id", reg.id.into()), ("addr", reg.addr.into())]))
+            .collect(),
+    )
+    .serialize(serializer)
+}
+
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn serialize_pit_state<S: Serializer>(
+    pit_state: &kvm_pit_state2,
+    serializer: S,
+) -> Result<S> {
+    let channels = pit_state
+        .channels
+        .iter()
+        .map(|channel| {
+            object(vec![
+                ("count", channel.count.into()),
+                ("latched_count", channel.latched_count.into()),
+                ("count_latched", channel.count_latched.into()),
+                ("status_latched", channel.status_latched.into()),
+                ("status", channel.status.into()),
+                ("read_state", channel.read_state.into()),
+                ("write_state", channel.write_state.into()),
+                ("write_latch", channel.write_latch.into()),
+                ("rw_mode", channel.rw_mode.into()),
+                ("mode", channel.mode.into()),
+                ("bcd", channel.bcd.into()),
+                ("gate", channel.gate.into()),
+                ("count_load_time", channel.count_load_time.into()),
+            ])
+        })
+        .collect();
+    object(vec![
+        ("channels", Value::Array(channels)),
+        ("flags", pit_state.flags.into()),
+    ])
+    .serialize(serializer)
+}
+
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn serialize_clock<S: Serializer>(clock: &kvm_clock_data, serializer: S) -> Result<S> {
+    object(vec![
+        ("clock", clock.clock.into()),
+        ("flags", clock.flags.into()),
+    ])
+    .serialize(serializer)
+}
+
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn serialize_irqchip<S: Serializer>(irqchip: &kvm_irqchip, serializer: S) -> Result<S> {
+    // The union holds the state of the chip identified by `chip_id`. Its fields are plain
+    // integers, so reading them is safe whatever they hold.
+    let chip = match irqchip.chip_id {
+        KVM_IRQCHIP_PIC_MASTER | KVM_IRQCHIP_PIC_SLAVE => {
+            ("pic", pic_to_json(unsafe { &irqchip.chip.pic }))
+        }
+        KVM_IRQCHIP_IOAPIC => ("ioapic", ioapic_to_json(unsafe { &irqchip.chip.ioapic })),
+        _ => ("chip", Value::Null),
+    };
+    object(vec![("chip_id", irqchip.chip_id.into()), chip]).serialize(serializer)
+}
+
+#[cfg(target_arch = "x86_64")]
+fn pic_to_json(pic: &kvm_pic_state) -> Value {
+    object(vec![
+        ("last_irr", pic.last_irr.into()),
+        ("irr", pic.irr.into()),
+        ("imr", pic.imr.into()),
+        ("isr", pic.isr.into()),
+        ("priority_add", pic.priority_add.into()),
+        ("irq_base", pic.irq_base.into()),
+        ("read_reg_select", pic.read_reg_select.into()),
+        ("poll", pic.poll.into()),
+        ("special_mask", pic.special_mask.into()),
+        ("init_state", pic.init_state.into()),
+        ("auto_eoi", pic.auto_eoi.into()),
+        ("rotate_on_auto_eoi", pic.rotate_on_auto_eoi.into()),
+        (
+            "special_fully_nested_mode",
+            pic.special_fully_nested_mode.into(),
+        ),
+        ("init4", pic.init4.into()),
+        ("elcr", pic.elcr.into()),
+        ("elcr_mask", pic.elcr_mask.into()),
+    ])
+}
+
+#[cfg(target_arch = "x86_64")]
+fn ioapic_to_json(ioapic: &kvm_ioapic_state) -> Value {
+    // Each redirection table entry is a union of its 64 bits and of their bit fields, which
+    // are decoded from the former.
+    let redirtbl = ioapic
+        .redirtbl
+        .iter()
+        .map(|entry| {
+            let bits = unsafe { entry.bits };
+            let field = |shift: u32, len: u32| Value::from((bits >> shift) & ((1 << len) - 1));
+            object(vec![
+                ("bits", bits.into()),
+                ("vector", field(0, 8)),
+                ("delivery_mode", field(8, 3)),
+                ("dest_mode", field(11, 1)),
+                ("delivery_status", field(12, 1)),
+                ("polarity", field(13, 1)),
+                ("remote_irr", field(14, 1)),
+                ("trig_mode", field(15, 1)),
+                ("mask", field(16, 1)),
+                ("dest_id", field(56, 8)),
+            ])
+        })
+        .collect();
+    object(vec![
+        ("base_address", ioapic.base_address.into()),
+        ("ioregsel", ioapic.ioregsel.into()),
+        ("id", ioapic.id.into()),
+        ("irr", ioapic.irr.into()),
+        ("redirtbl", Value::Array(redirtbl)),
+    ])
+}
+
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn serialize_cpuid<S: Serializer>(cpuid: &CpuId, serializer: S) -> Result<S> {
+    Value::Array(
+        cpuid
+            .as_slice()
+            .iter()
+            .map(|entry| {
+                object(vec![
+                    ("function", entry.function.into()),
+                    ("index", entry.index.into()),
+                    ("flags", entry.flags.into()),
+                    ("eax", entry.eax.into()),
+                    ("ebx", entry.ebx.into()),
+                    ("ecx", entry.ecx.into()),
+                    ("edx", entry.edx.into()),
+                ])
+            })
+            .collect(),
+    )
+    .serialize(serializer)
+}
+
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn serialize_msrs<S: Serializer>(msrs: &Msrs, serializer: S) -> Result<S> {
+    Value::Array(
+        msrs.as_slice()
+            .iter()
+            .map(|entry| {
+                object(vec![
+                    ("index", entry.index.into()),
+                    ("data", entry.data.into()),
+                ])
+            })
+            .collect(),
+    )
+    .serialize(serializer)
+}
+
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn serialize_debug_regs<S: Serializer>(
+    debug_regs: &kvm_debugregs,
+    serializer: S,
+) -> Result<S> {
+    object(vec![
+        ("db", Value::from(&debug_regs.db[..])),
+        ("dr6", debug_regs.dr6.into()),
+        ("dr7", debug_regs.dr7.into()),
+        ("flags", debug_regs.flags.into()),
+    ])
+    .serialize(serializer)
+}
+
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn serialize_lapic<S: Serializer>(lapic: &kvm_lapic_state, serializer: S) -> Result<S> {
+    // The registers page is declared as C characters, but holds raw bytes.
+    let regs: Vec<u8> = lapic.regs.iter().map(|&byte| byte as u8).collect();
+    object(vec![("regs", regs.into())]).serialize(serializer)
+}
+
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn serialize_regs<S: Serializer>(regs: &kvm_regs, serializer: S) -> Result<S> {
+    object(vec![
+        ("rax", regs.rax.into()),
+        ("rbx", regs.rbx.into()),
+        ("rcx", regs.rcx.into()),
+        ("rdx", regs.rdx.into()),
+        ("rsi", regs.rsi.into()),
+        ("rdi", regs.rdi.into()),
+        ("rsp", regs.rsp.into()),
+        ("rbp", regs.rbp.into()),
+        ("r8", regs.r8.into()),
+        ("r9", regs.r9.into()),
+        ("r10", regs.r10.into()),
+        ("r11", regs.r11.into()),
+        ("r12", regs.r12.into()),
+        ("r13", regs.r13.into()),
+        ("r14", regs.r14.into()),
+        ("r15", regs.r15.into()),
+        ("rip", regs.rip.into()),
+        ("rflags", regs.rflags.into()),
+    ])
+    .serialize(serializer)
+}
+
+#[cfg(target_arch = "x86_64")]
+fn segment_to_json(segment: &kvm_segment) -> Value {
+    object(vec![
+        ("base", segment.base.into()),
+        ("limit", segment.limit.into()),
+        ("selector", segment.selector.into()),
+        ("type", segment.type_.into()),
+        ("present", segment.present.into()),
+        ("dpl", segment.dpl.into()),
+        ("db", segment.db.into()),
+        ("s", segment.s.into()),
+        ("l", segment.l.into()),
+        ("g", segment.g.into()),
+        ("avl", segment.avl.into()),
+        ("unusable", segment.unusable.into()),
+    ])
+}
+
+#[cfg(target_arch = "x86_64")]
+fn dtable_to_json(dtable: &kvm_dtable) -> Value {
+    object(vec![
+        ("base", dtable.base.into()),
+        ("limit", dtable.limit.into()),
+    ])
+}
+
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn serialize_sregs<S: Serializer>(sregs: &kvm_sregs, serializer: S) -> Result<S> {
+    object(vec![
+        ("cs", segment_to_json(&sregs.cs)),
+        ("ds", segment_to_json(&sregs.ds)),
+        ("es", segment_to_json(&sregs.es)),
+        ("fs", segment_to_json(&sregs.fs)),
+        ("gs", segment_to_json(&sregs.gs)),
+        ("ss", segment_to_json(&sregs.ss)),
+        ("tr", segment_to_json(&sregs.tr)),
+        ("ldt", segment_to_json(&sregs.ldt)),
+        ("gdt", dtable_to_json(&sregs.gdt)),
+        ("idt", dtable_to_json(&sregs.idt)),
+        ("cr0", sregs.cr0.into()),
+        ("cr2", sregs.cr2.into()),
+        ("cr3", sregs.cr3.into()),
+        ("cr4", sregs.cr4.into()),
+        ("cr8", sregs.cr8.into()),
+        ("efer", sregs.efer.into()),
+        ("apic_base", sregs.apic_base.into()),
+        ("interrupt_bitmap", Value::from(&sregs.interrupt_bitmap[..])),
+    ])
+    .serialize(serializer)
+}
+
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn serialize_vcpu_events<S: Serializer>(
+    events: &kvm_vcpu_events,
+    serializer: S,
+) -> Result<S> {
+    object(vec![
+        (
+            "exception",
+            object(vec![
+                ("injected", events.exception.injected.into()),
+                ("nr", events.exception.nr.into()),
+                ("has_error_code", events.exception.has_error_code.into()),
+                ("pending", events.exception.pending.into()),
+                ("error_code", events.exception.error_code.into()),
+            ]),
+        ),
+        (
+            "interrupt",
+            object(vec![
+                ("injected", events.interrupt.injected.into()),
+                ("nr", events.interrupt.nr.into()),
+                ("soft", events.interrupt.soft.into()),
+                ("shadow", events.interrupt.shadow.into()),
+            ]),
+        ),
+        (
+            "nmi",
+            object(vec![
+                ("injected", events.nmi.injected.into()),
+                ("pending", events.nmi.pending.into()),
+                ("masked", events.nmi.masked.into()),
+            ]),
+        ),
+        ("sipi_vector", events.sipi_vector.into()),
+        ("flags", events.flags.into()),
+        (
+            "smi",
+            object(vec![
+                ("smm", events.smi.smm.into()),
+                ("pending", events.smi.pending.into()),
+                ("smm_inside_nmi", events.smi.smm_inside_nmi.into()),
+                ("latched_init", events.smi.latched_init.into()),
+            ]),
+        ),
+        ("exception_has_payload", events.exception_has_payload.into()),
+        ("exception_payload", events.exception_payload.into()),
+    ])
+    .serialize(serializer)
+}
+
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn serialize_xcrs<S: Serializer>(xcrs: &kvm_xcrs, serializer: S) -> Result<S> {
+    // Only the first `nr_xcrs` entries are used.
+    let used_xcrs = std::cmp::min(xcrs.nr_xcrs as usize, xcrs.xcrs.len());
+    let entries = xcrs.xcrs[..used_xcrs]
+        .iter()
+        .map(|xcr| object(vec![("xcr", xcr.xcr.into()), ("value", xcr.value.into())]))
+        .collect();
+    object(vec![
+        ("nr_xcrs", xcrs.nr_xcrs.into()),
+        ("flags", xcrs.flags.into()),
+        ("xcrs", Value::Array(entries)),
+    ])
+    .serialize(serializer)
+}
+
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn serialize_xsave<S: Serializer>(xsave: &kvm_xsave, serializer: S) -> Result<S> {
+    object(vec![("region", Value::from(&xsave.region[..]))]).serialize(serializer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_serialize_irqchip() {
+        let mut irqchip = kvm_irqchip {
+            chip_id: KVM_IRQCHIP_IOAPIC,
+            ..Default::default()
+        };
+        let mut ioapic = kvm_ioapic_state {
+            base_address: 0xfec0_0000,
+            ..Default::default()
+        };
+        // Vector 0x30, level triggered, masked, sent to the APIC 1.
+        ioapic.redirtbl[2].bits = 0x0100_0000_0001_8030;
+        irqchip.chip.ioapic = ioapic;
+
+        let value = serialize_irqchip(&irqchip, serde_json::value::Serializer).unwrap();
+        assert_eq!(value["chip_id"], json!(KVM_IRQCHIP_IOAPIC));
+        assert_eq!(value["ioapic"]["base_address"], json!(0xfec0_0000u64));
+        let entry = &value["ioapic"]["redirtbl"][2];
+        assert_eq!(entry["vector"], json!(0x30));
+        assert_eq!(entry["trig_mode"], json!(1));
+        assert_eq!(entry["mask"], json!(1));
+        assert_eq!(entry["dest_id"], json!(1));
+        assert_eq!(entry["delivery_mode"], json!(0));
+
+        let mut irqchip = kvm_irqchip {
+            chip_id: KVM_IRQCHIP_PIC_SLAVE,
+            ..Default::default()
+        };
+        irqchip.chip.pic = kvm_pic_state {
+            irq_base: 0x28,
+            ..Default::default()
+        };
+        let value = serialize_irqchip(&irqchip, serde_json::value::Serializer).unwrap();
+        assert_eq!(value["pic"]["irq_base"], json!(0x28));
+        assert!(value.get("ioapic").is_none());
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_serialize_xcrs() {
+        let mut xcrs = kvm_xcrs {
+            nr_xcrs: 1,
+            ..Default::default()
+        };
+        xcrs.xcrs[0].value = 7;
+        xcrs.xcrs[1].value = 3;
+
+        let value = serialize_xcrs(&xcrs, serde_json::value::Serializer).unwrap();
+        assert_eq!(value["xcrs"], json!([{"xcr": 0, "value": 7}]));
+    }
+
+    #[test]
+    fn test_serialize_mp_state() {
+        let mp_state = kvm_mp_state { mp_state: 3 };
+        assert_eq!(
+            serialize_mp_state(&mp_state, serde_json::value::Serializer).unwrap(),
+            json!({"mp_state": 3})
+        );
+    }
+}
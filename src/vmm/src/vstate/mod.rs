@@ -1,6 +1,7 @@
 // Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
 
+pub(crate) mod kvm_serialize;
 pub(crate) mod system;
 pub(crate) mod vcpu;
 pub(crate) mod vm;
@@ -10,9 +10,10 @@ use std::{
     result,
 };
 
-use crate::vstate::{vcpu::VcpuEmulation, vm::Vm};
+use crate::vstate::{kvm_serialize, vcpu::VcpuEmulation, vm::Vm};
 use kvm_ioctls::*;
 use logger::{error, IncMetric, METRICS};
+use serde::Serialize;
 use versionize::{VersionMap, Versionize, VersionizeResult};
 use versionize_derive::Versionize;
 use vm_memory::{Address, GuestAddress, GuestMemoryMmap};
@@ -170,9 +171,11 @@ impl KvmVcpu {
 }
 
 /// Structure holding VCPU kvm state.
-#[derive(Clone, Default, Versionize)]
+#[derive(Clone, Debug, Default, Serialize, Versionize)]
 pub struct VcpuState {
+    #[serde(serialize_with = "kvm_serialize::serialize_mp_state")]
     pub mp_state: kvm_bindings::kvm_mp_state,
+    #[serde(serialize_with = "kvm_serialize::serialize_one_regs")]
     pub regs: Vec<kvm_bindings::kvm_one_reg>,
     // We will be using the mpidr for passing it to the VmState.
     // The VmState will give this away for saving restoring the icc and redistributor
@@ -22,6 +22,8 @@ use crate::{
     vmm_config::machine_config::CpuFeaturesTemplate, vstate::vm::Vm, FC_EXIT_CODE_GENERIC_ERROR,
     FC_EXIT_CODE_OK,
 };
+#[cfg(target_arch = "x86_64")]
+use cpuid::custom::CustomCpuTemplate;
 use kvm_bindings::{KVM_SYSTEM_EVENT_RESET, KVM_SYSTEM_EVENT_SHUTDOWN};
 use kvm_ioctls::VcpuExit;
 use logger::{error, info, IncMetric, METRICS};
@@ -89,10 +91,15 @@ pub type Result<T> = result::Result<T, Error>;
 pub struct VcpuConfig {
     /// Number of guest VCPUs.
     pub vcpu_count: u8,
+    /// Maximum number of guest VCPUs, including the ones that can be hot-added after boot.
+    pub max_vcpu_count: u8,
     /// Enable hyperthreading in the CPUID configuration.
     pub ht_enabled: bool,
     /// CPUID template to use.
     pub cpu_template: Option<CpuFeaturesTemplate>,
+    /// User-defined CPU template, applied after `cpu_template`.
+    #[cfg(target_arch = "x86_64")]
+    pub custom_cpu_template: Option<CustomCpuTemplate>,
 }
 
 // Using this for easier explicit type-casting to help IDEs interpret the code.
@@ -902,8 +909,10 @@ mod tests {
         {
             let vcpu_config = VcpuConfig {
                 vcpu_count: 1,
+                max_vcpu_count: 1,
                 ht_enabled: false,
                 cpu_template: None,
+                custom_cpu_template: None,
             };
             vcpu.kvm_vcpu
                 .configure(
@@ -6,22 +6,25 @@
 // found in the THIRD-PARTY file.
 
 use std::{
-    fmt::{Display, Formatter},
+    fmt::{Debug, Display, Formatter},
     result,
 };
 
 use crate::vmm_config::machine_config::CpuFeaturesTemplate;
 use crate::vstate::{
+    kvm_serialize,
     vcpu::{VcpuConfig, VcpuEmulation},
     vm::Vm,
 };
-use cpuid::{c3, filter_cpuid, t2, VmSpec};
+use cpuid::custom::{self, MsrModifier};
+use cpuid::{c3, filter_cpuid, t2, zen1, VmSpec};
 use kvm_bindings::{
-    kvm_debugregs, kvm_lapic_state, kvm_mp_state, kvm_regs, kvm_sregs, kvm_vcpu_events, kvm_xcrs,
-    kvm_xsave, CpuId, MsrList, Msrs,
+    kvm_debugregs, kvm_lapic_state, kvm_mp_state, kvm_msr_entry, kvm_regs, kvm_sregs,
+    kvm_vcpu_events, kvm_xcrs, kvm_xsave, CpuId, MsrList, Msrs,
 };
 use kvm_ioctls::{VcpuExit, VcpuFd};
 use logger::{error, warn, IncMetric, METRICS};
+use serde::Serialize;
 use versionize::{VersionMap, Versionize, VersionizeResult};
 use versionize_derive::Versionize;
 use vm_memory::{Address, GuestAddress, GuestMemoryMmap};
@@ -47,6 +50,8 @@ pub enum Error {
     MSRSConfiguration(arch::x86_64::msr::Error),
     /// Error configuring the general purpose registers
     REGSConfiguration(arch::x86_64::regs::Error),
+    /// The MSR set by the custom CPU template is not supported.
+    UnsupportedMsr(u32),
     /// Error configuring the special registers
     SREGSConfiguration(arch::x86_64::regs::Error),
     /// Cannot open the VCPU file descriptor.
@@ -85,6 +90,8 @@ pub enum Error {
     VcpuSetMpState(kvm_ioctls::Error),
     /// Failed to set KVM vcpu msrs.
     VcpuSetMsrs(kvm_ioctls::Error),
+    /// The number of MSRS set by the kernel is unexpected.
+    VcpuSetMSRSIncomplete,
     /// Failed to set KVM vcpu regs.
     VcpuSetRegs(kvm_ioctls::Error),
     /// Failed to set KVM vcpu sregs.
@@ -118,6 +125,11 @@ impl Display for Error {
                 e
             ),
             SREGSConfiguration(e) => write!(f, "Error configuring the special registers: {:?}", e),
+            UnsupportedMsr(index) => write!(
+                f,
+                "The MSR {:#x} set by the custom CPU template is not supported",
+                index
+            ),
             FamError(e) => write!(f, "Failed FamStructWrapper operation: {:?}", e),
             FPUConfiguration(e) => write!(
                 f,
@@ -141,6 +153,7 @@ impl Display for Error {
             VcpuSetLapic(e) => write!(f, "Failed to set KVM vcpu lapic: {}", e),
             VcpuSetMpState(e) => write!(f, "Failed to set KVM vcpu mp state: {}", e),
             VcpuSetMsrs(e) => write!(f, "Failed to set KVM vcpu msrs: {}", e),
+            VcpuSetMSRSIncomplete => write!(f, "Unexpected number of MSRS set by the kernel"),
             VcpuSetRegs(e) => write!(f, "Failed to set KVM vcpu regs: {}", e),
             VcpuSetSregs(e) => write!(f, "Failed to set KVM vcpu sregs: {}", e),
             VcpuSetVcpuEvents(e) => write!(f, "Failed to set KVM vcpu event: {}", e),
@@ -196,34 +209,15 @@ impl KvmVcpu {
         guest_mem: &GuestMemoryMmap,
         kernel_start_addr: GuestAddress,
         vcpu_config: &VcpuConfig,
-        mut cpuid: CpuId,
+        cpuid: CpuId,
     ) -> Result<()> {
-        let cpuid_vm_spec = VmSpec::new(self.index, vcpu_config.vcpu_count, vcpu_config.ht_enabled)
-            .map_err(Error::CpuId)?;
-
-        filter_cpuid(&mut cpuid, &cpuid_vm_spec).map_err(|e| {
-            METRICS.vcpu.filter_cpuid.inc();
-            error!(
-                "Failure in configuring CPUID for vcpu {}: {:?}",
-                self.index, e
-            );
-            Error::CpuId(e)
-        })?;
-
-        if let Some(template) = vcpu_config.cpu_template {
-            match template {
-                CpuFeaturesTemplate::T2 => {
-                    t2::set_cpuid_entries(&mut cpuid, &cpuid_vm_spec).map_err(Error::CpuId)?
-                }
-                CpuFeaturesTemplate::C3 => {
-                    c3::set_cpuid_entries(&mut cpuid, &cpuid_vm_spec).map_err(Error::CpuId)?
-                }
-            }
-        }
-
+        let cpuid = build_vcpu_cpuid(self.index, vcpu_config, cpuid)?;
         self.fd.set_cpuid2(&cpuid).map_err(Error::VcpuSetCpuid)?;
 
         arch::x86_64::msr::setup_msrs(&self.fd).map_err(Error::MSRSConfiguration)?;
+        if let Some(template) = &vcpu_config.custom_cpu_template {
+            self.set_msr_modifiers(&template.msr_modifiers)?;
+        }
         arch::x86_64::regs::setup_regs(&self.fd, kernel_start_addr.raw_value() as u64)
             .map_err(Error::REGSConfiguration)?;
         arch::x86_64::regs::setup_fpu(&self.fd).map_err(Error::FPUConfiguration)?;
@@ -232,6 +226,17 @@ impl KvmVcpu {
         Ok(())
     }
 
+    /// Overrides the MSRs set by a custom CPU template.
+    fn set_msr_modifiers(&self, modifiers: &[MsrModifier]) -> Result<()> {
+        let entries = msr_modifier_entries(modifiers, &self.msr_list)?;
+        let msrs = Msrs::from_entries(&entries).map_err(Error::FamError)?;
+        let nmsrs = self.fd.set_msrs(&msrs).map_err(Error::VcpuSetMsrs)?;
+        if nmsrs != entries.len() {
+            return Err(Error::VcpuSetMSRSIncomplete);
+        }
+        Ok(())
+    }
+
     /// Sets a Port Mapped IO bus for this vcpu.
     pub fn set_pio_bus(&mut self, pio_bus: devices::Bus) {
         self.pio_bus = Some(pio_bus);
@@ -424,24 +429,126 @@ impl KvmVcpu {
     }
 }
 
-#[derive(Clone, Versionize)]
+/// Builds the CPUID of the vCPU `cpu_index` from `supported_cpuid`, the CPUID supported by
+/// KVM, by normalizing it and applying the CPU templates of `vcpu_config`.
+pub fn build_vcpu_cpuid(
+    cpu_index: u8,
+    vcpu_config: &VcpuConfig,
+    supported_cpuid: CpuId,
+) -> Result<CpuId> {
+    // The topology accounts for the vCPUs that can be hot-added, so that it doesn't change
+    // when they are.
+    let cpuid_vm_spec = VmSpec::new(
+        cpu_index,
+        vcpu_config.max_vcpu_count,
+        vcpu_config.ht_enabled,
+    )
+    .map_err(Error::CpuId)?;
+
+    let mut cpuid = supported_cpuid.clone();
+    filter_cpuid(&mut cpuid, &cpuid_vm_spec).map_err(|e| {
+        METRICS.vcpu.filter_cpuid.inc();
+        error!(
+            "Failure in configuring CPUID for vcpu {}: {:?}",
+            cpu_index, e
+        );
+        Error::CpuId(e)
+    })?;
+
+    if let Some(template) = vcpu_config.cpu_template {
+        match template {
+            CpuFeaturesTemplate::T2 => {
+                t2::set_cpuid_entries(&mut cpuid, &cpuid_vm_spec).map_err(Error::CpuId)?
+            }
+            CpuFeaturesTemplate::C3 => {
+                c3::set_cpuid_entries(&mut cpuid, &cpuid_vm_spec).map_err(Error::CpuId)?
+            }
+            CpuFeaturesTemplate::Zen1 => {
+                zen1::set_cpuid_entries(&mut cpuid, &cpuid_vm_spec).map_err(Error::CpuId)?
+            }
+        }
+    }
+
+    if let Some(template) = &vcpu_config.custom_cpu_template {
+        custom::set_cpuid_entries(
+            &mut cpuid,
+            &cpuid_vm_spec,
+            &template.cpuid_modifiers,
+            &supported_cpuid,
+        )
+        .map_err(Error::CpuId)?;
+    }
+
+    Ok(cpuid)
+}
+
+/// Returns the MSR entries set by a custom CPU template. Only the MSRs of `msr_list`, which
+/// are the ones saved in snapshots, can be set, so that a restored vCPU keeps the template.
+pub fn msr_modifier_entries(
+    modifiers: &[MsrModifier],
+    msr_list: &MsrList,
+) -> Result<Vec<kvm_msr_entry>> {
+    let mut entries = Vec::with_capacity(modifiers.len());
+    for modifier in modifiers.iter() {
+        if !msr_list.as_slice().contains(&modifier.index) {
+            return Err(Error::UnsupportedMsr(modifier.index));
+        }
+        entries.push(kvm_msr_entry {
+            index: modifier.index,
+            data: modifier.value,
+            ..Default::default()
+        });
+    }
+    Ok(entries)
+}
+
+#[derive(Clone, Serialize, Versionize)]
 /// Structure holding VCPU kvm state.
 // NOTICE: Any changes to this structure require a snapshot version bump.
 pub struct VcpuState {
+    #[serde(serialize_with = "kvm_serialize::serialize_cpuid")]
     pub cpuid: CpuId,
-    msrs: Msrs,
+    #[serde(serialize_with = "kvm_serialize::serialize_msrs")]
+    pub msrs: Msrs,
+    #[serde(serialize_with = "kvm_serialize::serialize_debug_regs")]
     debug_regs: kvm_debugregs,
+    #[serde(serialize_with = "kvm_serialize::serialize_lapic")]
     lapic: kvm_lapic_state,
+    #[serde(serialize_with = "kvm_serialize::serialize_mp_state")]
     mp_state: kvm_mp_state,
+    #[serde(serialize_with = "kvm_serialize::serialize_regs")]
     regs: kvm_regs,
+    #[serde(serialize_with = "kvm_serialize::serialize_sregs")]
     sregs: kvm_sregs,
+    #[serde(serialize_with = "kvm_serialize::serialize_vcpu_events")]
     vcpu_events: kvm_vcpu_events,
+    #[serde(serialize_with = "kvm_serialize::serialize_xcrs")]
     xcrs: kvm_xcrs,
+    #[serde(serialize_with = "kvm_serialize::serialize_xsave")]
     xsave: kvm_xsave,
     #[version(start = 2, default_fn = "default_tsc_khz", ser_fn = "ser_tsc")]
     pub tsc_khz: Option<u32>,
 }
 
+// The CPUID and MSR wrappers don't implement `Debug`, so their entries are printed instead.
+impl Debug for VcpuState {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        f.debug_struct("VcpuState")
+            .field("cpuid", &self.cpuid.as_slice())
+            .field("msrs", &self.msrs.as_slice())
+            .field("debug_regs", &self.debug_regs)
+            .field("lapic", &self.lapic)
+            .field("mp_state", &self.mp_state)
+            .field("regs", &self.regs)
+            .field("sregs", &self.sregs)
+            .field("vcpu_events", &self.vcpu_events)
+            .field("xcrs", &self.xcrs)
+            .field("xsave", &self.xsave)
+            .field("tsc_khz", &self.tsc_khz)
+            .finish()
+    }
+}
+
 impl VcpuState {
     fn default_tsc_khz(_: u16) -> Option<u32> {
         warn!("CPU TSC freq not found in snapshot");
@@ -470,7 +577,7 @@ mod tests {
 
     use super::*;
     use crate::vstate::vm::{tests::setup_vm, Vm};
-    use cpuid::common::{get_vendor_id_from_host, VENDOR_ID_INTEL};
+    use cpuid::common::{get_vendor_id_from_host, VENDOR_ID_AMD, VENDOR_ID_INTEL};
     use kvm_ioctls::Cap;
 
     impl Default for VcpuState {
@@ -504,8 +611,10 @@ mod tests {
 
         let mut vcpu_config = VcpuConfig {
             vcpu_count: 1,
+            max_vcpu_count: 1,
             ht_enabled: false,
             cpu_template: None,
+            custom_cpu_template: None,
         };
 
         assert!(vcpu
@@ -535,18 +644,118 @@ mod tests {
             vm.supported_cpuid().clone(),
         );
 
+        // Test configure while using the Zen1 template.
+        vcpu_config.cpu_template = Some(CpuFeaturesTemplate::Zen1);
+        let zen1_res = vcpu.configure(
+            &vm_mem,
+            GuestAddress(0),
+            &vcpu_config,
+            vm.supported_cpuid().clone(),
+        );
+
         match &get_vendor_id_from_host().unwrap() {
             VENDOR_ID_INTEL => {
                 assert!(t2_res.is_ok());
                 assert!(c3_res.is_ok());
+                assert!(zen1_res.is_err());
+            }
+            VENDOR_ID_AMD => {
+                assert!(t2_res.is_err());
+                assert!(c3_res.is_err());
+                assert!(zen1_res.is_ok());
             }
             _ => {
                 assert!(t2_res.is_err());
                 assert!(c3_res.is_err());
+                assert!(zen1_res.is_err());
             }
         }
     }
 
+    #[test]
+    fn test_configure_vcpu_custom_template() {
+        use cpuid::custom::{
+            CpuidLeafModifier, CpuidRegister, CpuidRegisterModifier, CustomCpuTemplate,
+        };
+
+        // MSR_IA32_SYSENTER_CS.
+        const MSR_INDEX: u32 = 0x174;
+
+        let (vm, mut vcpu, vm_mem) = setup_vcpu(0x10000);
+        let mut template = CustomCpuTemplate {
+            cpuid_modifiers: vec![CpuidLeafModifier {
+                leaf: 0x1,
+                subleaf: 0,
+                modifiers: vec![CpuidRegisterModifier {
+                    register: CpuidRegister::Ecx,
+                    // SSE3.
+                    bitmask: 0x1,
+                    value: 0,
+                }],
+            }],
+            msr_modifiers: vec![MsrModifier {
+                index: MSR_INDEX,
+                value: 0x10,
+            }],
+        };
+        let mut vcpu_config = VcpuConfig {
+            vcpu_count: 1,
+            max_vcpu_count: 1,
+            ht_enabled: false,
+            cpu_template: None,
+            custom_cpu_template: Some(template.clone()),
+        };
+
+        vcpu.configure(
+            &vm_mem,
+            GuestAddress(0),
+            &vcpu_config,
+            vm.supported_cpuid().clone(),
+        )
+        .unwrap();
+        let state = vcpu.save_state().unwrap();
+        let leaf = state
+            .cpuid
+            .as_slice()
+            .iter()
+            .find(|entry| entry.function == 0x1)
+            .unwrap();
+        assert_eq!(leaf.ecx & 0x1, 0);
+        let msr = state
+            .msrs
+            .as_slice()
+            .iter()
+            .find(|entry| entry.index == MSR_INDEX)
+            .unwrap();
+        assert_eq!(msr.data, 0x10);
+
+        // The host doesn't support the MSR.
+        template.msr_modifiers[0].index = 0xdead_beef;
+        vcpu_config.custom_cpu_template = Some(template.clone());
+        match vcpu.configure(
+            &vm_mem,
+            GuestAddress(0),
+            &vcpu_config,
+            vm.supported_cpuid().clone(),
+        ) {
+            Err(Error::UnsupportedMsr(0xdead_beef)) => (),
+            _ => panic!("Test failed."),
+        }
+
+        // The host doesn't support the leaf.
+        template.cpuid_modifiers[0].leaf = 0x4fff_ffff;
+        vcpu_config.custom_cpu_template = Some(template);
+        match vcpu.configure(
+            &vm_mem,
+            GuestAddress(0),
+            &vcpu_config,
+            vm.supported_cpuid().clone(),
+        ) {
+            Err(Error::CpuId(cpuid::Error::UnsupportedLeaf(0x4fff_ffff, 0))) => (),
+            _ => panic!("Test failed."),
+        }
+    }
+
     #[test]
     fn test_vcpu_cpuid_restore() {
         let (_vm, vcpu, _) = setup_vcpu(0x1000);
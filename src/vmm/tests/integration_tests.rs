@@ -1,22 +1,35 @@
 // Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
+use std::collections::HashMap;
 use std::io;
 use std::io::{Seek, SeekFrom};
+#[cfg(target_arch = "x86_64")]
+use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Duration;
 
+#[cfg(target_arch = "x86_64")]
+use logger::{IncMetric, METRICS};
 use snapshot::Snapshot;
 use utils::tempfile::TempFile;
 use vmm::builder::{build_microvm_for_boot, build_microvm_from_snapshot, setup_serial_device};
-use vmm::persist::{self, snapshot_state_sanity_check, LoadSnapshotError, MicrovmState};
+use vmm::persist::{
+    self, snapshot_state_sanity_check, CreateSnapshotError, LoadSnapshotError, MicrovmState,
+};
 use vmm::resources::VmResources;
 use vmm::seccomp_filters::{get_filters, SeccompConfig};
 use vmm::version_map::VERSION_MAP;
-use vmm::vmm_config::snapshot::{CreateSnapshotParams, SnapshotType};
+use vmm::vmm_config::snapshot::{
+    CreateSnapshotParams, LoadSnapshotParams, MemFileFormat, SnapshotDestinationType, SnapshotType,
+};
+#[cfg(target_arch = "x86_64")]
+use vmm::Vmm;
 use vmm::{EventManager, FC_EXIT_CODE_OK};
 
 use vmm::utilities::mock_devices::MockSerialInput;
-use vmm::utilities::mock_resources::{MockVmResources, NOISY_KERNEL_IMAGE};
+use vmm::utilities::mock_resources::{
+    MockBootSourceConfig, MockVmConfig, MockVmResources, NOISY_KERNEL_IMAGE,
+};
 #[cfg(target_arch = "x86_64")]
 use vmm::utilities::test_utils::dirty_tracking_vmm;
 use vmm::utilities::test_utils::{create_vmm, default_vmm};
@@ -84,6 +97,105 @@ fn test_pause_resume_microvm() {
     vmm.lock().unwrap().stop(FC_EXIT_CODE_OK);
 }
 
+#[test]
+fn test_hotplug_vcpus_bounds() {
+    // The default microVM has a single vCPU, and no room for more.
+    let (vmm, _) = default_vmm(None);
+
+    // Asking for the current vCPU count is a no-op.
+    assert!(vmm.lock().unwrap().hotplug_vcpus(1).is_ok());
+    assert_eq!(
+        format!("{:?}", vmm.lock().unwrap().hotplug_vcpus(0).err()),
+        "Some(VcpuRemoval(1, 0))"
+    );
+    assert_eq!(
+        format!("{:?}", vmm.lock().unwrap().hotplug_vcpus(2).err()),
+        "Some(TooManyVcpus(2, 1))"
+    );
+    vmm.lock().unwrap().stop(FC_EXIT_CODE_OK);
+}
+
+#[cfg(target_arch = "x86_64")]
+fn hotplug_vmm(vm_config: MockVmConfig) -> (Arc<Mutex<Vmm>>, EventManager) {
+    let mut event_manager = EventManager::new().unwrap();
+    let empty_seccomp_filters = get_filters(SeccompConfig::None).unwrap();
+    let boot_source_cfg = MockBootSourceConfig::new()
+        .with_default_boot_args()
+        .with_kernel(NOISY_KERNEL_IMAGE);
+    let resources: VmResources = MockVmResources::new()
+        .with_boot_source(boot_source_cfg.into())
+        .with_vm_config(vm_config.into())
+        .into();
+
+    let vmm = build_microvm_for_boot(
+        &InstanceInfo::default(),
+        &resources,
+        &mut event_manager,
+        &empty_seccomp_filters,
+    )
+    .unwrap();
+    (vmm, event_manager)
+}
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+fn test_hotplug_vcpus() {
+    let snapshot_file = TempFile::new().unwrap();
+    let memory_file = TempFile::new().unwrap();
+    let (vmm, _) = hotplug_vmm(MockVmConfig::new().with_max_vcpu_count(2));
+    let hotplug_count = METRICS.vcpu.hotplug_count.count();
+
+    // Hot-adding only succeeds once the new vCPU reports it runs.
+    vmm.lock().unwrap().hotplug_vcpus(2).unwrap();
+    assert_eq!(METRICS.vcpu.hotplug_count.count(), hotplug_count + 1);
+
+    // The hot-added vCPU is paused along with the boot one, since the state of a running
+    // vCPU can't be saved.
+    vmm.lock().unwrap().pause_vm().unwrap();
+    let snapshot_params = CreateSnapshotParams {
+        snapshot_type: SnapshotType::Full,
+        snapshot_path: snapshot_file.as_path().to_path_buf(),
+        mem_file_path: memory_file.as_path().to_path_buf(),
+        destination_type: SnapshotDestinationType::File,
+        mem_file_format: MemFileFormat::Raw,
+        version: None,
+        integrity_key: None,
+    };
+    {
+        let mut locked_vmm = vmm.lock().unwrap();
+        persist::create_snapshot(&mut locked_vmm, &snapshot_params, VERSION_MAP.clone()).unwrap();
+    }
+    vmm.lock().unwrap().stop(FC_EXIT_CODE_OK);
+
+    let snapshot_len = snapshot_file.as_file().metadata().unwrap().len() as usize;
+    let restored_microvm_state: MicrovmState = Snapshot::load(
+        &mut snapshot_file.as_file(),
+        snapshot_len,
+        VERSION_MAP.clone(),
+    )
+    .unwrap();
+    assert_eq!(restored_microvm_state.vcpu_states.len(), 2);
+    assert_eq!(restored_microvm_state.plugged_vcpu_count, Some(2));
+}
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+fn test_hotplug_vcpus_ht_enabled() {
+    let (vmm, _) = hotplug_vmm(
+        MockVmConfig::new()
+            .with_vcpu_count(2)
+            .with_max_vcpu_count(4)
+            .with_ht_enabled(),
+    );
+
+    // The vCPUs are hot-added in pairs.
+    assert_eq!(
+        format!("{:?}", vmm.lock().unwrap().hotplug_vcpus(3).err()),
+        "Some(InvalidVcpuCount(3))"
+    );
+    vmm.lock().unwrap().stop(FC_EXIT_CODE_OK);
+}
+
 #[test]
 fn test_dirty_bitmap_error() {
     // Error case: dirty tracking disabled.
@@ -122,6 +234,29 @@ fn test_dirty_bitmap_success() {
     vmm.lock().unwrap().stop(FC_EXIT_CODE_OK);
 }
 
+#[test]
+#[cfg(target_arch = "x86_64")]
+fn test_dirty_page_stats() {
+    let (vmm, _) = dirty_tracking_vmm(Some(NOISY_KERNEL_IMAGE));
+
+    // Let it dirty some pages, then pause it so that no more pages are dirtied.
+    thread::sleep(Duration::from_millis(100));
+    vmm.lock().unwrap().pause_vm().unwrap();
+    let count_dirty_pages = |reset: bool| -> u64 {
+        let stats = vmm.lock().unwrap().dirty_page_stats(reset).unwrap();
+        assert!(!stats.is_empty());
+        stats.iter().map(|region| region.dirty_pages).sum()
+    };
+
+    let dirty_pages = count_dirty_pages(false);
+    assert!(dirty_pages > 0);
+    // The log is kept, so the same pages are counted again before being reset.
+    assert_eq!(count_dirty_pages(true), dirty_pages);
+    assert_eq!(count_dirty_pages(false), 0);
+
+    vmm.lock().unwrap().stop(FC_EXIT_CODE_OK);
+}
+
 #[test]
 fn test_disallow_snapshots_without_pausing() {
     let (vmm, _) = default_vmm(Some(NOISY_KERNEL_IMAGE));
@@ -162,7 +297,10 @@ fn verify_create_snapshot(is_diff: bool) -> (TempFile, TempFile) {
         snapshot_type,
         snapshot_path: snapshot_file.as_path().to_path_buf(),
         mem_file_path: memory_file.as_path().to_path_buf(),
+        destination_type: SnapshotDestinationType::File,
+        mem_file_format: MemFileFormat::Raw,
         version: Some(String::from("0.24.0")),
+        integrity_key: None,
     };
 
     {
@@ -227,6 +365,8 @@ fn verify_load_snapshot(snapshot_file: TempFile, memory_file: TempFile) {
         microvm_state,
         mem,
         false,
+        &[],
+        &HashMap::new(),
         &mut empty_seccomp_filters,
     )
     .unwrap();
@@ -253,6 +393,228 @@ fn test_create_and_load_snapshot() {
     verify_load_snapshot(snapshot_file, memory_file);
 }
 
+#[test]
+fn test_snapshot_integrity() {
+    use std::os::unix::fs::FileExt;
+
+    const KEY: &str = "000102030405060708090a0b0c0d0e0f";
+
+    let snapshot_file = TempFile::new().unwrap();
+    let memory_file = TempFile::new().unwrap();
+    let (vmm, _) = create_vmm(Some(NOISY_KERNEL_IMAGE), false);
+    thread::sleep(Duration::from_millis(200));
+    vmm.lock().unwrap().pause_vm().unwrap();
+
+    let snapshot_params = CreateSnapshotParams {
+        snapshot_type: SnapshotType::Full,
+        snapshot_path: snapshot_file.as_path().to_path_buf(),
+        mem_file_path: memory_file.as_path().to_path_buf(),
+        destination_type: SnapshotDestinationType::File,
+        mem_file_format: MemFileFormat::Raw,
+        version: None,
+        integrity_key: Some(String::from(KEY)),
+    };
+    {
+        let mut locked_vmm = vmm.lock().unwrap();
+        persist::create_snapshot(&mut locked_vmm, &snapshot_params, VERSION_MAP.clone()).unwrap();
+    }
+    vmm.lock().unwrap().stop(FC_EXIT_CODE_OK);
+
+    let load = |integrity_key: Option<&str>| {
+        let mut event_manager = EventManager::new().unwrap();
+        let empty_seccomp_filters = get_filters(SeccompConfig::None).unwrap();
+        let load_params = LoadSnapshotParams {
+            snapshot_path: snapshot_file.as_path().to_path_buf(),
+            mem_file_path: Some(memory_file.as_path().to_path_buf()),
+            mem_backend: None,
+            enable_diff_snapshots: false,
+            resume_vm: false,
+            network_overrides: vec![],
+            drive_path_overrides: HashMap::new(),
+            integrity_key: integrity_key.map(String::from),
+        };
+        persist::restore_from_snapshot(
+            &InstanceInfo::default(),
+            &mut event_manager,
+            &empty_seccomp_filters,
+            &load_params,
+            VERSION_MAP.clone(),
+        )
+        .map(|vmm| vmm.lock().unwrap().stop(FC_EXIT_CODE_OK))
+    };
+
+    // A different key doesn't match the tag.
+    match load(Some("000102030405060708090a0b0c0d0e0e")) {
+        Err(LoadSnapshotError::IntegrityCheck) => (),
+        res => panic!("Unexpected result: {:?}", res),
+    }
+    match load(Some("not hex")) {
+        Err(LoadSnapshotError::InvalidIntegrityKey) => (),
+        res => panic!("Unexpected result: {:?}", res),
+    }
+    // The tags are stored apart, so the snapshot can still be loaded without the key.
+    load(None).unwrap();
+    load(Some(KEY)).unwrap();
+
+    // Any change to the state file is detected before it is deserialized.
+    let mut byte = [0u8; 1];
+    let state_offset = snapshot_file.as_file().metadata().unwrap().len() / 2;
+    snapshot_file
+        .as_file()
+        .read_exact_at(&mut byte, state_offset)
+        .unwrap();
+    snapshot_file
+        .as_file()
+        .write_all_at(&[byte[0] ^ 1], state_offset)
+        .unwrap();
+    match load(Some(KEY)) {
+        Err(LoadSnapshotError::IntegrityCheck) => (),
+        res => panic!("Unexpected result: {:?}", res),
+    }
+    snapshot_file
+        .as_file()
+        .write_all_at(&byte, state_offset)
+        .unwrap();
+
+    // The tags are required with the key.
+    let tag_path = persist::integrity_tag_path(snapshot_file.as_path());
+    let tags = std::fs::read(&tag_path).unwrap();
+    std::fs::remove_file(&tag_path).unwrap();
+    match load(Some(KEY)) {
+        Err(LoadSnapshotError::SnapshotBackingFile(_, _)) => (),
+        res => panic!("Unexpected result: {:?}", res),
+    }
+    std::fs::write(&tag_path, &tags).unwrap();
+    load(Some(KEY)).unwrap();
+
+    // Any change to the memory file is detected.
+    memory_file
+        .as_file()
+        .read_exact_at(&mut byte, 4096)
+        .unwrap();
+    memory_file
+        .as_file()
+        .write_all_at(&[byte[0] ^ 1], 4096)
+        .unwrap();
+    match load(Some(KEY)) {
+        Err(LoadSnapshotError::IntegrityCheck) => (),
+        res => panic!("Unexpected result: {:?}", res),
+    }
+    memory_file.as_file().write_all_at(&byte, 4096).unwrap();
+    load(Some(KEY)).unwrap();
+
+    // So is data appended past the guest memory.
+    let mem_file_len = memory_file.as_file().metadata().unwrap().len();
+    memory_file
+        .as_file()
+        .write_all_at(&[0u8], mem_file_len)
+        .unwrap();
+    match load(Some(KEY)) {
+        Err(LoadSnapshotError::IntegrityCheck) => (),
+        res => panic!("Unexpected result: {:?}", res),
+    }
+    std::fs::remove_file(&tag_path).unwrap();
+}
+
+#[test]
+fn test_snapshot_huge_pages() {
+    let mut event_manager = EventManager::new().unwrap();
+    let empty_seccomp_filters = get_filters(SeccompConfig::None).unwrap();
+    let resources: VmResources = MockVmResources::new()
+        .with_boot_source(MockBootSourceConfig::new().with_default_boot_args().into())
+        .with_vm_config(
+            MockVmConfig::new()
+                .with_dirty_page_tracking()
+                .with_huge_pages()
+                .into(),
+        )
+        .into();
+    // The host may not have enough huge pages reserved.
+    let vmm = match build_microvm_for_boot(
+        &InstanceInfo::default(),
+        &resources,
+        &mut event_manager,
+        &empty_seccomp_filters,
+    ) {
+        Ok(vmm) => vmm,
+        Err(_) => return,
+    };
+    assert!(vmm.lock().unwrap().is_hugetlb_backed());
+    vmm.lock().unwrap().pause_vm().unwrap();
+
+    let snapshot_file = TempFile::new().unwrap();
+    let memory_file = TempFile::new().unwrap();
+    for snapshot_type in vec![SnapshotType::Full, SnapshotType::Diff] {
+        let snapshot_params = CreateSnapshotParams {
+            snapshot_type,
+            snapshot_path: snapshot_file.as_path().to_path_buf(),
+            mem_file_path: memory_file.as_path().to_path_buf(),
+            destination_type: SnapshotDestinationType::File,
+            mem_file_format: MemFileFormat::Raw,
+            version: None,
+            integrity_key: None,
+        };
+        let mut locked_vmm = vmm.lock().unwrap();
+        assert!(matches!(
+            persist::create_snapshot(&mut locked_vmm, &snapshot_params, VERSION_MAP.clone()),
+            Err(CreateSnapshotError::HugePages)
+        ));
+    }
+    // Nothing was written.
+    assert_eq!(memory_file.as_file().metadata().unwrap().len(), 0);
+
+    vmm.lock().unwrap().stop(FC_EXIT_CODE_OK);
+}
+
+#[test]
+fn test_stream_snapshot() {
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixListener;
+
+    let socket_dir = utils::tempdir::TempDir::new().unwrap();
+    let state_socket_path = socket_dir.as_path().join("state.sock");
+    let memory_socket_path = socket_dir.as_path().join("mem.sock");
+    // Each socket is drained into a file by its own receiver.
+    let receive = |socket_path: &std::path::Path| {
+        let listener = UnixListener::bind(socket_path).unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut content = Vec::new();
+            stream.read_to_end(&mut content).unwrap();
+            let file = TempFile::new().unwrap();
+            file.as_file().write_all(&content).unwrap();
+            file
+        })
+    };
+    let state_receiver = receive(&state_socket_path);
+    let memory_receiver = receive(&memory_socket_path);
+
+    let (vmm, _) = create_vmm(Some(NOISY_KERNEL_IMAGE), false);
+    thread::sleep(Duration::from_millis(200));
+    vmm.lock().unwrap().pause_vm().unwrap();
+
+    let snapshot_params = CreateSnapshotParams {
+        snapshot_type: SnapshotType::Full,
+        snapshot_path: state_socket_path,
+        mem_file_path: memory_socket_path,
+        destination_type: SnapshotDestinationType::UnixSocket,
+        mem_file_format: MemFileFormat::Raw,
+        version: None,
+        integrity_key: None,
+    };
+    {
+        let mut locked_vmm = vmm.lock().unwrap();
+        persist::create_snapshot(&mut locked_vmm, &snapshot_params, VERSION_MAP.clone()).unwrap();
+    }
+    vmm.lock().unwrap().stop(FC_EXIT_CODE_OK);
+
+    // The streamed snapshot can be loaded once saved to files.
+    verify_load_snapshot(
+        state_receiver.join().unwrap(),
+        memory_receiver.join().unwrap(),
+    );
+}
+
 #[test]
 fn test_snapshot_load_sanity_checks() {
     use vmm::vmm_config::machine_config::MAX_SUPPORTED_VCPUS;
@@ -330,6 +692,30 @@ fn test_snapshot_cpu_vendor() {
     assert!(validate_cpu_vendor(&microvm_state).is_ok());
 }
 
+#[cfg(target_arch = "x86_64")]
+#[test]
+fn test_snapshot_cpu_features() {
+    use vmm::persist::{validate_cpu_features, MissingCpuFeature};
+    let mut microvm_state = get_microvm_state_from_snapshot();
+
+    // The snapshot was created locally, so the host supports all its features.
+    assert!(validate_cpu_features(&microvm_state).is_ok());
+
+    // KVM never reports all the bits of the leaf 0x7 EBX, some of them being reserved.
+    for entry in microvm_state.vcpu_states[0].cpuid.as_mut_slice() {
+        if entry.function == 0x7 && entry.index == 0 {
+            entry.ebx = u32::MAX;
+        }
+    }
+    match validate_cpu_features(&microvm_state) {
+        Err(LoadSnapshotError::MissingCpuFeatures(features)) => match &features[..] {
+            [MissingCpuFeature::Cpuid(bits)] => assert_eq!((bits.leaf, bits.subleaf), (0x7, 0)),
+            _ => panic!("Test failed."),
+        },
+        _ => panic!("Test failed."),
+    }
+}
+
 #[cfg(target_arch = "x86_64")]
 #[test]
 fn test_snapshot_cpu_vendor_mismatch() {
@@ -396,12 +396,16 @@ class SnapshotLoad():
         )
 
     @staticmethod
-    def create_json(mem_file_path, snapshot_path, diff=False, resume=False):
+    def create_json(snapshot_path, mem_file_path=None, diff=False,
+                    resume=False, mem_backend=None):
         """Compose the json associated to this type of API request."""
         datax = {
-            'mem_file_path': mem_file_path,
             'snapshot_path': snapshot_path,
         }
+        if mem_backend is not None:
+            datax['mem_backend'] = mem_backend
+        else:
+            datax['mem_file_path'] = mem_file_path
         if diff:
             datax['enable_diff_snapshots'] = True
         if resume:
@@ -409,15 +413,40 @@ class SnapshotLoad():
         return datax
 
 
+class SnapshotCheck():
+    """Facility for sending check snapshot commands on the microvm."""
+
+    SNAPSHOT_CHECK_URL = 'snapshot/check'
+
+    def __init__(self, api_usocket_full_name, api_session):
+        """Specify the information needed for sending API requests."""
+        url_encoded_path = urllib.parse.quote_plus(api_usocket_full_name)
+        api_url = API_USOCKET_URL_PREFIX + url_encoded_path + '/'
+        self._snapshot_cfg_url = api_url + self.SNAPSHOT_CHECK_URL
+        self._api_session = api_session
+
+    def put(self, snapshot_path):
+        """Check that a snapshot can be loaded on the host."""
+        return self._api_session.put(
+            "{}".format(self._snapshot_cfg_url),
+            json={'snapshot_path': snapshot_path}
+        )
+
+
 class SnapshotHelper():
     """Facility for creation and loading of microvm snapshots."""
 
     def __init__(self, api_usocket_full_name, api_session):
         """Specify the information needed for sending API requests."""
+        self._check = SnapshotCheck(api_usocket_full_name, api_session)
         self._create = SnapshotCreate(api_usocket_full_name, api_session)
         self._load = SnapshotLoad(api_usocket_full_name, api_session)
         self._vm_state = Vm(api_usocket_full_name, api_session)
 
+    def check(self, snapshot_path):
+        """Check that a snapshot of the microvm can be loaded on the host."""
+        return self._check.put(snapshot_path=snapshot_path)
+
     def create(self, mem_file_path, snapshot_path, diff=False, version=None):
         """Create a snapshot of the microvm."""
         return self._create.put(
@@ -427,13 +456,15 @@ class SnapshotHelper():
             version=version
         )
 
-    def load(self, mem_file_path, snapshot_path, diff=False, resume=False):
+    def load(self, snapshot_path, mem_file_path=None, diff=False,
+             resume=False, mem_backend=None):
         """Load a snapshot of the microvm."""
         response = self._load.put(
             mem_file_path=mem_file_path,
             snapshot_path=snapshot_path,
             diff=diff,
-            resume=resume
+            resume=resume,
+            mem_backend=mem_backend
         )
 
         if resume and "unknown field `resume_vm`" in response.text:
@@ -442,7 +473,8 @@ class SnapshotHelper():
                 mem_file_path=mem_file_path,
                 snapshot_path=snapshot_path,
                 diff=diff,
-                resume=False
+                resume=False,
+                mem_backend=mem_backend
             )
             if response.status_code != 204:
                 return response
@@ -541,12 +573,19 @@ class MachineConfigure():
             mem_size_mib=None,
             ht_enabled=None,
             cpu_template=None,
-            track_dirty_pages=None):
+            custom_cpu_template_path=None,
+            track_dirty_pages=None,
+            huge_pages=None,
+            shared_memory=None,
+            max_vcpu_count=None):
         """Compose the json associated to this type of API request."""
         datax = {}
         if vcpu_count is not None:
             datax['vcpu_count'] = vcpu_count
 
+        if max_vcpu_count is not None:
+            datax['max_vcpu_count'] = max_vcpu_count
+
         if mem_size_mib is not None:
             datax['mem_size_mib'] = mem_size_mib
 
@@ -556,9 +595,18 @@ class MachineConfigure():
         if cpu_template is not None:
             datax['cpu_template'] = cpu_template
 
+        if custom_cpu_template_path is not None:
+            datax['custom_cpu_template_path'] = custom_cpu_template_path
+
         if track_dirty_pages is not None:
             datax['track_dirty_pages'] = track_dirty_pages
 
+        if huge_pages is not None:
+            datax['huge_pages'] = huge_pages
+
+        if shared_memory is not None:
+            datax['shared_memory'] = shared_memory
+
         return datax
 
 
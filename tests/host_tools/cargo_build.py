@@ -131,3 +131,20 @@ def run_seccompiler_bin(bpf_path,
     rc, _, _ = utils.run_cmd(cmd)
 
     assert rc == 0
+
+
+def get_example_binary(example):
+    """Build a Firecracker example and return the location of its binary."""
+    target = DEFAULT_BUILD_TARGET
+    cargo_build(
+        FC_WORKSPACE_TARGET_DIR,
+        extra_args='-p firecracker --release --target {} --example {}'.format(
+            target, example
+        ),
+        src_dir=FC_WORKSPACE_DIR,
+        extra_env='RUSTFLAGS="{}"'.format(get_rustflags())
+    )
+
+    return "{target_dir}/{target}/release/examples/{example}".format(
+        target_dir=FC_WORKSPACE_TARGET_DIR, target=target, example=example
+    )
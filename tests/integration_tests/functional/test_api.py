@@ -1065,7 +1065,9 @@ def test_get_full_config(test_microvm_with_ssh_and_balloon):
         'vcpu_count': 2,
         'mem_size_mib': 256,
         'ht_enabled': False,
-        'track_dirty_pages': False
+        'track_dirty_pages': False,
+        'huge_pages': 'None',
+        'shared_memory': False
     }
     expected_cfg['boot-source'] = {
         'kernel_image_path': '/vmlinux.bin',
@@ -0,0 +1,129 @@
+# Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+# SPDX-License-Identifier: Apache-2.0
+"""Test UFFD related functionality when resuming from snapshot."""
+
+import logging
+import os
+import subprocess
+
+from retry import retry
+
+from conftest import init_microvm
+from framework.builder import MicrovmBuilder, SnapshotBuilder
+from framework.utils import run_cmd
+from host_tools.cargo_build import get_example_binary
+
+import host_tools.network as net_tools  # pylint: disable=import-error
+
+SOCKET_PATH = "uffd.sock"
+
+
+@retry(delay=0.2, tries=10)
+def _wait_for_socket(socket_path):
+    assert os.path.exists(socket_path)
+
+
+def _spawn_handler(vm, mem_file_path):
+    """Start the example handler, listening inside the jail of `vm`."""
+    handler_bin = get_example_binary("uffd_valid_handler")
+    socket_path = os.path.join(vm.jailer.chroot_path(), SOCKET_PATH)
+
+    # pylint: disable=consider-using-with
+    handler = subprocess.Popen([handler_bin, socket_path, mem_file_path])
+    _wait_for_socket(socket_path)
+    # Firecracker runs as an unprivileged user inside the jail.
+    run_cmd('chown {}:{} {}'.format(vm.jailer.uid, vm.jailer.gid,
+                                    socket_path))
+    return handler
+
+
+def _spawn_vm_for_snapshot(bin_cloner_path, root_path, snapshot):
+    """Spawn a microVM with the resources needed to load `snapshot`."""
+    vm = init_microvm(root_path, bin_cloner_path)
+    vm.spawn(log_level='Info')
+
+    for disk in snapshot.disks:
+        vm.create_jailed_resource(disk)
+    vm.ssh_config['ssh_key_path'] = snapshot.ssh_key.local_path()
+    for iface in snapshot.net_ifaces:
+        vm.create_tap_and_ssh_config(host_ip=iface.host_ip,
+                                     guest_ip=iface.guest_ip,
+                                     netmask_len=iface.netmask,
+                                     tapname=iface.tap_name)
+    return vm
+
+
+def _load_with_uffd(vm, snapshot):
+    return vm.snapshot.load(
+        snapshot_path=vm.create_jailed_resource(snapshot.vmstate),
+        mem_backend={
+            'backend_path': '/' + SOCKET_PATH,
+            'backend_type': 'Uffd'
+        },
+        resume=True
+    )
+
+
+def _create_snapshot(vm_builder):
+    vm_instance = vm_builder.build_vm_nano()
+    basevm = vm_instance.vm
+    basevm.start()
+
+    ssh_connection = net_tools.SSHConnection(basevm.ssh_config)
+    exit_code, _, _ = ssh_connection.execute_command("sync")
+    assert exit_code == 0
+
+    snapshot = SnapshotBuilder(basevm).create(
+        [vm_instance.disks[0].local_path()],
+        vm_instance.ssh_key
+    )
+    basevm.kill()
+    return snapshot
+
+
+def test_unbound_socket(bin_cloner_path):
+    """
+    Test the error case of loading a snapshot with no handler listening.
+
+    @type: functional
+    """
+    vm_builder = MicrovmBuilder(bin_cloner_path)
+    snapshot = _create_snapshot(vm_builder)
+
+    vm = _spawn_vm_for_snapshot(bin_cloner_path, vm_builder.root_path,
+                                snapshot)
+    response = _load_with_uffd(vm, snapshot)
+
+    assert vm.api_session.is_status_bad_request(response.status_code)
+    assert "Cannot send the userfaultfd to the page fault handler" \
+        in response.text
+
+
+def test_valid_handler(bin_cloner_path):
+    """
+    Test a guest restored with its memory served by a page fault handler.
+
+    @type: functional
+    """
+    logger = logging.getLogger("uffd_valid_handler")
+
+    vm_builder = MicrovmBuilder(bin_cloner_path)
+    logger.info("Create snapshot")
+    snapshot = _create_snapshot(vm_builder)
+
+    logger.info("Load snapshot with the uffd memory backend")
+    vm = _spawn_vm_for_snapshot(bin_cloner_path, vm_builder.root_path,
+                                snapshot)
+    handler = _spawn_handler(vm, snapshot.mem)
+    try:
+        response = _load_with_uffd(vm, snapshot)
+        assert vm.api_session.is_status_no_content(response.status_code), \
+            response.text
+
+        # The guest memory is now served by the handler.
+        ssh_connection = net_tools.SSHConnection(vm.ssh_config)
+        exit_code, _, _ = ssh_connection.execute_command("sync")
+        assert exit_code == 0
+    finally:
+        vm.kill()
+        handler.kill()